@@ -0,0 +1,50 @@
+/*
+ * The C API for clipivot, which gets built with the `ffi` feature:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * See src/ffi.rs for the details of each function.
+ */
+#ifndef CLIPIVOT_H
+#define CLIPIVOT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The description of a pivot table */
+typedef struct ClipivotBuilder ClipivotBuilder;
+/* The table a pivot table computed, or the error that stopped it */
+typedef struct ClipivotResult ClipivotResult;
+
+ClipivotBuilder *clipivot_builder_new(void);
+/* Each of these returns 0 if it worked and -1 otherwise */
+int clipivot_builder_rows(ClipivotBuilder *builder, const char *name);
+int clipivot_builder_columns(ClipivotBuilder *builder, const char *name);
+int clipivot_builder_values(ClipivotBuilder *builder, const char *name);
+int clipivot_builder_aggregate(ClipivotBuilder *builder, const char *function);
+int clipivot_builder_delimiter(ClipivotBuilder *builder, uint8_t delimiter);
+int clipivot_builder_has_headers(ClipivotBuilder *builder, int has_headers);
+int clipivot_builder_skip_empty(ClipivotBuilder *builder, int skip_empty);
+void clipivot_builder_free(ClipivotBuilder *builder);
+
+/* The result needs to be freed with clipivot_result_free, even if it holds an error */
+ClipivotResult *clipivot_run_path(const ClipivotBuilder *builder, const char *path);
+ClipivotResult *clipivot_run_buffer(const ClipivotBuilder *builder, const uint8_t *data, size_t len);
+
+/* NULL if the table was computed; otherwise, a message that lives as long as the result */
+const char *clipivot_result_error(const ClipivotResult *result);
+/* NULL if the result holds an error; otherwise, a string to free with clipivot_string_free */
+char *clipivot_result_csv(const ClipivotResult *result);
+char *clipivot_result_json(const ClipivotResult *result);
+void clipivot_result_free(ClipivotResult *result);
+void clipivot_string_free(char *text);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
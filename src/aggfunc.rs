@@ -10,12 +10,20 @@
 //! the outputs to standard output.
 
 use crate::parsing::DecimalWrapper;
+use crate::spill::Spill;
+use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::Decimal;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::f64::consts::PI;
 use std::marker::PhantomData;
 
 /// Accumulates records from a stream, in order to allow functions to be optimized for minimal memory usage.
-pub trait Accumulate<I, O> {
+///
+/// Accumulators need to be `Clone` so that partial results (like the total of a row) can be
+/// computed without consuming them, `Send` so that records can be aggregated on several
+/// threads (with `merge` combining the results), and `Spill` so that their state can be
+/// written to disk when a table has too many cells to keep in memory.
+pub trait Accumulate<I, O>: Clone + Send + Spill {
     /// Creates a new object with an initial value (often based on the value of `item`.)
     ///
     /// This has a separate function for the initialization because some functions like sample standard deviation
@@ -23,15 +31,88 @@ pub trait Accumulate<I, O> {
     fn new(item: I) -> Self;
     /// Adds a new value to the accumulator.
     fn update(&mut self, item: I);
+    /// Adds a new value to the accumulator, returning an error message (rather than panicking)
+    /// if the result can't be represented, like a sum that overflows. This is what gets called
+    /// for each record, so only accumulators that can overflow need to override it.
+    fn try_update(&mut self, item: I) -> Result<(), String> {
+        self.update(item);
+        Ok(())
+    }
+    /// Creates a new object like `new`, given the position of the value's record in the input.
+    /// Only the accumulators whose result depends on the order of the values (like `Mode`,
+    /// whose ties go to the item that got there first) need to override this, so that merging
+    /// the accumulators of different threads or files gives the same result as adding every
+    /// value in order.
+    fn new_at(item: I, _position: RecordPosition) -> Self {
+        Self::new(item)
+    }
+    /// Adds a new value like `try_update`, given the position of its record (see `new_at`).
+    /// This is what gets called for each record.
+    fn try_update_at(&mut self, item: I, _position: RecordPosition) -> Result<(), String> {
+        self.try_update(item)
+    }
     /// Computes the final value. Returns an option value, which is usually guaranteed to be Some(val)
     /// (with the exception of `StdDev`.)
     fn compute(&self) -> Option<O>;
+    /// Combines another accumulator into this one, so the result is the same as if every value
+    /// added to `other` had been added to `self`. This is what allows rows to get rolled up into
+    /// an "Other" category.
+    fn merge(&mut self, other: Self);
+    /// Combines another accumulator into this one, returning an error message if the result
+    /// can't be represented (like `try_update`).
+    fn try_merge(&mut self, other: Self) -> Result<(), String> {
+        self.merge(other);
+        Ok(())
+    }
+    /// A rough estimate of the memory the accumulator holds beyond its own size (like the values
+    /// a median keeps track of), which `--max-memory` uses to tell when a table has grown too
+    /// large. This gets checked every time a cell is updated, so it has to be cheap to compute,
+    /// and it doesn't count the text of the strings an accumulator holds on to.
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// The position of a record in the input: the index of the file it came from (since several
+/// files get aggregated as if they were one file), followed by its record number in that file.
+/// Positions compare in the order the records appear in the files.
+pub type RecordPosition = (usize, usize);
+
+/// Roughly how much memory each entry of a hash table or B-tree holding `T`s takes up. Both
+/// usually have some room to spare (somewhere between half and all of their slots are full),
+/// and a hash table keeps an extra byte of metadata for each slot.
+pub fn entry_size<T>() -> usize {
+    std::mem::size_of::<T>() * 3 / 2 + 1
+}
+
+/// Addition that returns `None` when the result doesn't fit in the type, instead of panicking
+/// (like `Decimal` does) or wrapping around.
+pub trait CheckedAdd: Sized {
+    /// Adds `other`, or returns `None` if the result overflows.
+    fn checked_add(self, other: Self) -> Option<Self>;
+}
+
+/// Subtraction that returns `None` when the difference doesn't fit in the output type,
+/// instead of panicking (like `Decimal` does).
+pub trait CheckedSub<O>: Sized {
+    /// Subtracts `other`, or returns `None` if the result overflows.
+    fn checked_sub(self, other: Self) -> Option<O>;
+}
+
+/// The error for a sum that's too large (or too small) to represent
+fn overflow_err() -> String {
+    format!(
+        "The sum overflowed (it's more than {} or less than -{})",
+        Decimal::max_value(),
+        Decimal::max_value()
+    )
 }
 
 /// The total number of records added to the accumulator.
+#[derive(Clone)]
 pub struct Count<I>(usize, PhantomData<I>);
 
-impl<I> Accumulate<I, usize> for Count<I> {
+impl<I: Clone + Send> Accumulate<I, usize> for Count<I> {
     fn new(_item: I) -> Count<I> {
         Count(1, PhantomData)
     }
@@ -43,15 +124,23 @@ impl<I> Accumulate<I, usize> for Count<I> {
     fn compute(&self) -> Option<usize> {
         Some(self.0)
     }
+
+    fn merge(&mut self, other: Self) {
+        self.0 += other.0;
+    }
 }
 
 /// The total number of *unique* records.
+#[derive(Clone)]
 pub struct CountUnique<I>(HashSet<I>);
 
 impl<I> Accumulate<I, usize> for CountUnique<I>
 where
     I: std::cmp::Eq,
     I: std::hash::Hash,
+    I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> CountUnique<I> {
         let mut vals = HashSet::new();
@@ -66,22 +155,40 @@ where
     fn compute(&self) -> Option<usize> {
         Some(self.0.len())
     }
+
+    fn merge(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    fn heap_size(&self) -> usize {
+        self.0.len() * entry_size::<I>()
+    }
+}
+
+/// Whether a value can't be compared with anything, even itself (like a floating point `NaN`).
+/// The minimum and maximum treat these values as "sticky", so a `NaN` propagates to the result
+/// rather than being kept or ignored depending on where it appears.
+fn unordered<I: PartialOrd>(item: &I) -> bool {
+    item.partial_cmp(item).is_none()
 }
 
 /// The largest value (or the value that would appear last in a sorted array)
+#[derive(Clone)]
 pub struct Maximum<I>(I);
 
 impl<I> Accumulate<I, I> for Maximum<I>
 where
     I: std::cmp::PartialOrd,
     I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> Maximum<I> {
         Maximum(item)
     }
 
     fn update(&mut self, item: I) {
-        if self.0 < item {
+        if !unordered(&self.0) && (unordered(&item) || self.0 < item) {
             self.0 = item;
         }
     }
@@ -89,10 +196,15 @@ where
     fn compute(&self) -> Option<I> {
         Some(self.0.clone())
     }
+
+    fn merge(&mut self, other: Self) {
+        self.update(other.0);
+    }
 }
 
 /// The mean. This is only implemented for `DecimalWrapper`, though it  could probably be extended for floating point
 /// types.
+#[derive(Clone)]
 pub struct Mean {
     running_sum: DecimalWrapper,
     running_count: usize,
@@ -111,16 +223,40 @@ impl Accumulate<DecimalWrapper, DecimalWrapper> for Mean {
         self.running_count += 1;
     }
 
+    fn try_update(&mut self, item: DecimalWrapper) -> Result<(), String> {
+        self.running_sum = self
+            .running_sum
+            .checked_add(item)
+            .ok_or_else(overflow_err)?;
+        self.running_count += 1;
+        Ok(())
+    }
+
     fn compute(&self) -> Option<DecimalWrapper> {
         let decimal_count = Decimal::new(self.running_count as i64, 0);
         let result = self.running_sum.item / decimal_count;
         Some(DecimalWrapper { item: result })
     }
+
+    fn merge(&mut self, other: Self) {
+        self.running_sum.item += other.running_sum.item;
+        self.running_count += other.running_count;
+    }
+
+    fn try_merge(&mut self, other: Self) -> Result<(), String> {
+        self.running_sum = self
+            .running_sum
+            .checked_add(other.running_sum)
+            .ok_or_else(overflow_err)?;
+        self.running_count += other.running_count;
+        Ok(())
+    }
 }
 
 /// The median value. I've stored values in a `BTreeMap` in order to minimize memory usage.
 /// As a result, this is the least performant of all the functions (running at `Nlog(m)`, rather than
 /// the `N` of all the other algorithms (where `m` is the number of *unique* values in the accumulator).
+#[derive(Clone)]
 pub struct Median {
     values: BTreeMap<DecimalWrapper, usize>,
     num: usize,
@@ -145,48 +281,216 @@ impl Accumulate<DecimalWrapper, DecimalWrapper> for Median {
     }
 
     fn compute(&self) -> Option<DecimalWrapper> {
-        let mut cur_count = 0;
-        let mut cur_val = DecimalWrapper {
-            item: Decimal::new(0, 0),
+        Some(median_of(&self.values, self.num))
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (item, count) in other.values {
+            *self.values.entry(item).or_insert(0) += count;
+        }
+        self.num += other.num;
+    }
+
+    fn heap_size(&self) -> usize {
+        self.values.len() * entry_size::<(DecimalWrapper, usize)>()
+    }
+}
+
+/// The median of `num` values, stored as a (non-empty) map from each value to the number of
+/// times it appears
+fn median_of(values: &BTreeMap<DecimalWrapper, usize>, num: usize) -> DecimalWrapper {
+    let mut cur_count = 0;
+    let mut cur_val = DecimalWrapper {
+        item: Decimal::new(0, 0),
+    };
+    // creating an iter bc we're stopping at N/2
+    let mut iter = values.iter();
+    while (cur_count as f64) < (num as f64 / 2.) {
+        // should break before iter.next().is_none()
+        let (result, count) = iter.next().unwrap();
+        cur_count += count;
+        cur_val = *result;
+    }
+    // -- take the mean if we have an even number of records and end at *exactly* the midpoint.
+    if num % 2 == 0 && ((cur_count as f64) - (num as f64 / 2.)).abs() < f64::EPSILON {
+        // iter.next() will always be Some(_) because this is always initialized with
+        let (low, high) = (cur_val.item, iter.next().unwrap().0.item);
+        let two = Decimal::new(2, 0);
+        let item = match low.checked_add(high) {
+            Some(sum) => sum / two,
+            // values whose sum overflows have the same sign, so their difference can't overflow
+            None => low + (high - low) / two,
         };
-        // creating an iter bc we're stopping at N/2
-        let mut iter = self.values.iter();
-        while (cur_count as f64) < (self.num as f64 / 2.) {
-            // should break before iter.next().is_none()
-            let (result, count) = iter.next().unwrap();
-            cur_count += count;
-            cur_val = *result;
-        }
-        // -- take the mean if we have an even number of records and end at *exactly* the midpoint.
-        if (self.num % 2) == 0
-            && ((cur_count as f64) - (self.num as f64 / 2.)).abs() < std::f64::EPSILON
-        {
-            // iter.next() will always be Some(_) because this is always initialized with
-            let median = (cur_val + *iter.next().unwrap().0)
-                / DecimalWrapper {
-                    item: Decimal::new(2, 0),
-                };
-            Some(median)
-        } else {
-            Some(cur_val)
+        DecimalWrapper { item }
+    } else {
+        cur_val
+    }
+}
+
+/// The number of values (for `ApproxMedian`) or distinct items (for `ApproxMode`) that
+/// the approximate functions keep track of. Both of them are exact until they've seen twice
+/// this many distinct values.
+pub const SKETCH_SIZE: usize = 1000;
+
+/// An approximation of the median that uses a bounded amount of memory (with `--approx`),
+/// regardless of how many unique values it sees.
+///
+/// This is a [t-digest](https://github.com/tdunning/t-digest): it works like `Median` until it
+/// holds more than `2 * SKETCH_SIZE` unique values, and then it compresses them by replacing runs
+/// of neighboring values with their (weighted) mean, called a centroid. How many values a centroid
+/// can stand in for depends on where it falls, following the t-digest's k₁ scale function,
+/// `k(q) = δ / 2π · asin(2q - 1)` (where `δ` is `SKETCH_SIZE` and `q` is the quantile): each centroid
+/// spans at most one unit of `k`. So a centroid around quantile `q` holds at most
+/// `2π · √(q(1 - q)) / δ` of the values, which keeps the sketch to about `SKETCH_SIZE / 2` centroids
+/// after each compression. For the median, that's `π / δ` (around 0.3% of the values), so the
+/// median is always within about 0.2 percentiles of the true median, and the bounds are tighter
+/// toward the extremes (within about 0.07 percentiles for the 1st and 99th percentiles).
+#[derive(Clone)]
+pub struct ApproxMedian {
+    values: BTreeMap<DecimalWrapper, usize>,
+    num: usize,
+}
+
+impl ApproxMedian {
+    /// The (approximate) value at quantile `q` (between 0 and 1), interpolating linearly between
+    /// the two values around it. Like the median, this is exact until the values get compressed.
+    pub fn quantile(&self, q: f64) -> DecimalWrapper {
+        let rank = q.clamp(0., 1.) * (self.num - 1) as f64;
+        let lower = rank.floor() as usize;
+        let mut seen = 0;
+        let mut below = None;
+        let mut above = None;
+        for (val, count) in &self.values {
+            seen += count;
+            if below.is_none() && seen > lower {
+                below = Some(*val);
+            }
+            if seen > lower + 1 {
+                above = Some(*val);
+                break;
+            }
+        }
+        // the rank is always less than the number of values
+        let below = below.unwrap();
+        match (above, Decimal::from_f64(rank - lower as f64)) {
+            (Some(above), Some(fraction)) if fraction != Decimal::new(0, 0) => DecimalWrapper {
+                item: (below.item + (above.item - below.item) * fraction)
+                    .round_dp(below.item.scale().max(above.item.scale()) + QUANTILE_DIGITS)
+                    .normalize(),
+            },
+            _ => below,
+        }
+    }
+
+    fn compress(&mut self) {
+        if self.values.len() <= 2 * SKETCH_SIZE {
+            return;
+        }
+        let total = self.num as f64;
+        let mut compressed = BTreeMap::new();
+        let mut values = std::mem::take(&mut self.values).into_iter();
+        // this is always non-empty, since it holds more than 2 * SKETCH_SIZE values
+        let (mut cur_val, mut cur_count) = values.next().unwrap();
+        // the number of values in the centroids before the current one
+        let mut before = 0;
+        let mut limit = quantile_limit(0.);
+        for (val, count) in values {
+            if ((before + cur_count + count) as f64 / total) <= limit {
+                cur_val.item = weighted_mean(cur_val.item, cur_count, val.item, count);
+                cur_count += count;
+            } else {
+                *compressed.entry(cur_val).or_insert(0) += cur_count;
+                before += cur_count;
+                limit = quantile_limit(before as f64 / total);
+                cur_val = val;
+                cur_count = count;
+            }
+        }
+        *compressed.entry(cur_val).or_insert(0) += cur_count;
+        self.values = compressed;
+    }
+}
+
+/// The number of digits `ApproxMedian::quantile` keeps beyond the precision of the values
+const QUANTILE_DIGITS: u32 = 4;
+
+/// The highest quantile a centroid that starts at quantile `q` can reach, which is one unit of
+/// the k₁ scale function above `q`
+fn quantile_limit(q: f64) -> f64 {
+    let delta = SKETCH_SIZE as f64;
+    let k = delta / (2. * PI) * (2. * q - 1.).asin();
+    let angle = 2. * PI * (k + 1.) / delta;
+    if angle >= PI / 2. {
+        1.
+    } else {
+        (angle.sin() + 1.) / 2.
+    }
+}
+
+/// The mean of `first` (appearing `first_count` times) and `second` (appearing `second_count`
+/// times), rounded to the precision of the values (since comparing decimals with different
+/// numbers of digits is slow)
+fn weighted_mean(
+    first: Decimal,
+    first_count: usize,
+    second: Decimal,
+    second_count: usize,
+) -> Decimal {
+    let scale = first.scale().max(second.scale());
+    // moves the first value toward the second one, rather than adding up the values
+    // (which could overflow)
+    let weight =
+        Decimal::from(second_count as u64) / Decimal::from((first_count + second_count) as u64);
+    (first + (second - first) * weight).round_dp(scale)
+}
+
+impl Accumulate<DecimalWrapper, DecimalWrapper> for ApproxMedian {
+    fn new(item: DecimalWrapper) -> ApproxMedian {
+        let mut values = BTreeMap::new();
+        values.insert(item, 1);
+        ApproxMedian { values, num: 1 }
+    }
+
+    fn update(&mut self, item: DecimalWrapper) {
+        *self.values.entry(item).or_insert(0) += 1;
+        self.num += 1;
+        self.compress();
+    }
+
+    fn compute(&self) -> Option<DecimalWrapper> {
+        Some(median_of(&self.values, self.num))
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (item, count) in other.values {
+            *self.values.entry(item).or_insert(0) += count;
         }
+        self.num += other.num;
+        self.compress();
+    }
+
+    fn heap_size(&self) -> usize {
+        self.values.len() * entry_size::<(DecimalWrapper, usize)>()
     }
 }
 
 /// The minimum value
+#[derive(Clone)]
 pub struct Minimum<I>(I);
 
 impl<I> Accumulate<I, I> for Minimum<I>
 where
     I: std::cmp::PartialOrd,
     I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> Minimum<I> {
         Minimum(item)
     }
 
     fn update(&mut self, item: I) {
-        if self.0 > item {
+        if !unordered(&self.0) && (unordered(&item) || self.0 > item) {
             self.0 = item;
         }
     }
@@ -194,10 +498,15 @@ where
     fn compute(&self) -> Option<I> {
         Some(self.0.clone())
     }
+
+    fn merge(&mut self, other: Self) {
+        self.update(other.0);
+    }
 }
 
 /// A combination of the minimum and maximum values, producing a string concatenating
 /// the minimum value and the maximum value together, separated by a hyphen.
+#[derive(Clone)]
 pub struct MinMax<I> {
     max_val: I,
     min_val: I,
@@ -208,6 +517,8 @@ where
     I: std::fmt::Display,
     I: std::cmp::PartialOrd,
     I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> MinMax<I> {
         MinMax {
@@ -217,7 +528,13 @@ where
     }
 
     fn update(&mut self, item: I) {
-        if self.min_val > item {
+        if unordered(&self.min_val) {
+            return;
+        }
+        if unordered(&item) {
+            self.min_val = item.clone();
+            self.max_val = item;
+        } else if self.min_val > item {
             self.min_val = item;
         } else if self.max_val < item {
             self.max_val = item;
@@ -227,6 +544,22 @@ where
     fn compute(&self) -> Option<String> {
         Some(format!("{} - {}", self.min_val, self.max_val))
     }
+
+    fn merge(&mut self, other: Self) {
+        if unordered(&self.min_val) {
+            return;
+        }
+        if unordered(&other.min_val) {
+            *self = other;
+            return;
+        }
+        if self.min_val > other.min_val {
+            self.min_val = other.min_val;
+        }
+        if self.max_val < other.max_val {
+            self.max_val = other.max_val;
+        }
+    }
 }
 
 /// The most commonly appearing item.
@@ -235,10 +568,24 @@ where
 /// the item that reached the maximum value first. So in the case of
 /// ["a", "b", "b", "a"], it will return "b" because "b" was the first
 /// value to appear twice.
+///
+/// The item that reached the maximum first is the one whose last appearance came first, so
+/// each item keeps the position of the last record it appeared in. That lets `merge` pick the
+/// same item no matter how the records were split between threads or files.
+#[derive(Clone)]
 pub struct Mode<I> {
-    histogram: HashMap<I, usize>,
+    histogram: HashMap<I, ModeEntry>,
     max_count: usize,
     max_val: I,
+    /// The position of the last record that was added, for the updates that don't have one
+    last_position: RecordPosition,
+}
+
+/// The number of times an item appeared (for `Mode`), along with the last record it appeared in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModeEntry {
+    count: usize,
+    last_seen: RecordPosition,
 }
 
 impl<I> Accumulate<I, I> for Mode<I>
@@ -247,34 +594,181 @@ where
     I: std::cmp::Eq,
     I: std::hash::Hash,
     I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> Mode<I> {
+        Mode::new_at(item, (0, 0))
+    }
+
+    fn update(&mut self, item: I) {
+        let (file, record) = self.last_position;
+        let _ = self.try_update_at(item, (file, record + 1));
+    }
+
+    fn new_at(item: I, position: RecordPosition) -> Mode<I> {
         let mut histogram = HashMap::new();
         let max_val = item.clone();
-        histogram.insert(item, 1);
+        let entry = ModeEntry {
+            count: 1,
+            last_seen: position,
+        };
+        histogram.insert(item, entry);
         Mode {
             histogram,
             max_count: 1,
             max_val,
+            last_position: position,
         }
     }
 
-    fn update(&mut self, item: I) {
+    fn try_update_at(&mut self, item: I, position: RecordPosition) -> Result<(), String> {
         // barely adapted from https://docs.rs/indexmap/1.0.2/indexmap/map/struct.IndexMap.html
-        let new_count = *self.histogram.get(&item).unwrap_or(&0) + 1;
+        let new_count = self.histogram.get(&item).map_or(0, |entry| entry.count) + 1;
         if new_count > self.max_count {
             self.max_count = new_count;
             self.max_val = item.clone();
         }
-        *self.histogram.entry(item).or_insert(0) += 1;
+        self.histogram.insert(
+            item,
+            ModeEntry {
+                count: new_count,
+                last_seen: position,
+            },
+        );
+        self.last_position = position;
+        Ok(())
     }
 
     fn compute(&self) -> Option<I> {
         Some(self.max_val.clone())
     }
+
+    fn merge(&mut self, other: Self) {
+        for (item, entry) in other.histogram {
+            let merged = self.histogram.entry(item).or_insert(ModeEntry {
+                count: 0,
+                last_seen: entry.last_seen,
+            });
+            merged.count += entry.count;
+            merged.last_seen = merged.last_seen.max(entry.last_seen);
+        }
+        self.last_position = self.last_position.max(other.last_position);
+        // the item that reached the highest count first is the one whose last record came first
+        let mode = self.histogram.iter().max_by(|(_, a), (_, b)| {
+            a.count
+                .cmp(&b.count)
+                .then_with(|| b.last_seen.cmp(&a.last_seen))
+        });
+        if let Some((item, entry)) = mode {
+            self.max_count = entry.count;
+            self.max_val = item.clone();
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.histogram.len() * entry_size::<(I, ModeEntry)>()
+    }
+}
+
+/// An approximation of the mode that uses a bounded amount of memory (with `--approx`),
+/// regardless of how many distinct items it sees.
+///
+/// This works like `Mode`, except that once it has counted more than `2 * SKETCH_SIZE` distinct
+/// items, it forgets all but the `SKETCH_SIZE` most common ones. (This is a variant of the
+/// [Misra-Gries algorithm](https://en.wikipedia.org/wiki/Misra%E2%80%93Gries_summary).)
+/// An item that gets forgotten starts being counted again from zero, so any item making up
+/// a sizable fraction of the values is still found, but the counts of rare items (and so the
+/// choice between items that are nearly tied) may be off.
+#[derive(Clone)]
+pub struct ApproxMode<I> {
+    histogram: HashMap<I, usize>,
+    max_count: usize,
+    max_val: I,
+}
+
+impl<I> ApproxMode<I>
+where
+    I: std::cmp::PartialOrd,
+    I: std::cmp::Eq,
+    I: std::hash::Hash,
+{
+    fn prune(&mut self) {
+        if self.histogram.len() <= 2 * SKETCH_SIZE {
+            return;
+        }
+        let mut counts: Vec<(I, usize)> = self.histogram.drain().collect();
+        // items with the same count are kept in order, not in the (random) order of the histogram
+        counts.sort_unstable_by(|(item, count), (other, other_count)| {
+            other_count
+                .cmp(count)
+                .then(item.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        counts.truncate(SKETCH_SIZE);
+        self.histogram = counts.into_iter().collect();
+    }
+}
+
+impl<I> Accumulate<I, I> for ApproxMode<I>
+where
+    I: std::cmp::PartialOrd,
+    I: std::cmp::Eq,
+    I: std::hash::Hash,
+    I: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
+{
+    fn new(item: I) -> ApproxMode<I> {
+        let mut histogram = HashMap::new();
+        let max_val = item.clone();
+        histogram.insert(item, 1);
+        ApproxMode {
+            histogram,
+            max_count: 1,
+            max_val,
+        }
+    }
+
+    fn update(&mut self, item: I) {
+        let count = self.histogram.entry(item.clone()).or_insert(0);
+        *count += 1;
+        if *count > self.max_count {
+            self.max_count = *count;
+            self.max_val = item;
+        }
+        self.prune();
+    }
+
+    fn compute(&self) -> Option<I> {
+        Some(self.max_val.clone())
+    }
+
+    fn merge(&mut self, other: Self) {
+        // ties go to the smallest item, like they do for `Mode`
+        for (item, count) in other.histogram {
+            let new_count = self.histogram.get(&item).unwrap_or(&0) + count;
+            if new_count > self.max_count || (new_count == self.max_count && item < self.max_val) {
+                self.max_count = new_count;
+                self.max_val = item.clone();
+            }
+            self.histogram.insert(item, new_count);
+        }
+        if other.max_count > self.max_count {
+            self.max_count = other.max_count;
+            self.max_val = other.max_val;
+        }
+        self.prune();
+    }
+
+    fn heap_size(&self) -> usize {
+        self.histogram.len() * entry_size::<(I, usize)>()
+    }
 }
 
 /// The range, or the difference between the minimum and maximum values (where the minimum value is subtracted from the maximum value).
+/// A range that doesn't fit in the output type is an error as soon as the value that makes it
+/// too large gets added.
+#[derive(Clone)]
 pub struct Range<I, O> {
     max_val: I,
     min_val: I,
@@ -284,8 +778,13 @@ pub struct Range<I, O> {
 impl<I, O> Accumulate<I, O> for Range<I, O>
 where
     I: std::cmp::PartialOrd,
-    I: std::ops::Sub<Output = O>,
+    I: CheckedSub<O>,
+    I: std::fmt::Display,
     I: std::marker::Copy,
+    O: std::clone::Clone,
+    I: std::marker::Send,
+    I: Spill,
+    O: std::marker::Send,
 {
     #[allow(clippy::clone_on_copy)]
     fn new(item: I) -> Range<I, O> {
@@ -305,57 +804,208 @@ where
         }
     }
 
+    fn try_update(&mut self, item: I) -> Result<(), String> {
+        self.update(item);
+        self.check_overflow()
+    }
+
     fn compute(&self) -> Option<O> {
-        Some(self.max_val - self.min_val)
+        self.max_val.checked_sub(self.min_val)
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.update(other.min_val);
+        self.update(other.max_val);
+    }
+
+    fn try_merge(&mut self, other: Self) -> Result<(), String> {
+        self.merge(other);
+        self.check_overflow()
+    }
+}
+
+impl<I, O> Range<I, O>
+where
+    I: CheckedSub<O>,
+    I: std::fmt::Display,
+    I: std::marker::Copy,
+{
+    /// Returns an error if the difference between the minimum and maximum overflows
+    fn check_overflow(&self) -> Result<(), String> {
+        match self.max_val.checked_sub(self.min_val) {
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "The range overflowed (the difference between {} and {} is too large to represent)",
+                self.max_val, self.min_val
+            )),
+        }
     }
 }
 
-/// Computes the *sample* variance in a single pass, using
-/// [Welford's algorithm](https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm).
+/// An exact sum of floating point numbers, stored as a list of non-overlapping partial sums
+/// from smallest to largest (Shewchuk's algorithm, as it's used by Python's `math.fsum`).
+/// Since nothing gets rounded until `total` is computed, the result doesn't depend on the order
+/// in which the numbers were added, so it's the same however the records are split between threads.
+#[derive(Clone, Default)]
+struct ExactSum {
+    partials: Vec<f64>,
+    /// The (ordinary) sum of the `NaN` and infinite numbers, which can't be added exactly
+    non_finite: f64,
+}
+
+impl ExactSum {
+    fn add(&mut self, mut item: f64) {
+        if !item.is_finite() {
+            self.non_finite += item;
+            return;
+        }
+        let mut kept = 0;
+        for idx in 0..self.partials.len() {
+            let mut partial = self.partials[idx];
+            if item.abs() < partial.abs() {
+                std::mem::swap(&mut item, &mut partial);
+            }
+            let high = item + partial;
+            let low = partial - (high - item);
+            if low != 0. {
+                self.partials[kept] = low;
+                kept += 1;
+            }
+            item = high;
+        }
+        self.partials.truncate(kept);
+        self.partials.push(item);
+    }
+
+    /// Adds the exact product of two numbers
+    fn add_product(&mut self, first: f64, second: f64) {
+        let product = first * second;
+        self.add(product);
+        // a fused multiply-add computes the rounding error of the product exactly
+        self.add(first.mul_add(second, -product));
+    }
+
+    fn merge(&mut self, other: &ExactSum) {
+        for &partial in &other.partials {
+            self.add(partial);
+        }
+        self.non_finite += other.non_finite;
+    }
+
+    /// The sum, rounded to the nearest floating point number
+    fn total(&self) -> f64 {
+        // (this is also true of NaN)
+        if self.non_finite != 0. {
+            return self.non_finite;
+        }
+        let partials = &self.partials;
+        let mut idx = partials.len();
+        if idx == 0 {
+            return 0.;
+        }
+        idx -= 1;
+        let mut high = partials[idx];
+        let mut low = 0.;
+        while idx > 0 {
+            let sum = high;
+            idx -= 1;
+            let partial = partials[idx];
+            high = sum + partial;
+            low = partial - (high - sum);
+            if low != 0. {
+                break;
+            }
+        }
+        // round half to even, rather than rounding twice, when the rest of the partials
+        // push the sum past the halfway point
+        if idx > 0 && ((low < 0. && partials[idx - 1] < 0.) || (low > 0. && partials[idx - 1] > 0.))
+        {
+            let doubled = low * 2.;
+            let rounded = high + doubled;
+            if doubled == rounded - high {
+                high = rounded;
+            }
+        }
+        high
+    }
+}
 
-/// The attributes in this method refer to the same ones described in
-/// *Accuracy and Stability of Numerical Algorithms* by Higham (2nd Edition, page 11).
+/// Computes the *sample* standard deviation in a single pass. This keeps exact sums of the
+/// values and of their squares (with `ExactSum`), so it doesn't lose precision to cancellation
+/// and gives exactly the same result no matter what order the values are added (or merged) in.
+#[derive(Clone)]
 pub struct StdDev {
-    // solution from Nicholas Higham: Accuracy and Stability of Numerical Algorithms
-    // Second Edition, 2002, p. 11
-    q: f64,
-    m: f64,
+    sum: ExactSum,
+    sum_of_squares: ExactSum,
     /// The number of records parsed so far
     num_records: f64,
 }
 
 impl Accumulate<f64, f64> for StdDev {
     fn new(item: f64) -> Self {
-        StdDev {
-            q: 0.,
-            m: item,
-            num_records: 1.,
-        }
+        let mut stddev = StdDev {
+            sum: ExactSum::default(),
+            sum_of_squares: ExactSum::default(),
+            num_records: 0.,
+        };
+        stddev.update(item);
+        stddev
     }
 
     fn update(&mut self, item: f64) {
         self.num_records += 1.;
-        let squared_diff = (item - self.m).powi(2);
-        self.q += ((self.num_records - 1.) * squared_diff) / self.num_records;
-        self.m += (item - self.m) / self.num_records;
+        self.sum.add(item);
+        self.sum_of_squares.add_product(item, item);
     }
 
     fn compute(&self) -> Option<f64> {
         if self.num_records <= 1. {
             return None;
         }
-        Some((self.q / (self.num_records - 1.)).sqrt())
+        // the standard deviation of values including an infinity (or NaN) is undefined
+        if !self.sum.total().is_finite() {
+            return Some(f64::NAN);
+        }
+        // n * (the sum of squares) - (the sum)^2, computed exactly and then rounded once
+        let mut numerator = ExactSum::default();
+        for &partial in &self.sum_of_squares.partials {
+            numerator.add_product(partial, self.num_records);
+        }
+        for &first in &self.sum.partials {
+            for &second in &self.sum.partials {
+                numerator.add_product(-first, second);
+            }
+        }
+        let numerator = numerator.total();
+        // the squares of values past about 1.34e154 overflow, which would otherwise leave them
+        // out (or leave a NaN that looks like 0 once it's clamped)
+        if !self.sum_of_squares.total().is_finite() || !numerator.is_finite() {
+            return Some(f64::INFINITY);
+        }
+        // rounding can only leave a numerator slightly below 0 when the values are all equal
+        let variance = numerator / (self.num_records * (self.num_records - 1.));
+        Some(variance.max(0.).sqrt())
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.sum.merge(&other.sum);
+        self.sum_of_squares.merge(&other.sum_of_squares);
+        self.num_records += other.num_records;
     }
 }
 
 /// The running sum of a stream of values.
+#[derive(Clone)]
 pub struct Sum<I>(I);
 
 impl<I> Accumulate<I, I> for Sum<I>
 where
     I: std::ops::AddAssign,
+    I: CheckedAdd,
     I: std::fmt::Display,
     I: std::marker::Copy,
+    I: std::marker::Send,
+    I: Spill,
 {
     fn new(item: I) -> Sum<I> {
         Sum(item)
@@ -365,9 +1015,225 @@ where
         self.0 += item;
     }
 
+    fn try_update(&mut self, item: I) -> Result<(), String> {
+        self.0 = self.0.checked_add(item).ok_or_else(overflow_err)?;
+        Ok(())
+    }
+
     fn compute(&self) -> Option<I> {
         Some(self.0)
     }
+
+    fn merge(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+
+    fn try_merge(&mut self, other: Self) -> Result<(), String> {
+        self.try_update(other.0)
+    }
+}
+
+// The state of each accumulator, for spilling cells to disk (see the `spill` module)
+
+impl<I> Spill for Count<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.0.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Count(usize::load(fields)?, PhantomData))
+    }
+}
+
+impl<I: Spill + Eq + std::hash::Hash> Spill for CountUnique<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.0.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(CountUnique(HashSet::load(fields)?))
+    }
+}
+
+impl<I: Spill> Spill for Maximum<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.0.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Maximum(I::load(fields)?))
+    }
+}
+
+impl<I: Spill> Spill for Minimum<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.0.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Minimum(I::load(fields)?))
+    }
+}
+
+impl Spill for Mean {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.running_sum.save(fields);
+        self.running_count.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Mean {
+            running_sum: DecimalWrapper::load(fields)?,
+            running_count: usize::load(fields)?,
+        })
+    }
+}
+
+impl Spill for Median {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.values.save(fields);
+        self.num.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Median {
+            values: BTreeMap::load(fields)?,
+            num: usize::load(fields)?,
+        })
+    }
+}
+
+impl Spill for ApproxMedian {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.values.save(fields);
+        self.num.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(ApproxMedian {
+            values: BTreeMap::load(fields)?,
+            num: usize::load(fields)?,
+        })
+    }
+}
+
+impl<I: Spill> Spill for MinMax<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.min_val.save(fields);
+        self.max_val.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(MinMax {
+            min_val: I::load(fields)?,
+            max_val: I::load(fields)?,
+        })
+    }
+}
+
+impl Spill for ModeEntry {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.count.save(fields);
+        self.last_seen.0.save(fields);
+        self.last_seen.1.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(ModeEntry {
+            count: usize::load(fields)?,
+            last_seen: (usize::load(fields)?, usize::load(fields)?),
+        })
+    }
+}
+
+impl<I: Spill + Eq + std::hash::Hash> Spill for Mode<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.histogram.save(fields);
+        self.max_count.save(fields);
+        self.max_val.save(fields);
+        self.last_position.0.save(fields);
+        self.last_position.1.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Mode {
+            histogram: HashMap::load(fields)?,
+            max_count: usize::load(fields)?,
+            max_val: I::load(fields)?,
+            last_position: (usize::load(fields)?, usize::load(fields)?),
+        })
+    }
+}
+
+impl<I: Spill + Eq + std::hash::Hash> Spill for ApproxMode<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.histogram.save(fields);
+        self.max_count.save(fields);
+        self.max_val.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(ApproxMode {
+            histogram: HashMap::load(fields)?,
+            max_count: usize::load(fields)?,
+            max_val: I::load(fields)?,
+        })
+    }
+}
+
+impl<I: Spill, O> Spill for Range<I, O> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.min_val.save(fields);
+        self.max_val.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Range {
+            min_val: I::load(fields)?,
+            max_val: I::load(fields)?,
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl Spill for ExactSum {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.partials.save(fields);
+        self.non_finite.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(ExactSum {
+            partials: Vec::load(fields)?,
+            non_finite: f64::load(fields)?,
+        })
+    }
+}
+
+impl Spill for StdDev {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.sum.save(fields);
+        self.sum_of_squares.save(fields);
+        self.num_records.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(StdDev {
+            sum: ExactSum::load(fields)?,
+            sum_of_squares: ExactSum::load(fields)?,
+            num_records: f64::load(fields)?,
+        })
+    }
+}
+
+impl<I: Spill> Spill for Sum<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.0.save(fields);
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        Some(Sum(I::load(fields)?))
+    }
 }
 
 #[cfg(test)]
@@ -408,7 +1274,7 @@ mod tests {
     fn test_max_dates() {
         // there's probably a better way of handling this, but this uses the same
         // code as parsing does, so shouldn't affect either
-        let _ex = parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
+        parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
         let date_updates = vec![
             "2019-02-03 12:23:10",
             "2020-01-03 13:45:02",
@@ -453,7 +1319,7 @@ mod tests {
     fn test_min_dates() {
         // there's probably a better way of handling this, but this uses the same
         // code as parsing does, so shouldn't affect either
-        let _ex = parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
+        parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
         let date_updates = vec![
             "2019-02-03 12:23:10",
             "2020-01-03 13:45:02",
@@ -504,7 +1370,7 @@ mod tests {
     fn test_minmax_dates() {
         // there's probably a better way of handling this, but this uses the same
         // code as parsing does, so shouldn't affect either
-        let _ex = parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
+        parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
         let date_updates = vec![
             "2019-02-03 12:23:10",
             "2020-01-03 13:45:02",
@@ -540,7 +1406,7 @@ mod tests {
     fn test_range_dates() {
         // there's probably a better way of handling this, but this uses the same
         // code as parsing does, so shouldn't affect either
-        let _ex = parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
+        parsing::set_date_format("%Y-%m-%d %H:%M:%S".to_string());
         let date_updates = vec![
             "2019-02-03 00:00:00",
             "2020-01-03 12:00:00",
@@ -571,7 +1437,7 @@ mod tests {
         assert_eq!(dec_vals.compute().unwrap().to_string(), "2.5".to_string());
         let mult_middle_vals: DecimalWrapper = "3".parse().unwrap();
         let mut mult_median = Median::new(mult_middle_vals);
-        for val in vec!["5", "6", "1", "4", "3"] {
+        for val in ["5", "6", "1", "4", "3"] {
             mult_median.update(val.parse().unwrap());
         }
         assert_eq!(
@@ -580,6 +1446,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_approx_median() {
+        // with only a few unique values, the approximation is exact
+        let decimals = |vals: &[&str]| -> Vec<DecimalWrapper> {
+            vals.iter().map(|v| v.parse().unwrap()).collect()
+        };
+        let mut approx: ApproxMedian = accumulate(decimals(&["5", "6", "1", "4", "3", "3"]));
+        assert_eq!(approx.compute().unwrap().to_string(), "3.5");
+        approx.merge(accumulate(decimals(&["10", "10"])));
+        assert_eq!(approx.compute().unwrap().to_string(), "4.5");
+        assert_eq!(approx.quantile(0.5).to_string(), "4.5");
+        assert_eq!(approx.quantile(0.25).to_string(), "3");
+        assert_eq!(approx.quantile(0.9).to_string(), "10");
+        // with lots of unique values, it keeps a bounded number of them and stays close
+        let mut approx = ApproxMedian::new("0".parse().unwrap());
+        for i in 1..20_000 {
+            approx.update(DecimalWrapper {
+                item: Decimal::from((i * 7919) % 20_000),
+            });
+        }
+        assert!(approx.values.len() <= 2 * SKETCH_SIZE);
+        let median = approx.compute().unwrap().item;
+        assert!((median - Decimal::from(10_000)).abs() < Decimal::from(40));
+        // the tails are even closer
+        let tail = approx.quantile(0.01).item;
+        assert!((tail - Decimal::from(200)).abs() < Decimal::from(15));
+        assert_eq!(approx.quantile(0.).to_string(), "0");
+        assert_eq!(approx.quantile(1.).to_string(), "19999");
+    }
+
+    #[test]
+    fn test_approx_mode() {
+        let strings =
+            |vals: &[&str]| -> Vec<String> { vals.iter().map(|v| v.to_string()).collect() };
+        let mut approx: ApproxMode<String> = accumulate(strings(&["b", "a", "b", "a"]));
+        assert_eq!(approx.compute().unwrap(), "b");
+        approx.merge(accumulate(strings(&["a"])));
+        assert_eq!(approx.compute().unwrap(), "a");
+        // a common item is still found among lots of rare ones
+        let mut approx = ApproxMode::new(0);
+        for i in 1..100_000 {
+            approx.update(if i % 10 == 0 { 42 } else { i });
+        }
+        assert!(approx.histogram.len() <= 2 * SKETCH_SIZE);
+        assert_eq!(approx.compute().unwrap(), 42);
+    }
+
     #[test]
     fn test_range_decimals() {
         let updates = vec!["1.2", "2E3", "10000"];
@@ -624,8 +1537,178 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sum_overflow() {
+        let max = DecimalWrapper {
+            item: Decimal::max_value(),
+        };
+        let one: DecimalWrapper = "1".parse().unwrap();
+        let mut summation = Sum::new(max);
+        assert!(summation.try_update(one).is_err());
+        // the sum is left as it was, so it's still the largest value that could be represented
+        assert_eq!(summation.compute().unwrap(), max);
+        let negative: DecimalWrapper = "-1".parse().unwrap();
+        assert!(summation.try_update(negative).is_ok());
+        let mut mean = Mean::new(max);
+        assert!(mean.try_merge(Mean::new(max)).is_err());
+        assert!(mean.try_update(negative).is_ok());
+        assert_eq!(
+            mean.compute().unwrap().item,
+            (max.item - one.item) / Decimal::new(2, 0)
+        );
+    }
+
+    #[test]
+    fn test_range_and_median_overflow() {
+        let max = DecimalWrapper {
+            item: Decimal::max_value(),
+        };
+        let min = DecimalWrapper {
+            item: Decimal::min_value(),
+        };
+        let one: DecimalWrapper = "1".parse().unwrap();
+        let mut range: Range<DecimalWrapper, Decimal> = Range::new(max);
+        assert!(range.try_update(one).is_ok());
+        assert!(range.try_update(min).is_err());
+        let mut merged: Range<DecimalWrapper, Decimal> = Range::new(max);
+        assert!(merged.try_merge(Range::new(min)).is_err());
+        // the midpoint of two values whose sum overflows is still between them
+        let below_max = DecimalWrapper {
+            item: max.item - one.item,
+        };
+        let median: Median = accumulate(vec![max, below_max]);
+        let midpoint = median.compute().unwrap();
+        assert!(below_max <= midpoint && midpoint <= max);
+        let median: Median = accumulate(vec![min, max]);
+        assert_eq!(median.compute().unwrap().item, Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn test_nan_propagates() {
+        let values = vec![1., f64::NAN, 3.];
+        let max: Maximum<f64> = accumulate(values.clone());
+        assert!(max.compute().unwrap().is_nan());
+        let min: Minimum<f64> = accumulate(values.clone());
+        assert!(min.compute().unwrap().is_nan());
+        let minmax: MinMax<f64> = accumulate(values);
+        assert_eq!(minmax.compute().unwrap(), "NaN - NaN");
+        let mut merged: Maximum<f64> = accumulate(vec![1., 2.]);
+        merged.merge(Maximum::new(f64::NAN));
+        assert!(merged.compute().unwrap().is_nan());
+        let infinite: Maximum<f64> = accumulate(vec![1., f64::INFINITY, 3.]);
+        assert_eq!(infinite.compute(), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn test_exact_sum() {
+        let mut sum = ExactSum::default();
+        for &item in &[1e100, 1., -1e100, 1e-20, 0.1, 0.2] {
+            sum.add(item);
+        }
+        // adding these up one at a time would lose the 1
+        assert_eq!(sum.total(), 1.3);
+        let mut reversed = ExactSum::default();
+        for &item in &[0.2, 0.1, 1e-20, -1e100, 1., 1e100] {
+            reversed.add(item);
+        }
+        assert_eq!(sum.total(), reversed.total());
+        let values = [0.1, 0.7, 1e9 + 0.3, 12.5, -3.25];
+        let sequential: StdDev = accumulate(values.to_vec());
+        let mut merged: StdDev = accumulate(values[3..].to_vec());
+        merged.merge(accumulate(values[..3].to_vec()));
+        assert_eq!(sequential.compute(), merged.compute());
+    }
+
+    #[test]
+    fn test_mode_merge_ties() {
+        // builds a mode from the values of the records at `positions`
+        let mode_at = |vals: &[(&str, RecordPosition)]| {
+            let mut mode = Mode::new_at(vals[0].0.to_string(), vals[0].1);
+            for (val, position) in &vals[1..] {
+                mode.try_update_at(val.to_string(), *position).unwrap();
+            }
+            mode
+        };
+        let records = [
+            ("b", (0, 0)),
+            ("a", (0, 1)),
+            ("a", (0, 2)),
+            ("b", (0, 3)),
+            ("c", (1, 0)),
+            ("c", (1, 1)),
+        ];
+        // "a" was the first to appear twice, however the records get split up
+        assert_eq!(mode_at(&records).compute().unwrap(), "a");
+        for split in 1..records.len() {
+            for _ in 0..20 {
+                let mut first = mode_at(&records[..split]);
+                first.merge(mode_at(&records[split..]));
+                assert_eq!(first.compute().unwrap(), "a", "split at {}", split);
+                let mut second = mode_at(&records[split..]);
+                second.merge(mode_at(&records[..split]));
+                assert_eq!(second.compute().unwrap(), "a", "split at {}", split);
+            }
+        }
+        let mut odd = mode_at(&[records[0], records[2], records[4]]);
+        odd.merge(mode_at(&[records[1], records[3], records[5]]));
+        assert_eq!(odd.compute().unwrap(), "a");
+        let mut spilled = vec![];
+        odd.save(&mut spilled);
+        let loaded = Mode::<String>::load(&mut spilled.iter().map(String::as_str)).unwrap();
+        assert_eq!(loaded.histogram, odd.histogram);
+    }
+
+    /// Builds an accumulator from a (non-empty) list of values
+    fn accumulate<T: Accumulate<I, O>, I, O>(items: Vec<I>) -> T {
+        let mut items = items.into_iter();
+        let mut acc = T::new(items.next().unwrap());
+        for item in items {
+            acc.update(item);
+        }
+        acc
+    }
+
+    #[test]
+    fn test_merge() {
+        let decimals = |vals: &[&str]| -> Vec<DecimalWrapper> {
+            vals.iter().map(|v| v.parse().unwrap()).collect()
+        };
+        let mut mean: Mean = accumulate(decimals(&["1", "2"]));
+        mean.merge(accumulate(decimals(&["6"])));
+        assert_eq!(mean.compute().unwrap().to_string(), "3");
+        let mut median: Median = accumulate(decimals(&["1", "10"]));
+        median.merge(accumulate(decimals(&["2", "3", "3"])));
+        assert_eq!(median.compute().unwrap().to_string(), "3");
+        let mut minmax: MinMax<DecimalWrapper> = accumulate(decimals(&["4", "5"]));
+        minmax.merge(accumulate(decimals(&["1", "2"])));
+        assert_eq!(minmax.compute().unwrap(), "1 - 5");
+        let strings =
+            |vals: &[&str]| -> Vec<String> { vals.iter().map(|v| v.to_string()).collect() };
+        let mut mode: Mode<String> = accumulate(strings(&["a", "b", "a"]));
+        mode.merge(accumulate(strings(&["b", "b"])));
+        assert_eq!(mode.compute().unwrap(), "b");
+        let mut unique: CountUnique<String> = accumulate(strings(&["a", "b"]));
+        unique.merge(accumulate(strings(&["b", "c"])));
+        assert_eq!(unique.compute().unwrap(), 3);
+        let mut count: Count<String> = accumulate(strings(&["a", "b"]));
+        count.merge(accumulate(strings(&["c"])));
+        assert_eq!(count.compute().unwrap(), 3);
+    }
+
     proptest! {
         #![proptest_config(Config::with_cases(100))]
+        #[test]
+        fn test_stddev_merge_matches_update(
+            first in prop::collection::vec(-1e6f64..1e6, 1 .. 50),
+            second in prop::collection::vec(-1e6f64..1e6, 1 .. 50)
+        ) {
+            let mut merged: StdDev = accumulate(first.clone());
+            merged.merge(accumulate(second.clone()));
+            let combined: StdDev = accumulate(first.into_iter().chain(second).collect());
+            let (merged, combined) = (merged.compute().unwrap(), combined.compute().unwrap());
+            assert!(approx::relative_eq!(merged, combined, max_relative = 1e-9, epsilon = 1e-6));
+        }
+
         #[test]
         fn test_count_gets_raw_count(mut string_vecs in prop::collection::vec(any::<String>(), 1 .. 50)) {
             let total_count = string_vecs.len();
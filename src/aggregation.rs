@@ -3,39 +3,272 @@
 //! It has three main methods: `new`, which initializes the data; `aggregate`, which takes
 //! a csv file and creates an `IndexMap` of `Accumulator`s; and `write_results` which
 //! outputs the aggregated values to standard output.
-use crate::aggfunc::Accumulate;
-use crate::errors::{CsvCliError, CsvCliResult};
-use crate::parsing::INPUT_DATE_FORMAT;
-use indexmap::set::IndexSet;
+use crate::aggfunc::{entry_size, Accumulate};
+use crate::cache::ParseCache;
+use crate::cells::Cells;
+use crate::errors::{CsvCliError, CsvCliResult, ErrorColumn};
+use crate::expression::Expr;
+use crate::hashing::FastIndexSet;
+use crate::index::Chunk;
+use crate::keys::KeyField;
+use crate::output::{CellTransform, NumberLocale, PivotTable, RecodeRule, WriteCell};
+use crate::parsing::ValueParser;
+use crate::pivot::{PivotRow, Rows, Table};
+use crate::progress::{format_bytes, ProgressBar, REDRAW_INTERVAL};
+use crate::record::{record_text, Record};
+use crate::rejects::RejectWriter;
+use crate::sorting::{par_sort_by, Comparator};
+use crate::spill::{merge_runs, CellOrder, Spill, SpillRun, SpilledCell};
+use crate::sql::Crosstab;
+use indexmap::map::IndexMap;
+use indexmap::Equivalent;
 use lazy_static::lazy_static;
-use std::collections::hash_map::Entry;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
 
-const FIELD_SEPARATOR: &str = "_<sep>_";
+/// The position of a row's aggregate in the record that `Aggregator::with_having` conditions see
+pub const HAVING_VALUE: usize = 0;
+/// The position of a row's number of records in the record that `Aggregator::with_having` conditions see
+pub const HAVING_COUNT: usize = 1;
+
+/// The label of the row and column holding the totals, when `Aggregator::with_totals` is set
+pub const TOTAL_LABEL: &str = "Total";
+
+/// The default string used to join the values of multiple column fields into a single header
+pub const FIELD_SEPARATOR: &str = "_<sep>_";
+const ESCAPE_CHAR: char = '\\';
+/// The first field of a saved state (see `Aggregator::save_state`)
+const STATE_MARKER: &str = "clipivot-state";
+/// The first field of a checkpoint (see `Aggregator::with_checkpoints`)
+const CHECKPOINT_MARKER: &str = "clipivot-checkpoint";
+/// The version of the format states (and checkpoints) get saved in, which changes whenever the layout of the state
+/// of an accumulator does
+const STATE_VERSION: &str = "1";
+/// How many records get read between updates of the progress bar
+const PROGRESS_INTERVAL: usize = 1000;
+/// How many records get sent to an aggregation thread at a time, when there is more than one thread
+const THREAD_BATCH_SIZE: usize = 1000;
+/// How many batches of records can be waiting for each aggregation thread
+const CHANNEL_BATCHES_PER_THREAD: usize = 4;
+/// The fewest cells a plain table needs before its cells get formatted on several threads
+const PARALLEL_WRITE_MIN_CELLS: usize = 100_000;
+/// Roughly how many cells each thread formats at a time, when a table gets written on several
+/// threads
+const WRITE_BLOCK_CELLS: usize = 1 << 16;
 lazy_static! {
-    static ref EMPTY_VALUES: HashSet<&'static str> = vec!["", "null", "nan", "none", "na", "n/a"]
-        .iter()
-        .cloned()
-        .collect();
+    pub(crate) static ref EMPTY_VALUES: HashSet<&'static str> =
+        ["", "null", "nan", "none", "na", "n/a"]
+            .iter()
+            .cloned()
+            .collect();
+}
+
+/// Joins the values of a composite key, escaping any values that would otherwise make
+/// the joined string ambiguous. (Keys with a single value are left alone.)
+pub(crate) fn join_key(key: &[String], separator: &str) -> String {
+    if key.len() == 1 || separator.is_empty() {
+        return key.join(separator);
+    }
+    let escaped_sep = format!("{}{}", ESCAPE_CHAR, separator);
+    key.iter()
+        .map(|val| {
+            val.replace(ESCAPE_CHAR, &ESCAPE_CHAR.to_string().repeat(2))
+                .replace(separator, &escaped_sep)
+        })
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// The ID of a row (or column) key: its position among the rows (or columns)
+fn key_id(keys: &FastIndexSet<Vec<String>>, key: &[String]) -> Option<usize> {
+    keys.get_full(key).map(|(id, _)| id)
+}
+
+/// Looks up the ID of a row (or column) key, adding the key (along with its record count and the
+/// line it first appears on) if it's new
+fn intern(
+    keys: &mut FastIndexSet<Vec<String>>,
+    counts: &mut Vec<usize>,
+    lines: &mut Vec<usize>,
+    key: &[String],
+    line_num: usize,
+) -> usize {
+    let id = match key_id(keys, key) {
+        Some(id) => id,
+        None => {
+            keys.insert(key.to_vec());
+            counts.push(0);
+            lines.push(line_num);
+            keys.len() - 1
+        }
+    };
+    counts[id] += 1;
+    id
+}
+
+/// The most fields a key can have and still get looked up without being copied out of the
+/// record (see `Aggregator::add_count`)
+const MAX_BORROWED_FIELDS: usize = 4;
+/// The number of skipped records whose line numbers get kept (see `with_skip_bad_records`)
+pub const BAD_RECORDS_KEPT: usize = 5;
+
+/// A row or column key made of fields borrowed from a record. It hashes just like the
+/// `Vec<String>` the key gets stored as, so it can be looked up without allocating anything.
+struct BorrowedKey<'a, 'b>(&'b [&'a str]);
+
+impl Hash for BorrowedKey<'_, '_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // a `&str` hashes the same way as a `String`, and so do slices of them
+        self.0.hash(state);
+    }
+}
+
+impl Equivalent<Vec<String>> for BorrowedKey<'_, '_> {
+    fn equivalent(&self, key: &Vec<String>) -> bool {
+        self.0.len() == key.len() && self.0.iter().zip(key).all(|(a, b)| *a == b)
+    }
+}
+
+/// Like `intern`, but for a key made of borrowed fields, which only get copied if the key is new.
+/// Returns the ID of the key, and whether it's new.
+fn intern_fields(
+    keys: &mut FastIndexSet<Vec<String>>,
+    counts: &mut Vec<usize>,
+    lines: &mut Vec<usize>,
+    key: &[&str],
+    line_num: usize,
+) -> (usize, bool) {
+    let (id, new) = match keys.get_full(&BorrowedKey(key)) {
+        Some((id, _)) => (id, false),
+        None => {
+            keys.insert(key.iter().map(|field| field.to_string()).collect());
+            counts.push(0);
+            lines.push(line_num);
+            (keys.len() - 1, true)
+        }
+    };
+    counts[id] += 1;
+    (id, new)
+}
+
+/// The fields of a key that comes straight from `cols` (or the `total` key, without any
+/// columns), borrowed from the record and stored in `buf`. `names` are the names of the fields,
+/// for error messages.
+fn borrowed_key<'a, 'b>(
+    record: &'a csv::ByteRecord,
+    cols: &[usize],
+    names: &[String],
+    line_num: usize,
+    buf: &'b mut [&'a str; MAX_BORROWED_FIELDS],
+) -> CsvCliResult<&'b [&'a str]> {
+    if cols.is_empty() {
+        buf[0] = "total";
+        return Ok(&buf[..1]);
+    }
+    for (i, (field, &col)) in buf.iter_mut().zip(cols).enumerate() {
+        *field = record.field(col).map_err(|err| CsvCliError::ParsingError {
+            line_num,
+            str_to_parse: record_text(record),
+            err: format!("Could not compute the key: {}", err),
+            column: Some(Box::new(ErrorColumn {
+                name: names.get(i).cloned(),
+                index: col,
+            })),
+            position: record.position().cloned(),
+        })?;
+    }
+    Ok(&buf[..cols.len()])
 }
 
+/// A row of the output, along with the aggregated rows it combines
+type RowGroup<'a> = (Vec<String>, Vec<&'a Vec<String>>);
+
 /// How the rows or columns are going to be sorted
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OutputOrder {
-    /// Results appear in index order
+    /// Results appear in index order: the order in which they first appear in the input.
+    /// (Rows and columns are stored in insertion-ordered sets, so this never depends on hashing.)
     IndexOrder,
     /// The results appear sorted in ascending order
     Ascending,
     /// The results appear sorted in descending order
     Descending,
+    /// The results appear sorted from the smallest total to the largest
+    ByTotalAscending,
+    /// The results appear sorted from the largest total to the smallest
+    ByTotalDescending,
+    /// The results appear in the order their first field appears in the list, with any values
+    /// missing from the list appearing afterwards (in index order)
+    Custom(Vec<String>),
+}
+
+/// How to sample the records of a file, rather than aggregating all of them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Sample {
+    /// Aggregates each record with the given probability (between 0 and 1)
+    Fraction(f64),
+    /// Aggregates a uniformly random sample of (at most) the given number of records
+    Size(usize),
+}
+
+/// What to do with records that a filter can't be evaluated on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FilterErrorPolicy {
+    /// Skips the record, as though it didn't match the filter
+    Skip,
+    /// Keeps the record, as though it matched the filter
+    Keep,
+    /// Stops with a `ParsingError`
+    Error,
+}
+
+/// What to do with `NaN` and infinite values (like `NaN`, `inf`, and `-Infinity`) when values
+/// get parsed as numbers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NanPolicy {
+    /// Skips the value (counting it in `RecordCounts::nan_values`)
+    Skip,
+    /// Aggregates the value, so it can make the result `NaN` or infinite. This only works for
+    /// aggregations whose values are floating point numbers; the others can't hold them and
+    /// stop with a `ParsingError`.
+    Propagate,
+    /// Stops with a `ParsingError`
+    Error,
+}
+
+/// Whether a value is a literal for `NaN` or an infinity, ignoring case
+fn is_non_finite(value: &str) -> bool {
+    let unsigned = value
+        .strip_prefix(|c| c == '+' || c == '-')
+        .unwrap_or(value);
+    ["nan", "inf", "infinity"]
+        .iter()
+        .any(|literal| unsigned.eq_ignore_ascii_case(literal))
+}
+
+/// Whether a group of records is a row or a column of the pivot table
+#[derive(Debug, Clone, Copy)]
+enum Axis {
+    Rows,
+    Columns,
 }
 
 /// The general type of data being used. I've used this to implement better error handling.
 /// See [the GitHub](https://github.com/maxblee/clipivot#functions) page for more details on the
 /// meaning of these functions.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
 pub enum ParsingStrategy {
     /// For accumulators that hold and manipulate text (string) data.
     Text,
@@ -48,312 +281,4846 @@ pub enum ParsingStrategy {
     __Nonexhaustive,
 }
 
+/// The number of records an `Aggregator` read, and how many of them it skipped (and why)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RecordCounts {
+    /// The records that were read from the input
+    pub read: usize,
+    /// The records that had at least one value aggregated
+    pub aggregated: usize,
+    /// The records that didn't meet the filters
+    pub filtered: usize,
+    /// The records whose keys got dropped (by a lookup table that drops unmapped values)
+    pub dropped_keys: usize,
+    /// The values that were skipped because they were empty
+    pub empty_values: usize,
+    /// The values that were skipped because they were `NaN` or infinite (see `NanPolicy`)
+    pub nan_values: usize,
+    /// The records that were skipped because they couldn't be parsed (see `with_skip_bad_records`)
+    pub bad_records: usize,
+}
+
+/// Where an aggregation saves checkpoints of its progress, and how often (see `with_checkpoints`)
+#[derive(Debug, Clone, PartialEq)]
+struct Checkpoints {
+    path: PathBuf,
+    signature: String,
+    interval: Duration,
+    last_saved: Instant,
+}
+
+/// A budget for the memory the cells and keys of a table can take up (see `with_max_memory`)
+#[derive(Debug, Clone, PartialEq)]
+struct MemoryLimit {
+    bytes: usize,
+    /// Why the cells can't be spilled to disk once the table goes over the budget, if they can't
+    no_spill_reason: Option<String>,
+}
+
+/// The error for a saved state (or checkpoint) that can't be read back
+fn corrupted_state() -> CsvCliError {
+    CsvCliError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The saved pivot table was corrupted",
+    ))
+}
+
+/// Roughly how much memory a row or column key takes up once it's been stored (along with its
+/// record count and the line it first appeared on)
+fn key_size(key: &[String]) -> usize {
+    entry_size::<(u64, usize)>()
+        + std::mem::size_of::<Vec<String>>()
+        + 2 * std::mem::size_of::<usize>()
+        + key
+            .iter()
+            .map(|field| std::mem::size_of::<String>() + field.len())
+            .sum::<usize>()
+}
+
+/// The size of the pivot table an `Aggregator` computed (before any rows or columns get dropped
+/// while writing it)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TableSize {
+    /// The number of distinct row keys
+    pub rows: usize,
+    /// The number of distinct column keys
+    pub columns: usize,
+    /// The number of cells that have a value
+    pub cells: usize,
+}
+
 /// The object that computes the aggregations and writes to standard output.
 #[derive(Debug, PartialEq)]
 pub struct Aggregator<T, I, O>
 where
     T: Accumulate<I, O>,
     I: std::str::FromStr,
-    O: std::fmt::Display,
+    O: WriteCell,
 {
-    aggregations: HashMap<(String, String), T>,
-    indexes: IndexSet<String>,
-    columns: IndexSet<String>,
-    index_cols: Vec<usize>,
-    column_cols: Vec<usize>,
-    values_col: usize,
+    /// The accumulator of each cell, keyed by the ID of its row, the ID of its column, and the
+    /// position of its values column. The ID of a row (or column) is its position in `indexes`
+    /// (or `columns`), so each distinct key only gets stored once.
+    aggregations: Cells<T>,
+    indexes: FastIndexSet<Vec<String>>,
+    columns: FastIndexSet<Vec<String>>,
+    /// The line each row key first appeared on (in the same order as `indexes`, while the records
+    /// are being read), which is used to merge the rows of several threads in order
+    index_lines: Vec<usize>,
+    /// The line each column key first appeared on (in the same order as `columns`)
+    column_lines: Vec<usize>,
+    index_cols: Vec<KeyField>,
+    column_cols: Vec<KeyField>,
+    values_cols: Vec<usize>,
+    /// An expression that computes the value of each record, instead of reading it from `values_cols`
+    value_expr: Option<Expr>,
+    /// The names of the values columns, used to label the output when there is more than one
+    value_names: Vec<String>,
+    key_separator: String,
+    /// Labels for the index columns in the header row
+    row_labels: Vec<String>,
+    /// The names of the row fields, which label the index columns unless `row_labels` overrides them
+    index_names: Vec<String>,
+    /// The names of the column fields, which label the column fields in sparse output
+    column_names: Vec<String>,
+    /// A prefix added to each of the column headers
+    header_prefix: String,
+    /// Whether to write one header row per column field instead of joining the fields together
+    multi_header: bool,
+    /// The number of rows to keep (with the rest rolled up into a row with the given label)
+    top_rows: Option<(usize, String)>,
+    /// Conditions that records need to meet to get aggregated
+    filters: Vec<Expr>,
+    /// What to do with records the filters can't be evaluated on
+    filter_error_policy: FilterErrorPolicy,
+    /// What to do with `NaN` and infinite values
+    nan_policy: NanPolicy,
+    /// Whether records that can't be parsed get skipped instead of being an error
+    skip_bad_records: bool,
+    /// The most records that can be skipped (because they couldn't be parsed) before it's an error
+    max_bad_records: Option<usize>,
+    /// How many of the skipped records get kept (with their line numbers and errors)
+    bad_records_kept: usize,
+    /// Which of the files being aggregated together this aggregator reads, so the cells know
+    /// which records came first (since the line numbers start over in each file)
+    file_index: usize,
+    /// Where the records that got skipped because they couldn't be parsed get written
+    rejects: Option<RejectWriter>,
+    /// The minimum number of records in a row or column (where smaller groups get dropped,
+    /// or combined into a group with the given label)
+    min_count: Option<(usize, Option<String>)>,
+    /// A condition that rows need to meet once they've been aggregated
+    having: Option<Expr>,
+    /// Transformations applied to the cells of the finished table, in order
+    cell_transforms: Vec<CellTransform>,
+    /// Rules for recoding the cells of the finished table, once everything else has been done
+    recode_rules: Vec<RecodeRule>,
+    /// The locale whose separators the numbers of the output get written with, if there is one
+    out_locale: Option<NumberLocale>,
+    /// Whether to remove the rows and columns whose cells are all empty or zero
+    drop_empty: bool,
+    /// Whether to write the non-empty cells as (row, column, value) records instead of a table
+    sparse: bool,
+    /// Whether to add a row and a column holding the totals of the columns and rows
+    totals: bool,
+    /// Whether to add subtotal rows for each level of the row fields
+    subtotals: bool,
+    /// The number of records in each row (in the same order as `indexes`)
+    row_counts: Vec<usize>,
+    /// The number of records in each column (in the same order as `columns`)
+    column_counts: Vec<usize>,
+    /// The maximum number of records to read
+    limit: Option<usize>,
+    /// How to sample the input records, if at all
+    sample: Option<Sample>,
+    /// The seed of the random number generator that picks the sample, if it was given one
+    seed: Option<u64>,
+    /// The number of records that were sampled, and the number of records in the file
+    sample_size: Option<(usize, usize)>,
+    /// How many records were read and skipped
+    record_counts: RecordCounts,
+    /// The record numbers and line numbers of the first few records that were skipped because
+    /// they couldn't be parsed, along with the errors they ran into and their text
+    bad_records: Vec<(usize, u64, String, String)>,
+    /// The progress bar drawn while the records get read, if there is one
+    progress_bar: Option<ProgressBar>,
     skip_null: bool,
+    null_values: Option<HashSet<String>>,
     row_order: OutputOrder,
     column_order: OutputOrder,
+    /// How the values of the row and column fields get compared when they're sorted
+    comparator: Comparator,
     parsing_strategy: ParsingStrategy,
-    input_type: PhantomData<I>,
-    output_type: PhantomData<O>,
+    /// The number of threads that aggregate the records
+    threads: usize,
+    /// The number of (row, column) combinations the table is expected to have, for pre-sizing
+    /// the cells (and rows) before any records get read
+    group_hint: Option<usize>,
+    /// The largest number of cells to keep in memory before spilling them to disk
+    max_cells: Option<usize>,
+    /// The cells that have been spilled to disk, sorted by their rows
+    spill_runs: Vec<SpillRun>,
+    /// The budget for the memory the table takes up, if there is one
+    memory_limit: Option<MemoryLimit>,
+    /// Roughly how much memory the accumulators hold beyond their own size
+    cell_heap_bytes: usize,
+    /// Roughly how much memory the row keys take up
+    row_key_bytes: usize,
+    /// Roughly how much memory the column keys take up
+    column_key_bytes: usize,
+    /// Whether the records are only being read for their rows and columns (by `scan_keys`)
+    scanning_keys: bool,
+    /// The columns the row and column keys come from, when the table just counts records and
+    /// its keys come straight from a few columns (so records get added with `add_count`)
+    count_columns: Option<(Vec<usize>, Vec<usize>)>,
+    /// Where to save checkpoints while the records get read, if anywhere
+    checkpoints: Option<Checkpoints>,
+    /// The number of records a checkpoint this aggregator resumed from had already read
+    resumed_records: usize,
+    /// The dates each values column parsed recently
+    parse_caches: Vec<ParseCache<I>>,
+    /// How the values get parsed
+    value_parser: ValueParser<I>,
+    // these are function pointers so the aggregator can be sent between threads whatever I and O are
+    input_type: PhantomData<fn() -> I>,
+    output_type: PhantomData<fn() -> O>,
 }
 
 impl<T, I, O> Aggregator<T, I, O>
 where
-    T: Accumulate<I, O>,
-    I: std::str::FromStr,
-    O: std::fmt::Display,
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
 {
     pub fn new(
         index_cols: Vec<usize>,
         column_cols: Vec<usize>,
-        values_col: usize,
+        values_cols: Vec<usize>,
         skip_null: bool,
         row_order: OutputOrder,
         column_order: OutputOrder,
         parsing_strategy: ParsingStrategy,
     ) -> Aggregator<T, I, O> {
-        let aggregations = HashMap::new();
-        let indexes = IndexSet::new();
-        let columns = IndexSet::new();
+        // with several values columns, the cells of each (row, column) combination get looked
+        // up together
+        let aggregations = if values_cols.len() > 1 {
+            Cells::columnar(values_cols.len())
+        } else {
+            Cells::default()
+        };
+        let indexes = FastIndexSet::default();
+        let columns = FastIndexSet::default();
         Aggregator {
             aggregations,
             indexes,
             columns,
-            index_cols,
-            column_cols,
-            values_col,
+            index_lines: Vec::new(),
+            column_lines: Vec::new(),
+            index_cols: index_cols.into_iter().map(KeyField::from).collect(),
+            column_cols: column_cols.into_iter().map(KeyField::from).collect(),
+            values_cols,
+            value_expr: None,
+            value_names: Vec::new(),
+            key_separator: FIELD_SEPARATOR.to_string(),
+            row_labels: Vec::new(),
+            index_names: Vec::new(),
+            column_names: Vec::new(),
+            header_prefix: String::new(),
+            multi_header: false,
+            top_rows: None,
+            filters: Vec::new(),
+            filter_error_policy: FilterErrorPolicy::Error,
+            nan_policy: NanPolicy::Error,
+            skip_bad_records: false,
+            max_bad_records: None,
+            bad_records_kept: BAD_RECORDS_KEPT,
+            file_index: 0,
+            rejects: None,
+            min_count: None,
+            having: None,
+            cell_transforms: Vec::new(),
+            recode_rules: Vec::new(),
+            out_locale: None,
+            drop_empty: false,
+            sparse: false,
+            totals: false,
+            subtotals: false,
+            row_counts: Vec::new(),
+            column_counts: Vec::new(),
+            limit: None,
+            sample: None,
+            seed: None,
+            sample_size: None,
+            record_counts: RecordCounts::default(),
+            bad_records: Vec::new(),
+            progress_bar: None,
             skip_null,
+            null_values: None,
             row_order,
             column_order,
+            comparator: Comparator::default(),
             parsing_strategy,
+            threads: 1,
+            group_hint: None,
+            max_cells: None,
+            spill_runs: Vec::new(),
+            memory_limit: None,
+            cell_heap_bytes: 0,
+            row_key_bytes: 0,
+            column_key_bytes: 0,
+            scanning_keys: false,
+            count_columns: None,
+            checkpoints: None,
+            resumed_records: 0,
+            parse_caches: Vec::new(),
+            value_parser: ValueParser::default(),
             input_type: PhantomData,
             output_type: PhantomData,
         }
     }
 
-    /// Takes a CSV (from anything that impl's Read) and aggregates information
-    /// from the struct's settings. Does not write anything.
-    pub fn aggregate<R: std::io::Read>(&mut self, rdr: &mut csv::Reader<R>) -> CsvCliResult<()> {
-        let mut line_num = 0;
-        let mut record = csv::StringRecord::new();
-        while rdr.read_record(&mut record)? {
-            self.add_record(&record, line_num)?;
-            line_num += 1;
-        }
-        Ok(())
+    /// Sets the string used to join the values of multiple column fields into a single column header
+    /// (defaulting to `FIELD_SEPARATOR`).
+    ///
+    /// To guarantee that two different keys never produce the same header, any value containing
+    /// the separator (or a backslash) gets escaped with a backslash before the values are joined.
+    pub fn with_key_separator(mut self, separator: &str) -> Self {
+        self.key_separator = separator.to_string();
+        self
     }
 
-    /// Writes the aggregated information into a list of records
-    pub fn to_vec(&self) -> Vec<Vec<String>> {
-        let mut rows = vec![];
-        rows.push(self.get_pivot_header());
-        for row in &self.indexes {
-            rows.push(self.get_pivot_row(row));
-        }
-        rows
+    /// Sets the labels of the index columns in the header row. By default, the index columns
+    /// are labeled with the names of the row fields (or left blank if the file doesn't have a header row).
+    pub fn with_row_labels(mut self, labels: Vec<String>) -> Self {
+        self.row_labels = labels;
+        self
     }
 
-    /// Writes the aggregated information to standard output.
-    pub fn write_results<W: io::Write>(&mut self, writer: &mut csv::Writer<W>) -> CsvCliResult<()> {
-        self.prepare_write()?;
-        writer.write_record(self.get_pivot_header())?;
-        for row in &self.indexes {
-            writer.write_record(self.get_pivot_row(row))?;
+    /// Sets the row fields, which (unlike the column indexes passed to `new`)
+    /// can derive their keys from something other than the value of a column.
+    pub fn with_row_fields(mut self, fields: Vec<KeyField>) -> Self {
+        self.index_cols = fields;
+        self
+    }
+
+    /// Sets the column fields, which (unlike the column indexes passed to `new`)
+    /// can derive their keys from something other than the value of a column.
+    pub fn with_column_fields(mut self, fields: Vec<KeyField>) -> Self {
+        self.column_cols = fields;
+        self
+    }
+
+    /// Adds a prefix to each of the generated column headers (e.g. `year_` to produce `year_2019`),
+    /// which avoids collisions when the output is joined to other tables.
+    pub fn with_header_prefix(mut self, prefix: &str) -> Self {
+        self.header_prefix = prefix.to_string();
+        self
+    }
+
+    /// Writes one header row per column field (plus one for the values columns
+    /// if there are several of them), instead of joining the fields into a single header.
+    pub fn with_multi_header(mut self, multi_header: bool) -> Self {
+        self.multi_header = multi_header;
+        self
+    }
+
+    /// Only keeps the `num_rows` largest rows (ranked by the aggregate of the entire row, or by the
+    /// number of records if that isn't numeric), combining the rest into a single row labeled `label`.
+    pub fn with_top_rows(mut self, num_rows: usize, label: &str) -> Self {
+        self.top_rows = Some((num_rows, label.to_string()));
+        self
+    }
+
+    /// Aggregates the result of an expression (like `price * quantity`) for each record,
+    /// rather than the values of a column. Records that the expression can't be evaluated on
+    /// result in a `ParsingError`.
+    pub fn with_value_expr(mut self, expr: Expr) -> Self {
+        self.value_expr = Some(expr);
+        self
+    }
+
+    /// Adds a condition that records need to meet in order to get aggregated.
+    /// Records that the filter can't be evaluated on are handled according to
+    /// `with_filter_error_policy`.
+    pub fn with_filter(mut self, filter: Expr) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Sets what happens to records that one of the filters can't be evaluated on.
+    /// (By default, they result in a `ParsingError`.)
+    pub fn with_filter_error_policy(mut self, policy: FilterErrorPolicy) -> Self {
+        self.filter_error_policy = policy;
+        self
+    }
+
+    /// Sets what happens to `NaN` and infinite values when values get parsed as numbers.
+    /// (By default, they result in a `ParsingError`.) Values that count as empty get skipped
+    /// before this applies.
+    pub fn with_nan_policy(mut self, policy: NanPolicy) -> Self {
+        self.nan_policy = policy;
+        self
+    }
+
+    /// Skips the records that can't be parsed (because they have the wrong number of fields, or
+    /// because one of their keys, values, or filters can't be computed), instead of stopping with
+    /// an error, and counts them in `RecordCounts::bad_records`. A record only gets skipped before
+    /// any of its values get added, so a sum that overflows is still an error.
+    pub fn with_skip_bad_records(mut self, skip: bool) -> Self {
+        self.skip_bad_records = skip;
+        self
+    }
+
+    /// Stops with an `InvalidConfiguration` error once more than `max` records have been skipped
+    /// (with `with_skip_bad_records`), since that many bad records usually means the input isn't
+    /// what the aggregator expects (like a file with a different delimiter). With several
+    /// threads, the error can come after a few more records than that have been skipped.
+    pub fn with_max_bad_records(mut self, max: usize) -> Self {
+        self.max_bad_records = Some(max);
+        self
+    }
+
+    /// Keeps the first `kept` records that get skipped (with `with_skip_bad_records`), along with
+    /// the errors they ran into, instead of the first `BAD_RECORDS_KEPT`
+    pub fn with_bad_records_kept(mut self, kept: usize) -> Self {
+        self.bad_records_kept = kept;
+        self
+    }
+
+    /// Writes each record that gets skipped (with `with_skip_bad_records`) to `rejects`, along
+    /// with the reason it got skipped. The header row (if the input has one) gets written first.
+    pub fn with_rejects(mut self, rejects: RejectWriter) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    /// Drops the rows and columns with fewer than `min_count` records, or combines them into
+    /// a single row (or column) named `label` if there's a label.
+    pub fn with_min_count(mut self, min_count: usize, label: Option<&str>) -> Self {
+        self.min_count = Some((min_count, label.map(String::from)));
+        self
+    }
+
+    /// Stops reading the file after `limit` records.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Only aggregates a random sample of the records, either by including each record
+    /// with a given probability or by keeping a fixed-size reservoir of records.
+    pub fn with_sample(mut self, sample: Sample) -> Self {
+        self.sample = Some(sample);
+        self
+    }
+
+    /// Seeds the random number generator that picks the sample (see `with_sample`), so that
+    /// the same seed picks the same records of the same input every time (with the same
+    /// version of clipivot). Without a seed, each run picks a different sample.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets how the values get parsed (by default, with the `FromStr` implementation of the
+    /// input type). `ValueParser::dates` parses dates with its own format, rather than the
+    /// global one from `parsing::set_date_format`.
+    pub fn with_value_parser(mut self, value_parser: ValueParser<I>) -> Self {
+        self.value_parser = value_parser;
+        self
+    }
+
+    /// Aggregates the records on `threads` threads, while the file gets parsed on the current
+    /// thread. Each thread aggregates the records it receives into its own cells, and the cells
+    /// get merged at the end (in the order of the records, so rows and columns still appear
+    /// in index order).
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets aside room for `groups` (row, column) combinations before the records get read, so
+    /// the cells don't need to get rehashed over and over as a very large table fills up.
+    /// This only changes how fast the table gets aggregated, not the table itself.
+    pub fn with_group_hint(mut self, groups: usize) -> Self {
+        self.group_hint = Some(groups);
+        self
+    }
+
+    /// Keeps at most `max_cells` cells in memory. Whenever the table reaches that many cells,
+    /// they get written to a temporary file (sorted by their rows) and the table starts over,
+    /// and the files get merged back together as the table gets written. The rows then always
+    /// come out sorted (in ascending order, unless they're sorted in descending order), and
+    /// options that need the whole table at once (like totals) can't be used.
+    pub fn with_max_cells(mut self, max_cells: usize) -> Self {
+        self.max_cells = Some(max_cells.max(1));
+        self
+    }
+
+    /// Keeps a (rough) estimate of the memory the cells and the row and column keys take up
+    /// under `bytes`. Once the table reaches that much, its cells get spilled to disk (as with
+    /// `with_max_cells`) if they can be, so the rows come out sorted. If they can't be (because
+    /// the table has totals, for instance), aggregating the table fails with an error instead of
+    /// running out of memory.
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(MemoryLimit {
+            bytes: bytes.max(1),
+            no_spill_reason: None,
+        });
+        self
+    }
+
+    /// Saves a checkpoint of the table to `path` every `interval` while the records get read, so
+    /// that an aggregation that gets interrupted can pick up where it left off with `resume`.
+    /// Each checkpoint holds the state of every cell (as `save_state` would write it) along with
+    /// the position of the first record it doesn't cover, and it replaces the checkpoint before
+    /// it all at once, so there's always a whole checkpoint at `path` once the first one gets
+    /// saved. Checkpoints only get saved while the records get read on a single thread (without
+    /// sampling), and `signature` works the way it does for `save_state`.
+    pub fn with_checkpoints(mut self, path: PathBuf, signature: &str, interval: Duration) -> Self {
+        self.checkpoints = Some(Checkpoints {
+            path,
+            signature: signature.to_string(),
+            interval,
+            last_saved: Instant::now(),
+        });
+        self
+    }
+
+    /// A rough estimate of the memory the cells and the row and column keys currently take up
+    pub fn memory_estimate(&self) -> usize {
+        self.aggregations.memory_size()
+            + self.cell_heap_bytes
+            + self.row_key_bytes
+            + self.column_key_bytes
+    }
+
+    /// Whether any of the cells have been spilled to disk
+    pub fn spilled(&self) -> bool {
+        !self.spill_runs.is_empty()
+    }
+
+    /// Returns the number of records that were sampled and the total number of records read,
+    /// if the aggregator was set to sample its input.
+    pub fn sample_size(&self) -> Option<(usize, usize)> {
+        self.sample_size
+    }
+
+    /// Only writes the rows that meet a condition once they've been aggregated, like SQL's
+    /// `HAVING` clause. The condition gets evaluated on a record holding the aggregate of the
+    /// entire row (for the first values column) at position `HAVING_VALUE` and the number of
+    /// records in the row at position `HAVING_COUNT`.
+    pub fn with_having(mut self, having: Expr) -> Self {
+        self.having = Some(having);
+        self
+    }
+
+    /// Adds a `Total` column holding the aggregate of each row and a `Total` row holding the aggregate
+    /// of each column. Totals get computed by merging the accumulators of the row (or column), so the
+    /// total of a `mean` is the mean of all of the row's values rather than the mean of its cells.
+    /// (The column is left out if there aren't any column fields, and the row if there aren't any
+    /// row fields.) Totals are added after any cell transformations have been applied.
+    pub fn with_totals(mut self, totals: bool) -> Self {
+        self.totals = totals;
+        self
+    }
+
+    /// Adds a subtotal row above each group of rows that share their first few index values
+    /// (so with `--rows state,county`, each state gets a subtotal row above its counties),
+    /// ordering the rows so that each group's rows appear together. Subtotals are aggregated
+    /// the same way as the totals from `with_totals`.
+    pub fn with_subtotals(mut self, subtotals: bool) -> Self {
+        self.subtotals = subtotals;
+        self
+    }
+
+    /// Sets how the values of the row and column fields get compared when the rows or columns
+    /// are sorted in ascending or descending order. Defaults to `Comparator::Alphabetical`.
+    pub fn with_comparator(mut self, comparator: Comparator) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    /// Adds a transformation of the finished table's cells (like converting them into percentages),
+    /// which gets applied after any transformations that were added before it.
+    pub fn with_cell_transform(mut self, transform: CellTransform) -> Self {
+        self.cell_transforms.push(transform);
+        self
+    }
+
+    /// Skips the values in `values` (compared case-insensitively) instead of the default
+    /// empty values ("", NULL, NaN, NONE, NA, and N/A). This implies skipping empty values.
+    pub fn with_null_values(mut self, values: Vec<String>) -> Self {
+        self.skip_null = true;
+        self.null_values = Some(values.iter().map(|val| val.to_lowercase()).collect());
+        self
+    }
+
+    /// Removes the rows and columns of the output whose cells are all empty or zero, after the
+    /// cell transformations and totals have been computed (but before the cells get recoded)
+    pub fn with_drop_empty(mut self, drop_empty: bool) -> Self {
+        self.drop_empty = drop_empty;
+        self
+    }
+
+    /// Writes the results in a "long" format, with one record for each non-empty cell holding
+    /// the cell's row fields, its column fields, and its values, instead of writing a table.
+    /// This keeps the output small when most combinations of rows and columns are empty.
+    /// Cell transformations, totals, and recoding rules don't apply to sparse output.
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Sets rules for recoding the cells of the output (like replacing zeros with a dash).
+    /// Recoding happens last, after the cell transformations and totals, so it also
+    /// applies to the total row and column.
+    pub fn with_recode_rules(mut self, rules: Vec<RecodeRule>) -> Self {
+        self.recode_rules = rules;
+        self
+    }
+
+    /// Writes the numbers in the cells of the output with the decimal and grouping separators
+    /// of `locale` (in `write_results`, after any recoding). The keys in the row and column
+    /// fields stay the way they were read.
+    pub fn with_out_locale(mut self, locale: NumberLocale) -> Self {
+        self.out_locale = Some(locale);
+        self
+    }
+
+    /// The locale set by `with_out_locale`, if there is one
+    pub fn out_locale(&self) -> Option<NumberLocale> {
+        self.out_locale
+    }
+
+    /// Draws a progress bar on standard error while the records get read, for an input
+    /// with `total_bytes` bytes
+    pub fn with_progress_bar(mut self, total_bytes: u64) -> Self {
+        self.progress_bar = Some(ProgressBar::new(total_bytes));
+        self
+    }
+
+    /// Tells the progress bar (if there is one) that the input has `total_records` records, so
+    /// it can count records instead of bytes
+    pub fn with_total_records(mut self, total_records: usize) -> Self {
+        if let Some(progress_bar) = &mut self.progress_bar {
+            progress_bar.set_total_records(total_records);
         }
-        writer.flush()?;
-        Ok(())
+        self
     }
 
-    /// This prepares a pivot table for output (sorting it
-    /// and verifying that there's more than 1 row)
-    fn prepare_write(&mut self) -> CsvCliResult<()> {
-        if self.columns.is_empty() {
-            return Err(CsvCliError::InvalidConfiguration(
-                "Did not parse any lines before finishing".to_string(),
+    /// Checks the fields for configurations that are probably mistakes (but aren't errors),
+    /// like aggregating a column that's also a row field, returning a warning for each one
+    pub fn configuration_warnings(&self, headers: &csv::StringRecord) -> Vec<String> {
+        let name = |idx: usize| match headers.get(idx) {
+            Some(header) if !header.is_empty() => format!("`{}`", header),
+            _ => format!("column {}", idx),
+        };
+        let mut warnings = Vec::new();
+        let rows: Vec<usize> = self
+            .index_cols
+            .iter()
+            .filter_map(KeyField::plain_column)
+            .collect();
+        let columns: Vec<usize> = self
+            .column_cols
+            .iter()
+            .filter_map(KeyField::plain_column)
+            .collect();
+        // whether a field is the first occurrence of its column, so duplicates only get one warning
+        let is_first = |fields: &[usize], i: usize| !fields[..i].contains(&fields[i]);
+        for (fields, kind) in &[(&rows, "row"), (&columns, "column")] {
+            for (i, idx) in fields.iter().enumerate() {
+                if !is_first(fields, i) {
+                    warnings.push(format!(
+                        "{} is selected as a {} field more than once",
+                        name(*idx),
+                        kind
+                    ));
+                }
+            }
+        }
+        for (_, idx) in rows
+            .iter()
+            .enumerate()
+            .filter(|&(i, idx)| is_first(&rows, i) && columns.contains(idx))
+        {
+            warnings.push(format!(
+                "{} is both a row field and a column field, so only the cells where they match will have values",
+                name(*idx)
             ));
         }
-        self.sort_results();
+        if self.value_expr.is_none() {
+            for idx in &self.values_cols {
+                let kind = match (rows.contains(idx), columns.contains(idx)) {
+                    (true, _) => "row",
+                    (_, true) => "column",
+                    _ => continue,
+                };
+                warnings.push(format!(
+                    "{} is both a values column and a {} field, so every value in a cell is the same",
+                    name(*idx),
+                    kind
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Writes the fields that were selected for the rows, columns, and values (given the
+    /// header row of the file), along with the number of filters
+    pub fn write_fields<W: io::Write>(
+        &self,
+        headers: &csv::StringRecord,
+        wtr: &mut W,
+    ) -> CsvCliResult<()> {
+        let describe = |fields: &[KeyField]| {
+            if fields.is_empty() {
+                return "none".to_string();
+            }
+            fields
+                .iter()
+                .map(|field| match field.column_index() {
+                    Some(idx) => format!("{} (column {})", field.name(headers), idx),
+                    None => field.name(headers),
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        writeln!(wtr, "Rows: {}", describe(&self.index_cols))?;
+        writeln!(wtr, "Columns: {}", describe(&self.column_cols))?;
+        let values_fields: Vec<KeyField> = self
+            .values_cols
+            .iter()
+            .map(|&idx| KeyField::from(idx))
+            .collect();
+        let values = match &self.value_expr {
+            Some(_) => "an expression".to_string(),
+            None => describe(&values_fields),
+        };
+        writeln!(wtr, "Values: {}", values)?;
+        writeln!(wtr, "Filters: {}", self.filters.len())?;
         Ok(())
     }
 
-    fn get_pivot_header(&self) -> Vec<String> {
-        let mut header = vec![String::new()];
-        for col in &self.columns {
-            header.push(col.to_string());
+    /// Describes how the records of a CSV would get aggregated, without aggregating anything:
+    /// writes the fields that were selected, followed by the keys and values of the first
+    /// `num_records` records (with a note for any records that would be skipped or that
+    /// couldn't be parsed). This is meant for debugging a configuration against a large file.
+    pub fn write_plan<R, W>(
+        &self,
+        rdr: &mut csv::Reader<R>,
+        wtr: &mut W,
+        num_records: usize,
+    ) -> CsvCliResult<()>
+    where
+        R: std::io::Read,
+        W: io::Write,
+    {
+        let headers = if rdr.has_headers() {
+            rdr.headers()?.clone()
+        } else {
+            csv::StringRecord::new()
+        };
+        self.write_fields(&headers, wtr)?;
+        writeln!(wtr)?;
+        writeln!(wtr, "The first {} records:", num_records)?;
+
+        let mut preview = csv::Writer::from_writer(&mut *wtr);
+        let mut header: Vec<String> = self.index_cols.iter().map(|f| f.name(&headers)).collect();
+        header.extend(self.column_cols.iter().map(|f| f.name(&headers)));
+        match &self.value_expr {
+            Some(_) => header.push("value".to_string()),
+            None => header.extend(
+                self.values_cols
+                    .iter()
+                    .map(|&idx| headers.get(idx).unwrap_or_default().to_string()),
+            ),
         }
-        header
+        header.push("note".to_string());
+        preview.write_record(&header)?;
+        let has_values = !self.values_cols.is_empty() || self.value_expr.is_some();
+        let mut record = csv::ByteRecord::new();
+        let mut line_num = 0;
+        while line_num < num_records && rdr.read_byte_record(&mut record)? {
+            let mut notes = Vec::new();
+            let mut row = Vec::new();
+            for field in self.index_cols.iter().chain(&self.column_cols) {
+                match field.get_key(&record, line_num) {
+                    Ok(Some(key)) => row.push(key),
+                    Ok(None) => {
+                        row.push(String::new());
+                        notes.push("skipped (the key isn't in the lookup table)".to_string());
+                    }
+                    Err(err) => {
+                        row.push(String::new());
+                        notes.push(format!("could not compute the key: {}", err));
+                    }
+                }
+            }
+            let mut values = Vec::new();
+            match &self.value_expr {
+                Some(expr) => match self.compute_value(expr, &record, line_num) {
+                    Ok(value) => values.push(value),
+                    Err(err) => {
+                        values.push(String::new());
+                        notes.push(err.to_string());
+                    }
+                },
+                None => values.extend(
+                    self.values_cols
+                        .iter()
+                        .map(|&idx| record.field(idx).unwrap_or_default().to_string()),
+                ),
+            }
+            for value in &values {
+                if self.skip_null && self.is_null(value) {
+                    notes.push(format!("skipped the empty value `{}`", value));
+                } else if has_values && self.value_parser.parse(value).is_none() {
+                    notes.push(format!(
+                        "could not parse `{}`: {}",
+                        value,
+                        self.describe_err()
+                    ));
+                }
+            }
+            if !self.passes_filters(&record, line_num).unwrap_or(true) {
+                notes.push("filtered out".to_string());
+            }
+            row.extend(values);
+            row.push(notes.join("; "));
+            preview.write_record(&row)?;
+            line_num += 1;
+        }
+        preview.flush()?;
+        Ok(())
     }
 
-    /// this gets a single row of the pivot table, given the index value
-    fn get_pivot_row(&self, row_value: &str) -> Vec<String> {
-        let mut record = vec![row_value.to_string()];
-        for col in &self.columns {
-            let cell = self
-                .aggregations
-                .get(&(row_value.to_string(), col.to_string()))
-                .map_or(String::new(), |v| {
-                    v.compute()
-                        .map(|v| v.to_string())
-                        .unwrap_or_else(String::new)
-                });
-            record.push(cell);
+    /// Takes a CSV (from anything that impl's Read) and aggregates information
+    /// from the struct's settings. Does not write anything.
+    pub fn aggregate<R: std::io::Read>(&mut self, rdr: &mut csv::Reader<R>) -> CsvCliResult<()> {
+        self.prepare_aggregation(rdr)?;
+        if self.threads > 1 {
+            return self.aggregate_in_parallel(rdr);
         }
-        record
+        self.read_records(rdr, |agg, record, line_num| {
+            agg.add_or_skip_record(record, line_num).map(|_| true)
+        })
     }
 
-    fn add_record(&mut self, record: &csv::StringRecord, line_num: usize) -> CsvCliResult<()> {
-        let value_string = record.get(self.values_col).unwrap();
-        if !(self.skip_null && EMPTY_VALUES.contains(value_string.to_ascii_lowercase().as_str())) {
-            let index_vals = self.get_column_string(&self.index_cols, record);
-            self.indexes.insert(index_vals.clone());
-            let column_vals = self.get_column_string(&self.column_cols, record);
-            self.columns.insert(column_vals.clone());
-            self.update_aggregations(index_vals, column_vals, value_string, line_num)?;
+    /// Reads the names of the fields from the header row (if there is one) and checks and
+    /// sets up the aggregator before any records get read
+    fn prepare_aggregation<R: std::io::Read>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+    ) -> CsvCliResult<()> {
+        if rdr.has_headers() {
+            let headers = rdr.headers()?;
+            self.value_names = self
+                .values_cols
+                .iter()
+                .map(|&i| headers.get(i).unwrap_or_default().to_string())
+                .collect();
+            self.index_names = self.index_cols.iter().map(|f| f.name(headers)).collect();
+            self.column_names = self.column_cols.iter().map(|f| f.name(headers)).collect();
+            if let Some(rejects) = &self.rejects {
+                rejects.write_headers(rdr.byte_headers()?)?;
+            }
         }
+        if self.max_cells.is_some() {
+            self.check_spilling()?;
+        }
+        if self.memory_limit.is_some() {
+            let no_spill_reason = if self.aggregations.is_dense() {
+                Some(
+                    "A dense matrix of cells (from --two-pass) can't be spilled to disk"
+                        .to_string(),
+                )
+            } else {
+                self.check_spilling().err().map(|err| match err {
+                    CsvCliError::InvalidConfiguration(reason) => reason,
+                    err => err.to_string(),
+                })
+            };
+            if let Some(limit) = self.memory_limit.as_mut() {
+                limit.no_spill_reason = no_spill_reason;
+            }
+        }
+        if let Some(groups) = self.group_hint {
+            self.reserve_groups(groups);
+        }
+        self.count_columns = self.get_count_columns();
         Ok(())
     }
 
-    fn get_column_string(&self, columns: &[usize], record: &csv::StringRecord) -> String {
-        if columns.is_empty() {
-            return "total".to_string();
+    /// The columns of the row and column keys, if the table just counts records (without any
+    /// values columns) and each key comes straight from at most `MAX_BORROWED_FIELDS` columns
+    fn get_count_columns(&self) -> Option<(Vec<usize>, Vec<usize>)> {
+        if !self.values_cols.is_empty() || self.value_expr.is_some() {
+            return None;
         }
-        let mut column_records = Vec::new();
-        for column in columns {
-            let string_val = record.get(*column).unwrap();
-            column_records.push(string_val.to_string());
-        }
-        column_records.join(FIELD_SEPARATOR)
+        let columns = |fields: &[KeyField]| -> Option<Vec<usize>> {
+            if fields.len() > MAX_BORROWED_FIELDS {
+                return None;
+            }
+            fields.iter().map(KeyField::plain_column).collect()
+        };
+        Some((columns(&self.index_cols)?, columns(&self.column_cols)?))
     }
 
-    fn describe_err(&self) -> String {
-        match self.parsing_strategy {
-            ParsingStrategy::Text => "Failed to parse as text".to_string(),
-            ParsingStrategy::Numeric => "Failed to parse as numeric".to_string(),
-            ParsingStrategy::Date => format!(
-                "Could not parse as date with {} format",
-                INPUT_DATE_FORMAT.lock().unwrap()
-            ),
-            _ => "Generic parsing error".to_string(),
+    /// Reads the columns of the records of `rdr` without aggregating any of their values and
+    /// describes the pivot table as a `Crosstab` (for `--to-sql`). Returns an error if the table
+    /// uses something a query can't express, like a transformed field or a filter.
+    pub fn scan_crosstab<R: io::Read>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+    ) -> CsvCliResult<Crosstab> {
+        let plain = |fields: &[KeyField]| fields.iter().all(|field| field.plain_column().is_some());
+        let unsupported = if !plain(&self.index_cols) || !plain(&self.column_cols) {
+            Some("a row or column field that gets transformed (or @index)")
+        } else if self.value_expr.is_some() {
+            Some("a value expression")
+        } else if !self.filters.is_empty() {
+            Some("a filter")
+        } else {
+            None
+        };
+        if let Some(unsupported) = unsupported {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "A pivot table with {} can't be written as SQL",
+                unsupported
+            )));
+        }
+        self.scan_keys(rdr)?;
+        let mut columns = self.columns.clone();
+        let comparator = self.comparator;
+        match &self.column_order {
+            OutputOrder::IndexOrder => {}
+            OutputOrder::Ascending => {
+                sort_keys(&mut columns, 1, |a, b| comparator.compare_keys(a, b))
+            }
+            OutputOrder::Descending => {
+                sort_keys(&mut columns, 1, |a, b| comparator.compare_keys(b, a))
+            }
+            OutputOrder::Custom(order) => sort_by_list(&mut columns, order),
+            OutputOrder::ByTotalAscending | OutputOrder::ByTotalDescending => {
+                return Err(CsvCliError::InvalidConfiguration(
+                    "Sorting the columns by their totals can't be written as SQL".to_string(),
+                ))
+            }
         }
+        Ok(Crosstab {
+            rows: self.index_names.clone(),
+            columns: self.column_names.clone(),
+            column_keys: columns.into_iter().collect(),
+            values: self.value_names.clone(),
+            skip_empty: self.skip_null,
+            row_order: self.row_order.clone(),
+        })
     }
 
-    fn update_aggregations(
-        &mut self,
-        indexname: String,
-        columnname: String,
-        input_str: &str,
-        line_num: usize,
-    ) -> CsvCliResult<()> {
-        let parsed_val = input_str.parse().or_else(|_| {
-            Err(CsvCliError::ParsingError {
-                line_num,
-                str_to_parse: input_str.to_string(),
-                err: self.describe_err(),
-            })
+    /// Reads the rows and columns of the records of `rdr` without aggregating any of their
+    /// values (for `--two-pass`), and then sets aside a dense matrix with room for every
+    /// combination of them, so the cells don't have to be stored in a hash map. The records then
+    /// need to be read again, from the start, with `aggregate`.
+    ///
+    /// Records only get scanned the same way they'd get aggregated (so a record that doesn't pass
+    /// the filters, or only has empty values with `-e`, doesn't add any rows or columns), and the
+    /// rows and columns keep the order they first appear in.
+    pub fn scan_keys<R: std::io::Read>(&mut self, rdr: &mut csv::Reader<R>) -> CsvCliResult<()> {
+        let conflict = if self.sample.is_some() {
+            Some("sampling, since a sample could pick different records on each pass")
+        } else if self.max_cells.is_some() {
+            Some("--max-cells, since the cells of a dense matrix can't be spilled to disk")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "Reading the input twice (with --two-pass) can't be combined with {}",
+                conflict
+            )));
+        }
+        self.prepare_aggregation(rdr)?;
+        self.scanning_keys = true;
+        let scanned = self.read_records(rdr, |agg, record, line_num| {
+            agg.add_or_skip_record(record, line_num).map(|_| true)
+        });
+        self.scanning_keys = false;
+        scanned?;
+        // the counts start over for the pass that actually aggregates the records
+        self.record_counts = RecordCounts::default();
+        self.bad_records.clear();
+        self.row_counts.iter_mut().for_each(|count| *count = 0);
+        self.column_counts.iter_mut().for_each(|count| *count = 0);
+        self.aggregations = Cells::dense(
+            self.indexes.len(),
+            self.columns.len(),
+            self.num_value_slots(),
+        )
+        .ok_or_else(|| {
+            CsvCliError::InvalidConfiguration(format!(
+                "The table has too many combinations of rows and columns ({} rows and {} columns) to fit in a dense matrix",
+                self.indexes.len(),
+                self.columns.len()
+            ))
         })?;
+        if let Some(limit) = &self.memory_limit {
+            if self.memory_estimate() >= limit.bytes {
+                return Err(CsvCliError::InvalidConfiguration(format!(
+                    "A dense matrix for the table's {} rows and {} columns would take up about {}, more than the memory budget of {}",
+                    self.indexes.len(),
+                    self.columns.len(),
+                    format_bytes(self.memory_estimate() as u64),
+                    format_bytes(limit.bytes as u64)
+                )));
+            }
+        }
+        Ok(())
+    }
 
-        match self.aggregations.entry((indexname, columnname)) {
-            Entry::Occupied(entry) => {
-                entry.into_mut().update(parsed_val);
+    /// Aggregates a file that has been split into chunks of records (using an index), with a
+    /// thread that parses and aggregates each chunk. `rdr` is only used for the header row, and
+    /// each chunk's reader has to start at the first record of its chunk (without a header row).
+    /// Records that get sampled have to be read in order, so this falls back to reading the whole
+    /// file from `rdr` with `aggregate` when the aggregator samples its input.
+    ///
+    /// The positions of the records that get read from the chunks start at the byte offset and
+    /// record number of each chunk, but their line numbers start over at 1 in each chunk, since
+    /// the index doesn't say which line each chunk starts on.
+    pub fn aggregate_chunks<R, C>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+        chunks: Vec<(Chunk, csv::Reader<C>)>,
+    ) -> CsvCliResult<()>
+    where
+        R: std::io::Read,
+        C: std::io::Read + std::io::Seek + Send,
+    {
+        if self.sample.is_some() {
+            return self.aggregate(rdr);
+        }
+        self.prepare_aggregation(rdr)?;
+        let failed = AtomicBool::new(false);
+        // the number of records every thread has read, for the progress bar
+        let records_read = AtomicUsize::new(0);
+        let header_row = usize::from(rdr.has_headers());
+        let workers: Vec<Self> = chunks.iter().map(|_| self.worker()).collect();
+        let limit = self.limit;
+        let mut progress_bar = self.progress_bar.take();
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = workers
+                .into_iter()
+                .zip(chunks)
+                .map(|(mut worker, (chunk, mut chunk_rdr))| {
+                    let (failed, records_read) = (&failed, &records_read);
+                    scope.spawn(move || {
+                        let mut position = csv::Position::new();
+                        position
+                            .set_byte(chunk.byte)
+                            .set_line(1)
+                            .set_record((chunk.first_record + header_row) as u64);
+                        let end = chunk.first_record + chunk.num_records;
+                        let end = limit.map_or(end, |limit| end.min(limit));
+                        let mut line_num = chunk.first_record;
+                        let mut record = csv::ByteRecord::new();
+                        let result = chunk_rdr.seek(position).map_err(CsvCliError::from);
+                        let result = result.and_then(|_| {
+                            while line_num < end && !failed.load(Ordering::Relaxed) {
+                                if !worker.read_record(
+                                    &mut chunk_rdr,
+                                    &mut record,
+                                    &mut line_num,
+                                    Some(end),
+                                )? {
+                                    break;
+                                }
+                                worker.add_or_skip_record(&record, line_num)?;
+                                line_num += 1;
+                                if line_num % PROGRESS_INTERVAL == 0 {
+                                    records_read.fetch_add(PROGRESS_INTERVAL, Ordering::Relaxed);
+                                }
+                            }
+                            Ok(())
+                        });
+                        worker.record_counts.read =
+                            line_num.max(chunk.first_record) - chunk.first_record;
+                        match result {
+                            Ok(()) => Ok(worker),
+                            Err(err) => {
+                                failed.store(true, Ordering::Relaxed);
+                                Err((line_num, err))
+                            }
+                        }
+                    })
+                })
+                .collect();
+            if let Some(progress_bar) = &mut progress_bar {
+                while !handles.iter().all(|handle| handle.is_finished()) {
+                    progress_bar.update(0, records_read.load(Ordering::Relaxed));
+                    std::thread::sleep(REDRAW_INTERVAL);
+                }
+                progress_bar.finish();
             }
-            Entry::Vacant(entry) => {
-                entry.insert(T::new(parsed_val));
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("an aggregation thread panicked"))
+                .collect()
+        });
+        self.progress_bar = progress_bar;
+        let workers = Self::collect_workers(results)?;
+        self.record_counts.read = workers.iter().map(|w| w.record_counts.read).sum();
+        self.merge_workers(workers)
+    }
+
+    /// Aggregates several files with the same header row, as if they were a single file holding
+    /// the records of each file in turn (so the rows and columns keep the order they first appear
+    /// in across the files). Each file gets aggregated into a table of its own, with the files
+    /// split between the aggregator's threads, and the tables get merged together once every file
+    /// has been read. Each reader has to start at the beginning of its file.
+    ///
+    /// If a file can't be aggregated, this returns its position in `readers` along with the error
+    /// (whose line numbers and positions refer to that file). Errors that don't come from any one
+    /// file, like cells that overflow once the files' tables get merged, come without a position.
+    /// With a limit on the number of
+    /// records, the files get read one at a time, in order, and files can't be sampled.
+    pub fn aggregate_files<R>(
+        &mut self,
+        mut readers: Vec<csv::Reader<R>>,
+    ) -> Result<(), (Option<usize>, CsvCliError)>
+    where
+        R: std::io::Read + Send,
+    {
+        if self.sample.is_some() {
+            return Err((
+                None,
+                CsvCliError::InvalidConfiguration(
+                    "Several files can't be sampled at once".to_string(),
+                ),
+            ));
+        }
+        let first = match readers.first_mut() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let headers = first
+            .headers()
+            .map_err(|err| (Some(0), err.into()))?
+            .clone();
+        self.prepare_aggregation(first)
+            .map_err(|err| (Some(0), err))?;
+        for (file_idx, rdr) in readers.iter_mut().enumerate().skip(1) {
+            if *rdr.headers().map_err(|err| (Some(file_idx), err.into()))? != headers {
+                return Err((
+                    Some(file_idx),
+                    CsvCliError::InvalidConfiguration(
+                        "Its header row doesn't match the header row of the first file".to_string(),
+                    ),
+                ));
             }
+        }
+        let num_files = readers.len();
+        // every file's table stays in memory until they all get merged
+        let workers: Vec<Self> = (0..num_files)
+            .map(|file_idx| {
+                let mut worker = self.worker();
+                worker.file_index = file_idx;
+                worker.max_cells = self.max_cells.map(|cells| (cells / num_files).max(1));
+                if let (Some(limit), Some(worker_limit)) =
+                    (&self.memory_limit, worker.memory_limit.as_mut())
+                {
+                    worker_limit.bytes = (limit.bytes / num_files).max(1);
+                }
+                worker
+            })
+            .collect();
+        let threads = if self.limit.is_some() {
+            1
+        } else {
+            self.threads.clamp(1, num_files)
         };
+        let queue = Mutex::new(readers.into_iter().zip(workers).enumerate());
+        let remaining = Mutex::new(self.limit);
+        let failed = AtomicBool::new(false);
+        // the number of bytes and records every thread has read, for the progress bar
+        let bytes_read = AtomicUsize::new(0);
+        let records_read = AtomicUsize::new(0);
+        let mut progress_bar = self.progress_bar.take();
+        let mut results: Vec<(usize, CsvCliResult<Self>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    let (queue, remaining) = (&queue, &remaining);
+                    let (failed, bytes_read, records_read) = (&failed, &bytes_read, &records_read);
+                    scope.spawn(move || {
+                        let mut results = Vec::new();
+                        loop {
+                            // the lock is only held while taking the next file
+                            let next = queue.lock().unwrap().next();
+                            let (file_idx, (mut rdr, mut worker)) = match next {
+                                Some(next) if !failed.load(Ordering::Relaxed) => next,
+                                _ => return results,
+                            };
+                            // there's only one thread when there's a limit, so the files get
+                            // read in order
+                            worker.limit = *remaining.lock().unwrap();
+                            let mut last_byte = 0;
+                            let result = worker.read_records(&mut rdr, |agg, record, line_num| {
+                                agg.add_or_skip_record(record, line_num)?;
+                                if line_num % PROGRESS_INTERVAL == 0 {
+                                    let byte = record.position().map_or(0, |pos| pos.byte());
+                                    bytes_read
+                                        .fetch_add((byte - last_byte) as usize, Ordering::Relaxed);
+                                    last_byte = byte;
+                                    records_read.fetch_add(PROGRESS_INTERVAL, Ordering::Relaxed);
+                                }
+                                Ok(!failed.load(Ordering::Relaxed))
+                            });
+                            if let Some(limit) = remaining.lock().unwrap().as_mut() {
+                                *limit -= worker.record_counts.read;
+                            }
+                            if result.is_err() {
+                                failed.store(true, Ordering::Relaxed);
+                            }
+                            results.push((file_idx, result.map(|_| worker)));
+                        }
+                    })
+                })
+                .collect();
+            if let Some(progress_bar) = &mut progress_bar {
+                while !handles.iter().all(|handle| handle.is_finished()) {
+                    progress_bar.update(
+                        bytes_read.load(Ordering::Relaxed) as u64,
+                        records_read.load(Ordering::Relaxed),
+                    );
+                    std::thread::sleep(REDRAW_INTERVAL);
+                }
+                progress_bar.finish();
+            }
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("an aggregation thread panicked"))
+                .collect()
+        });
+        self.progress_bar = progress_bar;
+        results.sort_by_key(|(file_idx, _)| *file_idx);
+        let mut workers = Vec::new();
+        // the line numbers of each file continue on from the files before it, so the rows and
+        // columns of the merged table keep the order they first appear in
+        let mut offset = 0;
+        let mut starts = Vec::with_capacity(results.len());
+        for (file_idx, result) in results {
+            let mut worker = result.map_err(|err| (Some(file_idx), err))?;
+            worker
+                .index_lines
+                .iter_mut()
+                .for_each(|line| *line += offset);
+            worker
+                .column_lines
+                .iter_mut()
+                .for_each(|line| *line += offset);
+            starts.push(offset);
+            offset += worker.record_counts.read;
+            workers.push(worker);
+        }
+        self.record_counts.read = offset;
+        self.merge_workers(workers).map_err(|mut err| {
+            // the record's line number counts the records of every file before it
+            let mut file_idx = None;
+            if let CsvCliError::ParsingError { line_num, .. } = &mut err {
+                let idx = starts
+                    .iter()
+                    .rposition(|&start| start <= *line_num)
+                    .unwrap_or(0);
+                *line_num -= starts[idx];
+                file_idx = Some(idx);
+            }
+            (file_idx, err)
+        })
+    }
+
+    /// Writes the rows and columns of the table (along with their record counts) and the state of
+    /// every cell to `wtr`, so a later run can load them with `load_state` and only aggregate the
+    /// records that were added since. `signature` describes the pivot table (its function and its
+    /// fields), so a state doesn't get merged into a different kind of table.
+    ///
+    /// This has to be called before `write_results`, which can combine rows or drop them.
+    /// A state is saved as CSV, with each record starting with what it describes: the signature
+    /// first, then a `row` or `column` record for every row and column (in the order they
+    /// first appeared), and then a `cell` record for every cell.
+    pub fn save_state<W: io::Write>(&self, wtr: W, signature: &str) -> CsvCliResult<()> {
+        self.write_state(wtr, &[STATE_MARKER, STATE_VERSION, signature])
+    }
 
+    /// Writes the state of the table after a first record of `header`
+    fn write_state<W: io::Write>(&self, wtr: W, header: &[&str]) -> CsvCliResult<()> {
+        if self.spilled() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "The state of a table whose cells were spilled to disk can't be saved".to_string(),
+            ));
+        }
+        let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(wtr);
+        wtr.write_record(header)?;
+        let mut fields = Vec::new();
+        let keys = [
+            ("row", &self.indexes, &self.row_counts),
+            ("column", &self.columns, &self.column_counts),
+        ];
+        for (kind, keys, counts) in keys {
+            for (key, count) in keys.iter().zip(counts) {
+                fields.clear();
+                fields.push(kind.to_string());
+                count.save(&mut fields);
+                fields.extend(key.iter().cloned());
+                wtr.write_record(&fields)?;
+            }
+        }
+        // sorting the cells keeps the state the same from one run to the next
+        let mut cells: Vec<_> = self.aggregations.iter().collect();
+        cells.sort_unstable_by_key(|(key, _)| *key);
+        for ((row_id, col_id, value_idx), acc) in cells {
+            fields.clear();
+            fields.push("cell".to_string());
+            row_id.save(&mut fields);
+            col_id.save(&mut fields);
+            value_idx.save(&mut fields);
+            acc.save(&mut fields);
+            wtr.write_record(&fields)?;
+        }
+        wtr.flush()?;
         Ok(())
     }
 
-    fn sort_results(&mut self) {
-        match self.column_order {
-            OutputOrder::Ascending => self.columns.sort(),
-            OutputOrder::Descending => self.columns.sort_by(|a, b| b.cmp(a)),
-            OutputOrder::IndexOrder => {}
+    /// Merges a state written by `save_state` into the table, as if the records it was built from
+    /// had been read before any of the records that get aggregated next (so its rows and columns
+    /// come first). Returns an error if `rdr` doesn't hold a state or holds the state of a table
+    /// with a different `signature`.
+    pub fn load_state<R: io::Read>(&mut self, rdr: R, signature: &str) -> CsvCliResult<()> {
+        self.read_state(rdr, STATE_MARKER, signature).map(|_| ())
+    }
+
+    /// Loads a checkpoint saved by `with_checkpoints` (with the same `signature`), returning the
+    /// position of the first record it doesn't cover. Seeking the reader to that position and
+    /// then calling `aggregate` picks the aggregation up where the checkpoint left off, with the
+    /// records numbered as though they had all been read in one go.
+    pub fn resume<R: io::Read>(&mut self, rdr: R, signature: &str) -> CsvCliResult<csv::Position> {
+        let progress = self.read_state(rdr, CHECKPOINT_MARKER, signature)?;
+        let progress: Vec<u64> = progress
+            .iter()
+            .map(|field| field.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| corrupted_state())?;
+        let (position, counts) = match progress.as_slice() {
+            // checkpoints from before `nan_values` and `bad_records` were counted have fewer counts
+            [byte, line, record, counts @ ..] if (5..=7).contains(&counts.len()) => {
+                let mut position = csv::Position::new();
+                position.set_byte(*byte).set_line(*line).set_record(*record);
+                (position, counts)
+            }
+            _ => return Err(corrupted_state()),
         };
-        match self.row_order {
-            OutputOrder::Ascending => self.indexes.sort(),
-            OutputOrder::Descending => self.indexes.sort_by(|a, b| b.cmp(a)),
-            OutputOrder::IndexOrder => {}
+        self.record_counts = RecordCounts {
+            read: counts[0] as usize,
+            aggregated: counts[1] as usize,
+            filtered: counts[2] as usize,
+            dropped_keys: counts[3] as usize,
+            empty_values: counts[4] as usize,
+            nan_values: counts.get(5).map_or(0, |&count| count as usize),
+            bad_records: counts.get(6).map_or(0, |&count| count as usize),
         };
+        self.resumed_records = self.record_counts.read;
+        Ok(position)
     }
+
+    /// Saves a checkpoint if it's been long enough since the last one, given the position of the
+    /// next record and the number of records that have been read so far
+    fn save_checkpoint_if_due(
+        &mut self,
+        position: &csv::Position,
+        records_read: usize,
+    ) -> CsvCliResult<()> {
+        let (path, signature) = match &self.checkpoints {
+            Some(checkpoints) if checkpoints.last_saved.elapsed() >= checkpoints.interval => {
+                (checkpoints.path.clone(), checkpoints.signature.clone())
+            }
+            _ => return Ok(()),
+        };
+        let counts = self.record_counts;
+        let progress: Vec<String> = [
+            position.byte(),
+            position.line(),
+            position.record(),
+            records_read as u64,
+            counts.aggregated as u64,
+            counts.filtered as u64,
+            counts.dropped_keys as u64,
+            counts.empty_values as u64,
+            counts.nan_values as u64,
+            counts.bad_records as u64,
+        ]
+        .iter()
+        .map(u64::to_string)
+        .collect();
+        let mut header = vec![CHECKPOINT_MARKER, STATE_VERSION, &signature];
+        header.extend(progress.iter().map(String::as_str));
+        // the new checkpoint only replaces the old one once it's been written in full
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        self.write_state(io::BufWriter::new(File::create(&tmp_path)?), &header)?;
+        fs::rename(&tmp_path, &path)?;
+        if let Some(checkpoints) = self.checkpoints.as_mut() {
+            checkpoints.last_saved = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Reads a state (or checkpoint) whose first field is `marker`, returning the fields of its
+    /// first record that come after the signature
+    fn read_state<R: io::Read>(
+        &mut self,
+        rdr: R,
+        marker: &str,
+        signature: &str,
+    ) -> CsvCliResult<Vec<String>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(rdr);
+        let mut record = csv::StringRecord::new();
+        if !rdr.read_record(&mut record)? || record.get(0) != Some(marker) {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "The file doesn't hold a {}",
+                if marker == STATE_MARKER {
+                    "saved pivot table"
+                } else {
+                    "checkpoint"
+                }
+            )));
+        }
+        if record.get(1) != Some(STATE_VERSION) {
+            return Err(CsvCliError::InvalidConfiguration(
+                "The file was saved by a different version of clipivot".to_string(),
+            ));
+        }
+        let saved = record.get(2).unwrap_or_default();
+        if saved != signature {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "The file was saved for a different pivot table ({}), not for {}",
+                saved, signature
+            )));
+        }
+        let header: Vec<String> = record.iter().skip(3).map(String::from).collect();
+        // the IDs the saved rows and columns have in this table
+        let mut row_ids = Vec::new();
+        let mut col_ids = Vec::new();
+        while rdr.read_record(&mut record)? {
+            let mut fields = record.iter();
+            match fields.next() {
+                Some(kind @ ("row" | "column")) => {
+                    let count = usize::load(&mut fields).ok_or_else(corrupted_state)?;
+                    let key: Vec<String> = fields.map(String::from).collect();
+                    let (keys, counts, lines, ids, key_bytes) = if kind == "row" {
+                        (
+                            &mut self.indexes,
+                            &mut self.row_counts,
+                            &mut self.index_lines,
+                            &mut row_ids,
+                            &mut self.row_key_bytes,
+                        )
+                    } else {
+                        (
+                            &mut self.columns,
+                            &mut self.column_counts,
+                            &mut self.column_lines,
+                            &mut col_ids,
+                            &mut self.column_key_bytes,
+                        )
+                    };
+                    if key_id(keys, &key).is_none() {
+                        *key_bytes += key_size(&key);
+                    }
+                    let id = intern(keys, counts, lines, &key, 0);
+                    counts[id] = counts[id] - 1 + count;
+                    ids.push(id);
+                }
+                Some("cell") => {
+                    let row_id = usize::load(&mut fields).and_then(|id| row_ids.get(id));
+                    let col_id = usize::load(&mut fields).and_then(|id| col_ids.get(id));
+                    let value_idx = usize::load(&mut fields)
+                        .filter(|&value_idx| value_idx < self.num_value_slots());
+                    let acc = T::load(&mut fields);
+                    let (key, acc) = match (row_id, col_id, value_idx, acc) {
+                        (Some(&row_id), Some(&col_id), Some(value_idx), Some(acc)) => {
+                            ((row_id, col_id, value_idx), acc)
+                        }
+                        _ => return Err(corrupted_state()),
+                    };
+                    self.cell_heap_bytes += acc.heap_size();
+                    match self.aggregations.get_mut(&key) {
+                        Some(merged) => merged.merge(acc),
+                        None => self.aggregations.insert(key, acc),
+                    }
+                }
+                _ => return Err(corrupted_state()),
+            }
+        }
+        Ok(header)
+    }
+
+    /// Reads the next record of `rdr` into `record`, returning `false` at the end of the input (or
+    /// once `line_num` gets to `end`). When bad records get skipped, records with the wrong number
+    /// of fields get counted and skipped (moving `line_num` past them) instead of being an error.
+    fn read_record<R: std::io::Read>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+        record: &mut csv::ByteRecord,
+        line_num: &mut usize,
+        end: Option<usize>,
+    ) -> CsvCliResult<bool> {
+        while end.map_or(true, |end| *line_num < end) {
+            match rdr.read_byte_record(record) {
+                Ok(read) => return Ok(read),
+                Err(err) => match err.kind() {
+                    csv::ErrorKind::UnequalLengths { pos, .. } if self.skip_bad_records => {
+                        let line = pos.as_ref().map_or(0, csv::Position::line);
+                        self.skip_bad_record(record, *line_num, line, &err.to_string())?;
+                        *line_num += 1;
+                    }
+                    _ => return Err(err.into()),
+                },
+            }
+        }
+        Ok(false)
+    }
+
+    /// Counts a record that got skipped because it couldn't be parsed (writing it to the file
+    /// for rejected records, if there is one), keeping its line number and its error if it's one
+    /// of the first few
+    fn skip_bad_record(
+        &mut self,
+        record: &csv::ByteRecord,
+        line_num: usize,
+        line: u64,
+        err: &str,
+    ) -> CsvCliResult<()> {
+        if let Some(rejects) = &self.rejects {
+            // the first pass of --two-pass runs into the same bad records as the second one
+            if !self.scanning_keys {
+                rejects.write_record(record, err)?;
+            }
+        }
+        self.record_counts.bad_records += 1;
+        if self.bad_records.len() < self.bad_records_kept {
+            self.bad_records
+                .push((line_num, line, err.to_string(), record_text(record)));
+        }
+        self.check_bad_records()
+    }
+
+    /// Returns an error (about the first record that got skipped) if more records have been
+    /// skipped than `with_max_bad_records` allows
+    fn check_bad_records(&self) -> CsvCliResult<()> {
+        let max = match self.max_bad_records {
+            Some(max) if self.record_counts.bad_records > max => max,
+            _ => return Ok(()),
+        };
+        let mut err = format!(
+            "More than {} record{} couldn't be parsed (see --max-errors), so the input might not be formatted the way you expect (for instance, it might use a different delimiter)",
+            max,
+            if max == 1 { "" } else { "s" }
+        );
+        // the skipped records don't get saved in checkpoints
+        let (line_num, str_to_parse) = match self.bad_records.first() {
+            Some((line_num, line, first_err, text)) => {
+                err.push_str(&format!(
+                    ". The first one was on line {}: {}",
+                    line, first_err
+                ));
+                (*line_num, text.clone())
+            }
+            None => (self.record_counts.read, String::new()),
+        };
+        Err(CsvCliError::ParsingError {
+            line_num,
+            str_to_parse,
+            err,
+            column: None,
+            position: None,
+        })
+    }
+
+    /// Reads the records of `rdr` (stopping at the limit, and sampling them if the aggregator
+    /// samples its input), passing each record that should get aggregated to `add`
+    /// (in file order) until `add` returns `false`
+    fn read_records<R, F>(&mut self, rdr: &mut csv::Reader<R>, mut add: F) -> CsvCliResult<()>
+    where
+        R: std::io::Read,
+        F: FnMut(&mut Self, &csv::ByteRecord, usize) -> CsvCliResult<bool>,
+    {
+        // after resuming from a checkpoint, the records are numbered from where it left off
+        let mut line_num = self.resumed_records;
+        let mut record = csv::ByteRecord::new();
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut num_sampled = 0;
+        // the reservoir for fixed-size samples, along with the line numbers of the records
+        let mut reservoir: Vec<(usize, csv::ByteRecord)> = Vec::new();
+        while self.read_record(rdr, &mut record, &mut line_num, self.limit)? {
+            let keep_going = match self.sample {
+                None => add(self, &record, line_num)?,
+                Some(Sample::Fraction(fraction)) => {
+                    if rng.gen::<f64>() < fraction {
+                        num_sampled += 1;
+                        add(self, &record, line_num)?
+                    } else {
+                        true
+                    }
+                }
+                Some(Sample::Size(size)) => {
+                    if reservoir.len() < size {
+                        reservoir.push((line_num, record.clone()));
+                    } else {
+                        let replaced = rng.gen_range(0, line_num + 1);
+                        if replaced < size {
+                            reservoir[replaced] = (line_num, record.clone());
+                        }
+                    }
+                    true
+                }
+            };
+            line_num += 1;
+            if line_num % PROGRESS_INTERVAL == 0 {
+                if let Some(progress_bar) = &mut self.progress_bar {
+                    progress_bar.update(rdr.position().byte(), line_num);
+                }
+                if self.sample.is_none() {
+                    self.save_checkpoint_if_due(rdr.position(), line_num)?;
+                }
+            }
+            if !keep_going {
+                break;
+            }
+        }
+        if let Some(progress_bar) = &mut self.progress_bar {
+            progress_bar.finish();
+        }
+        if !reservoir.is_empty() {
+            // aggregate the records in file order, so rows and columns keep their usual order
+            reservoir.sort_by_key(|(record_num, _)| *record_num);
+            num_sampled = reservoir.len();
+            for (record_num, sampled) in &reservoir {
+                if !add(self, sampled, *record_num)? {
+                    break;
+                }
+            }
+        }
+        if self.sample.is_some() {
+            self.sample_size = Some((num_sampled, line_num));
+        }
+        self.record_counts.read = line_num;
+        Ok(())
+    }
+
+    /// Aggregates the records on several threads. The records get parsed on this thread and
+    /// sent in batches (over a bounded channel, so parsing can't get too far ahead) to the
+    /// worker threads, which each aggregate the batches they receive into their own cells.
+    /// Once the whole file has been read, the cells of the workers get merged together.
+    fn aggregate_in_parallel<R: std::io::Read>(
+        &mut self,
+        rdr: &mut csv::Reader<R>,
+    ) -> CsvCliResult<()> {
+        // the batches get sent to the threads in turn (rather than to whichever thread is free),
+        // so each thread aggregates the same records every time and the cells get merged
+        // the same way, which keeps floating point results and ties byte-for-byte identical
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..self.threads)
+            .map(|_| {
+                mpsc::sync_channel::<Vec<(usize, csv::ByteRecord)>>(CHANNEL_BATCHES_PER_THREAD)
+            })
+            .unzip();
+        // set when a worker runs into an error, so the rest of the file doesn't get read
+        let failed = AtomicBool::new(false);
+        let workers: Vec<Self> = (0..self.threads).map(|_| self.worker()).collect();
+        let (read_result, results) = std::thread::scope(|scope| {
+            let handles: Vec<_> = workers
+                .into_iter()
+                .zip(receivers)
+                .map(|(mut worker, receiver)| {
+                    let failed = &failed;
+                    scope.spawn(move || loop {
+                        let batch = match receiver.recv() {
+                            Ok(batch) => batch,
+                            // the channel is closed once every record has been sent
+                            Err(_) => return Ok(worker),
+                        };
+                        for (line_num, record) in &batch {
+                            if let Err(err) = worker.add_or_skip_record(record, *line_num) {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err((*line_num, err));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let mut batch = Vec::with_capacity(THREAD_BATCH_SIZE);
+            let mut next_thread = 0;
+            let read_result = self.read_records(rdr, |_, record, line_num| {
+                batch.push((line_num, record.clone()));
+                if batch.len() == THREAD_BATCH_SIZE {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(THREAD_BATCH_SIZE));
+                    // sending only fails if the thread has stopped (because of an error)
+                    let sent = senders[next_thread].send(full).is_ok();
+                    next_thread = (next_thread + 1) % senders.len();
+                    return Ok(sent && !failed.load(Ordering::Relaxed));
+                }
+                Ok(true)
+            });
+            if read_result.is_ok() && !batch.is_empty() {
+                let _ = senders[next_thread].send(batch);
+            }
+            // closing the channels tells the workers there aren't any more records
+            drop(senders);
+            let results: Vec<Result<Self, (usize, CsvCliError)>> = handles
+                .into_iter()
+                .map(|handle| handle.join().expect("an aggregation thread panicked"))
+                .collect();
+            (read_result, results)
+        });
+        let workers = Self::collect_workers(results)?;
+        read_result?;
+        self.merge_workers(workers)
+    }
+
+    /// The workers that aggregated their records without any errors, or the error for the
+    /// earliest record if any of them ran into one
+    fn collect_workers(
+        results: Vec<Result<Self, (usize, CsvCliError)>>,
+    ) -> CsvCliResult<Vec<Self>> {
+        let mut workers = Vec::new();
+        let mut first_error: Option<(usize, CsvCliError)> = None;
+        for result in results {
+            match result {
+                Ok(worker) => workers.push(worker),
+                // the workers only see records that were read without errors, so their errors
+                // come before any error reading the file
+                Err((line_num, err)) => {
+                    if first_error
+                        .as_ref()
+                        .map_or(true, |(first, _)| line_num < *first)
+                    {
+                        first_error = Some((line_num, err));
+                    }
+                }
+            }
+        }
+        match first_error {
+            Some((_, err)) => Err(err),
+            None => Ok(workers),
+        }
+    }
+
+    /// The number of records that were read, and how many of them were skipped
+    pub fn record_counts(&self) -> RecordCounts {
+        self.record_counts
+    }
+
+    /// The line numbers of the first few records (up to `with_bad_records_kept`) that were skipped
+    /// because they couldn't be parsed. With several files, the line numbers are the lines of
+    /// the records in their own files.
+    pub fn bad_record_lines(&self) -> Vec<u64> {
+        self.bad_records.iter().map(|&(_, line, ..)| line).collect()
+    }
+
+    /// The line numbers of the first few records that were skipped (like `bad_record_lines`),
+    /// along with the errors they ran into
+    pub fn bad_records(&self) -> Vec<(u64, &str)> {
+        self.bad_records
+            .iter()
+            .map(|(_, line, err, _)| (*line, err.as_str()))
+            .collect()
+    }
+
+    /// The number of row keys, column keys, and populated cells that were aggregated
+    pub fn table_size(&self) -> TableSize {
+        TableSize {
+            rows: self.indexes.len(),
+            columns: self.columns.len(),
+            cells: self.aggregations.len(),
+        }
+    }
+
+    /// The counts in the cells of the first values column, with a row of counts for each row
+    /// key and a count for each column key (for the chi-square test of `--chi2`). Cells that
+    /// weren't aggregated (or don't hold a number) count as 0.
+    pub fn cell_counts(&self) -> Vec<Vec<f64>> {
+        let mut counts = vec![vec![0.; self.columns.len()]; self.indexes.len()];
+        for (row_id, col_id, value_idx) in self.aggregations.keys() {
+            if value_idx != 0 {
+                continue;
+            }
+            if let Some(count) = self
+                .aggregations
+                .get(&(row_id, col_id, value_idx))
+                .and_then(|acc| acc.compute())
+                .and_then(|val| val.to_value().as_f64())
+            {
+                counts[row_id][col_id] = count;
+            }
+        }
+        counts
+    }
+
+    /// Finishes the pivot table (sorting its rows and columns) and computes its cells, keeping
+    /// the rows and columns as keys rather than turning the table into records. This is what a
+    /// `Pivot` returns, so it leaves out the options that only change the records that get
+    /// written, like totals and cell transformations.
+    pub fn into_table(self) -> CsvCliResult<Table>
+    where
+        T: 'static,
+        I: 'static,
+        O: 'static,
+    {
+        self.into_rows()?.into_table()
+    }
+
+    /// Finishes the pivot table like `into_table`, but computes its cells one row at a time, as
+    /// the rows get taken from the iterator it returns
+    pub fn into_rows(mut self) -> CsvCliResult<Rows>
+    where
+        T: 'static,
+        I: 'static,
+        O: 'static,
+    {
+        if self.spilled() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "A table whose cells were spilled to disk can only be written out".to_string(),
+            ));
+        }
+        if !self.is_plain_table() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "A table with totals, cell transformations, or other changes to its cells can only be written out"
+                    .to_string(),
+            ));
+        }
+        self.prepare_write()?;
+        let num_value_slots = self.num_value_slots();
+        let num_columns = self.columns.len();
+        let aggregations = std::mem::take(&mut self.aggregations);
+        let rows = std::mem::take(&mut self.indexes)
+            .into_iter()
+            .enumerate()
+            .map(move |(row_id, key)| {
+                let mut cells = Vec::with_capacity(num_columns * num_value_slots);
+                for col_id in 0..num_columns {
+                    for value_idx in 0..num_value_slots {
+                        let val = aggregations
+                            .get(&(row_id, col_id, value_idx))
+                            .and_then(|acc| acc.compute());
+                        cells.push(val.map(|val| val.to_value()));
+                    }
+                }
+                Ok(PivotRow { key, cells })
+            });
+        Ok(Rows::new(
+            std::mem::take(&mut self.index_names),
+            std::mem::take(&mut self.column_names),
+            std::mem::take(&mut self.value_names),
+            std::mem::take(&mut self.columns).into_iter().collect(),
+            num_value_slots,
+            Box::new(rows),
+        ))
+    }
+
+    /// Computes the cells of the pivot table, applying any cell transformations
+    pub fn to_table(&self) -> CsvCliResult<PivotTable> {
+        let row_groups = self.get_row_groups();
+        let mut table = PivotTable {
+            headers: self.get_pivot_headers(),
+            rows: row_groups
+                .iter()
+                .map(|(key, rows)| match rows.as_slice() {
+                    [row] if *row == key => Ok(self.get_pivot_row(key)),
+                    _ => self.get_merged_row(key, rows),
+                })
+                .collect::<CsvCliResult<_>>()?,
+            num_index_columns: self.num_index_columns(),
+            num_value_slots: self.num_value_slots(),
+            column_keys: self
+                .columns
+                .iter()
+                .map(|col| join_key(col, &self.key_separator))
+                .collect(),
+        };
+        for transform in &self.cell_transforms {
+            transform.apply(&mut table)?;
+        }
+        if self.totals {
+            self.add_totals(&mut table, &row_groups)?;
+        }
+        if self.drop_empty {
+            table.drop_empty();
+        }
+        table.recode(&self.recode_rules);
+        Ok(table)
+    }
+
+    /// The rows of the output, each paired with the aggregated rows it combines. This is just
+    /// every row on its own unless there are subtotals, in which case each subtotal row combines
+    /// the rows sharing the first few index values.
+    fn get_row_groups(&self) -> Vec<RowGroup<'_>> {
+        let rows: Vec<&Vec<String>> = self.indexes.iter().collect();
+        let mut groups = Vec::new();
+        if self.subtotals {
+            self.add_subtotal_groups(&rows, 0, &mut groups);
+        } else {
+            groups.extend(rows.iter().map(|row| ((*row).clone(), vec![*row])));
+        }
+        groups
+    }
+
+    /// Adds a subtotal row for each distinct value of the index field at `level` (in the order
+    /// the values first appear), followed by the rows beneath it
+    fn add_subtotal_groups<'a>(
+        &self,
+        rows: &[&'a Vec<String>],
+        level: usize,
+        groups: &mut Vec<RowGroup<'a>>,
+    ) {
+        let num_levels = self.num_index_columns();
+        if level + 1 >= num_levels {
+            groups.extend(rows.iter().map(|row| ((*row).clone(), vec![*row])));
+            return;
+        }
+        let mut children: IndexMap<&str, Vec<&'a Vec<String>>> = IndexMap::new();
+        for row in rows {
+            children.entry(row[level].as_str()).or_default().push(*row);
+        }
+        for members in children.values() {
+            let mut key = members[0][..=level].to_vec();
+            key.push(TOTAL_LABEL.to_string());
+            key.resize(num_levels, String::new());
+            groups.push((key, members.clone()));
+            self.add_subtotal_groups(members, level + 1, groups);
+        }
+    }
+
+    /// A row of the pivot table that combines several aggregated rows
+    fn get_merged_row(&self, key: &[String], rows: &[&Vec<String>]) -> CsvCliResult<Vec<String>> {
+        let mut record = key.to_vec();
+        for col in &self.columns {
+            for value_idx in 0..self.num_value_slots() {
+                record.push(
+                    self.merge_cells(rows, &[col], value_idx)?
+                        .unwrap_or_default(),
+                );
+            }
+        }
+        Ok(record)
+    }
+
+    /// Adds the totals column and row to a table
+    fn add_totals(&self, table: &mut PivotTable, row_groups: &[RowGroup<'_>]) -> CsvCliResult<()> {
+        let num_value_slots = self.num_value_slots();
+        let all_rows: Vec<&Vec<String>> = self.indexes.iter().collect();
+        let all_cols: Vec<&Vec<String>> = self.columns.iter().collect();
+        let total_col = self.total_key(self.column_cols.len().max(1));
+        if !self.column_cols.is_empty() {
+            table.headers = self.get_headers_for(self.columns.iter().chain(Some(&total_col)));
+            table
+                .column_keys
+                .push(join_key(&total_col, &self.key_separator));
+            for ((_, rows), record) in row_groups.iter().zip(table.rows.iter_mut()) {
+                for value_idx in 0..num_value_slots {
+                    record.push(
+                        self.merge_cells(rows, &all_cols, value_idx)?
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+        }
+        if !self.index_cols.is_empty() {
+            let mut record = self.total_key(self.num_index_columns());
+            for col in &self.columns {
+                for value_idx in 0..num_value_slots {
+                    record.push(
+                        self.merge_cells(&all_rows, &[col], value_idx)?
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+            if !self.column_cols.is_empty() {
+                for value_idx in 0..num_value_slots {
+                    record.push(
+                        self.merge_cells(&all_rows, &all_cols, value_idx)?
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+            table.rows.push(record);
+        }
+        Ok(())
+    }
+
+    /// The key of a total row (or column) with the given number of fields
+    fn total_key(&self, width: usize) -> Vec<String> {
+        let mut key = vec![String::new(); width];
+        key[0] = TOTAL_LABEL.to_string();
+        key
+    }
+
+    /// The aggregate of the given values column across a set of rows and columns. This is an
+    /// error if the cells can't be combined (like sums that overflow when they're added up).
+    fn merge_cells(
+        &self,
+        rows: &[&Vec<String>],
+        cols: &[&Vec<String>],
+        value_idx: usize,
+    ) -> CsvCliResult<Option<String>> {
+        let mut total: Option<T> = None;
+        for row in rows {
+            for col in cols {
+                if let Some(acc) = self.cell(row, col, value_idx) {
+                    let merged = match total.as_mut() {
+                        Some(total) => total.try_merge(acc.clone()),
+                        None => {
+                            total = Some(acc.clone());
+                            Ok(())
+                        }
+                    };
+                    merged.map_err(|err| CsvCliError::ParsingError {
+                        line_num: key_id(&self.indexes, row).map_or(0, |id| self.index_lines[id]),
+                        str_to_parse: row.join(" "),
+                        err: format!("{} when adding up the cells of a total", err),
+                        column: self.value_column(value_idx),
+                        position: None,
+                    })?;
+                }
+            }
+        }
+        Ok(total
+            .and_then(|total| total.compute())
+            .map(|val| val.to_cell()))
+    }
+
+    /// The accumulator of a cell, if any values were aggregated into it
+    fn cell(&self, row: &[String], col: &[String], value_idx: usize) -> Option<&T> {
+        let row_id = key_id(&self.indexes, row)?;
+        let col_id = key_id(&self.columns, col)?;
+        self.aggregations.get(&(row_id, col_id, value_idx))
+    }
+
+    /// Writes the aggregated information into a list of records
+    pub fn to_vec(&self) -> CsvCliResult<Vec<Vec<String>>> {
+        self.to_table().map(PivotTable::into_records)
+    }
+
+    /// Writes the non-empty cells of the pivot table into a list of records, each holding
+    /// a cell's row fields, its column fields, and its values (starting with a header row)
+    pub fn to_sparse_vec(&self) -> Vec<Vec<String>> {
+        let header = self.get_sparse_header();
+        // only look up the cells that were aggregated, rather than every (row, column) pair
+        let mut row_cells: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (row_id, col_id, _) in self.aggregations.keys() {
+            row_cells.entry(row_id).or_default().push(col_id);
+        }
+        let mut records = vec![header];
+        for (row_id, row) in self.indexes.iter().enumerate() {
+            let mut cols = match row_cells.remove(&row_id) {
+                Some(cols) => cols,
+                None => continue,
+            };
+            cols.sort_unstable();
+            cols.dedup();
+            for col_id in cols {
+                let col = self.columns.get_index(col_id).unwrap();
+                let values: Vec<Option<String>> = (0..self.num_value_slots())
+                    .map(|value_idx| {
+                        self.aggregations
+                            .get(&(row_id, col_id, value_idx))
+                            .and_then(|acc| acc.compute())
+                            .map(|val| val.to_cell())
+                    })
+                    .collect();
+                if values.iter().all(Option::is_none) {
+                    continue;
+                }
+                let mut record = row.clone();
+                if !self.column_cols.is_empty() {
+                    record.extend(col.iter().cloned());
+                }
+                record.extend(values.into_iter().map(Option::unwrap_or_default));
+                records.push(record);
+            }
+        }
+        records
+    }
+
+    /// The header of the sparse output: the row fields, the column fields, and the values columns
+    fn get_sparse_header(&self) -> Vec<String> {
+        let mut header = self.get_index_labels();
+        header.extend(
+            (0..self.column_cols.len())
+                .map(|i| self.column_names.get(i).cloned().unwrap_or_default()),
+        );
+        if self.num_value_slots() > 1 {
+            header.extend(
+                (0..self.num_value_slots())
+                    .map(|i| self.value_names.get(i).cloned().unwrap_or_default()),
+            );
+        } else {
+            header.push("value".to_string());
+        }
+        header
+    }
+
+    /// Writes the aggregated information to standard output.
+    pub fn write_results<W: io::Write>(&mut self, writer: &mut csv::Writer<W>) -> CsvCliResult<()> {
+        if self.max_cells.is_some() || self.spilled() {
+            return self.write_spilled(writer);
+        }
+        self.prepare_write()?;
+        if self.is_plain_table() {
+            return self.write_plain_table(writer);
+        }
+        let records = if self.sparse {
+            let mut records = self.to_sparse_vec();
+            if let Some(locale) = &self.out_locale {
+                let num_index_columns = records[0].len() - self.num_value_slots().max(1);
+                for record in records.iter_mut().skip(1) {
+                    record
+                        .iter_mut()
+                        .skip(num_index_columns)
+                        .for_each(|cell| locale.format_cell(cell));
+                }
+            }
+            records
+        } else {
+            let mut table = self.to_table()?;
+            if let Some(locale) = &self.out_locale {
+                table.localize(locale);
+            }
+            table.into_records()
+        };
+        for record in records {
+            writer.write_record(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Whether the table gets written just as it was aggregated, without any totals,
+    /// transformations, or other changes to its cells
+    fn is_plain_table(&self) -> bool {
+        !self.sparse
+            && !self.totals
+            && !self.subtotals
+            && !self.drop_empty
+            && self.cell_transforms.is_empty()
+            && self.recode_rules.is_empty()
+            && self.out_locale.is_none()
+    }
+
+    /// Writes a plain table one row at a time, formatting each cell into the same buffer rather
+    /// than building every cell of the table as its own string first
+    fn write_plain_table<W: io::Write>(&self, writer: &mut csv::Writer<W>) -> CsvCliResult<()> {
+        for header in self.get_pivot_headers() {
+            writer.write_record(header)?;
+        }
+        let num_value_slots = self.num_value_slots();
+        let row_width = self.columns.len() * num_value_slots;
+        if self.threads > 1 && self.indexes.len() * row_width >= PARALLEL_WRITE_MIN_CELLS {
+            return self.write_plain_table_parallel(writer, row_width);
+        }
+        let mut buf = String::new();
+        for (row_id, row) in self.indexes.iter().enumerate() {
+            for field in row {
+                writer.write_field(field)?;
+            }
+            for col_id in 0..self.columns.len() {
+                for value_idx in 0..num_value_slots {
+                    buf.clear();
+                    if let Some(val) = self
+                        .aggregations
+                        .get(&(row_id, col_id, value_idx))
+                        .and_then(|acc| acc.compute())
+                    {
+                        val.write_cell(&mut buf);
+                    }
+                    writer.write_field(&buf)?;
+                }
+            }
+            writer.write_record(None::<&[u8]>)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes a large plain table, formatting the cells of the next few blocks of rows on separate
+    /// threads while the rows get written in order on this one
+    fn write_plain_table_parallel<W: io::Write>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        row_width: usize,
+    ) -> CsvCliResult<()> {
+        let block_rows = (WRITE_BLOCK_CELLS / row_width.max(1)).max(1);
+        let (num_columns, num_value_slots) = (self.columns.len(), self.num_value_slots());
+        let cells = &self.aggregations;
+        let mut start = 0;
+        while start < self.indexes.len() {
+            let blocks: Vec<Range<usize>> = (0..self.threads)
+                .map(|block| start + block * block_rows)
+                .take_while(|&first| first < self.indexes.len())
+                .map(|first| first..(first + block_rows).min(self.indexes.len()))
+                .collect();
+            start = blocks.last().map_or(start, |block| block.end);
+            let formatted: Vec<(Range<usize>, FormattedCells)> = std::thread::scope(|scope| {
+                let handles: Vec<_> = blocks
+                    .into_iter()
+                    .map(|rows| {
+                        scope.spawn(move || {
+                            let formatted =
+                                format_cells(cells, rows.clone(), num_columns, num_value_slots);
+                            (rows, formatted)
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+            for (rows, block) in formatted {
+                let mut cell_start = 0;
+                let mut ends = block.ends.iter();
+                for row_id in rows {
+                    for field in self.indexes.get_index(row_id).unwrap() {
+                        writer.write_field(field)?;
+                    }
+                    for &end in ends.by_ref().take(row_width) {
+                        writer.write_field(&block.text[cell_start..end])?;
+                        cell_start = end;
+                    }
+                    writer.write_record(None::<&[u8]>)?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Makes sure none of the options that need the whole table at once are used along with
+    /// a limit on the number of cells in memory
+    fn check_spilling(&self) -> CsvCliResult<()> {
+        let options = [
+            (self.totals, "totals"),
+            (self.subtotals, "subtotals"),
+            (self.top_rows.is_some(), "a limit on the number of rows"),
+            (self.min_count.is_some(), "a minimum count"),
+            (self.having.is_some(), "a `having` condition"),
+            (
+                !self.cell_transforms.is_empty(),
+                "transformations of the cells",
+            ),
+            (!self.recode_rules.is_empty(), "recoded cells"),
+            (self.drop_empty, "dropping empty rows and columns"),
+            (
+                match self.row_order {
+                    OutputOrder::ByTotalAscending
+                    | OutputOrder::ByTotalDescending
+                    | OutputOrder::Custom(_) => true,
+                    OutputOrder::IndexOrder | OutputOrder::Ascending | OutputOrder::Descending => {
+                        false
+                    }
+                },
+                "sorting the rows by their totals or by a list",
+            ),
+        ];
+        match options.iter().find(|(used, _)| *used) {
+            Some((_, option)) => Err(CsvCliError::InvalidConfiguration(format!(
+                "Cells can only be spilled to disk (with a limit on the number of cells) for plain pivot tables, so they can't be combined with {}",
+                option
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The order the cells get spilled in, which is also the order the rows get written in
+    fn cell_order(&self) -> CellOrder {
+        CellOrder {
+            comparator: self.comparator,
+            descending: self.row_order == OutputOrder::Descending,
+        }
+    }
+
+    /// Writes the cells in memory to a new run on disk (sorted by their rows), and empties the
+    /// table. The columns stay in memory, since they're needed to write the table.
+    fn spill(&mut self) -> CsvCliResult<()> {
+        if self.aggregations.is_empty() {
+            return Ok(());
+        }
+        let order = self.cell_order();
+        let mut cells: Vec<(&[String], &[String], usize, &T)> = self
+            .aggregations
+            .iter()
+            .map(|((row_id, col_id, value_idx), acc)| {
+                (
+                    self.indexes.get_index(row_id).unwrap().as_slice(),
+                    self.columns.get_index(col_id).unwrap().as_slice(),
+                    value_idx,
+                    acc,
+                )
+            })
+            .collect();
+        cells.sort_unstable_by(|a, b| order.compare((a.0, a.1, a.2), (b.0, b.1, b.2)));
+        let run = SpillRun::write(cells)?;
+        self.spill_runs.push(run);
+        self.aggregations.clear();
+        self.indexes.clear();
+        self.row_counts.clear();
+        self.index_lines.clear();
+        self.cell_heap_bytes = 0;
+        self.row_key_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes a table whose cells were spilled to disk, merging the runs one row at a time
+    fn write_spilled<W: io::Write>(&mut self, writer: &mut csv::Writer<W>) -> CsvCliResult<()> {
+        if self.columns.is_empty() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "Did not parse any lines before finishing".to_string(),
+            ));
+        }
+        self.spill()?;
+        // the columns are sorted as usual, while the rows are sorted as they get merged
+        self.sort_results()?;
+        let headers = if self.sparse {
+            vec![self.get_sparse_header()]
+        } else {
+            self.get_pivot_headers()
+        };
+        for header in headers {
+            writer.write_record(header)?;
+        }
+        let num_value_slots = self.num_value_slots();
+        let widths = (self.num_index_columns(), self.column_cols.len().max(1));
+        let mut row: Option<(Vec<String>, Vec<Option<T>>)> = None;
+        merge_runs(
+            &self.spill_runs,
+            widths,
+            self.cell_order(),
+            |total: &mut T, part| total.merge(part),
+            |cell: SpilledCell<T>| {
+                if let Some((key, cells)) = &row {
+                    if *key != cell.row {
+                        self.write_spilled_row(writer, key, cells)?;
+                        row = None;
+                    }
+                }
+                let (_, cells) = row.get_or_insert_with(|| {
+                    (
+                        cell.row.clone(),
+                        vec![None; self.columns.len() * num_value_slots],
+                    )
+                });
+                if let Some(col_id) = key_id(&self.columns, &cell.column) {
+                    cells[col_id * num_value_slots + cell.value_idx] = Some(cell.state);
+                }
+                Ok(())
+            },
+        )?;
+        if let Some((key, cells)) = &row {
+            self.write_spilled_row(writer, key, cells)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes one row of a table whose cells were spilled to disk, given the cells of each
+    /// column (and values column) in order
+    fn write_spilled_row<W: io::Write>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        key: &[String],
+        cells: &[Option<T>],
+    ) -> CsvCliResult<()> {
+        let compute = |cell: &Option<T>| {
+            cell.as_ref().and_then(|acc| acc.compute()).map(|val| {
+                let mut text = val.to_cell();
+                if let Some(locale) = &self.out_locale {
+                    locale.format_cell(&mut text);
+                }
+                text
+            })
+        };
+        if !self.sparse {
+            let mut record = key.to_vec();
+            record.extend(cells.iter().map(|cell| compute(cell).unwrap_or_default()));
+            writer.write_record(record)?;
+            return Ok(());
+        }
+        for (col, col_cells) in self
+            .columns
+            .iter()
+            .zip(cells.chunks(self.num_value_slots()))
+        {
+            let values: Vec<Option<String>> = col_cells.iter().map(compute).collect();
+            if values.iter().all(Option::is_none) {
+                continue;
+            }
+            let mut record = key.to_vec();
+            if !self.column_cols.is_empty() {
+                record.extend(col.iter().cloned());
+            }
+            record.extend(values.into_iter().map(Option::unwrap_or_default));
+            writer.write_record(record)?;
+        }
+        Ok(())
+    }
+
+    /// This prepares a pivot table for output (sorting it
+    /// and verifying that there's more than 1 row)
+    fn prepare_write(&mut self) -> CsvCliResult<()> {
+        if self.columns.is_empty() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "Did not parse any lines before finishing".to_string(),
+            ));
+        }
+        self.sort_results()?;
+        self.apply_min_count();
+        self.apply_having()?;
+        self.roll_up_rows()?;
+        self.check_baselines()
+    }
+
+    /// Makes sure that the baseline column of every `CellTransform::Baseline` exists
+    fn check_baselines(&self) -> CsvCliResult<()> {
+        for transform in &self.cell_transforms {
+            if let CellTransform::Baseline(column, _, _) = transform {
+                let exists = self
+                    .columns
+                    .iter()
+                    .any(|col| &join_key(col, &self.key_separator) == column);
+                if !exists {
+                    return Err(CsvCliError::InvalidConfiguration(format!(
+                        "Could not find the baseline column `{}` in the output",
+                        column
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the rows that don't meet the `having` condition
+    fn apply_having(&mut self) -> CsvCliResult<()> {
+        let having = match &self.having {
+            Some(having) => having,
+            None => return Ok(()),
+        };
+        let mut failing = HashSet::new();
+        for (row, count) in self.indexes.iter().zip(&self.row_counts) {
+            let summary = csv::StringRecord::from(vec![
+                self.row_value(row)?.unwrap_or_default(),
+                count.to_string(),
+            ]);
+            match having.matches(&summary) {
+                Ok(true) => {}
+                Ok(false) => {
+                    failing.insert(row.clone());
+                }
+                Err(err) => {
+                    return Err(CsvCliError::InvalidConfiguration(format!(
+                        "Could not evaluate the condition on the row `{}`: {}",
+                        join_key(row, &self.key_separator),
+                        err
+                    )))
+                }
+            }
+        }
+        self.collapse_groups(Axis::Rows, &failing, None);
+        Ok(())
+    }
+
+    /// Combines every row outside of the `top_rows` largest into a single catch-all row
+    fn roll_up_rows(&mut self) -> CsvCliResult<()> {
+        let (num_rows, label) = match &self.top_rows {
+            Some((num_rows, label)) if self.indexes.len() > *num_rows => (*num_rows, label.clone()),
+            _ => return Ok(()),
+        };
+        let rows: Vec<Vec<String>> = self.indexes.iter().cloned().collect();
+        let scores: Vec<Option<f64>> = rows
+            .iter()
+            .map(|row| self.row_total(row))
+            .collect::<CsvCliResult<_>>()?;
+        let mut ranking: Vec<usize> = (0..rows.len()).collect();
+        if scores.iter().all(Option::is_some) {
+            ranking.sort_by(|&a, &b| {
+                scores[b]
+                    .partial_cmp(&scores[a])
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        } else {
+            ranking.sort_by_key(|&idx| std::cmp::Reverse(self.row_counts[idx]));
+        }
+        let rolled_up: HashSet<Vec<String>> = ranking[num_rows..]
+            .iter()
+            .map(|&idx| rows[idx].clone())
+            .collect();
+        self.collapse_groups(Axis::Rows, &rolled_up, Some(&label));
+        Ok(())
+    }
+
+    /// Drops (or combines into a catch-all group) the rows and columns with fewer than `min_count` records
+    fn apply_min_count(&mut self) {
+        let (min_count, label) = match &self.min_count {
+            Some((min_count, label)) => (*min_count, label.clone()),
+            None => return,
+        };
+        for &axis in &[Axis::Rows, Axis::Columns] {
+            let (keys, counts) = match axis {
+                Axis::Rows => (&self.indexes, &self.row_counts),
+                Axis::Columns => (&self.columns, &self.column_counts),
+            };
+            let small_groups: HashSet<Vec<String>> = keys
+                .iter()
+                .zip(counts)
+                .filter(|(_, &count)| count < min_count)
+                .map(|(group, _)| group.clone())
+                .collect();
+            self.collapse_groups(axis, &small_groups, label.as_deref());
+        }
+    }
+
+    /// Removes a set of row (or column) groups from the pivot table, merging them into a single
+    /// group named `label` if there is one.
+    fn collapse_groups(&mut self, axis: Axis, groups: &HashSet<Vec<String>>, label: Option<&str>) {
+        if groups.is_empty() {
+            return;
+        }
+        let width = match axis {
+            Axis::Rows => self.num_index_columns(),
+            Axis::Columns => self.column_cols.len().max(1),
+        };
+        let other_key = label.map(|label| {
+            let mut key = vec![String::new(); width];
+            key[0] = label.to_string();
+            key
+        });
+        let (keys, counts) = match axis {
+            Axis::Rows => (&self.indexes, &self.row_counts),
+            Axis::Columns => (&self.columns, &self.column_counts),
+        };
+        let collapsed_count: usize = keys
+            .iter()
+            .zip(counts)
+            .filter(|(key, _)| groups.contains(*key))
+            .map(|(_, count)| count)
+            .sum();
+        let mut kept: FastIndexSet<Vec<String>> = keys
+            .iter()
+            .filter(|key| !groups.contains(*key))
+            .cloned()
+            .collect();
+        // the cells of the collapsed groups move to the catch-all group (if there is one)
+        let other_id = other_key.map(|other_key| kept.insert_full(other_key).0);
+        let new_ids: Vec<Option<usize>> = keys
+            .iter()
+            .map(|key| match key_id(&kept, key) {
+                Some(id) if !groups.contains(key) => Some(id),
+                _ => other_id,
+            })
+            .collect();
+        self.set_keys(axis, kept, &new_ids);
+        if let Some(other_id) = other_id {
+            let counts = match axis {
+                Axis::Rows => &mut self.row_counts,
+                Axis::Columns => &mut self.column_counts,
+            };
+            counts[other_id] += collapsed_count;
+        }
+    }
+
+    /// Replaces the rows (or columns) with `keys`, where `new_ids` holds the new ID of each of
+    /// the old rows (or `None` if it's getting dropped). The cells of the old rows move to their
+    /// new IDs, and cells that end up with the same ID get merged.
+    fn set_keys(&mut self, axis: Axis, keys: FastIndexSet<Vec<String>>, new_ids: &[Option<usize>]) {
+        let (rows, columns) = match axis {
+            Axis::Rows => (keys.len(), self.columns.len()),
+            Axis::Columns => (self.indexes.len(), keys.len()),
+        };
+        let mut aggregations = self.aggregations.empty_like(rows, columns);
+        // the cells that end up with the same ID get merged in order, rather than in the
+        // order of a hash table, so the result doesn't depend on how the cells were stored
+        let mut cells: Vec<_> = std::mem::take(&mut self.aggregations).into_iter().collect();
+        cells.sort_unstable_by_key(|(key, _)| *key);
+        for ((row_id, col_id, value_idx), acc) in cells {
+            let new_key = match axis {
+                Axis::Rows => new_ids[row_id].map(|row_id| (row_id, col_id, value_idx)),
+                Axis::Columns => new_ids[col_id].map(|col_id| (row_id, col_id, value_idx)),
+            };
+            if let Some(new_key) = new_key {
+                match aggregations.get_mut(&new_key) {
+                    Some(merged) => merged.merge(acc),
+                    None => aggregations.insert(new_key, acc),
+                }
+            }
+        }
+        self.aggregations = aggregations;
+        let (old_keys, counts, lines) = match axis {
+            Axis::Rows => (
+                &mut self.indexes,
+                &mut self.row_counts,
+                &mut self.index_lines,
+            ),
+            Axis::Columns => (
+                &mut self.columns,
+                &mut self.column_counts,
+                &mut self.column_lines,
+            ),
+        };
+        let mut new_counts = vec![0; keys.len()];
+        let mut new_lines = vec![usize::MAX; keys.len()];
+        for (old_id, (&count, &line_num)) in counts.iter().zip(lines.iter()).enumerate() {
+            if let Some(new_id) = new_ids[old_id] {
+                new_counts[new_id] += count;
+                new_lines[new_id] = new_lines[new_id].min(line_num);
+            }
+        }
+        *old_keys = keys;
+        *counts = new_counts;
+        *lines = new_lines;
+    }
+
+    /// Reorders the rows (or columns), keeping the IDs of the cells in line with their new positions
+    fn reorder_keys(&mut self, axis: Axis, keys: FastIndexSet<Vec<String>>) {
+        let old_keys = match axis {
+            Axis::Rows => &self.indexes,
+            Axis::Columns => &self.columns,
+        };
+        let new_ids: Vec<Option<usize>> = old_keys.iter().map(|key| key_id(&keys, key)).collect();
+        self.set_keys(axis, keys, &new_ids);
+    }
+
+    /// The aggregate of the first values column across an entire row, if it's numeric
+    fn row_total(&self, row: &[String]) -> CsvCliResult<Option<f64>> {
+        Ok(self.row_value(row)?.and_then(|total| total.parse().ok()))
+    }
+
+    /// The aggregate of the first values column across an entire row
+    fn row_value(&self, row: &[String]) -> CsvCliResult<Option<String>> {
+        let cols: Vec<&Vec<String>> = self.columns.iter().collect();
+        let row = row.to_vec();
+        self.merge_cells(&[&row], &cols, 0)
+    }
+
+    /// The number of aggregated values per (row, column) pair. This is the same as the number of values
+    /// columns, except when there's no values column at all (which only makes sense with `count`),
+    /// in which case every record gets counted once.
+    fn num_value_slots(&self) -> usize {
+        self.values_cols.len().max(1)
+    }
+
+    /// The number of index columns in the output (one per row field, or a single
+    /// `total` column if there aren't any row fields)
+    fn num_index_columns(&self) -> usize {
+        self.index_cols.len().max(1)
+    }
+
+    /// The header rows of the pivot table: a single row unless `multi_header` is set
+    fn get_pivot_headers(&self) -> Vec<Vec<String>> {
+        self.get_headers_for(self.columns.iter())
+    }
+
+    /// The header rows for a given set of columns
+    fn get_headers_for<'a, C>(&self, columns: C) -> Vec<Vec<String>>
+    where
+        C: Iterator<Item = &'a Vec<String>>,
+    {
+        if !self.multi_header {
+            return vec![self.get_pivot_header(columns)];
+        }
+        let mut levels = Vec::new();
+        for col in columns {
+            for value_idx in 0..self.num_value_slots() {
+                levels.push(self.get_column_levels(col, value_idx));
+            }
+        }
+        let num_levels = levels.first().map_or(1, Vec::len);
+        (0..num_levels)
+            .map(|level| {
+                // the index labels go on the last header row, right above the index values
+                let mut header = if level + 1 == num_levels {
+                    self.get_index_labels()
+                } else {
+                    vec![String::new(); self.num_index_columns()]
+                };
+                header.extend(levels.iter().map(|cells| cells[level].clone()));
+                header
+            })
+            .collect()
+    }
+
+    fn get_index_labels(&self) -> Vec<String> {
+        (0..self.num_index_columns())
+            .map(|i| {
+                self.row_labels
+                    .get(i)
+                    .or_else(|| self.index_names.get(i))
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    fn get_pivot_header<'a, C>(&self, columns: C) -> Vec<String>
+    where
+        C: Iterator<Item = &'a Vec<String>>,
+    {
+        let mut header = self.get_index_labels();
+        for col in columns {
+            for value_idx in 0..self.num_value_slots() {
+                header.push(self.get_column_label(col, value_idx));
+            }
+        }
+        header
+    }
+
+    /// Labels an output column. When there are multiple values columns, each of the
+    /// column names gets suffixed with the name of the values column (e.g. `2019_sales`),
+    /// or simply gets the name of the values column if no columns were selected.
+    fn get_column_label(&self, col_key: &[String], value_idx: usize) -> String {
+        let col = format!(
+            "{}{}",
+            self.header_prefix,
+            join_key(col_key, &self.key_separator)
+        );
+        if self.values_cols.len() <= 1 {
+            return col;
+        }
+        let value_name = self
+            .value_names
+            .get(value_idx)
+            .cloned()
+            .unwrap_or_else(|| self.values_cols[value_idx].to_string());
+        if self.column_cols.is_empty() {
+            format!("{}{}", self.header_prefix, value_name)
+        } else {
+            format!("{}_{}", col, value_name)
+        }
+    }
+
+    /// Splits the label of an output column into one cell per header row: one for each
+    /// column field (with the header prefix on the top level only), followed by the name
+    /// of the values column if there are several of them.
+    fn get_column_levels(&self, col_key: &[String], value_idx: usize) -> Vec<String> {
+        let mut levels: Vec<String> = if self.column_cols.is_empty() {
+            Vec::new()
+        } else {
+            col_key.to_vec()
+        };
+        if let Some(top) = levels.first_mut() {
+            top.insert_str(0, &self.header_prefix);
+        }
+        if self.values_cols.len() > 1 {
+            let value_name = self
+                .value_names
+                .get(value_idx)
+                .cloned()
+                .unwrap_or_else(|| self.values_cols[value_idx].to_string());
+            if levels.is_empty() {
+                levels.push(format!("{}{}", self.header_prefix, value_name));
+            } else {
+                levels.push(value_name);
+            }
+        }
+        if levels.is_empty() {
+            levels.push(self.get_column_label(col_key, value_idx));
+        }
+        levels
+    }
+
+    /// this gets a single row of the pivot table, given the index value
+    fn get_pivot_row(&self, row_key: &[String]) -> Vec<String> {
+        let mut record = row_key.to_vec();
+        let row_id = key_id(&self.indexes, row_key);
+        for col_id in 0..self.columns.len() {
+            for value_idx in 0..self.num_value_slots() {
+                let cell = row_id
+                    .and_then(|row_id| self.aggregations.get(&(row_id, col_id, value_idx)))
+                    .and_then(|acc| acc.compute())
+                    .map_or(String::new(), |val| val.to_cell());
+                record.push(cell);
+            }
+        }
+        record
+    }
+
+    /// An aggregator with the same fields and filters as this one, but without any cells,
+    /// for aggregating some of the records on another thread
+    fn worker(&self) -> Self {
+        let mut worker = Aggregator::new(
+            Vec::new(),
+            Vec::new(),
+            self.values_cols.clone(),
+            self.skip_null,
+            self.row_order.clone(),
+            OutputOrder::IndexOrder,
+            self.parsing_strategy,
+        );
+        worker.comparator = self.comparator;
+        worker.index_cols = self.index_cols.clone();
+        worker.column_cols = self.column_cols.clone();
+        worker.value_expr = self.value_expr.clone();
+        worker.value_names = self.value_names.clone();
+        worker.filters = self.filters.clone();
+        worker.filter_error_policy = self.filter_error_policy;
+        worker.nan_policy = self.nan_policy;
+        worker.skip_bad_records = self.skip_bad_records;
+        worker.max_bad_records = self.max_bad_records;
+        worker.bad_records_kept = self.bad_records_kept;
+        worker.rejects = self.rejects.clone();
+        worker.null_values = self.null_values.clone();
+        worker.count_columns = self.count_columns.clone();
+        worker.value_parser = self.value_parser.clone();
+        if let Some(groups) = self.group_hint {
+            // each thread only sees its share of the records
+            worker.reserve_groups(groups / self.threads);
+        }
+        if let Some(max_cells) = self.max_cells {
+            worker.max_cells = Some((max_cells / self.threads).max(1));
+        }
+        worker.memory_limit = self.memory_limit.as_ref().map(|limit| MemoryLimit {
+            bytes: (limit.bytes / self.threads).max(1),
+            no_spill_reason: limit.no_spill_reason.clone(),
+        });
+        worker
+    }
+
+    /// Sets aside room for `groups` cells in each values column (and for as many rows, since
+    /// there can't be more rows than cells)
+    fn reserve_groups(&mut self, groups: usize) {
+        self.aggregations
+            .reserve(groups.saturating_mul(self.num_value_slots()));
+        self.indexes.reserve(groups);
+        self.row_counts.reserve(groups);
+        self.index_lines.reserve(groups);
+    }
+
+    /// Merges the cells (and record counts) of the aggregators returned by `worker` into this one.
+    /// The rows and columns get added in the order they first appear in the input, so the
+    /// result is the same as if all of the records had been aggregated by this aggregator.
+    ///
+    /// Each worker only holds part of the records of a cell, so a sum can overflow here even
+    /// though none of the workers' sums did. That error points at the first record of the row.
+    fn merge_workers(&mut self, workers: Vec<Self>) -> CsvCliResult<()> {
+        let mut rows: Vec<(usize, &Vec<String>)> = Vec::new();
+        let mut columns: Vec<(usize, &Vec<String>)> = Vec::new();
+        for worker in &workers {
+            rows.extend(worker.index_lines.iter().copied().zip(&worker.indexes));
+            columns.extend(worker.column_lines.iter().copied().zip(&worker.columns));
+        }
+        rows.sort_by_key(|(line_num, _)| *line_num);
+        columns.sort_by_key(|(line_num, _)| *line_num);
+        for (line_num, key) in rows {
+            if self.indexes.insert(key.clone()) {
+                self.index_lines.push(line_num);
+                self.row_counts.push(0);
+                self.row_key_bytes += key_size(key);
+            }
+        }
+        for (line_num, key) in columns {
+            if self.columns.insert(key.clone()) {
+                self.column_lines.push(line_num);
+                self.column_counts.push(0);
+                self.column_key_bytes += key_size(key);
+            }
+        }
+        for worker in workers {
+            // the IDs of the worker's rows and columns, as rows and columns of this aggregator
+            let row_ids: Vec<usize> = worker
+                .indexes
+                .iter()
+                .map(|key| key_id(&self.indexes, key).unwrap())
+                .collect();
+            let col_ids: Vec<usize> = worker
+                .columns
+                .iter()
+                .map(|key| key_id(&self.columns, key).unwrap())
+                .collect();
+            for ((row_id, col_id, value_idx), acc) in worker.aggregations {
+                let key = (row_ids[row_id], col_ids[col_id], value_idx);
+                let merged = match self.aggregations.get_mut(&key) {
+                    Some(merged) => merged.try_merge(acc),
+                    None => {
+                        self.aggregations.insert(key, acc);
+                        Ok(())
+                    }
+                };
+                merged.map_err(|err| CsvCliError::MergeError {
+                    cell: format!(
+                        "row `{}`, column `{}`",
+                        self.indexes.get_index(key.0).unwrap().join(" "),
+                        self.columns.get_index(key.1).unwrap().join(" ")
+                    ),
+                    err,
+                    column: self.value_column(value_idx),
+                })?;
+            }
+            for (row_id, count) in row_ids.into_iter().zip(worker.row_counts) {
+                self.row_counts[row_id] += count;
+            }
+            for (col_id, count) in col_ids.into_iter().zip(worker.column_counts) {
+                self.column_counts[col_id] += count;
+            }
+            self.spill_runs.extend(worker.spill_runs);
+            self.cell_heap_bytes += worker.cell_heap_bytes;
+            let counts = worker.record_counts;
+            self.record_counts.aggregated += counts.aggregated;
+            self.record_counts.filtered += counts.filtered;
+            self.record_counts.dropped_keys += counts.dropped_keys;
+            self.record_counts.empty_values += counts.empty_values;
+            self.record_counts.nan_values += counts.nan_values;
+            self.record_counts.bad_records += counts.bad_records;
+            self.bad_records.extend(worker.bad_records);
+        }
+        // each worker kept its own first few bad records, which include the first few overall
+        self.bad_records.sort_unstable();
+        self.bad_records.truncate(self.bad_records_kept);
+        self.check_bad_records()
+    }
+
+    /// Adds a record, or skips it if it can't be parsed and bad records get skipped
+    fn add_or_skip_record(
+        &mut self,
+        record: &csv::ByteRecord,
+        line_num: usize,
+    ) -> CsvCliResult<()> {
+        let aggregated = self.record_counts.aggregated;
+        match self.add_record(record, line_num) {
+            // the count only goes up once the first of the record's values gets added, and the
+            // record can't be skipped after that
+            Err(err @ CsvCliError::ParsingError { .. })
+                if self.skip_bad_records && self.record_counts.aggregated == aggregated =>
+            {
+                let line = record.position().map_or(0, csv::Position::line);
+                self.skip_bad_record(record, line_num, line, &err.to_string())
+            }
+            result => result,
+        }
+    }
+
+    fn add_record(&mut self, record: &csv::ByteRecord, line_num: usize) -> CsvCliResult<()> {
+        if !self.passes_filters(record, line_num)? {
+            self.record_counts.filtered += 1;
+            return Ok(());
+        }
+        if let Some(count_columns) = self.count_columns.take() {
+            let result = self.add_count(record, line_num, &count_columns);
+            self.count_columns = Some(count_columns);
+            return result;
+        }
+        let index_vals =
+            self.get_column_string(&self.index_cols, &self.index_names, record, line_num)?;
+        let column_vals =
+            self.get_column_string(&self.column_cols, &self.column_names, record, line_num)?;
+        let (index_vals, column_vals) = match (index_vals, column_vals) {
+            (Some(index_vals), Some(column_vals)) => (index_vals, column_vals),
+            // one of the fields dropped the record
+            _ => {
+                self.record_counts.dropped_keys += 1;
+                return Ok(());
+            }
+        };
+        let has_values = !self.values_cols.is_empty() || self.value_expr.is_some();
+        if self.skip_bad_records {
+            self.check_values(record, line_num)?;
+        }
+        let mut ids = None;
+        for value_idx in 0..self.num_value_slots() {
+            let value_string = self.value_string(record, line_num, value_idx)?;
+            let value_string = value_string.as_ref();
+            if self.skip_null && has_values && self.is_null(value_string) {
+                self.record_counts.empty_values += 1;
+                continue;
+            }
+            if self.parsing_strategy == ParsingStrategy::Numeric && is_non_finite(value_string) {
+                match self.nan_policy {
+                    NanPolicy::Skip => {
+                        self.record_counts.nan_values += 1;
+                        continue;
+                    }
+                    NanPolicy::Error => {
+                        return Err(self.nan_error(
+                            value_string,
+                            value_idx,
+                            line_num,
+                            record.position(),
+                        ));
+                    }
+                    NanPolicy::Propagate => {}
+                }
+            }
+            // only add the keys once a record has a (non-null) value
+            let (row_id, col_id) = match ids {
+                Some(ids) => ids,
+                None => {
+                    self.record_counts.aggregated += 1;
+                    let (num_rows, num_columns) = (self.indexes.len(), self.columns.len());
+                    let row_id = intern(
+                        &mut self.indexes,
+                        &mut self.row_counts,
+                        &mut self.index_lines,
+                        &index_vals,
+                        line_num,
+                    );
+                    let col_id = intern(
+                        &mut self.columns,
+                        &mut self.column_counts,
+                        &mut self.column_lines,
+                        &column_vals,
+                        line_num,
+                    );
+                    if self.indexes.len() > num_rows {
+                        self.row_key_bytes += key_size(&index_vals);
+                    }
+                    if self.columns.len() > num_columns {
+                        self.column_key_bytes += key_size(&column_vals);
+                    }
+                    ids = Some((row_id, col_id));
+                    (row_id, col_id)
+                }
+            };
+            if self.scanning_keys {
+                break;
+            }
+            self.update_aggregations(
+                row_id,
+                col_id,
+                value_idx,
+                value_string,
+                line_num,
+                record.position(),
+            )?;
+        }
+        self.check_table_size(line_num)
+    }
+
+    /// Adds a record to a table that just counts records, given the columns its row and column
+    /// keys come from (see `get_count_columns`). There aren't any values to read or parse, and
+    /// the keys get looked up straight from the fields of the record, so nothing gets allocated
+    /// unless the record has a new row or column.
+    /// The text of one of the values of a record (computing it if the values come from an
+    /// expression)
+    fn value_string<'a>(
+        &self,
+        record: &'a csv::ByteRecord,
+        line_num: usize,
+        value_idx: usize,
+    ) -> CsvCliResult<Cow<'a, str>> {
+        match (&self.value_expr, self.values_cols.get(value_idx)) {
+            (Some(expr), _) => Ok(Cow::Owned(self.compute_value(expr, record, line_num)?)),
+            (None, Some(&col)) => {
+                record
+                    .field(col)
+                    .map(Cow::Borrowed)
+                    .map_err(|err| CsvCliError::ParsingError {
+                        line_num,
+                        str_to_parse: record_text(record),
+                        err,
+                        column: self.value_column(value_idx),
+                        position: record.position().cloned(),
+                    })
+            }
+            (None, None) => Ok(Cow::Borrowed("")),
+        }
+    }
+
+    /// Checks that every value of a record can be aggregated (so a bad record can be skipped
+    /// before any of its values get added), returning the error that aggregating it would run
+    /// into if one of them can't
+    fn check_values(&self, record: &csv::ByteRecord, line_num: usize) -> CsvCliResult<()> {
+        if self.values_cols.is_empty() && self.value_expr.is_none() {
+            return Ok(());
+        }
+        for value_idx in 0..self.num_value_slots() {
+            let value_string = self.value_string(record, line_num, value_idx)?;
+            if self.skip_null && self.is_null(&value_string) {
+                continue;
+            }
+            if self.parsing_strategy == ParsingStrategy::Numeric && is_non_finite(&value_string) {
+                match self.nan_policy {
+                    NanPolicy::Skip => continue,
+                    NanPolicy::Error => {
+                        return Err(self.nan_error(
+                            &value_string,
+                            value_idx,
+                            line_num,
+                            record.position(),
+                        ))
+                    }
+                    NanPolicy::Propagate => {}
+                }
+            }
+            if self.value_parser.parse(&value_string).is_none() {
+                return Err(self.parse_error(
+                    &value_string,
+                    value_idx,
+                    line_num,
+                    record.position(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The error for a `NaN` or infinite value when they aren't allowed
+    fn nan_error(
+        &self,
+        input_str: &str,
+        value_idx: usize,
+        line_num: usize,
+        position: Option<&csv::Position>,
+    ) -> CsvCliError {
+        CsvCliError::ParsingError {
+            line_num,
+            str_to_parse: input_str.to_string(),
+            err: "NaN and infinite values aren't allowed (see --nan)".to_string(),
+            column: self.value_column(value_idx),
+            position: position.cloned(),
+        }
+    }
+
+    /// The error for a value that can't be parsed
+    fn parse_error(
+        &self,
+        input_str: &str,
+        value_idx: usize,
+        line_num: usize,
+        position: Option<&csv::Position>,
+    ) -> CsvCliError {
+        CsvCliError::ParsingError {
+            line_num,
+            str_to_parse: input_str.to_string(),
+            err: if self.nan_policy == NanPolicy::Propagate && is_non_finite(input_str) {
+                "This function can't hold NaN or infinite values, so they can't be propagated (see --nan)"
+                    .to_string()
+            } else {
+                self.describe_err()
+            },
+            column: self.value_column(value_idx),
+            position: position.cloned(),
+        }
+    }
+
+    fn add_count(
+        &mut self,
+        record: &csv::ByteRecord,
+        line_num: usize,
+        (index_cols, column_cols): &(Vec<usize>, Vec<usize>),
+    ) -> CsvCliResult<()> {
+        let mut index_buf = [""; MAX_BORROWED_FIELDS];
+        let mut column_buf = [""; MAX_BORROWED_FIELDS];
+        let index_vals = borrowed_key(
+            record,
+            index_cols,
+            &self.index_names,
+            line_num,
+            &mut index_buf,
+        )?;
+        let column_vals = borrowed_key(
+            record,
+            column_cols,
+            &self.column_names,
+            line_num,
+            &mut column_buf,
+        )?;
+        self.record_counts.aggregated += 1;
+        let (row_id, new_row) = intern_fields(
+            &mut self.indexes,
+            &mut self.row_counts,
+            &mut self.index_lines,
+            index_vals,
+            line_num,
+        );
+        let (col_id, new_column) = intern_fields(
+            &mut self.columns,
+            &mut self.column_counts,
+            &mut self.column_lines,
+            column_vals,
+            line_num,
+        );
+        if new_row {
+            self.row_key_bytes += key_size(self.indexes.get_index(row_id).unwrap());
+        }
+        if new_column {
+            self.column_key_bytes += key_size(self.columns.get_index(col_id).unwrap());
+        }
+        if self.scanning_keys {
+            return Ok(());
+        }
+        self.update_aggregations(row_id, col_id, 0, "", line_num, record.position())?;
+        self.check_table_size(line_num)
+    }
+
+    /// Spills the cells once the table has as many of them as it can hold, or once it goes over
+    /// its memory budget
+    fn check_table_size(&mut self, line_num: usize) -> CsvCliResult<()> {
+        if self
+            .max_cells
+            .is_some_and(|max_cells| self.aggregations.len() >= max_cells)
+        {
+            self.spill()?;
+        }
+        if let Some(limit) = &self.memory_limit {
+            if self.memory_estimate() >= limit.bytes {
+                self.enforce_memory_limit(line_num)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Spills the cells to disk when the table has gone over its memory budget, or returns an
+    /// error if they can't be spilled (or if the columns, which have to stay in memory, take up
+    /// the whole budget on their own)
+    fn enforce_memory_limit(&mut self, line_num: usize) -> CsvCliResult<()> {
+        let limit = match self.memory_limit.clone() {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let reason = match limit.no_spill_reason {
+            Some(reason) => reason,
+            None => {
+                self.spill()?;
+                if self.memory_estimate() < limit.bytes {
+                    return Ok(());
+                }
+                "The columns of the table have to stay in memory, even when its cells get spilled to disk".to_string()
+            }
+        };
+        Err(CsvCliError::InvalidConfiguration(format!(
+            "The table took up more than the memory budget of {} by the record with index {}. {}",
+            format_bytes(limit.bytes as u64),
+            line_num,
+            reason
+        )))
+    }
+
+    /// Evaluates the value expression for a record
+    fn compute_value(
+        &self,
+        expr: &Expr,
+        record: &csv::ByteRecord,
+        line_num: usize,
+    ) -> CsvCliResult<String> {
+        expr.eval(record)
+            .map(|value| value.to_string())
+            .map_err(|err| CsvCliError::ParsingError {
+                line_num,
+                str_to_parse: record_text(record),
+                err: format!("Could not compute the value: {}", err),
+                column: None,
+                position: record.position().cloned(),
+            })
+    }
+
+    /// Whether a record meets all of the filters
+    fn passes_filters(&self, record: &csv::ByteRecord, line_num: usize) -> CsvCliResult<bool> {
+        for filter in &self.filters {
+            match filter.matches(record) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(_) if self.filter_error_policy == FilterErrorPolicy::Keep => {}
+                Err(_) if self.filter_error_policy == FilterErrorPolicy::Skip => return Ok(false),
+                Err(err) => {
+                    return Err(CsvCliError::ParsingError {
+                        line_num,
+                        str_to_parse: record_text(record),
+                        err: format!("Could not evaluate the filter: {}", err),
+                        column: None,
+                        position: record.position().cloned(),
+                    })
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Gets the composite key for a record, with one item per selected field
+    /// (or `None` if one of the fields drops the record)
+    fn get_column_string(
+        &self,
+        fields: &[KeyField],
+        names: &[String],
+        record: &csv::ByteRecord,
+        line_num: usize,
+    ) -> CsvCliResult<Option<Vec<String>>> {
+        if fields.is_empty() {
+            return Ok(Some(vec!["total".to_string()]));
+        }
+        // every field gets computed (so an error in one of them comes up even if another one
+        // drops the record)
+        let mut keys = Some(Vec::with_capacity(fields.len()));
+        for (i, field) in fields.iter().enumerate() {
+            let key = field
+                .get_key(record, line_num)
+                .map_err(|err| CsvCliError::ParsingError {
+                    line_num,
+                    str_to_parse: record_text(record),
+                    err: format!("Could not compute the key: {}", err),
+                    column: field.column_index().map(|index| {
+                        Box::new(ErrorColumn {
+                            name: names.get(i).cloned(),
+                            index,
+                        })
+                    }),
+                    position: record.position().cloned(),
+                })?;
+            match (key, keys.as_mut()) {
+                (Some(key), Some(keys)) => keys.push(key),
+                // the field dropped the record
+                (None, _) => keys = None,
+                (Some(_), None) => {}
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Whether a value counts as empty (when empty values are skipped)
+    fn is_null(&self, value: &str) -> bool {
+        match &self.null_values {
+            Some(null_values) => null_values.contains(&value.to_lowercase()),
+            None => EMPTY_VALUES.contains(value.to_ascii_lowercase().as_str()),
+        }
+    }
+
+    /// The column a value comes from, unless it's computed by a value expression
+    fn value_column(&self, value_idx: usize) -> Option<Box<ErrorColumn>> {
+        if self.value_expr.is_some() {
+            return None;
+        }
+        self.values_cols.get(value_idx).map(|&index| {
+            Box::new(ErrorColumn {
+                name: self.value_names.get(value_idx).cloned(),
+                index,
+            })
+        })
+    }
+
+    fn describe_err(&self) -> String {
+        match self.parsing_strategy {
+            ParsingStrategy::Text => "Failed to parse as text".to_string(),
+            ParsingStrategy::Numeric => "Failed to parse as numeric".to_string(),
+            ParsingStrategy::Date => format!(
+                "Could not parse as date with {} format",
+                self.value_parser.date_format()
+            ),
+            _ => "Generic parsing error".to_string(),
+        }
+    }
+
+    fn update_aggregations(
+        &mut self,
+        row_id: usize,
+        col_id: usize,
+        value_idx: usize,
+        input_str: &str,
+        line_num: usize,
+        position: Option<&csv::Position>,
+    ) -> CsvCliResult<()> {
+        // parsing text just copies it, and plain numbers get parsed faster than they could be
+        // looked up, so only dates get cached
+        let cached = match self.parsing_strategy {
+            ParsingStrategy::Date => self
+                .parse_caches
+                .get_mut(value_idx)
+                .and_then(|cache| cache.get(input_str)),
+            _ => None,
+        };
+        let parsed_val = match cached {
+            Some(parsed_val) => parsed_val,
+            None => {
+                let parsed_val = self
+                    .value_parser
+                    .parse(input_str)
+                    .ok_or_else(|| self.parse_error(input_str, value_idx, line_num, position))?;
+                if self.parsing_strategy == ParsingStrategy::Date {
+                    if self.parse_caches.len() <= value_idx {
+                        self.parse_caches
+                            .resize_with(value_idx + 1, ParseCache::new);
+                    }
+                    self.parse_caches[value_idx].insert(input_str, parsed_val.clone());
+                }
+                parsed_val
+            }
+        };
+
+        let key = (row_id, col_id, value_idx);
+        match self.aggregations.get_mut(&key) {
+            Some(acc) => {
+                let heap_size = acc.heap_size();
+                let updated = acc.try_update_at(parsed_val, (self.file_index, line_num));
+                self.cell_heap_bytes =
+                    (self.cell_heap_bytes + acc.heap_size()).saturating_sub(heap_size);
+                updated.map_err(|err| CsvCliError::ParsingError {
+                    line_num,
+                    str_to_parse: input_str.to_string(),
+                    err,
+                    column: self.value_column(value_idx),
+                    position: position.cloned(),
+                })?;
+            }
+            None => {
+                let acc = T::new_at(parsed_val, (self.file_index, line_num));
+                self.cell_heap_bytes += acc.heap_size();
+                self.aggregations.insert(key, acc);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sort_results(&mut self) -> CsvCliResult<()> {
+        let (comparator, threads) = (self.comparator, self.threads);
+        for &axis in &[Axis::Columns, Axis::Rows] {
+            let (order, keys) = match axis {
+                Axis::Rows => (&self.row_order, &self.indexes),
+                Axis::Columns => (&self.column_order, &self.columns),
+            };
+            let mut sorted = keys.clone();
+            match order {
+                OutputOrder::Ascending => {
+                    sort_keys(&mut sorted, threads, |a, b| comparator.compare_keys(a, b))
+                }
+                OutputOrder::Descending => {
+                    sort_keys(&mut sorted, threads, |a, b| comparator.compare_keys(b, a))
+                }
+                OutputOrder::ByTotalAscending => self.sort_by_totals(axis, false, &mut sorted)?,
+                OutputOrder::ByTotalDescending => self.sort_by_totals(axis, true, &mut sorted)?,
+                OutputOrder::Custom(order) => sort_by_list(&mut sorted, order),
+                OutputOrder::IndexOrder => continue,
+            };
+            self.reorder_keys(axis, sorted);
+        }
+        Ok(())
+    }
+
+    /// Sorts the rows or columns by the aggregate of their first values column. Like `top_rows`,
+    /// this falls back to sorting by the number of records if some of the totals aren't numbers.
+    /// Groups with the same total keep their original order.
+    fn sort_by_totals(
+        &self,
+        axis: Axis,
+        descending: bool,
+        keys: &mut FastIndexSet<Vec<String>>,
+    ) -> CsvCliResult<()> {
+        let groups: Vec<Vec<String>> = match axis {
+            Axis::Rows => self.indexes.iter().cloned().collect(),
+            Axis::Columns => self.columns.iter().cloned().collect(),
+        };
+        let all_rows: Vec<&Vec<String>> = self.indexes.iter().collect();
+        let totals: Vec<Option<f64>> = groups
+            .iter()
+            .map(|group| match axis {
+                Axis::Rows => self.row_total(group),
+                Axis::Columns => Ok(self
+                    .merge_cells(&all_rows, &[group], 0)?
+                    .and_then(|total| total.parse().ok())),
+            })
+            .collect::<CsvCliResult<_>>()?;
+        let totals: Option<Vec<f64>> = totals.into_iter().collect();
+        let totals = totals.unwrap_or_else(|| {
+            let counts = match axis {
+                Axis::Rows => &self.row_counts,
+                Axis::Columns => &self.column_counts,
+            };
+            counts.iter().map(|&count| count as f64).collect()
+        });
+        let scores: HashMap<Vec<String>, f64> = groups.into_iter().zip(totals).collect();
+        let compare = |a: &Vec<String>, b: &Vec<String>| {
+            let ordering = scores[a]
+                .partial_cmp(&scores[b])
+                .unwrap_or(std::cmp::Ordering::Equal);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        keys.sort_by(compare);
+        Ok(())
+    }
+}
+
+/// Sorts a set of keys, on several threads if there are enough of them (see `par_sort_by`)
+fn sort_keys<F>(keys: &mut FastIndexSet<Vec<String>>, threads: usize, compare: F)
+where
+    F: Fn(&Vec<String>, &Vec<String>) -> std::cmp::Ordering + Sync,
+{
+    if threads <= 1 {
+        keys.sort_by(compare);
+        return;
+    }
+    let mut sorted: Vec<Vec<String>> = std::mem::take(keys).into_iter().collect();
+    par_sort_by(&mut sorted, threads, compare);
+    *keys = sorted.into_iter().collect();
+}
+
+/// The text of the cells of a block of rows, formatted by one of the threads writing a table
+struct FormattedCells {
+    text: String,
+    /// Where the text of each cell ends, going across each row and then down the rows
+    ends: Vec<usize>,
+}
+
+/// Formats the cells of a block of rows of a plain table
+fn format_cells<T, I, O>(
+    cells: &Cells<T>,
+    rows: Range<usize>,
+    num_columns: usize,
+    num_value_slots: usize,
+) -> FormattedCells
+where
+    T: Accumulate<I, O>,
+    O: WriteCell,
+{
+    let mut formatted = FormattedCells {
+        text: String::new(),
+        ends: Vec::with_capacity(rows.len() * num_columns * num_value_slots),
+    };
+    for row_id in rows {
+        for col_id in 0..num_columns {
+            for value_idx in 0..num_value_slots {
+                if let Some(val) = cells
+                    .get(&(row_id, col_id, value_idx))
+                    .and_then(|acc| acc.compute())
+                {
+                    val.write_cell(&mut formatted.text);
+                }
+                formatted.ends.push(formatted.text.len());
+            }
+        }
+    }
+    formatted
+}
+
+/// Sorts a set of keys by the position of their first field in a list, putting the keys whose
+/// first field isn't in the list at the end
+fn sort_by_list(keys: &mut FastIndexSet<Vec<String>>, order: &[String]) {
+    let positions: HashMap<&str, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(pos, val)| (val.as_str(), pos))
+        .rev()
+        .collect();
+    let position = |key: &Vec<String>| {
+        key.first()
+            .and_then(|val| positions.get(val.as_str()))
+            .copied()
+            .unwrap_or(order.len())
+    };
+    keys.sort_by(|a, b| position(a).cmp(&position(b)));
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::aggfunc::Count;
-    use csv::StringRecord;
-    use indexmap::IndexSet;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggfunc::{Count, Maximum, Mean, Median, Sum};
+    use crate::errors::{MAX_VALUE_CHARS, PARSING_EXIT_CODE};
+    use crate::keys::{KeyTransform, Normalization};
+    use crate::output::Value;
+    use crate::parsing::DecimalWrapper;
+    use csv::ByteRecord;
+    use indexmap::IndexSet;
+
+    fn setup_simple() -> Aggregator<Count<String>, String, usize> {
+        Aggregator::new(
+            vec![0, 2],
+            vec![3, 4],
+            vec![1],
+            false,
+            OutputOrder::IndexOrder,
+            OutputOrder::Ascending,
+            ParsingStrategy::Text,
+        )
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    fn test_add_record() {
+        let mut agg = setup_simple();
+        let record_vec = vec!["Columbus", "Playoffs", "OH", "Blue Jackets", "Hockey"];
+        let csv_record = ByteRecord::from(record_vec);
+        agg.add_record(&csv_record, 0).unwrap();
+        let cell = agg.cell(
+            &["Columbus".to_string(), "OH".to_string()],
+            &["Blue Jackets".to_string(), "Hockey".to_string()],
+            0,
+        );
+        assert_eq!(cell.unwrap().compute(), Some(1));
+        assert_eq!(agg.aggregations.keys().collect::<Vec<_>>(), vec![(0, 0, 0)]);
+        let mut expected_indexes = IndexSet::new();
+        expected_indexes.insert(vec!["Columbus".to_string(), "OH".to_string()]);
+        assert_eq!(agg.indexes, expected_indexes);
+        let mut expected_columns = IndexSet::new();
+        expected_columns.insert(vec!["Blue Jackets".to_string(), "Hockey".to_string()]);
+        assert_eq!(agg.columns, expected_columns);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    fn test_no_columnnames() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![],
+            vec![],
+            vec![0],
+            false,
+            OutputOrder::IndexOrder,
+            OutputOrder::Ascending,
+            ParsingStrategy::Text,
+        );
+        let record_vec = ByteRecord::from(vec!["hello"]);
+        agg.add_record(&record_vec, 0);
+        let new_record = ByteRecord::from(vec!["goodbye"]);
+        agg.add_record(&new_record, 1);
+        let mut expected_indexes = IndexSet::new();
+        expected_indexes.insert(vec!["total".to_string()]);
+        assert_eq!(expected_indexes, agg.indexes);
+        let mut expected_columns = IndexSet::new();
+        expected_columns.insert(vec!["total".to_string()]);
+        assert_eq!(expected_columns, agg.columns);
+        let count = agg.cell(&["total".to_string()], &["total".to_string()], 0);
+        assert_eq!(count.unwrap().compute(), Some(2));
+    }
+
+    #[test]
+    fn test_cell_counts() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![0],
+            false,
+            OutputOrder::IndexOrder,
+            OutputOrder::IndexOrder,
+            ParsingStrategy::Text,
+        );
+        for (line, record) in [["a", "x"], ["a", "y"], ["a", "x"], ["b", "y"]]
+            .iter()
+            .enumerate()
+        {
+            agg.add_record(&ByteRecord::from(record.to_vec()), line)
+                .unwrap();
+        }
+        assert_eq!(agg.cell_counts(), vec![vec![2., 1.], vec![0., 1.]]);
+    }
+
+    #[test]
+    fn test_count_fast_path() {
+        let mut keys = FastIndexSet::default();
+        keys.insert(vec!["CA".to_string(), "a".to_string()]);
+        assert_eq!(
+            keys.get_full(&BorrowedKey(&["CA", "a"])).map(|(id, _)| id),
+            Some(0)
+        );
+        assert_eq!(keys.get_full(&BorrowedKey(&["CA"])).map(|(id, _)| id), None);
+        assert_eq!(
+            keys.get_full(&BorrowedKey(&["CAa"])).map(|(id, _)| id),
+            None
+        );
+
+        let data = "state,cat,total\nCA,a,1\nNY,b,2\nCA,a,3\nCA,b,4\n";
+        let new_agg = || -> Aggregator<Count<String>, String, usize> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Text,
+            )
+        };
+        let mut agg = new_agg();
+        agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        assert!(agg.count_columns.is_some());
+        let count = |agg: &Aggregator<Count<String>, String, usize>, row: &str, col: &str| {
+            agg.cell(&[row.to_string()], &[col.to_string()], 0)
+                .and_then(|cell| cell.compute())
+        };
+        assert_eq!(count(&agg, "CA", "a"), Some(2));
+        assert_eq!(count(&agg, "CA", "b"), Some(1));
+        assert_eq!(count(&agg, "NY", "b"), Some(1));
+        assert_eq!(agg.row_counts, vec![3, 1]);
+        assert_eq!(agg.record_counts.aggregated, 4);
+        // a key that doesn't come straight from a column still counts the same way
+        let mut transformed = new_agg()
+            .with_row_fields(vec![KeyField::column(0)
+                .with_transform(KeyTransform::Normalize(vec![Normalization::Upper]))]);
+        transformed
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        assert!(transformed.count_columns.is_none());
+        assert_eq!(count(&transformed, "CA", "a"), Some(2));
+        assert_eq!(transformed.row_counts, agg.row_counts);
+    }
+
+    #[test]
+    fn test_no_vals_is_error() {
+        let mut agg = setup_simple();
+        let cursor_data: Vec<u8> = Vec::new();
+        let mut writer = csv::Writer::from_writer(io::Cursor::new(cursor_data));
+        assert!(agg.write_results(&mut writer).is_err());
+    }
+
+    #[test]
+    fn test_vector_output() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![],
+            vec![1],
+            false,
+            OutputOrder::IndexOrder,
+            OutputOrder::Ascending,
+            ParsingStrategy::Text,
+        );
+        let data = [
+            ByteRecord::from(vec!["example".to_string(), "record".to_string()]),
+            ByteRecord::from(vec!["example".to_string(), "again".to_string()]),
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(record, count).unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let results = agg.to_vec().unwrap();
+        let expected = vec![
+            vec![String::new(), "total".to_string()],
+            vec!["example".to_string(), "2".to_string()],
+        ];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_multiple_values_columns() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![2, 3],
+            true,
+            OutputOrder::IndexOrder,
+            OutputOrder::Ascending,
+            ParsingStrategy::Text,
+        );
+        agg.value_names = vec!["sales".to_string(), "profit".to_string()];
+        let data = [
+            ByteRecord::from(vec!["east", "2019", "1", "2"]),
+            ByteRecord::from(vec!["east", "2019", "3", ""]),
+            ByteRecord::from(vec!["east", "2020", "", "4"]),
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(record, count).unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let expected = vec![
+            vec![
+                String::new(),
+                "2019_sales".to_string(),
+                "2019_profit".to_string(),
+                "2020_sales".to_string(),
+                "2020_profit".to_string(),
+            ],
+            vec![
+                "east".to_string(),
+                "2".to_string(),
+                "1".to_string(),
+                String::new(),
+                "1".to_string(),
+            ],
+        ];
+        assert_eq!(agg.to_vec().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_composite_keys() {
+        let mut agg = setup_simple();
+        let data = [
+            ByteRecord::from(vec!["Columbus", "Playoffs", "OH", "Blue Jackets", "Hockey"]),
+            ByteRecord::from(vec!["Columbus", "Playoffs", "OH", "Crew", "Soccer"]),
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(record, count).unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let expected = vec![
+            vec![
+                String::new(),
+                String::new(),
+                "Blue Jackets_<sep>_Hockey".to_string(),
+                "Crew_<sep>_Soccer".to_string(),
+            ],
+            vec![
+                "Columbus".to_string(),
+                "OH".to_string(),
+                "1".to_string(),
+                "1".to_string(),
+            ],
+        ];
+        assert_eq!(agg.to_vec().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_key_separator_never_collides() {
+        let sep = " | ";
+        let first = join_key(&["a | b".to_string(), "c".to_string()], sep);
+        let second = join_key(&["a".to_string(), "b | c".to_string()], sep);
+        assert_ne!(first, second);
+        assert_eq!(first, "a\\ | b | c".to_string());
+        let backslash = join_key(&["a\\".to_string(), "| c".to_string()], sep);
+        assert_ne!(
+            backslash,
+            join_key(&["a".to_string(), "\\| c".to_string()], sep)
+        );
+        assert_eq!(join_key(&["a | b".to_string()], sep), "a | b".to_string());
+    }
+
+    #[test]
+    fn test_output_labels() {
+        let mut agg = setup_simple()
+            .with_row_labels(vec!["city".to_string()])
+            .with_header_prefix("team_")
+            .with_key_separator("/");
+        let record = ByteRecord::from(vec!["Columbus", "Playoffs", "OH", "Crew", "Soccer"]);
+        agg.add_record(&record, 0).unwrap();
+        agg.prepare_write().unwrap();
+        assert_eq!(
+            agg.get_pivot_headers()[0],
+            vec![
+                "city".to_string(),
+                String::new(),
+                "team_Crew/Soccer".to_string()
+            ]
+        );
+    }
 
-    fn setup_simple() -> Aggregator<Count<String>, String, usize> {
-        Aggregator::new(
-            vec![0, 2],
-            vec![3, 4],
-            1,
-            false,
+    #[test]
+    fn test_multi_header() {
+        let mut agg = setup_simple()
+            .with_row_labels(vec!["city".to_string(), "state".to_string()])
+            .with_header_prefix("team_")
+            .with_multi_header(true);
+        let data = [
+            ByteRecord::from(vec!["Columbus", "Playoffs", "OH", "Crew", "Soccer"]),
+            ByteRecord::from(vec!["Columbus", "Playoffs", "OH", "Blue Jackets", "Hockey"]),
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(record, count).unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            agg.get_pivot_headers(),
+            vec![
+                to_strings(&["", "", "team_Blue Jackets", "team_Crew"]),
+                to_strings(&["city", "state", "Hockey", "Soccer"]),
+            ]
+        );
+        assert_eq!(
+            agg.to_vec().unwrap()[2],
+            to_strings(&["Columbus", "OH", "1", "1"])
+        );
+    }
+
+    #[test]
+    fn test_top_rows() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![],
+            true,
             OutputOrder::IndexOrder,
             OutputOrder::Ascending,
             ParsingStrategy::Text,
         )
+        .with_top_rows(2, "Other");
+        let data = [
+            ["a", "x"],
+            ["b", "x"],
+            ["b", "y"],
+            ["c", "y"],
+            ["c", "y"],
+            ["c", "x"],
+            ["d", "y"],
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(&ByteRecord::from(record.to_vec()), count)
+                .unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            agg.to_vec().unwrap(),
+            vec![
+                to_strings(&["", "x", "y"]),
+                to_strings(&["b", "1", "1"]),
+                to_strings(&["c", "1", "2"]),
+                to_strings(&["Other", "1", "1"]),
+            ]
+        );
     }
 
-    #[allow(unused_must_use)]
     #[test]
-    fn test_add_record() {
-        let mut agg = setup_simple();
-        let record_vec = vec!["Columbus", "Playoffs", "OH", "Blue Jackets", "Hockey"];
-        let csv_record = StringRecord::from(record_vec);
-        agg.add_record(&csv_record, 0).unwrap();
-        let expected_record = (
-            "Columbus_<sep>_OH".to_string(),
-            "Blue Jackets_<sep>_Hockey".to_string(),
+    fn test_min_count() {
+        let setup = || -> Aggregator<Count<String>, String, usize> {
+            let mut agg = Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Text,
+            );
+            let data = [["a", "x"], ["a", "x"], ["a", "y"], ["b", "x"]];
+            for (count, record) in data.iter().enumerate() {
+                agg.add_record(&ByteRecord::from(record.to_vec()), count)
+                    .unwrap();
+            }
+            agg
+        };
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        let mut dropped = setup().with_min_count(2, None);
+        dropped.prepare_write().unwrap();
+        assert_eq!(
+            dropped.to_vec().unwrap(),
+            vec![to_strings(&["", "x"]), to_strings(&["a", "2"])]
         );
-        assert!(agg.aggregations.contains_key(&expected_record));
+        let mut bucketed = setup().with_min_count(2, Some("Other"));
+        bucketed.prepare_write().unwrap();
         assert_eq!(
-            agg.aggregations.get(&expected_record).unwrap().compute(),
-            Some(1)
+            bucketed.to_vec().unwrap(),
+            vec![
+                to_strings(&["", "x", "Other"]),
+                to_strings(&["a", "2", "1"]),
+                to_strings(&["Other", "1", ""]),
+            ]
         );
-        let mut expected_indexes = IndexSet::new();
-        expected_indexes.insert("Columbus_<sep>_OH".to_string());
-        assert_eq!(agg.indexes, expected_indexes);
-        let mut expected_columns = IndexSet::new();
-        expected_columns.insert("Blue Jackets_<sep>_Hockey".to_string());
-        assert_eq!(agg.columns, expected_columns);
     }
 
-    #[allow(unused_must_use)]
     #[test]
-    fn test_no_columnnames() {
+    fn test_totals() {
+        let mut agg: Aggregator<Mean, DecimalWrapper, DecimalWrapper> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![2],
+            false,
+            OutputOrder::IndexOrder,
+            OutputOrder::IndexOrder,
+            ParsingStrategy::Numeric,
+        )
+        .with_totals(true);
+        let data = [
+            ["a", "x", "1"],
+            ["a", "x", "3"],
+            ["a", "y", "8"],
+            ["b", "y", "4"],
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(&ByteRecord::from(record.to_vec()), count)
+                .unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        // the totals are the means of all of the values, not the means of the cells
+        assert_eq!(
+            agg.to_vec().unwrap(),
+            vec![
+                to_strings(&["", "x", "y", "Total"]),
+                to_strings(&["a", "2", "8", "4"]),
+                to_strings(&["b", "", "4", "4"]),
+                to_strings(&["Total", "2", "6", "4"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_by_totals() {
         let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
             vec![],
+            false,
+            OutputOrder::ByTotalDescending,
+            OutputOrder::ByTotalAscending,
+            ParsingStrategy::Text,
+        );
+        let data = [
+            ["a", "x"],
+            ["b", "y"],
+            ["b", "x"],
+            ["c", "x"],
+            ["b", "z"],
+            ["c", "y"],
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(&ByteRecord::from(record.to_vec()), count)
+                .unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(agg.get_pivot_headers()[0], to_strings(&["", "z", "y", "x"]));
+        let rows: Vec<String> = agg.to_vec().unwrap()[1..]
+            .iter()
+            .map(|row| row[0].clone())
+            .collect();
+        assert_eq!(rows, to_strings(&["b", "c", "a"]));
+    }
+
+    #[test]
+    fn test_sort_by_list() {
+        let mut keys: FastIndexSet<Vec<String>> = [["b", "1"], ["c", "2"], ["a", "3"], ["d", "4"]]
+            .iter()
+            .map(|key| key.iter().map(|s| s.to_string()).collect())
+            .collect();
+        sort_by_list(&mut keys, &["c".to_string(), "a".to_string()]);
+        let firsts: Vec<&str> = keys.iter().map(|key| key[0].as_str()).collect();
+        assert_eq!(firsts, vec!["c", "a", "b", "d"]);
+    }
+
+    #[test]
+    fn test_index_order() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
             vec![],
-            0,
             false,
             OutputOrder::IndexOrder,
+            OutputOrder::IndexOrder,
+            ParsingStrategy::Text,
+        );
+        // enough keys that a hash-based order would almost certainly scramble them
+        let keys: Vec<String> = (0..200).rev().map(|i| format!("key{}", i)).collect();
+        for (count, key) in keys.iter().enumerate() {
+            let record = ByteRecord::from(vec![key.as_str(), key.as_str()]);
+            agg.add_record(&record, count).unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let results = agg.to_vec().unwrap();
+        assert_eq!(results[0][1..].to_vec(), keys);
+        let rows: Vec<String> = results[1..].iter().map(|row| row[0].clone()).collect();
+        assert_eq!(rows, keys);
+    }
+
+    #[test]
+    fn test_sparse() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![2],
+            false,
+            OutputOrder::Ascending,
             OutputOrder::Ascending,
             ParsingStrategy::Text,
         );
-        let record_vec = StringRecord::from(vec!["hello"]);
-        agg.add_record(&record_vec, 0);
-        let new_record = StringRecord::from(vec!["goodbye"]);
-        agg.add_record(&new_record, 1);
-        let mut expected_indexes = IndexSet::new();
-        expected_indexes.insert("total".to_string());
-        assert_eq!(expected_indexes, agg.indexes);
-        let mut expected_columns = IndexSet::new();
-        expected_columns.insert("total".to_string());
-        assert_eq!(expected_columns, agg.columns);
-        let count = agg
-            .aggregations
-            .get(&("total".to_string(), "total".to_string()));
-        assert_eq!(count.unwrap().compute(), Some(2));
+        let data = [
+            ["b", "y", "1"],
+            ["a", "x", "2"],
+            ["a", "z", "3"],
+            ["b", "y", "4"],
+        ];
+        for (count, record) in data.iter().enumerate() {
+            agg.add_record(&ByteRecord::from(record.to_vec()), count)
+                .unwrap();
+        }
+        agg.prepare_write().unwrap();
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            agg.to_sparse_vec(),
+            vec![
+                to_strings(&["", "", "value"]),
+                to_strings(&["a", "x", "1"]),
+                to_strings(&["a", "z", "1"]),
+                to_strings(&["b", "y", "2"]),
+            ]
+        );
     }
 
     #[test]
-    fn test_no_vals_is_error() {
-        let mut agg = setup_simple();
-        let cursor_data: Vec<u8> = Vec::new();
-        let mut writer = csv::Writer::from_writer(io::Cursor::new(cursor_data));
-        assert!(agg.write_results(&mut writer).is_err());
+    fn test_write_plan() {
+        let agg: Aggregator<Mean, DecimalWrapper, DecimalWrapper> = Aggregator::new(
+            vec![0],
+            vec![],
+            vec![1],
+            true,
+            OutputOrder::Ascending,
+            OutputOrder::Ascending,
+            ParsingStrategy::Numeric,
+        );
+        let data = "region,amount\nEast,10\nWest,NA\nEast,ten\nWest,5\n";
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let mut plan = Vec::new();
+        agg.write_plan(&mut rdr, &mut plan, 3).unwrap();
+        assert_eq!(
+            String::from_utf8(plan).unwrap(),
+            "Rows: region (column 0)\n\
+             Columns: none\n\
+             Values: amount (column 1)\n\
+             Filters: 0\n\n\
+             The first 3 records:\n\
+             region,amount,note\n\
+             East,10,\n\
+             West,NA,skipped the empty value `NA`\n\
+             East,ten,could not parse `ten`: Failed to parse as numeric\n"
+        );
     }
 
     #[test]
-    fn test_vector_output() {
+    fn test_record_counts() {
+        let mut agg: Aggregator<Mean, DecimalWrapper, DecimalWrapper> = Aggregator::new(
+            vec![0],
+            vec![],
+            vec![1],
+            true,
+            OutputOrder::Ascending,
+            OutputOrder::Ascending,
+            ParsingStrategy::Numeric,
+        )
+        .with_filter(Expr::parse("region != 'West'", |_| Ok(0)).unwrap());
+        let data = "region,amount\nEast,10\nEast,NA\nWest,5\nEast,1\n";
+        agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        assert_eq!(
+            agg.record_counts(),
+            RecordCounts {
+                read: 4,
+                aggregated: 2,
+                filtered: 1,
+                dropped_keys: 0,
+                empty_values: 1,
+                nan_values: 0,
+                bad_records: 0,
+            }
+        );
+        assert_eq!(
+            agg.table_size(),
+            TableSize {
+                rows: 1,
+                columns: 1,
+                cells: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_nan_policy() {
+        let data = "region,amount\nEast,10\nEast,NaN\nWest,-inf\nWest,5\n";
+        let new_agg = |policy| -> Aggregator<Maximum<f64>, f64, f64> {
+            Aggregator::new(
+                vec![0],
+                vec![],
+                vec![1],
+                false,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+            .with_nan_policy(policy)
+        };
+        let mut agg = new_agg(NanPolicy::Error);
+        let err = agg
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("`NaN` with index 1"));
+        let mut agg = new_agg(NanPolicy::Skip);
+        agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        assert_eq!(agg.record_counts().nan_values, 2);
+        let table = agg.into_table().unwrap();
+        assert_eq!(table.cell(0, 0, 0), Some(&Value::Float(10.)));
+        assert_eq!(table.cell(1, 0, 0), Some(&Value::Float(5.)));
+        let mut agg = new_agg(NanPolicy::Propagate);
+        agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        let table = agg.into_table().unwrap();
+        assert!(table
+            .cell(0, 0, 0)
+            .and_then(Value::as_f64)
+            .unwrap()
+            .is_nan());
+        assert_eq!(table.cell(1, 0, 0), Some(&Value::Float(5.)));
+    }
+
+    #[test]
+    fn test_skip_bad_records() {
+        let mut data = "region,units,amount\nEast,1,2\nEast,3,x\nWest,1,2,3\n".to_string();
+        for _ in 0..3000 {
+            data.push_str("West,4,5\n");
+        }
+        data.push_str("West\nEast,,1\n");
+        let new_agg = |skip| -> Aggregator<Sum<DecimalWrapper>, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![],
+                vec![1, 2],
+                false,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+            .with_skip_bad_records(skip)
+        };
+        assert!(new_agg(false)
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .is_err());
+        for threads in 1..=3 {
+            let mut agg = new_agg(true).with_threads(threads);
+            agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            assert_eq!(agg.record_counts().bad_records, 4);
+            assert_eq!(agg.bad_record_lines(), vec![3, 4, 3005, 3006]);
+            let mut kept = new_agg(true).with_threads(threads).with_bad_records_kept(2);
+            kept.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            assert_eq!(kept.record_counts().bad_records, 4);
+            assert_eq!(kept.bad_record_lines(), vec![3, 4]);
+            assert_eq!(kept.bad_records()[0].0, 3);
+            let table = agg.into_table().unwrap();
+            // the record with a bad amount doesn't add its units either
+            assert_eq!(table.cell(0, 0, 0).and_then(Value::as_f64), Some(1.));
+            assert_eq!(table.cell(0, 0, 1).and_then(Value::as_f64), Some(2.));
+            assert_eq!(table.cell(1, 0, 0).and_then(Value::as_f64), Some(12000.));
+            let mut agg = new_agg(true).with_threads(threads).with_max_bad_records(3);
+            let err = agg
+                .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap_err();
+            assert!(err.to_string().contains("More than 3 records"), "{}", err);
+            assert!(err.to_string().contains("The first one was on line 3"));
+            assert_eq!(err.exit_code(), PARSING_EXIT_CODE);
+            let err = new_agg(true)
+                .with_threads(threads)
+                .with_max_bad_records(1)
+                .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("More than 1 record couldn't"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_parallel_write() {
+        let mut data = "region,product,amount\n".to_string();
+        for i in 0..120_000 {
+            // enough rows to get sorted and written on several threads, in no particular order
+            data.push_str(&format!(
+                "r{:05},p{},{}\n",
+                i * 7919 % 60_000,
+                i / 60_000,
+                i
+            ));
+        }
         let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
             vec![0],
+            vec![1],
             vec![],
-            1,
+            true,
+            OutputOrder::Descending,
+            OutputOrder::IndexOrder,
+            ParsingStrategy::Text,
+        );
+        agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        let mut write = |threads| {
+            agg.threads = threads;
+            let mut wtr = csv::Writer::from_writer(Vec::new());
+            agg.write_results(&mut wtr).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        let single = write(1);
+        assert!(
+            single.starts_with("region,p0,p1\nr59999,1,1\n"),
+            "{}",
+            &single[..50]
+        );
+        assert_eq!(single.lines().count(), 60_001);
+        assert_eq!(write(3), single);
+    }
+
+    #[test]
+    fn test_threads() {
+        let new_agg = || -> Aggregator<Mean, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+            .with_filter(Expr::parse("region != 'South'", |_| Ok(0)).unwrap())
+        };
+        let mut data = "region,product,amount\n".to_string();
+        let regions = ["West", "East", "South", "North"];
+        for i in 0..5000 {
+            let amount = if i % 7 == 0 {
+                "NA".to_string()
+            } else {
+                i.to_string()
+            };
+            // new products keep appearing, so they get seen first by different threads
+            data.push_str(&format!("{},p{},{}\n", regions[i % 4], i / 700, amount));
+        }
+        let mut single = new_agg();
+        single
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        let mut threaded = new_agg().with_threads(3);
+        threaded
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        // the rows keep the order they first appear in
+        assert_eq!(threaded.to_vec().unwrap(), single.to_vec().unwrap());
+        assert_eq!(threaded.record_counts(), single.record_counts());
+        assert_eq!(threaded.table_size(), single.table_size());
+
+        // errors refer to the same (first) record as they would on a single thread
+        let mut data = "region,product,amount\n".to_string();
+        for i in 0..5000 {
+            let amount = if i == 1500 || i == 3500 { "x" } else { "1" };
+            data.push_str(&format!("West,a,{}\n", amount));
+        }
+        let err = new_agg()
+            .with_threads(2)
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("`x` with index 1500"), "{}", err);
+    }
+
+    #[test]
+    fn test_scan_keys() {
+        let new_agg = |row_order| -> Aggregator<Mean, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                row_order,
+                OutputOrder::Descending,
+                ParsingStrategy::Numeric,
+            )
+            .with_filter(Expr::parse("region != 'South'", |_| Ok(0)).unwrap())
+        };
+        let mut data = "region,product,amount\n".to_string();
+        let regions = ["West", "East", "South", "North", "Central"];
+        for i in 0..2000 {
+            // Central never has a value, so it shouldn't get a row
+            let amount = if i % 5 == 4 || i % 7 == 0 {
+                "NA".to_string()
+            } else {
+                i.to_string()
+            };
+            data.push_str(&format!("{},p{},{}\n", regions[i % 5], i % 9, amount));
+        }
+        for row_order in [OutputOrder::IndexOrder, OutputOrder::Ascending] {
+            let mut single = new_agg(row_order.clone());
+            single
+                .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            let mut two_pass = new_agg(row_order);
+            two_pass
+                .scan_keys(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            assert!(two_pass.aggregations.is_dense());
+            assert_eq!(two_pass.record_counts(), RecordCounts::default());
+            two_pass
+                .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            assert!(two_pass.aggregations.is_dense());
+            assert_eq!(two_pass.to_vec().unwrap(), single.to_vec().unwrap());
+            assert_eq!(two_pass.to_sparse_vec(), single.to_sparse_vec());
+            assert_eq!(two_pass.record_counts(), single.record_counts());
+            assert_eq!(two_pass.table_size(), single.table_size());
+        }
+        let err = new_agg(OutputOrder::IndexOrder)
+            .with_max_cells(10)
+            .scan_keys(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("--max-cells"));
+    }
+
+    #[test]
+    fn test_aggregate_chunks() {
+        let new_agg = || -> Aggregator<Count<DecimalWrapper>, DecimalWrapper, usize> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let mut data = "region,product,amount\n".to_string();
+        let mut offsets = Vec::new();
+        for i in 0..3000 {
+            offsets.push(data.len() as u64);
+            let amount = if i == 2500 { "x" } else { "1" };
+            data.push_str(&format!("r{},p{},{}\n", i % 4, i / 400, amount));
+        }
+        let chunks_of = |data: &str, sizes: &[usize]| {
+            let mut first_record = 0;
+            let mut chunks = Vec::new();
+            for &num_records in sizes {
+                let chunk = Chunk {
+                    byte: offsets[first_record],
+                    first_record,
+                    num_records,
+                };
+                let rdr = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(io::Cursor::new(data.to_string()));
+                chunks.push((chunk, rdr));
+                first_record += num_records;
+            }
+            chunks
+        };
+        let clean = data.replace(",x\n", ",1\n");
+        let mut single = new_agg();
+        single
+            .aggregate(&mut csv::Reader::from_reader(clean.as_bytes()))
+            .unwrap();
+        let mut chunked = new_agg().with_threads(3);
+        chunked
+            .aggregate_chunks(
+                &mut csv::Reader::from_reader(clean.as_bytes()),
+                chunks_of(&clean, &[1000, 1000, 1000]),
+            )
+            .unwrap();
+        assert_eq!(chunked.to_vec().unwrap(), single.to_vec().unwrap());
+        assert_eq!(chunked.record_counts(), single.record_counts());
+
+        // the limit applies to the whole file
+        let mut limited = new_agg().with_threads(3).with_limit(1500);
+        limited
+            .aggregate_chunks(
+                &mut csv::Reader::from_reader(clean.as_bytes()),
+                chunks_of(&clean, &[1000, 1000, 1000]),
+            )
+            .unwrap();
+        assert_eq!(limited.record_counts().read, 1500);
+
+        // errors refer to the record's number in the whole file and its byte offset
+        let err = new_agg()
+            .with_threads(2)
+            .aggregate_chunks(
+                &mut csv::Reader::from_reader(data.as_bytes()),
+                chunks_of(&data, &[1500, 1500]),
+            )
+            .unwrap_err();
+        let message = format!(
+            "`x` with index 2500 (column `amount`, field 2, line 1001, byte {})",
+            offsets[2500]
+        );
+        assert!(err.to_string().contains(&message), "{}", err);
+    }
+
+    #[test]
+    fn test_aggregate_files() {
+        let new_agg = || -> Aggregator<Sum<DecimalWrapper>, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let files: Vec<String> = (0..3)
+            .map(|day| {
+                let mut data = "region,product,amount\n".to_string();
+                for i in 0..700 {
+                    data.push_str(&format!("r{},p{},{}\n", (i + day) % 5, i / 300, i));
+                }
+                data
+            })
+            .collect();
+        fn readers(files: &[String]) -> Vec<csv::Reader<&[u8]>> {
+            files
+                .iter()
+                .map(|data| csv::Reader::from_reader(data.as_bytes()))
+                .collect()
+        }
+        let mut combined = files[0].clone();
+        for data in &files[1..] {
+            combined.push_str(data.split_once('\n').unwrap().1);
+        }
+        let mut single = new_agg();
+        single
+            .aggregate(&mut csv::Reader::from_reader(combined.as_bytes()))
+            .unwrap();
+        for threads in 1..=3 {
+            let mut merged = new_agg().with_threads(threads);
+            merged.aggregate_files(readers(&files)).unwrap();
+            assert_eq!(merged.to_vec().unwrap(), single.to_vec().unwrap());
+            assert_eq!(merged.record_counts(), single.record_counts());
+        }
+
+        // the limit applies to all of the files together
+        let mut limited = new_agg().with_threads(3).with_limit(1000);
+        limited.aggregate_files(readers(&files)).unwrap();
+        assert_eq!(limited.record_counts().read, 1000);
+
+        // errors say which file they came from
+        let mut mismatched = files.clone();
+        mismatched[2] = mismatched[2].replacen("amount", "total", 1);
+        let (file_idx, err) = new_agg().aggregate_files(readers(&mismatched)).unwrap_err();
+        assert_eq!(file_idx, Some(2));
+        assert!(err.to_string().contains("header row"), "{}", err);
+        let mut invalid = files.clone();
+        invalid[1].push_str("r1,p0,x\n");
+        let (file_idx, err) = new_agg()
+            .with_threads(2)
+            .aggregate_files(readers(&invalid))
+            .unwrap_err();
+        assert_eq!(file_idx, Some(1));
+        assert!(err.to_string().contains("`x` with index 700"), "{}", err);
+
+        // but sums that only overflow once the files get merged don't come from any one of them
+        let half = "region,product,amount\nr1,p0,39614081257132168796771975168\n".to_string();
+        let (file_idx, err) = new_agg()
+            .with_threads(2)
+            .aggregate_files(readers(&[half.clone(), half]))
+            .unwrap_err();
+        assert_eq!(file_idx, None);
+        assert!(matches!(err, CsvCliError::MergeError { .. }), "{:?}", err);
+        assert!(
+            err.to_string()
+                .starts_with("Could not combine the cell in row `r1`, column `p0`"),
+            "{}",
+            err
+        );
+        assert!(!err.to_string().contains("index"), "{}", err);
+    }
+
+    #[test]
+    fn test_state() {
+        let new_agg = || -> Aggregator<Median, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let history = "region,product,amount\nwest,a,1\neast,b,2\nwest,b,3\n";
+        let new_records = "region,product,amount\nnorth,a,4\nwest,a,5\neast,c,6\n";
+        let mut combined = new_agg();
+        combined
+            .aggregate(&mut csv::Reader::from_reader(
+                format!("{}{}", history, new_records.split_once('\n').unwrap().1).as_bytes(),
+            ))
+            .unwrap();
+        let mut saved = new_agg();
+        saved
+            .aggregate(&mut csv::Reader::from_reader(history.as_bytes()))
+            .unwrap();
+        let mut state = Vec::new();
+        saved.save_state(&mut state, "median").unwrap();
+        let mut resumed = new_agg();
+        resumed.load_state(state.as_slice(), "median").unwrap();
+        resumed
+            .aggregate(&mut csv::Reader::from_reader(new_records.as_bytes()))
+            .unwrap();
+        assert_eq!(resumed.to_vec().unwrap(), combined.to_vec().unwrap());
+        assert_eq!(resumed.row_counts, combined.row_counts);
+        assert_eq!(resumed.column_counts, combined.column_counts);
+
+        let err = new_agg().load_state(state.as_slice(), "mean").unwrap_err();
+        assert!(err.to_string().contains("different pivot table"), "{}", err);
+        let corrupted = String::from_utf8(state)
+            .unwrap()
+            .replace("cell,0,0,0", "cell,9,0,0");
+        assert!(new_agg()
+            .load_state(corrupted.as_bytes(), "median")
+            .is_err());
+        assert!(new_agg().load_state(history.as_bytes(), "median").is_err());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let new_agg = || -> Aggregator<Mean, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::IndexOrder,
+                OutputOrder::IndexOrder,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let mut data = "region,product,amount\n".to_string();
+        for i in 0..3000 {
+            data.push_str(&format!("r{},p{},{}\n", i % 7, i / 1100, i % 13));
+        }
+        let path = std::env::temp_dir().join(format!("clipivot-checkpoint-{}", std::process::id()));
+        let mut full = new_agg();
+        full.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        // a run that stops partway through leaves the last checkpoint behind
+        let mut interrupted = new_agg().with_limit(2500).with_checkpoints(
+            path.clone(),
+            "mean",
+            Duration::from_secs(0),
+        );
+        interrupted
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap();
+        let checkpoint = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut resumed = new_agg();
+        let position = resumed.resume(checkpoint.as_slice(), "mean").unwrap();
+        assert_eq!(resumed.record_counts().read, 2000);
+        let mut rdr = csv::Reader::from_reader(io::Cursor::new(data.as_bytes()));
+        rdr.seek(position).unwrap();
+        resumed.aggregate(&mut rdr).unwrap();
+        assert_eq!(resumed.to_vec().unwrap(), full.to_vec().unwrap());
+        assert_eq!(resumed.record_counts(), full.record_counts());
+        // a checkpoint isn't a saved state, and it only resumes the same pivot table
+        assert!(new_agg().load_state(checkpoint.as_slice(), "mean").is_err());
+        assert!(new_agg().resume(checkpoint.as_slice(), "median").is_err());
+    }
+
+    #[test]
+    fn test_max_cells() {
+        let new_agg = |row_order| -> Aggregator<Median, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                row_order,
+                OutputOrder::Ascending,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let mut data = "row,column,amount\n".to_string();
+        for i in 0..500 {
+            data.push_str(&format!("r{},c{},{}\n", i % 37, i % 3, i));
+        }
+        let write = |agg: &mut Aggregator<Median, DecimalWrapper, DecimalWrapper>| {
+            agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            agg.write_results(&mut wtr).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        for row_order in [OutputOrder::Ascending, OutputOrder::Descending] {
+            let expected = write(&mut new_agg(row_order.clone()));
+            // the cells of each row end up spread across several runs
+            let mut spilled = new_agg(row_order.clone()).with_max_cells(10);
+            assert_eq!(write(&mut spilled), expected);
+            assert!(spilled.spill_runs.len() > 10);
+            let mut threaded = new_agg(row_order).with_max_cells(10).with_threads(2);
+            assert_eq!(write(&mut threaded), expected);
+        }
+        // rows are always sorted once the cells can get spilled
+        let mut sorted = new_agg(OutputOrder::Ascending).with_sparse(true);
+        let mut spilled = new_agg(OutputOrder::IndexOrder)
+            .with_sparse(true)
+            .with_max_cells(5);
+        assert_eq!(write(&mut spilled), write(&mut sorted));
+
+        let err = new_agg(OutputOrder::Ascending)
+            .with_totals(true)
+            .with_max_cells(10)
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("combined with totals"), "{}", err);
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let new_agg = || -> Aggregator<Median, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![1],
+                vec![2],
+                true,
+                OutputOrder::Ascending,
+                OutputOrder::Ascending,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let mut data = "row,column,amount\n".to_string();
+        for i in 0..500 {
+            data.push_str(&format!("r{},c{},{}\n", i % 37, i % 3, i));
+        }
+        let write = |agg: &mut Aggregator<Median, DecimalWrapper, DecimalWrapper>| {
+            agg.aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+                .unwrap();
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            agg.write_results(&mut wtr).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        let mut in_memory = new_agg();
+        let expected = write(&mut in_memory);
+        // the median's values count toward the estimate, along with the cells and keys
+        assert!(in_memory.memory_estimate() > 500 * std::mem::size_of::<DecimalWrapper>());
+        let mut roomy = new_agg().with_max_memory(1 << 30);
+        assert_eq!(write(&mut roomy), expected);
+        assert!(!roomy.spilled());
+        let mut spilled = new_agg().with_max_memory(2000);
+        assert_eq!(write(&mut spilled), expected);
+        assert!(spilled.spilled());
+        let mut threaded = new_agg().with_max_memory(2000).with_threads(2);
+        assert_eq!(write(&mut threaded), expected);
+
+        let err = new_agg()
+            .with_totals(true)
+            .with_max_memory(2000)
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("combined with totals"), "{}", err);
+        // the columns have to stay in memory
+        let err = new_agg()
+            .with_max_memory(1)
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("columns of the table"), "{}", err);
+    }
+
+    #[test]
+    fn test_parsing_error_context() {
+        let new_agg = || -> Aggregator<Mean, DecimalWrapper, DecimalWrapper> {
+            Aggregator::new(
+                vec![0],
+                vec![],
+                vec![1],
+                false,
+                OutputOrder::Ascending,
+                OutputOrder::Ascending,
+                ParsingStrategy::Numeric,
+            )
+        };
+        let data = "region,amount\nEast,10\nWest,ten\n";
+        let err = new_agg()
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not parse record `ten` with index 1 (column `amount`, field 1, line 3, byte 22): Failed to parse as numeric"
+        );
+        // the key column is named too
+        let data = b"region,amount\nEast,10\nW\xffst,5\n";
+        let err = new_agg()
+            .aggregate(&mut csv::Reader::from_reader(&data[..]))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("with index 1 (column `region`, field 0, line 3, byte 22)"),
+            "{}",
+            err
+        );
+        // long values get cut short
+        let long = "9x".repeat(100);
+        let data = format!("region,amount\nEast,{}\n", long);
+        let err = new_agg()
+            .aggregate(&mut csv::Reader::from_reader(data.as_bytes()))
+            .unwrap_err();
+        let message = format!("`{}...` with index 0", &long[..MAX_VALUE_CHARS]);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Could not parse record {}", message)));
+        assert!(err
+            .to_json()
+            .contains(&format!("\"value\": \"{}...\"", &long[..MAX_VALUE_CHARS])));
+        assert!(err
+            .to_json()
+            .contains("\"column\": \"amount\", \"column_index\": 1"));
+    }
+
+    #[test]
+    fn test_configuration_warnings() {
+        let headers = csv::StringRecord::from(vec!["region", "month", "amount"]);
+        let agg: Aggregator<Mean, DecimalWrapper, DecimalWrapper> = Aggregator::new(
+            vec![0, 0],
+            vec![0],
+            vec![2, 1],
+            false,
+            OutputOrder::Ascending,
+            OutputOrder::Ascending,
+            ParsingStrategy::Numeric,
+        );
+        assert_eq!(
+            agg.configuration_warnings(&headers),
+            vec![
+                "`region` is selected as a row field more than once",
+                "`region` is both a row field and a column field, so only the cells where they match will have values",
+            ]
+        );
+        let agg: Aggregator<Mean, DecimalWrapper, DecimalWrapper> = Aggregator::new(
+            vec![0],
+            vec![1],
+            vec![1, 2],
             false,
+            OutputOrder::Ascending,
+            OutputOrder::Ascending,
+            ParsingStrategy::Numeric,
+        );
+        assert_eq!(
+            agg.configuration_warnings(&csv::StringRecord::new()),
+            vec!["column 1 is both a values column and a column field, so every value in a cell is the same"]
+        );
+        let agg = agg.with_column_fields(vec![KeyField::record_number()]);
+        assert!(agg.configuration_warnings(&headers).is_empty());
+    }
+
+    #[test]
+    fn test_count_without_values_column() {
+        let mut agg: Aggregator<Count<String>, String, usize> = Aggregator::new(
+            vec![0],
+            vec![],
+            vec![],
+            true,
             OutputOrder::IndexOrder,
             OutputOrder::Ascending,
             ParsingStrategy::Text,
         );
-        let data = vec![
-            StringRecord::from(vec!["example".to_string(), "record".to_string()]),
-            StringRecord::from(vec!["example".to_string(), "again".to_string()]),
-        ];
-        for (count, record) in data.iter().enumerate() {
-            agg.add_record(&record, count).unwrap();
+        for (count, record) in [vec!["a", ""], vec!["a", "x"], vec!["b", ""]]
+            .iter()
+            .enumerate()
+        {
+            agg.add_record(&ByteRecord::from(record.clone()), count)
+                .unwrap();
         }
         agg.prepare_write().unwrap();
-        let results = agg.to_vec();
         let expected = vec![
             vec![String::new(), "total".to_string()],
-            vec!["example".to_string(), "2".to_string()],
+            vec!["a".to_string(), "2".to_string()],
+            vec!["b".to_string(), "1".to_string()],
         ];
-        assert_eq!(results, expected);
+        assert_eq!(agg.to_vec().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_index_columns_named_after_fields() {
+        let data = "city,season,state,team,sport\nColumbus,Playoffs,OH,Crew,Soccer\n";
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let mut agg = setup_simple();
+        agg.aggregate(&mut rdr).unwrap();
+        agg.prepare_write().unwrap();
+        assert_eq!(agg.get_pivot_headers()[0][..2], ["city", "state"]);
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let mut agg = setup_simple().with_row_labels(vec!["town".to_string()]);
+        agg.aggregate(&mut rdr).unwrap();
+        agg.prepare_write().unwrap();
+        assert_eq!(agg.get_pivot_headers()[0][..2], ["town", "state"]);
     }
 }
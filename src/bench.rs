@@ -0,0 +1,160 @@
+//! The module for timing pivot tables, with `clipivot bench`.
+//!
+//! `bench` runs the same pivot table several times (throwing away the results) and writes how
+//! long each phase of each run took to standard error, along with how many megabytes and records
+//! were read each second. That makes it possible to tell whether a new version of `clipivot`
+//! (or a different set of options) is faster or slower on your own data. If you don't have a
+//! large file at hand, `--synthetic N` benchmarks a generated file with `N` records instead.
+use crate::logging::format_duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::time::Duration;
+
+/// The number of times a pivot table runs when `--runs` isn't given
+pub const DEFAULT_RUNS: usize = 3;
+
+/// The header of the synthetic data `--synthetic` generates
+pub const SYNTHETIC_HEADER: &str = "id,region,product,date,amount";
+const SYNTHETIC_REGIONS: [&str; 5] = ["North", "South", "East", "West", "Central"];
+/// The number of distinct products in the synthetic data
+const SYNTHETIC_PRODUCTS: usize = 200;
+/// The seed for generating synthetic data, so every benchmark reads the same records
+const SYNTHETIC_SEED: u64 = 1776;
+
+/// How long each phase of a single run took
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunTiming {
+    /// The number of bytes that were read
+    pub bytes: u64,
+    /// The number of records that were read
+    pub records: usize,
+    /// Setting up the aggregator (selecting the fields and so on)
+    pub configure: Duration,
+    /// Reading and aggregating the records
+    pub aggregate: Duration,
+    /// Computing the table and writing it (to nowhere)
+    pub write: Duration,
+}
+
+impl RunTiming {
+    /// The total time of the run
+    pub fn total(&self) -> Duration {
+        self.configure + self.aggregate + self.write
+    }
+
+    /// The number of megabytes aggregated each second
+    pub fn megabytes_per_second(&self) -> f64 {
+        per_second(self.bytes as f64 / (1024. * 1024.), self.aggregate)
+    }
+
+    /// The number of records aggregated each second
+    pub fn records_per_second(&self) -> f64 {
+        per_second(self.records as f64, self.aggregate)
+    }
+}
+
+impl fmt::Display for RunTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "configured in {}, aggregated {} records in {} ({:.1} MB/s, {:.0} records/s), wrote in {} ({} total)",
+            format_duration(self.configure),
+            self.records,
+            format_duration(self.aggregate),
+            self.megabytes_per_second(),
+            self.records_per_second(),
+            format_duration(self.write),
+            format_duration(self.total())
+        )
+    }
+}
+
+fn per_second(amount: f64, duration: Duration) -> f64 {
+    let seconds = duration.as_secs_f64();
+    if seconds > 0. {
+        amount / seconds
+    } else {
+        0.
+    }
+}
+
+/// Summarizes a set of runs with the throughput of the fastest and the median run
+pub fn summarize(timings: &[RunTiming]) -> String {
+    let mut by_speed: Vec<&RunTiming> = timings.iter().collect();
+    by_speed.sort_by_key(|timing| timing.aggregate);
+    match (by_speed.first(), by_speed.get(by_speed.len() / 2)) {
+        (Some(fastest), Some(median)) => format!(
+            "Fastest of {} runs: {:.1} MB/s, {:.0} records/s; median: {:.1} MB/s, {:.0} records/s",
+            timings.len(),
+            fastest.megabytes_per_second(),
+            fastest.records_per_second(),
+            median.megabytes_per_second(),
+            median.records_per_second()
+        ),
+        _ => "No runs".to_string(),
+    }
+}
+
+/// Generates a CSV with `num_records` records (and a header row) of made-up sales, for
+/// benchmarking without a file. The columns are `SYNTHETIC_HEADER`, and the same number of
+/// records always produces the same data.
+pub fn synthetic_data(num_records: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(SYNTHETIC_SEED);
+    let mut data = format!("{}\n", SYNTHETIC_HEADER).into_bytes();
+    for id in 0..num_records {
+        let record = format!(
+            "{},{},product_{},2020-{:02}-{:02},{}.{:02}\n",
+            id,
+            SYNTHETIC_REGIONS[rng.gen_range(0, SYNTHETIC_REGIONS.len())],
+            rng.gen_range(0, SYNTHETIC_PRODUCTS),
+            rng.gen_range(1, 13),
+            rng.gen_range(1, 29),
+            rng.gen_range(0, 1000),
+            rng.gen_range(0, 100)
+        );
+        data.extend(record.as_bytes());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthetic_data() {
+        let data = synthetic_data(100);
+        assert_eq!(data, synthetic_data(100));
+        let mut rdr = csv::Reader::from_reader(&data[..]);
+        assert_eq!(
+            rdr.headers().unwrap().iter().collect::<Vec<_>>().join(","),
+            SYNTHETIC_HEADER
+        );
+        let records: Vec<csv::StringRecord> = rdr.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 100);
+        assert_eq!(&records[99][0], "99");
+        assert!(records[0][4].parse::<f64>().is_ok());
+    }
+
+    #[test]
+    fn test_timings() {
+        let timing = |millis| RunTiming {
+            bytes: 1024 * 1024,
+            records: 1000,
+            configure: Duration::from_millis(1),
+            aggregate: Duration::from_millis(millis),
+            write: Duration::from_millis(2),
+        };
+        assert_eq!(timing(500).megabytes_per_second(), 2.);
+        assert_eq!(timing(500).records_per_second(), 2000.);
+        assert_eq!(
+            timing(500).to_string(),
+            "configured in 1.0ms, aggregated 1000 records in 500.0ms (2.0 MB/s, 2000 records/s), wrote in 2.0ms (503.0ms total)"
+        );
+        assert_eq!(
+            summarize(&[timing(1000), timing(250), timing(500)]),
+            "Fastest of 3 runs: 4.0 MB/s, 4000 records/s; median: 2.0 MB/s, 2000 records/s"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! The module for remembering the results of parsing the values that keep showing up in a column.
+//!
+//! Parsing a value (especially a date, which can mean trying several formats in turn) takes
+//! much longer than looking it up, and the values of a column often repeat: a file of
+//! transactions might have thousands of records for each day. So values columns keep a
+//! `ParseCache` of the values they've parsed, and so do the row and column fields that truncate
+//! dates with `--by`.
+use crate::hashing::FxHasher;
+use std::hash::Hasher;
+
+/// The number of raw values a `ParseCache` holds
+pub const PARSE_CACHE_SIZE: usize = 256;
+/// How many values a `ParseCache` looks up before deciding whether it's worth keeping
+pub const PARSE_CACHE_TRIAL: usize = 10_000;
+
+/// A small cache of the values a column parsed recently, so a value that keeps showing
+/// up (like the same handful of dates) doesn't get parsed over and over. Each raw string has a
+/// single slot, picked by its hash, and replaces whatever was in it before.
+///
+/// In a column where values rarely repeat, looking them up would only slow parsing down, so the
+/// cache turns itself off if fewer than a quarter of the first `PARSE_CACHE_TRIAL` values it
+/// looks up are already in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseCache<I> {
+    slots: Vec<Option<(String, I)>>,
+    lookups: usize,
+    hits: usize,
+    enabled: bool,
+}
+
+impl<I: Clone> Default for ParseCache<I> {
+    fn default() -> Self {
+        ParseCache::new()
+    }
+}
+
+impl<I: Clone> ParseCache<I> {
+    pub fn new() -> ParseCache<I> {
+        ParseCache {
+            slots: vec![None; PARSE_CACHE_SIZE],
+            lookups: 0,
+            hits: 0,
+            enabled: true,
+        }
+    }
+
+    fn slot(raw: &str) -> usize {
+        let mut hasher = FxHasher::default();
+        hasher.write(raw.as_bytes());
+        hasher.finish() as usize % PARSE_CACHE_SIZE
+    }
+
+    /// The parsed value of `raw`, if it's in the cache
+    pub fn get(&mut self, raw: &str) -> Option<I> {
+        if !self.enabled {
+            return None;
+        }
+        let parsed = match &self.slots[Self::slot(raw)] {
+            Some((cached, parsed)) if cached == raw => Some(parsed.clone()),
+            _ => None,
+        };
+        if self.lookups < PARSE_CACHE_TRIAL {
+            self.lookups += 1;
+            self.hits += usize::from(parsed.is_some());
+            if self.lookups == PARSE_CACHE_TRIAL && self.hits * 4 < self.lookups {
+                self.enabled = false;
+                self.slots = Vec::new();
+            }
+        }
+        parsed
+    }
+
+    pub fn insert(&mut self, raw: &str, parsed: I) {
+        if !self.enabled {
+            return;
+        }
+        match &mut self.slots[Self::slot(raw)] {
+            // reuses the string that's already there, rather than allocating a new one
+            Some((cached, old)) => {
+                cached.clear();
+                cached.push_str(raw);
+                *old = parsed;
+            }
+            slot => *slot = Some((raw.to_string(), parsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cache() {
+        let mut cache: ParseCache<usize> = ParseCache::new();
+        assert_eq!(cache.get("2020-01-01"), None);
+        cache.insert("2020-01-01", 1);
+        assert_eq!(cache.get("2020-01-01"), Some(1));
+        // a value whose raw string is in the same slot replaces the one that was there
+        let other = (0..)
+            .map(|i| format!("2020-01-{}", i))
+            .find(|raw| {
+                raw != "2020-01-01"
+                    && ParseCache::<usize>::slot(raw) == ParseCache::<usize>::slot("2020-01-01")
+            })
+            .unwrap();
+        cache.insert(&other, 2);
+        assert_eq!(cache.get("2020-01-01"), None);
+        assert_eq!(cache.get(&other), Some(2));
+
+        // a cache that rarely finds anything turns itself off
+        let mut cache: ParseCache<usize> = ParseCache::new();
+        for i in 0..PARSE_CACHE_TRIAL {
+            let raw = i.to_string();
+            if cache.get(&raw).is_none() {
+                cache.insert(&raw, i);
+            }
+        }
+        assert!(!cache.enabled);
+        cache.insert("0", 0);
+        assert_eq!(cache.get("0"), None);
+    }
+}
@@ -0,0 +1,514 @@
+//! The module for storing the cells of a pivot table.
+//!
+//! Usually, the cells live in a hash map keyed by the IDs of their row and column (and the
+//! position of their values column), so only the cells that actually get aggregated take up any
+//! room. With `--two-pass`, every row and column is known before any values get aggregated, so the
+//! cells can go in a single dense matrix instead: finding a cell is just arithmetic, and the cells
+//! sit next to each other in memory rather than being scattered around a hash table. That's faster
+//! (and much less fragmented) when most combinations of rows and columns have a cell, but it
+//! wastes memory on the empty combinations when few of them do.
+//!
+//! With several values columns, the cells are stored by column instead: each (row, column)
+//! combination gets a single ID from a hash map, and each values column keeps its cells in a
+//! vector indexed by those IDs. Every record only needs one hash lookup for all of its values
+//! (rather than one per values column), the keys don't get repeated for each values column, and
+//! the cells of a values column sit next to each other in memory.
+use crate::aggfunc::entry_size;
+use crate::hashing::FastHashMap;
+use std::collections::hash_map;
+use std::iter::Enumerate;
+
+/// The ID of a cell's row, the ID of its column, and the position of its values column
+pub type CellKey = (usize, usize, usize);
+
+/// The cells of a pivot table
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cells<T> {
+    /// Only the cells that have been aggregated, in a hash map
+    Sparse(FastHashMap<CellKey, T>),
+    /// A slot for every cell of a table with a fixed number of rows and columns
+    Dense(DenseCells<T>),
+    /// A vector of cells for each values column, indexed by the ID of their row and column
+    Columnar(ColumnarCells<T>),
+}
+
+/// A slot for every cell of a table, in order of their rows, then their columns, then their
+/// values columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct DenseCells<T> {
+    slots: Vec<Option<T>>,
+    rows: usize,
+    columns: usize,
+    values: usize,
+    /// The number of slots that hold a cell
+    len: usize,
+}
+
+impl<T> DenseCells<T> {
+    fn slot(&self, (row_id, col_id, value_idx): CellKey) -> Option<usize> {
+        if row_id < self.rows && col_id < self.columns && value_idx < self.values {
+            Some((row_id * self.columns + col_id) * self.values + value_idx)
+        } else {
+            None
+        }
+    }
+}
+
+/// The cells of a table with several values columns, with the cells of each values column in a
+/// vector of their own
+#[derive(Debug, Clone)]
+pub struct ColumnarCells<T> {
+    /// The ID of each (row, column) combination that has a cell in any of the values columns
+    ids: FastHashMap<(usize, usize), usize>,
+    /// The (row, column) combination with each ID
+    keys: Vec<(usize, usize)>,
+    /// The cells of each values column, indexed by the ID of their row and column
+    values: Vec<Vec<Option<T>>>,
+    /// The number of slots that hold a cell
+    len: usize,
+    /// The (row, column) combination that was looked up last, along with its ID, since the
+    /// values columns of a record get looked up one after another
+    last: Option<((usize, usize), usize)>,
+}
+
+impl<T> ColumnarCells<T> {
+    fn id(&mut self, row_col: (usize, usize)) -> Option<usize> {
+        match self.last {
+            Some((last, id)) if last == row_col => Some(id),
+            _ => {
+                let id = *self.ids.get(&row_col)?;
+                self.last = Some((row_col, id));
+                Some(id)
+            }
+        }
+    }
+
+    /// The ID of a (row, column) combination, giving it a new ID (with an empty slot in every
+    /// values column) if it doesn't have one
+    fn id_or_insert(&mut self, row_col: (usize, usize)) -> usize {
+        if let Some(id) = self.id(row_col) {
+            return id;
+        }
+        let id = self.keys.len();
+        self.ids.insert(row_col, id);
+        self.keys.push(row_col);
+        for column in &mut self.values {
+            column.push(None);
+        }
+        self.last = Some((row_col, id));
+        id
+    }
+}
+
+// what was looked up last doesn't change which cells a table holds
+impl<T: PartialEq> PartialEq for ColumnarCells<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids == other.ids && self.keys == other.keys && self.values == other.values
+    }
+}
+
+/// The key of the cell in slot `slot` of a dense table
+fn key_at(slot: usize, columns: usize, values: usize) -> CellKey {
+    (
+        slot / (columns * values),
+        (slot / values) % columns,
+        slot % values,
+    )
+}
+
+impl<T> Default for Cells<T> {
+    fn default() -> Self {
+        Cells::Sparse(FastHashMap::default())
+    }
+}
+
+impl<T> Cells<T> {
+    /// Empty dense storage for a table with `rows` rows, `columns` columns, and `values` values
+    /// columns, or `None` if the table has too many cells to allocate
+    pub fn dense(rows: usize, columns: usize, values: usize) -> Option<Self> {
+        let num_slots = rows.checked_mul(columns)?.checked_mul(values)?;
+        let bytes = num_slots.checked_mul(std::mem::size_of::<Option<T>>())?;
+        if bytes > isize::MAX as usize {
+            return None;
+        }
+        let mut slots = Vec::with_capacity(num_slots);
+        slots.resize_with(num_slots, || None);
+        Some(Cells::Dense(DenseCells {
+            slots,
+            rows,
+            columns,
+            values,
+            len: 0,
+        }))
+    }
+
+    /// Empty storage for a table with `values` values columns, with the cells of each values
+    /// column stored in a vector of their own
+    pub fn columnar(values: usize) -> Self {
+        Cells::Columnar(ColumnarCells {
+            ids: FastHashMap::default(),
+            keys: Vec::new(),
+            values: (0..values).map(|_| Vec::new()).collect(),
+            len: 0,
+            last: None,
+        })
+    }
+
+    /// Empty storage of the same kind, for a table with `rows` rows and `columns` columns
+    pub fn empty_like(&self, rows: usize, columns: usize) -> Self {
+        match self {
+            Cells::Sparse(map) => Cells::Sparse(FastHashMap::with_capacity_and_hasher(
+                map.len(),
+                Default::default(),
+            )),
+            Cells::Dense(dense) => Cells::dense(rows, columns, dense.values).unwrap_or_default(),
+            Cells::Columnar(columnar) => {
+                let mut cells = Cells::columnar(columnar.values.len());
+                cells.reserve(columnar.keys.len() * columnar.values.len());
+                cells
+            }
+        }
+    }
+
+    /// Whether the cells are stored in a dense matrix
+    pub fn is_dense(&self) -> bool {
+        matches!(self, Cells::Dense(_))
+    }
+
+    /// The number of cells
+    pub fn len(&self) -> usize {
+        match self {
+            Cells::Sparse(map) => map.len(),
+            Cells::Dense(dense) => dense.len,
+            Cells::Columnar(columnar) => columnar.len,
+        }
+    }
+
+    /// Whether there aren't any cells
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Roughly how much memory the cells take up (not counting anything they hold on the heap)
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Cells::Sparse(map) => map.len() * entry_size::<(CellKey, T)>(),
+            Cells::Dense(dense) => dense.slots.len() * std::mem::size_of::<Option<T>>(),
+            Cells::Columnar(columnar) => {
+                columnar.keys.len()
+                    * (entry_size::<((usize, usize), usize)>()
+                        + std::mem::size_of::<(usize, usize)>()
+                        + columnar.values.len() * std::mem::size_of::<Option<T>>())
+            }
+        }
+    }
+
+    /// The cell at `key`, if there is one
+    pub fn get(&self, key: &CellKey) -> Option<&T> {
+        match self {
+            Cells::Sparse(map) => map.get(key),
+            Cells::Dense(dense) => dense.slot(*key).and_then(|slot| dense.slots[slot].as_ref()),
+            Cells::Columnar(columnar) => {
+                let &(row_id, col_id, value_idx) = key;
+                let id = *columnar.ids.get(&(row_id, col_id))?;
+                columnar.values.get(value_idx)?[id].as_ref()
+            }
+        }
+    }
+
+    /// The cell at `key` (as a mutable reference), if there is one
+    pub fn get_mut(&mut self, key: &CellKey) -> Option<&mut T> {
+        match self {
+            Cells::Sparse(map) => map.get_mut(key),
+            Cells::Dense(dense) => match dense.slot(*key) {
+                Some(slot) => dense.slots[slot].as_mut(),
+                None => None,
+            },
+            Cells::Columnar(columnar) => {
+                let &(row_id, col_id, value_idx) = key;
+                let id = columnar.id((row_id, col_id))?;
+                columnar.values.get_mut(value_idx)?[id].as_mut()
+            }
+        }
+    }
+
+    /// Adds (or replaces) the cell at `key`. A key outside of a dense table moves every cell to
+    /// a hash map, so nothing gets lost if a table turns out to have more rows or columns than
+    /// it was allocated with.
+    pub fn insert(&mut self, key: CellKey, cell: T) {
+        match self {
+            Cells::Sparse(map) => {
+                map.insert(key, cell);
+            }
+            Cells::Dense(dense) => match dense.slot(key) {
+                Some(slot) => {
+                    if dense.slots[slot].replace(cell).is_none() {
+                        dense.len += 1;
+                    }
+                }
+                None => {
+                    let mut map: FastHashMap<CellKey, T> =
+                        std::mem::take(self).into_iter().collect();
+                    map.insert(key, cell);
+                    *self = Cells::Sparse(map);
+                }
+            },
+            Cells::Columnar(columnar) => {
+                let (row_id, col_id, value_idx) = key;
+                let id = columnar.id_or_insert((row_id, col_id));
+                while columnar.values.len() <= value_idx {
+                    columnar
+                        .values
+                        .push((0..columnar.keys.len()).map(|_| None).collect());
+                }
+                if columnar.values[value_idx][id].replace(cell).is_none() {
+                    columnar.len += 1;
+                }
+            }
+        }
+    }
+
+    /// Sets aside room for `additional` more cells (which only matters for a hash map)
+    pub fn reserve(&mut self, additional: usize) {
+        match self {
+            Cells::Sparse(map) => map.reserve(additional),
+            Cells::Dense(_) => {}
+            Cells::Columnar(columnar) => {
+                // there's a cell for each values column of each (row, column) combination
+                let combinations = additional / columnar.values.len().max(1);
+                columnar.ids.reserve(combinations);
+                columnar.keys.reserve(combinations);
+                for column in &mut columnar.values {
+                    column.reserve(combinations);
+                }
+            }
+        }
+    }
+
+    /// Removes every cell
+    pub fn clear(&mut self) {
+        match self {
+            Cells::Sparse(map) => map.clear(),
+            Cells::Dense(_) => *self = Cells::default(),
+            Cells::Columnar(columnar) => *self = Cells::columnar(columnar.values.len()),
+        }
+    }
+
+    /// The cells, along with their keys
+    pub fn iter(&self) -> Iter<'_, T> {
+        match self {
+            Cells::Sparse(map) => Iter::Sparse(map.iter()),
+            Cells::Dense(dense) => Iter::Dense {
+                slots: dense.slots.iter().enumerate(),
+                columns: dense.columns,
+                values: dense.values,
+            },
+            Cells::Columnar(columnar) => Iter::Columnar {
+                keys: &columnar.keys,
+                values: &columnar.values,
+                next: (0, 0),
+            },
+        }
+    }
+
+    /// The keys of the cells
+    pub fn keys(&self) -> impl Iterator<Item = CellKey> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+}
+
+/// An iterator over the cells of a table, along with their keys
+pub enum Iter<'a, T> {
+    Sparse(hash_map::Iter<'a, CellKey, T>),
+    Dense {
+        slots: Enumerate<std::slice::Iter<'a, Option<T>>>,
+        columns: usize,
+        values: usize,
+    },
+    Columnar {
+        keys: &'a [(usize, usize)],
+        values: &'a [Vec<Option<T>>],
+        /// The ID and the values column of the next slot to look at
+        next: (usize, usize),
+    },
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (CellKey, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Sparse(iter) => iter.next().map(|(&key, cell)| (key, cell)),
+            Iter::Dense {
+                slots,
+                columns,
+                values,
+            } => slots.find_map(|(slot, cell)| {
+                cell.as_ref()
+                    .map(|cell| (key_at(slot, *columns, *values), cell))
+            }),
+            Iter::Columnar { keys, values, next } => loop {
+                let (id, value_idx) = *next;
+                let &(row_id, col_id) = keys.get(id)?;
+                *next = if value_idx + 1 < values.len() {
+                    (id, value_idx + 1)
+                } else {
+                    (id + 1, 0)
+                };
+                if let Some(cell) = values.get(value_idx).and_then(|column| column[id].as_ref()) {
+                    return Some(((row_id, col_id, value_idx), cell));
+                }
+            },
+        }
+    }
+}
+
+/// An iterator that moves the cells out of a table, along with their keys
+pub enum IntoIter<T> {
+    Sparse(hash_map::IntoIter<CellKey, T>),
+    Dense {
+        slots: Enumerate<std::vec::IntoIter<Option<T>>>,
+        columns: usize,
+        values: usize,
+    },
+    Columnar {
+        keys: std::vec::IntoIter<(usize, usize)>,
+        values: Vec<std::vec::IntoIter<Option<T>>>,
+        /// The cells of the current (row, column) combination that haven't been returned yet,
+        /// in reverse order
+        pending: Vec<(CellKey, T)>,
+    },
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = (CellKey, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            IntoIter::Sparse(iter) => iter.next(),
+            IntoIter::Dense {
+                slots,
+                columns,
+                values,
+            } => slots
+                .find_map(|(slot, cell)| cell.map(|cell| (key_at(slot, *columns, *values), cell))),
+            IntoIter::Columnar {
+                keys,
+                values,
+                pending,
+            } => loop {
+                if let Some(cell) = pending.pop() {
+                    return Some(cell);
+                }
+                let (row_id, col_id) = keys.next()?;
+                for (value_idx, column) in values.iter_mut().enumerate().rev() {
+                    if let Some(Some(cell)) = column.next() {
+                        pending.push(((row_id, col_id, value_idx), cell));
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl<T> IntoIterator for Cells<T> {
+    type Item = (CellKey, T);
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        match self {
+            Cells::Sparse(map) => IntoIter::Sparse(map.into_iter()),
+            Cells::Dense(dense) => IntoIter::Dense {
+                slots: dense.slots.into_iter().enumerate(),
+                columns: dense.columns,
+                values: dense.values,
+            },
+            Cells::Columnar(columnar) => IntoIter::Columnar {
+                keys: columnar.keys.into_iter(),
+                values: columnar.values.into_iter().map(Vec::into_iter).collect(),
+                pending: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dense_cells() {
+        let mut cells: Cells<usize> = Cells::dense(3, 2, 2).unwrap();
+        assert!(cells.is_dense() && cells.is_empty());
+        cells.insert((2, 1, 0), 5);
+        cells.insert((0, 1, 1), 3);
+        cells.insert((0, 1, 1), 4);
+        *cells.get_mut(&(2, 1, 0)).unwrap() += 1;
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells.get(&(2, 1, 0)), Some(&6));
+        assert_eq!(cells.get(&(1, 1, 0)), None);
+        assert_eq!(cells.get(&(3, 0, 0)), None);
+        assert_eq!(
+            cells.iter().collect::<Vec<_>>(),
+            vec![((0, 1, 1), &4), ((2, 1, 0), &6)]
+        );
+        // a key outside of the table moves the cells to a hash map
+        cells.insert((5, 0, 0), 1);
+        assert!(!cells.is_dense());
+        let mut keys: Vec<CellKey> = cells.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![(0, 1, 1), (2, 1, 0), (5, 0, 0)]);
+        assert!(Cells::<usize>::dense(usize::MAX, 2, 1).is_none());
+    }
+
+    #[test]
+    fn test_columnar_cells() {
+        let mut cells: Cells<usize> = Cells::columnar(2);
+        cells.insert((3, 1, 1), 5);
+        cells.insert((0, 2, 0), 1);
+        cells.insert((3, 1, 0), 2);
+        cells.insert((3, 1, 0), 3);
+        *cells.get_mut(&(3, 1, 1)).unwrap() += 1;
+        *cells.get_mut(&(0, 2, 0)).unwrap() += 1;
+        assert!(cells.get_mut(&(0, 2, 1)).is_none());
+        assert_eq!(cells.len(), 3);
+        assert_eq!(cells.get(&(3, 1, 1)), Some(&6));
+        assert_eq!(cells.get(&(0, 2, 1)), None);
+        assert_eq!(cells.get(&(1, 1, 0)), None);
+        assert_eq!(
+            cells.iter().collect::<Vec<_>>(),
+            vec![((3, 1, 0), &3), ((3, 1, 1), &6), ((0, 2, 0), &2)]
+        );
+        // a cell in a values column the table didn't start out with still gets stored
+        cells.insert((0, 2, 3), 7);
+        assert_eq!(cells.get(&(0, 2, 3)), Some(&7));
+        assert_eq!(
+            cells.clone().into_iter().collect::<Vec<_>>(),
+            vec![
+                ((3, 1, 0), 3),
+                ((3, 1, 1), 6),
+                ((0, 2, 0), 2),
+                ((0, 2, 3), 7)
+            ]
+        );
+        let mut empty = cells.empty_like(0, 0);
+        assert!(empty.is_empty() && empty != cells);
+        empty.insert((9, 9, 1), 1);
+        assert_eq!(empty.keys().collect::<Vec<_>>(), vec![(9, 9, 1)]);
+        cells.clear();
+        assert!(cells.is_empty() && matches!(cells, Cells::Columnar(_)));
+    }
+
+    #[test]
+    fn test_sparse_cells() {
+        let mut cells: Cells<usize> = Cells::default();
+        cells.insert((1000, 7, 0), 1);
+        assert_eq!(cells.get(&(1000, 7, 0)), Some(&1));
+        assert_eq!(
+            cells.clone().into_iter().collect::<Vec<_>>(),
+            vec![((1000, 7, 0), 1)]
+        );
+        cells.clear();
+        assert!(cells.is_empty());
+    }
+}
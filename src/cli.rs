@@ -1,72 +1,710 @@
-use clap::{App, AppSettings, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, Shell};
 use lazy_static::lazy_static;
-use std::io;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Seek, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
 
 use rust_decimal::Decimal;
 
-use crate::aggfunc::*;
-use crate::aggregation::{Aggregator, OutputOrder, ParsingStrategy};
+use crate::aggfunc::Accumulate;
+use crate::aggregation::{
+    Aggregator, FilterErrorPolicy, NanPolicy, OutputOrder, ParsingStrategy, Sample,
+};
+use crate::bench::{self, RunTiming};
 use crate::cli_settings::CsvSettings;
-use crate::errors::{CsvCliError, CsvCliResult};
-use crate::parsing::{CustomDateObject, DecimalWrapper};
-
-const ALLOWED_AGGFUNCS: [&str; 11] = [
-    "count",
-    "countunique",
-    "max",
-    "mean",
-    "median",
-    "min",
-    "minmax",
-    "mode",
-    "range",
-    "stddev",
-    "sum",
+use crate::config::{self, Config, ConfigValue};
+use crate::describe;
+use crate::errors::{
+    json_string, CsvCliError, CsvCliResult, CONFIG_EXIT_CODE, CSV_EXIT_CODE, IO_EXIT_CODE,
+    PARSING_EXIT_CODE,
+};
+use crate::index::{self, Chunk, CsvIndex};
+use crate::interactive::{self, INTERACTIVE_COMMAND};
+use crate::keys::{UnmappedPolicy, DEFAULT_KEY_DATE_FORMATS};
+use crate::logging::{self, format_duration, Level};
+use crate::melt;
+use crate::output::{CellGroup, CellTransform, RankTies, RecodeRule, WriteCell};
+use crate::parsing::ValueParser;
+use crate::pivot::{Agg, Pivot, PivotBuilder, PivotTask, ValueType, DEFAULT_OTHER_LABEL};
+use crate::progress::format_bytes;
+use crate::sorting::Comparator;
+use crate::sql::Dialect;
+use crate::stats::{self, ChiSquare};
+
+/// A supported aggregation function, along with the documentation `--list-aggregations` prints
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregation {
+    /// The name you pass on the command line
+    pub name: &'static str,
+    /// What the function computes
+    pub description: &'static str,
+    /// The kinds of values the function accepts
+    pub values: &'static str,
+    /// An example of using the function
+    pub example: &'static str,
+}
+
+/// Every aggregation function, in the order they're listed in the help message. This is the
+/// table the names of the functions on the command line get checked against.
+pub const AGGREGATIONS: [Aggregation; 11] = [
+    Aggregation {
+        name: "count",
+        description: "Counts the number of matching records.",
+        values: "Any text. Doesn't need a values column.",
+        example: "clipivot count sales.csv -r region",
+    },
+    Aggregation {
+        name: "countunique",
+        description: "Counts the number of unique values in the matching records.",
+        values: "Any text.",
+        example: "clipivot countunique sales.csv -r region -v customer",
+    },
+    Aggregation {
+        name: "max",
+        description: "Returns the maximum value.",
+        values: "Text (compared alphabetically), numbers with -N, or dates with -F.",
+        example: "clipivot max sales.csv -r region -v amount -N",
+    },
+    Aggregation {
+        name: "mean",
+        description: "Returns the mean.",
+        values: "Numbers.",
+        example: "clipivot mean sales.csv -r region -v amount",
+    },
+    Aggregation {
+        name: "median",
+        description: "Returns the median value.",
+        values: "Numbers.",
+        example: "clipivot median sales.csv -r region -v amount",
+    },
+    Aggregation {
+        name: "min",
+        description: "Returns the minimum value.",
+        values: "Text (compared alphabetically), numbers with -N, or dates with -F.",
+        example: "clipivot min sales.csv -r region -v date -F %Y-%m-%d",
+    },
+    Aggregation {
+        name: "minmax",
+        description: "Returns both the minimum and maximum values, split by a hyphen.",
+        values: "Text (compared alphabetically), numbers with -N, or dates with -F.",
+        example: "clipivot minmax sales.csv -r region -v amount -N",
+    },
+    Aggregation {
+        name: "mode",
+        description: "Returns the most commonly appearing value.",
+        values: "Any text.",
+        example: "clipivot mode sales.csv -r region -v product",
+    },
+    Aggregation {
+        name: "range",
+        description:
+            "Returns the difference between the minimum and maximum values (in days, for dates).",
+        values: "Numbers, or dates with -F.",
+        example: "clipivot range sales.csv -r region -v amount",
+    },
+    Aggregation {
+        name: "stddev",
+        description: "Returns the sample standard deviation.",
+        values: "Numbers.",
+        example: "clipivot stddev sales.csv -r region -v amount",
+    },
+    Aggregation {
+        name: "sum",
+        description: "Returns the sum of the values.",
+        values: "Numbers.",
+        example: "clipivot sum sales.csv -r region -c month -v amount",
+    },
+];
+
+/// The name you can pass to `-v` to use every column that isn't a row or column field as a values column
+const ALL_OTHER_VALUES: &str = "all-other";
+/// The number of decimal places percentages get rounded to, unless `--percent-decimals` says otherwise
+const DEFAULT_PERCENT_DECIMALS: u32 = 1;
+/// The number of decimal places that z-scores get rounded to
+const ZSCORE_DECIMALS: u32 = 2;
+/// What cells suppressed by `--suppress-below` get replaced with
+const SUPPRESSED_LABEL: &str = "*";
+/// The number of decimal places that ratios to a `--baseline` column get rounded to
+const BASELINE_DECIMALS: u32 = 2;
+/// The number of records `--dry-run` shows
+const DRY_RUN_RECORDS: usize = 5;
+/// The size of the buffer the output gets written through, so a wide table gets written to
+/// standard output in large chunks rather than a line at a time
+const OUTPUT_BUFFER_SIZE: usize = 1 << 16;
+/// How many seconds go by between checkpoints (with `--checkpoint`), unless the user picks
+/// a different interval
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 60;
+/// The smallest input file that gets aggregated on several threads when the user doesn't set
+/// `--threads`, since starting the threads isn't worth it for a small file
+const DEFAULT_THREADS_MIN_BYTES: u64 = 64 << 20;
+/// The most threads a single file gets aggregated on when the user doesn't set `--threads`,
+/// since the file gets parsed on a single thread and more workers than this just wait on it
+const MAX_DEFAULT_THREADS: usize = 8;
+/// The options that can be combined with `--to-sql`, since they either describe the pivot table
+/// in a way a query can express or only change how the input gets read
+const SQL_OPTIONS: [&str; 28] = [
+    "aggfunc",
+    "filename",
+    "rows",
+    "columns",
+    "value",
+    "numeric",
+    "format",
+    "empty",
+    "noheader",
+    "strictheaders",
+    "delim",
+    "tab",
+    "indexcol",
+    "desccol",
+    "ascrow",
+    "descrow",
+    "order",
+    "sortrows",
+    "sortcols",
+    "colorder",
+    "colorderfile",
+    "sorttype",
+    "config",
+    "noconfig",
+    "verbose",
+    "quiet",
+    "tosql",
+    "sqltable",
 ];
 
 lazy_static! {
-    pub static ref CLI_ARGS : ArgMatches<'static> = App::new("clipivot")
+    pub static ref CLI_ARGS: ArgMatches<'static> = get_matches_with_config();
+    /// The names of the aggregation functions
+    pub static ref ALLOWED_AGGFUNCS: Vec<&'static str> =
+        AGGREGATIONS.iter().map(|agg| agg.name).collect();
+    /// The help message of the aggregation function argument
+    static ref AGGFUNC_HELP: String = iter::once(
+        "The function you use to run across the pivot table. Run with --list-aggregations for details and examples.".to_string()
+    )
+    .chain(AGGREGATIONS.iter().map(|agg| format!("- {}: {}", agg.name, agg.description)))
+    .collect::<Vec<String>>()
+    .join("\n");
+}
+
+/// The settings a configuration file (or an environment variable) can set: the name of each
+/// setting, the environment variable that sets it, the argument it fills in, whether that
+/// argument takes a value, and the arguments that override the setting when they're passed
+/// on the command line
+const CONFIG_OPTIONS: [(&str, &str, &str, bool, &[&str]); 19] = [
+    (
+        "delim",
+        "CSVPIVOT_DELIMITER",
+        "--delim",
+        true,
+        &["delim", "tab"],
+    ),
+    ("tab", "CSVPIVOT_TAB", "-t", false, &["tab", "delim"]),
+    (
+        "no-header",
+        "CSVPIVOT_NO_HEADER",
+        "--no-header",
+        false,
+        &["noheader"],
+    ),
+    (
+        "strict-headers",
+        "CSVPIVOT_STRICT_HEADERS",
+        "--strict-headers",
+        false,
+        &["strictheaders"],
+    ),
+    (
+        "date-format",
+        "CSVPIVOT_DATE_FORMAT",
+        "-F",
+        true,
+        &["format"],
+    ),
+    ("numeric", "CSVPIVOT_NUMERIC", "-N", false, &["numeric"]),
+    ("skip-empty", "CSVPIVOT_SKIP_EMPTY", "-e", false, &["empty"]),
+    (
+        "null-values",
+        "CSVPIVOT_NULL_VALUES",
+        "--null-values",
+        true,
+        &["nullvalues"],
+    ),
+    (
+        "key-sep",
+        "CSVPIVOT_KEY_SEP",
+        "--key-sep",
+        true,
+        &["keysep"],
+    ),
+    (
+        "other-label",
+        "CSVPIVOT_OTHER_LABEL",
+        "--other-label",
+        true,
+        &["otherlabel"],
+    ),
+    (
+        "percent-decimals",
+        "CSVPIVOT_PERCENT_DECIMALS",
+        "--percent-decimals",
+        true,
+        &["percentdecimals"],
+    ),
+    (
+        "sort-type",
+        "CSVPIVOT_SORT_TYPE",
+        "--sort-type",
+        true,
+        &["sorttype"],
+    ),
+    ("order", "CSVPIVOT_ORDER", "--order", true, &["order"]),
+    (
+        "on-filter-error",
+        "CSVPIVOT_ON_FILTER_ERROR",
+        "--on-filter-error",
+        true,
+        &["onfiltererror"],
+    ),
+    ("nan", "CSVPIVOT_NAN", "--nan", true, &["nan"]),
+    (
+        "out-locale",
+        "CSVPIVOT_OUT_LOCALE",
+        "--out-locale",
+        true,
+        &["outlocale"],
+    ),
+    (
+        "skip-bad-records",
+        "CSVPIVOT_SKIP_BAD_RECORDS",
+        "--skip-bad-records",
+        false,
+        &["skipbadrecords"],
+    ),
+    (
+        "max-errors",
+        "CSVPIVOT_MAX_ERRORS",
+        "--max-errors",
+        true,
+        &["maxerrors"],
+    ),
+    (
+        "quiet",
+        "CSVPIVOT_QUIET",
+        "-q",
+        false,
+        &["quiet", "verbose"],
+    ),
+];
+
+/// The name of the pseudo-subcommand that prints a shell completion script
+pub const COMPLETIONS_COMMAND: &str = "completions";
+
+/// The name of the pseudo-subcommand that runs a preset saved in the configuration file
+pub const RUN_COMMAND: &str = "run";
+
+/// The name of the pseudo-subcommand that saves a preset to the configuration file
+pub const SAVE_PRESET_COMMAND: &str = "save-preset";
+
+/// The name of the (hidden) pseudo-subcommand that prints a man page, for package maintainers
+pub const MAN_COMMAND: &str = "man";
+
+/// The name of the subcommand that creates a pivot table (which is also what `clipivot` does
+/// when it isn't given a subcommand)
+pub const PIVOT_COMMAND: &str = "pivot";
+
+/// The name of the subcommand that aggregates records by their row and column fields, writing
+/// one record per group instead of a table
+pub const AGG_COMMAND: &str = "agg";
+
+/// The name of the subcommand that melts a wide table into a long one
+pub const MELT_COMMAND: &str = "melt";
+
+/// The name of the subcommand that summarizes the columns of a file
+pub const DESCRIBE_COMMAND: &str = "describe";
+
+/// The name of the subcommand that times a pivot table over several runs
+pub const BENCH_COMMAND: &str = "bench";
+
+/// The name of the subcommand that checks whether every record of a file can be aggregated
+pub const VALIDATE_COMMAND: &str = "validate";
+
+/// The most records that couldn't be aggregated that `clipivot validate` lists
+const VALIDATE_REPORT_LIMIT: usize = 20;
+
+/// The help message describing the subcommands
+const SUBCOMMANDS_HELP: &str = "SUBCOMMANDS:
+    pivot <aggfunc> ...      Creates a pivot table (the same as leaving out the subcommand)
+    agg <aggfunc> ...        Aggregates the records in each group of row and column fields, writing one record per group
+    melt [filename]          Melts the columns of a wide table into (variable, value) records
+    describe [filename]      Summarizes the type, number of empty values, number of unique values, and range of each column
+    bench <aggfunc> ...      Runs a pivot table several times, writing the time and throughput of each run to standard error
+    validate <aggfunc> ...   Reads a file the way a pivot table would, listing the records that can't be aggregated and the type of each column
+    completions <shell>      Prints a shell completion script
+    interactive <filename>   Picks the fields of a pivot table interactively
+    run <preset> ...         Runs a preset saved in the config file
+    save-preset <name> ...   Saves the arguments of a pivot table as a preset";
+
+/// Builds the definition of the command-line interface
+fn build_app() -> App<'static, 'static> {
+    App::new("clipivot")
         .version(crate_version!())
         .author(crate_authors!())
         .about("A tool for creating pivot tables from the command line.\n\
         For more information, visit https://www.github.com/maxblee/clipivot")
         .setting(AppSettings::ArgRequiredElseHelp)
+        .after_help(SUBCOMMANDS_HELP)
         .arg(Arg::with_name("aggfunc")
-            .required(true)
+            .required_unless_one(&["listaggregations", "capabilities"])
             .index(1)
             .possible_values(&ALLOWED_AGGFUNCS)
-            .help("The function you use to run across the pivot table.
-            - count counts the number of matching records.
-            - countunique counts the number of unique matching records.
-            - max returns the maximum value of the records given a specified data type.
-            - mean returns the mean.
-            - median returns the median value. Requires numeric data.
-            - min returns the minimum value of the records given a specified data type.
-            - minmax returns both the minimum and maximum values of the records, split by a hyphen.
-            - mode returns the most commonly appearing value.
-            - range returns the difference between the minimum and maximum values. Returns the number of days in the case of dates.
-            - stddev returns the sample standard deviation.
-            - sum returns the sum of the values."))
+            .help(&AGGFUNC_HELP))
+        .arg(Arg::with_name("listaggregations")
+            .long("list-aggregations")
+            .help("Prints each aggregation function with a description, the kinds of values it accepts, and an example."))
+        .arg(errors_arg())
+        .arg(Arg::with_name("capabilities")
+            .long("capabilities")
+            .takes_value(true)
+            .possible_values(&["json"])
+            .help("Prints the aggregation functions, formats, subcommands, and options this version supports, for programs that build an interface around clipivot."))
         .arg(Arg::with_name("filename")
             .index(2)
-            .help("The path to the file you want to create a pivot table from"))
+            .multiple(true)
+            .help("The path to the file you want to create a pivot table from. Several files with the same header row get aggregated (each on its own thread) as if they were one file."))
         .arg(Arg::with_name("rows")
             .long("rows")
             .short("r")
             .takes_value(true)
             .multiple(true)
-            .help("The name of the index(es) to aggregate on. Accepts string fieldnames or 0-indexed fields."))
+            .number_of_values(1)
+            .help("The name of the index(es) to aggregate on. Accepts string fieldnames, 0-indexed fields, ranges like 0-2, or @index for the record number."))
         .arg(Arg::with_name("columns")
             .long("cols")
             .short("c")
             .takes_value(true)
             .multiple(true)
-            .help("The name of the column(s) to aggregate on. Accepts string fieldnames or 0-indexed fields."))
+            .number_of_values(1)
+            .help("The name of the column(s) to aggregate on. Accepts string fieldnames, 0-indexed fields, ranges like 0-2, or @index for the record number."))
+        .arg(Arg::with_name("timeseries")
+            .long("timeseries")
+            .takes_value(true)
+            .conflicts_with_all(&["columns", "colsregex", "colsextract", "colsexpr", "indexcol"])
+            .help("Uses a date column, truncated to a period, as the columns (e.g. order_date:month). The columns are sorted chronologically."))
+        .arg(Arg::with_name("rowsregex")
+            .long("rows-regex")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A regular expression selecting every header it matches as an index (e.g. '^demo_')."))
+        .arg(Arg::with_name("colsregex")
+            .long("cols-regex")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A regular expression selecting every header it matches as a column (e.g. '^demo_')."))
+        .arg(Arg::with_name("rowsextract")
+            .long("rows-extract")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Adds an index derived from a regex capture of a column, written as column:regex (e.g. 'url:^https?://([^/]+)')."))
+        .arg(Arg::with_name("colsextract")
+            .long("cols-extract")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Adds a column derived from a regex capture of a column, written as column:regex (e.g. 'url:^https?://([^/]+)')."))
+        .arg(Arg::with_name("rowsexpr")
+            .long("rows-expr")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Adds an index computed from an expression, like 'city ~ \", \" ~ state' (where ~ joins text together)."))
+        .arg(Arg::with_name("colsexpr")
+            .long("cols-expr")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Adds a column computed from an expression, like 'city ~ \", \" ~ state' (where ~ joins text together)."))
+        .arg(Arg::with_name("by")
+            .long("by")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Truncates dates in the row and column fields to a year, month, week, day, weekday, or hour before grouping. Use column:period (e.g. created:month) to only truncate one field."))
+        .arg(Arg::with_name("bin")
+            .long("bin")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Sorts the numbers in a row or column field into buckets, written as column:width=N or column:edges (e.g. age:0,18,35,65)."))
+        .arg(Arg::with_name("recode")
+            .long("recode")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A CSV file mapping the values of the row and column fields (in its first column) to replacement labels (in its second). Use column:file to only recode one field."))
+        .arg(Arg::with_name("unmapped")
+            .long("unmapped")
+            .takes_value(true)
+            .possible_values(&["keep", "drop", "other"])
+            .help("What to do with values that don't appear in a --recode file. Defaults to keep."))
+        .arg(Arg::with_name("toprows")
+            .long("top-rows")
+            .takes_value(true)
+            .help("Only shows the N largest rows (by the aggregate of the entire row), rolling the rest into a single row labeled by --other-label."))
+        .arg(Arg::with_name("mincount")
+            .long("min-count")
+            .takes_value(true)
+            .help("Drops the rows and columns with fewer than N records."))
+        .arg(Arg::with_name("smallgroups")
+            .long("small-groups")
+            .takes_value(true)
+            .possible_values(&["drop", "other"])
+            .requires("mincount")
+            .help("Whether to drop the groups below --min-count or combine them into a group labeled by --other-label. Defaults to drop."))
+        .arg(Arg::with_name("limit")
+            .long("limit")
+            .takes_value(true)
+            .help("Stops reading the file after N records."))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .takes_value(true)
+            .help("Aggregates the records on N threads, which makes large files faster to pivot. 0 uses one thread per core, and 1 reads and aggregates everything on a single thread. Defaults to one thread per core (up to 8) for files of at least 64 MB, and 1 otherwise."))
+        .arg(Arg::with_name("hintgroups")
+            .long("hint-groups")
+            .takes_value(true)
+            .help("Sets aside room for about N (row, column) combinations up front, which speeds up tables with millions of cells."))
+        .arg(Arg::with_name("maxcells")
+            .long("max-cells")
+            .takes_value(true)
+            .help("Keeps at most N cells in memory, spilling the rest to temporary files that get merged at the end. Rows come out sorted, and options like --totals can't be used."))
+        .arg(Arg::with_name("maxmemory")
+            .long("max-memory")
+            .takes_value(true)
+            .help("Keeps a rough estimate of the memory the table takes up under SIZE (like 512M or 2G). Once the table reaches it, its cells get spilled to temporary files (as with --max-cells) or, if they can't be, clipivot stops with an error."))
+        .arg(Arg::with_name("twopass")
+            .long("two-pass")
+            .help("Reads the file twice: first to find every row and column, and then to aggregate the values into a dense matrix of cells. Faster for tables where most rows have a value in most columns. Doesn't work with standard input."))
+        .arg(Arg::with_name("state")
+            .long("state")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Merges the records into the pivot table saved in FILE by an earlier run (if there is one), and then saves the updated table back to FILE, so each run only has to read the records that were added since the last one."))
+        .arg(Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Saves the progress of the pivot table to FILE every so often while the file gets read, so a run that gets interrupted can pick up where it left off with --resume. The checkpoint gets deleted once the run finishes."))
+        .arg(Arg::with_name("checkpointinterval")
+            .long("checkpoint-interval")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("How often --checkpoint saves the progress of the pivot table. [default: 60]"))
+        .arg(Arg::with_name("resume")
+            .long("resume")
+            .requires("checkpoint")
+            .help("Picks up from the checkpoint saved by --checkpoint (if there is one) instead of reading the file from the start."))
+        .arg(Arg::with_name("sample")
+            .long("sample")
+            .takes_value(true)
+            .conflicts_with("samplen")
+            .help("Only aggregates a random sample of the records, including each record with the given probability (like 0.01)."))
+        .arg(Arg::with_name("samplen")
+            .long("sample-n")
+            .takes_value(true)
+            .help("Only aggregates a random sample of N records."))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .value_name("N")
+            .help("Seeds the random sample from --sample or --sample-n, so that the same seed (and the same input) gives the same sample on every run."))
+        .arg(Arg::with_name("otherlabel")
+            .long("other-label")
+            .takes_value(true)
+            .help("The label for values grouped into a catch-all category. Defaults to Other."))
+        .arg(Arg::with_name("normalizekeys")
+            .long("normalize-keys")
+            .takes_value(true)
+            .help("Cleans up the values of the row and column fields before grouping them, using a comma-separated list of trim, lower, upper, and collapse-spaces."))
+        .arg(Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only aggregates the records matching an expression, like 'state == \"CA\" && amount > 100'. Columns can be referenced by name, `quoted name`, or $index. Records need to match every --filter."))
+        .arg(Arg::with_name("filterany")
+            .long("filter-any")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Like --filter, except that records only need to match one of the --filter-any expressions."))
+        .arg(Arg::with_name("percent")
+            .long("percent")
+            .takes_value(true)
+            .possible_values(&["row", "column", "total"])
+            .help("Converts each cell into a percentage of its row's total, its column's total, or the total of the entire table."))
+        .arg(Arg::with_name("percentdecimals")
+            .long("percent-decimals")
+            .takes_value(true)
+            .help("The number of decimal places to round percentages (from --percent and --pct-change) to. Defaults to 1."))
+        .arg(Arg::with_name("baseline")
+            .long("baseline")
+            .takes_value(true)
+            .help("Divides each cell by the cell in the same row of the named column (e.g. --baseline 2019), producing ratios to that column."))
+        .arg(Arg::with_name("baselinescale")
+            .long("baseline-scale")
+            .takes_value(true)
+            .requires("baseline")
+            .help("Multiplies the ratios from --baseline by this number. Use 100 for index numbers where the baseline is 100. Defaults to 1."))
+        .arg(Arg::with_name("accumulate")
+            .long("accumulate")
+            .takes_value(true)
+            .possible_values(&["columns", "rows"])
+            .help("Replaces each cell with a running total, either across the columns of each row or down the rows of each column."))
+        .arg(Arg::with_name("diff")
+            .long("diff")
+            .takes_value(true)
+            .possible_values(&["columns", "rows"])
+            .help("Replaces each cell with its difference from the previous column (or the previous row)."))
+        .arg(Arg::with_name("pctchange")
+            .long("pct-change")
+            .requires("diff")
+            .help("Makes --diff compute the percent change from the previous column (or row) instead of the difference."))
+        .arg(Arg::with_name("rank")
+            .long("rank")
+            .takes_value(true)
+            .possible_values(&["rows", "columns"])
+            .help("Replaces each cell with its rank within its row (or column), where the largest value is ranked 1."))
+        .arg(Arg::with_name("rankties")
+            .long("rank-ties")
+            .takes_value(true)
+            .possible_values(&["min", "dense", "average"])
+            .requires("rank")
+            .help("How to rank tied cells: min (1, 2, 2, 4), dense (1, 2, 2, 3), or average (1, 2.5, 2.5, 4). Defaults to min."))
+        .arg(Arg::with_name("zscore")
+            .long("zscore")
+            .takes_value(true)
+            .possible_values(&["rows", "columns"])
+            .help("Replaces each cell with its z-score: the number of standard deviations it lies from the mean of its row (or column)."))
+        .arg(Arg::with_name("outputrecode")
+            .long("output-recode")
+            .takes_value(true)
+            .help("Replaces the output cells matching a comma-separated list of rules, like '0=-,>1000=***'. Rules can match a value or compare numbers with <, <=, >, or >=."))
+        .arg(Arg::with_name("outlocale")
+            .long("out-locale")
+            .takes_value(true)
+            .value_name("LOCALE")
+            .help("Writes the numbers of the output with the decimal and grouping separators of a locale, like de-DE for 1.234,5. The keys in the row and column fields stay the way they were read, and the output gets delimited with semicolons when the locale uses a decimal comma."))
+        .arg(Arg::with_name("suppressbelow")
+            .long("suppress-below")
+            .takes_value(true)
+            .help("Replaces the cells with values below N with '*', for suppressing small counts in published tables."))
+        .arg(Arg::with_name("dropempty")
+            .long("drop-empty")
+            .help("Removes the rows and columns of the output where every cell is empty or zero."))
+        .arg(Arg::with_name("totals")
+            .long("totals")
+            .conflicts_with_all(&["percent", "baseline", "diff", "accumulate", "zscore", "rank"])
+            .help("Adds a Total column holding the aggregate of each row and a Total row holding the aggregate of each column."))
+        .arg(Arg::with_name("subtotals")
+            .long("subtotals")
+            .conflicts_with_all(&["percent", "baseline", "diff", "accumulate", "zscore", "rank"])
+            .help("With multiple row fields, adds a subtotal row above each group of rows sharing their first values (e.g. each state above its counties)."))
+        .arg(Arg::with_name("sparse")
+            .long("sparse")
+            .conflicts_with_all(&["percent", "baseline", "diff", "accumulate", "zscore", "rank", "totals", "subtotals", "dropempty", "outputrecode", "suppressbelow", "multiheader"])
+            .help("Writes one record per non-empty cell, holding its row fields, its column fields, and its value, instead of writing a table."))
+        .arg(Arg::with_name("approx")
+            .long("approx")
+            .help("Computes median and mode with a fixed amount of memory per cell, so they can handle columns with millions of distinct values. The results are exact until a cell has seen a few thousand distinct values and approximate after that."))
+        .arg(Arg::with_name("having")
+            .long("having")
+            .takes_value(true)
+            .help("Only shows the rows whose aggregate meets a condition, like 'sum > 1000'. The condition can refer to the aggregate of the row by the name of the function (or as value) and to its number of records as count."))
+        .arg(Arg::with_name("onfiltererror")
+            .long("on-filter-error")
+            .takes_value(true)
+            .possible_values(&["skip", "keep", "error"])
+            .help("What to do with records that a filter can't be evaluated on. Defaults to error."))
+        .arg(Arg::with_name("nan")
+            .long("nan")
+            .takes_value(true)
+            .possible_values(&["skip", "propagate", "error"])
+            .help("What to do with NaN and infinite values (like NaN, inf, or -Infinity) in the values column when values get parsed as numbers. propagate only works for functions that parse values as floating point numbers (max, min, and minmax with -N, and stddev), which return NaN or an infinity. Defaults to error."))
+        .arg(Arg::with_name("skipbadrecords")
+            .long("skip-bad-records")
+            .help("Skips the records that can't be parsed (like records with the wrong number of fields, or values that aren't numbers) instead of stopping with an error, and reports how many were skipped along with the line numbers of the first few."))
+        .arg(Arg::with_name("maxerrors")
+            .long("max-errors")
+            .takes_value(true)
+            .value_name("N")
+            .requires("skipbadrecords")
+            .help("Stops with an error once more than N records have been skipped with --skip-bad-records (since that usually means the input isn't formatted the way you expect, like when it has a different delimiter)."))
+        .arg(Arg::with_name("badrecords")
+            .long("bad-records")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("skipbadrecords")
+            .help("Writes the records skipped with --skip-bad-records to a CSV file (creating or replacing it), with the same fields they were read with and a reason column saying why each one got skipped."))
+        .arg(Arg::with_name("filterregex")
+            .long("filter-regex")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only aggregates the records where a column matches a regular expression, written as column:regex (e.g. 'product:^Widget')."))
+        .arg(Arg::with_name("where")
+            .long("where")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only aggregates the records where a column meets a numeric condition, like 'amount>=1000'."))
+        .arg(Arg::with_name("since")
+            .long("since")
+            .takes_value(true)
+            .requires("datecol")
+            .help("Only aggregates the records where --date-col is on or after this date."))
+        .arg(Arg::with_name("until")
+            .long("until")
+            .takes_value(true)
+            .requires("datecol")
+            .help("Only aggregates the records where --date-col is on or before this date."))
+        .arg(Arg::with_name("datecol")
+            .long("date-col")
+            .takes_value(true)
+            .help("The date column used by --since and --until."))
+        .arg(Arg::with_name("keysep")
+            .long("key-sep")
+            .takes_value(true)
+            .help("The string used to join the values of multiple column fields into a header. Defaults to '_<sep>_'."))
+        .arg(Arg::with_name("rowlabel")
+            .long("row-label")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("The label(s) to give the index column(s) in the output's header row."))
+        .arg(Arg::with_name("headerprefix")
+            .long("header-prefix")
+            .takes_value(true)
+            .help("A prefix to add to each of the generated column headers (e.g. year_ to produce year_2019)."))
+        .arg(Arg::with_name("multiheader")
+            .long("multi-header")
+            .help("Write one header row per column field (and per values column) instead of joining them into a single header."))
         .arg(Arg::with_name("value")
             .long("val")
             .short("v")
             .takes_value(true)
-            .required(true))
+            .help("The name of the field(s) you want to apply the aggregation function to. Multiple fields (e.g. sales,profit) each get their own output columns. Optional for count, which counts records. Use all-other to aggregate every column that isn't a row or column field."))
+        .arg(Arg::with_name("valueexpr")
+            .long("value-expr")
+            .takes_value(true)
+            .conflicts_with("value")
+            .help("Aggregates the result of an expression (like 'price * quantity') computed from each record, instead of a values column."))
         .arg(Arg::with_name("numeric")
             .short("N")
             .help("Parse values as numeric data. This is only necessary for min, max, and minmax, which can parse strings."))
@@ -77,9 +715,16 @@ lazy_static! {
         .arg(Arg::with_name("empty")
             .short("e")
             .help("Ignores empty/null values ('', NULL, NaN, NONE, NA, N/A)"))
+        .arg(Arg::with_name("nullvalues")
+            .long("null-values")
+            .takes_value(true)
+            .help("A comma-separated list of values (like 'NULL,-,missing') to ignore instead of the default empty/null values. Implies -e."))
         .arg(Arg::with_name("noheader")
             .long("no-header")
             .help("Skip the header row of the CSV file."))
+        .arg(Arg::with_name("strictheaders")
+            .long("strict-headers")
+            .help("Require column names to exactly match the header row, instead of also matching headers case-insensitively and ignoring surrounding whitespace."))
         .arg(Arg::with_name("delim")
             .short("d")
             .long("delim")
@@ -104,82 +749,2025 @@ lazy_static! {
             .short("D")
             .long("desc-rows")
             .help("Displays the rows in sorted, descending order (default is index order)."))
-        .get_matches();
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .help("A TOML file setting defaults for options like the delimiter and the date format. Defaults to clipivot/config.toml in $XDG_CONFIG_HOME (or ~/.config)."))
+        .arg(Arg::with_name("noconfig")
+            .long("no-config")
+            .conflicts_with("config")
+            .help("Ignores the default config file."))
+        .arg(Arg::with_name("order")
+            .long("order")
+            .takes_value(true)
+            .possible_values(&["input", "sorted"])
+            .help("Puts both the rows and the columns in the order they first appear in the input, or in sorted (ascending) order. The other sorting options override this for the rows or columns."))
+        .arg(Arg::with_name("sortrows")
+            .long("sort-rows")
+            .takes_value(true)
+            .possible_values(&["asc", "desc", "by-total-asc", "by-total-desc"])
+            .conflicts_with_all(&["ascrow", "descrow"])
+            .help("Sorts the rows in ascending or descending order (comparing their values according to --sort-type), or by the aggregate of each row with by-total-asc and by-total-desc."))
+        .arg(Arg::with_name("sortcols")
+            .long("sort-cols")
+            .takes_value(true)
+            .possible_values(&["asc", "desc", "by-total-asc", "by-total-desc"])
+            .conflicts_with_all(&["indexcol", "desccol"])
+            .help("Sorts the columns in ascending or descending order (comparing their values according to --sort-type), or by the aggregate of each column with by-total-asc and by-total-desc."))
+        .arg(Arg::with_name("roworder")
+            .long("row-order")
+            .takes_value(true)
+            .conflicts_with_all(&["ascrow", "descrow", "sortrows", "roworderfile"])
+            .help("A comma-separated list of values (like 'Mon,Tue,Wed') giving the order of the rows. Rows that aren't in the list appear afterwards."))
+        .arg(Arg::with_name("roworderfile")
+            .long("row-order-file")
+            .takes_value(true)
+            .conflicts_with_all(&["ascrow", "descrow", "sortrows"])
+            .help("Like --row-order, except that it reads the values from a file, one value per line."))
+        .arg(Arg::with_name("colorder")
+            .long("col-order")
+            .takes_value(true)
+            .conflicts_with_all(&["indexcol", "desccol", "sortcols", "colorderfile"])
+            .help("A comma-separated list of values (like 'Mon,Tue,Wed') giving the order of the columns. Columns that aren't in the list appear afterwards."))
+        .arg(Arg::with_name("colorderfile")
+            .long("col-order-file")
+            .takes_value(true)
+            .conflicts_with_all(&["indexcol", "desccol", "sortcols"])
+            .help("Like --col-order, except that it reads the values from a file, one value per line."))
+        .arg(Arg::with_name("sorttype")
+            .long("sort-type")
+            .takes_value(true)
+            .possible_values(&["alphabetical", "numeric", "natural", "chronological"])
+            .help("How to compare values when sorting rows and columns: alphabetically, as numbers, naturally (so item9 comes before item10), or as dates (so Feb-2020 comes before Jan-2021). Defaults to alphabetical."))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .multiple(true)
+            .help("Writes how the input gets read, how many records were read and skipped, and how long each phase took to standard error. Pass it twice for more detail."))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Doesn't write warnings (like ambiguous column names) or other non-fatal messages to standard error, and doesn't draw a progress bar unless --progress always is set. Errors still get written."))
+        .arg(Arg::with_name("progress")
+            .long("progress")
+            .takes_value(true)
+            .possible_values(&["auto", "always", "never"])
+            .help("Whether to draw a progress bar on standard error while reading a file. By default (auto), it's drawn when standard error is a terminal. It never gets drawn for standard input, since its size isn't known."))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .help("Writes a summary of the run to standard error: the numbers of records read and skipped, row keys, column keys, and populated cells, along with the wall time and peak memory usage."))
+        .arg(Arg::with_name("chi2")
+            .long("chi2")
+            .help("Writes a chi-square test of whether the row and column fields of a table of counts are related to standard error: the chi-square statistic, its degrees of freedom and p-value, and Cramér's V."))
+        .arg(Arg::with_name("bench")
+            .long("bench")
+            .hidden(true)
+            .help("Runs the pivot table several times (for `clipivot bench`), writing the time and throughput of each run to standard error instead of writing the table."))
+        .arg(Arg::with_name("validate")
+            .long("validate")
+            .hidden(true)
+            .help("Reads the input the way the pivot table would (for `clipivot validate`), writing the records that can't be aggregated and the type of each column instead of writing the table."))
+        .arg(Arg::with_name("runs")
+            .long("runs")
+            .takes_value(true)
+            .help("The number of times `clipivot bench` runs the pivot table. [default: 3]"))
+        .arg(Arg::with_name("synthetic")
+            .long("synthetic")
+            .takes_value(true)
+            .conflicts_with("filename")
+            .help("Has `clipivot bench` aggregate N generated records (with the columns id, region, product, date, and amount) instead of reading a file."))
+        .arg(Arg::with_name("dryrun")
+            .long("dry-run")
+            .help("Shows how the input would get read and which fields were selected, along with the first few records, without aggregating anything."))
+        .arg(Arg::with_name("tosql")
+            .long("to-sql")
+            .takes_value(true)
+            .possible_values(&["postgres", "sqlite", "mysql"])
+            .conflicts_with("dryrun")
+            .help("Writes an SQL query (for PostgreSQL, SQLite, or MySQL) that computes the pivot table from a table with the same columns as the input, instead of computing it. The columns of the pivot table still get read from the input."))
+        .arg(Arg::with_name("sqltable")
+            .long("sql-table")
+            .takes_value(true)
+            .requires("tosql")
+            .help("The name of the table the --to-sql query reads from. Defaults to the name of the input file without its extension (or `input` for standard input)."))
+}
+
+/// The arguments for reading a file, shared by the subcommands that don't create a pivot table
+fn reader_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("filename")
+            .index(1)
+            .help("The path to the file (or standard input, if it's left out)"),
+        Arg::with_name("delim")
+            .short("d")
+            .long("delim")
+            .takes_value(true)
+            .help("The delimiter used to separate fields. Defaults to ','."),
+        Arg::with_name("tab")
+            .short("t")
+            .conflicts_with("delim")
+            .help("Set the delimiter of the file to a tab."),
+        Arg::with_name("noheader")
+            .long("no-header")
+            .help("The file doesn't have a header row."),
+    ]
+}
+
+/// Builds the definition of `clipivot melt`
+fn build_melt_app() -> App<'static, 'static> {
+    App::new("clipivot melt")
+        .about("Melts the columns of a wide table into a long one, writing one record per melted column holding the ID columns, the name of the column, and its value.")
+        .args(&reader_args())
+        .arg(errors_arg())
+        .arg(Arg::with_name("id")
+            .long("id")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("The columns that are kept as they are. Accepts the same names, indexes, and ranges as --rows."))
+        .arg(Arg::with_name("vars")
+            .long("vars")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("The columns to melt. Defaults to every column that isn't an ID column."))
+        .arg(Arg::with_name("varname")
+            .long("var-name")
+            .takes_value(true)
+            .help("The name of the column holding the names of the melted columns. Defaults to 'variable'."))
+        .arg(Arg::with_name("valuename")
+            .long("value-name")
+            .takes_value(true)
+            .help("The name of the column holding the values of the melted columns. Defaults to 'value'."))
+        .arg(Arg::with_name("empty")
+            .short("e")
+            .long("skip-empty")
+            .help("Leaves out the empty/null values ('', NULL, NaN, NONE, NA, N/A), like the cells a pivot table writes for the groups without any records."))
+}
+
+/// Builds the definition of `clipivot describe`
+fn build_describe_app() -> App<'static, 'static> {
+    App::new("clipivot describe")
+        .about("Summarizes each column of a file: its type (number, date, or text), the number of non-empty and empty values, the number of unique values, its minimum and maximum, and its mean (for numbers).")
+        .args(&reader_args())
+        .arg(errors_arg())
 }
 
-fn run_and_init<T, I, O>(
+/// Turns `pivot ...` into the legacy invocation (`clipivot <aggfunc> ...`), and `agg ...` into
+/// the legacy invocation with `--sparse`, which writes one record per group of row and column fields
+fn expand_subcommand(mut args: Vec<String>) -> Vec<String> {
+    match args.get(1).map(String::as_str) {
+        Some(PIVOT_COMMAND) => {
+            args.remove(1);
+        }
+        Some(AGG_COMMAND) => {
+            args.remove(1);
+            args.push("--sparse".to_string());
+        }
+        Some(BENCH_COMMAND) => {
+            args.remove(1);
+            args.push("--bench".to_string());
+        }
+        Some(VALIDATE_COMMAND) => {
+            args.remove(1);
+            args.push("--validate".to_string());
+            if !args.iter().any(|arg| arg == "--skip-bad-records") {
+                args.push("--skip-bad-records".to_string());
+            }
+        }
+        _ => {}
+    }
+    args
+}
+
+/// Parses the command-line arguments, filling in the defaults from the configuration file
+/// (from `--config`, or the default location) for any options that weren't passed
+fn get_matches_with_config() -> ArgMatches<'static> {
+    let get_matches = || -> CsvCliResult<ArgMatches<'static>> {
+        let mut args = expand_subcommand(expand_preset(env::args().collect())?);
+        if let Some(aggfunc) = prompt_for_missing_aggfunc(&args)? {
+            args.insert(1, aggfunc);
+        }
+        let mut matches = parse_args(&args);
+        // options on the command line override environment variables,
+        // which override the configuration file
+        for get_defaults in &[get_env_args, get_config_args] {
+            let defaults = get_defaults(&matches)?;
+            if !defaults.is_empty() {
+                args = args[..1]
+                    .iter()
+                    .cloned()
+                    .chain(defaults)
+                    .chain(args[1..].iter().cloned())
+                    .collect();
+                matches = parse_args(&args);
+            }
+        }
+        Ok(matches)
+    };
+    get_matches().unwrap_or_else(|err| exit_with_error(&err))
+}
+
+/// Whether errors should be written as JSON objects (with `--errors json`). This looks at the
+/// raw arguments rather than the parsed ones, so it also covers errors in parsing them.
+pub fn json_errors(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--errors=json")
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "--errors" && pair[1] == "json")
+}
+
+/// Writes an error to standard error (as the JSON object from `CsvCliError::to_json`, with
+/// `--errors json`) and exits with the error's exit code
+pub fn exit_with_error(err: &CsvCliError) -> ! {
+    if json_errors(&env::args().collect::<Vec<_>>()) {
+        eprintln!("{}", err.to_json());
+    } else {
+        eprintln!("{}", err);
+    }
+    process::exit(err.exit_code())
+}
+
+/// The `--errors` option, which every command accepts
+fn errors_arg() -> Arg<'static, 'static> {
+    Arg::with_name("errors")
+        .long("errors")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .help("How errors get written to standard error: as text (the default) or as a JSON object with the class of the error, its message, its exit code, and the record number, value, column, line, and byte offset of the record that caused it (which are null when they don't apply).")
+}
+
+/// Writes the documentation of every aggregation function (for `--list-aggregations`)
+fn write_aggregations<W: io::Write>(wtr: &mut W) -> CsvCliResult<()> {
+    for agg in AGGREGATIONS.iter() {
+        writeln!(wtr, "{}", agg.name)?;
+        writeln!(wtr, "    {}", agg.description)?;
+        writeln!(wtr, "    Values: {}", agg.values)?;
+        writeln!(wtr, "    Example: {}", agg.example)?;
+    }
+    Ok(())
+}
+
+/// Writes a man page (in roff) to standard output, generated from the definition of the
+/// command-line interface, so `clipivot man > clipivot.1` can be shipped with a package
+pub fn write_man_page() -> CsvCliResult<()> {
+    let mut page = Vec::new();
+    write_man_page_to(&mut page)?;
+    io::stdout().write_all(&page)?;
+    Ok(())
+}
+
+fn write_man_page_to<W: io::Write>(wtr: &mut W) -> CsvCliResult<()> {
+    let app = build_app();
+    let about = app.p.meta.about.unwrap_or_default();
+    let mut about_lines = about.lines().map(str::trim);
+    writeln!(
+        wtr,
+        ".TH CLIPIVOT 1 \"\" \"clipivot {}\" \"User Commands\"",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(wtr, ".SH NAME")?;
+    writeln!(
+        wtr,
+        "clipivot \\- {}",
+        roff_escape(about_lines.next().unwrap_or_default())
+    )?;
+    writeln!(wtr, ".SH SYNOPSIS")?;
+    writeln!(
+        wtr,
+        "\\fBclipivot\\fR [\\fIOPTIONS\\fR] \\fIaggfunc\\fR [\\fIfilename\\fR]"
+    )?;
+    writeln!(wtr, ".br")?;
+    writeln!(wtr, "\\fBclipivot\\fR \\fIsubcommand\\fR ...")?;
+    writeln!(wtr, ".SH DESCRIPTION")?;
+    for line in about_lines {
+        writeln!(wtr, "{}", roff_escape(line))?;
+    }
+    let options = list_options(&app);
+    writeln!(wtr, ".SH ARGUMENTS")?;
+    for option in options.iter().filter(|option| option.positional) {
+        writeln!(wtr, ".TP")?;
+        writeln!(wtr, "\\fI{}\\fR", option.name)?;
+        write_man_help(wtr, option)?;
+    }
+    writeln!(wtr, ".SH OPTIONS")?;
+    for option in options.iter().filter(|option| !option.positional) {
+        let flags: Vec<String> = option
+            .short
+            .iter()
+            .chain(option.long.iter())
+            .map(|flag| format!("\\fB{}\\fR", roff_escape(flag)))
+            .collect();
+        writeln!(wtr, ".TP")?;
+        if option.takes_value {
+            writeln!(wtr, "{} \\fI{}\\fR", flags.join(", "), option.name)?;
+        } else {
+            writeln!(wtr, "{}", flags.join(", "))?;
+        }
+        write_man_help(wtr, option)?;
+    }
+    writeln!(wtr, ".SH SUBCOMMANDS")?;
+    for line in SUBCOMMANDS_HELP.lines().skip(1) {
+        // each line holds the usage of a subcommand, then (after a gap) its description
+        let line = line.trim();
+        if let Some(gap) = line.find("  ") {
+            writeln!(wtr, ".TP")?;
+            writeln!(wtr, "\\fB{}\\fR", roff_escape(&line[..gap]))?;
+            writeln!(wtr, "{}", roff_escape(line[gap..].trim()))?;
+        }
+    }
+    writeln!(wtr, ".SH EXIT STATUS")?;
+    for (code, description) in &[
+        (0, "The pivot table was created."),
+        (
+            CONFIG_EXIT_CODE,
+            "The configuration (including the command-line arguments) was invalid.",
+        ),
+        (PARSING_EXIT_CODE, "A value couldn't be parsed."),
+        (IO_EXIT_CODE, "A file couldn't be read or written."),
+        (CSV_EXIT_CODE, "The input wasn't a valid CSV file."),
+    ] {
+        writeln!(wtr, ".TP")?;
+        writeln!(wtr, "{}", code)?;
+        writeln!(wtr, "{}", description)?;
+    }
+    writeln!(wtr, ".SH SEE ALSO")?;
+    writeln!(wtr, "https://www.github.com/maxblee/clipivot")?;
+    Ok(())
+}
+
+/// Writes the help message of an argument (and its possible values) for the man page
+fn write_man_help<W: io::Write>(wtr: &mut W, option: &OptionInfo) -> io::Result<()> {
+    let lines: Vec<String> = option
+        .help
+        .lines()
+        .map(|line| roff_escape(line.trim()))
+        .collect();
+    writeln!(wtr, "{}", lines.join("\n.br\n"))?;
+    if !option.possible_values.is_empty() {
+        writeln!(wtr, ".br")?;
+        writeln!(
+            wtr,
+            "Possible values: {}",
+            roff_escape(&option.possible_values.join(", "))
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes text for roff, so that backslashes and hyphens print as themselves
+/// and lines starting with `.` or `'` don't get treated as requests
+fn roff_escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{}", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// An argument of the command-line interface, for documenting it outside of `--help`
+struct OptionInfo {
+    name: &'static str,
+    positional: bool,
+    /// The long flag, like `--rows`
+    long: Option<String>,
+    /// The short flag, like `-r`
+    short: Option<String>,
+    takes_value: bool,
+    possible_values: Vec<&'static str>,
+    help: &'static str,
+}
+
+/// Lists the arguments of an app: the positional arguments, then the flags, then the options
+/// that take values
+fn list_options(app: &App<'static, 'static>) -> Vec<OptionInfo> {
+    // clap 2 doesn't have a public way of listing the arguments of an app, so this reads the
+    // (hidden, but public) fields its completion scripts get generated from
+    let positionals = app.p.positionals.values().map(|pos| OptionInfo {
+        name: pos.b.name,
+        positional: true,
+        long: None,
+        short: None,
+        takes_value: true,
+        possible_values: pos.v.possible_vals.clone().unwrap_or_default(),
+        help: pos.b.help.unwrap_or_default(),
+    });
+    let flags = app
+        .p
+        .flags
+        .iter()
+        .map(|flag| (&flag.b, &flag.s, false, None));
+    let opts = app
+        .p
+        .opts
+        .iter()
+        .map(|opt| (&opt.b, &opt.s, true, opt.v.possible_vals.clone()));
+    let switches =
+        flags.chain(opts).map(
+            |(base, switched, takes_value, possible_values)| OptionInfo {
+                name: base.name,
+                positional: false,
+                long: switched.long.map(|long| format!("--{}", long)),
+                short: switched.short.map(|short| format!("-{}", short)),
+                takes_value,
+                possible_values: possible_values.unwrap_or_default(),
+                help: base.help.unwrap_or_default(),
+            },
+        );
+    positionals.chain(switches).collect()
+}
+
+/// Writes the aggregation functions, formats, subcommands, and options of this version of
+/// `clipivot` as JSON (for `--capabilities json`). The options come straight from the
+/// definition of the command-line interface, so they're always up to date.
+fn write_capabilities<W: io::Write>(wtr: &mut W) -> CsvCliResult<()> {
+    let list = |items: &[&str]| {
+        let items: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+        format!("[{}]", items.join(", "))
+    };
+    let aggregations: Vec<String> = AGGREGATIONS
+        .iter()
+        .map(|agg| {
+            format!(
+                "    {{\"name\": {}, \"description\": {}, \"values\": {}, \"example\": {}}}",
+                json_string(agg.name),
+                json_string(agg.description),
+                json_string(agg.values),
+                json_string(agg.example)
+            )
+        })
+        .collect();
+    let options: Vec<String> = list_options(&build_app())
+        .iter()
+        .map(|option| {
+            let json_or_null = |value: &Option<String>| {
+                value.as_deref().map_or("null".to_string(), json_string)
+            };
+            format!(
+                "    {{\"name\": {}, \"positional\": {}, \"long\": {}, \"short\": {}, \"takes_value\": {}, \"possible_values\": {}, \"help\": {}}}",
+                json_string(option.name),
+                option.positional,
+                json_or_null(&option.long),
+                json_or_null(&option.short),
+                option.takes_value,
+                list(&option.possible_values),
+                json_string(option.help)
+            )
+        })
+        .collect();
+    writeln!(wtr, "{{")?;
+    writeln!(wtr, "  \"name\": \"clipivot\",")?;
+    writeln!(
+        wtr,
+        "  \"version\": {},",
+        json_string(env!("CARGO_PKG_VERSION"))
+    )?;
+    writeln!(
+        wtr,
+        "  \"aggregations\": [\n{}\n  ],",
+        aggregations.join(",\n")
+    )?;
+    writeln!(wtr, "  \"input_formats\": {},", list(&["csv", "tsv"]))?;
+    writeln!(wtr, "  \"output_formats\": {},", list(&["csv"]))?;
+    writeln!(
+        wtr,
+        "  \"date_formats\": {},",
+        list(&DEFAULT_KEY_DATE_FORMATS)
+    )?;
+    writeln!(
+        wtr,
+        "  \"subcommands\": {},",
+        list(&[
+            PIVOT_COMMAND,
+            AGG_COMMAND,
+            MELT_COMMAND,
+            DESCRIBE_COMMAND,
+            BENCH_COMMAND,
+            VALIDATE_COMMAND,
+            COMPLETIONS_COMMAND,
+            INTERACTIVE_COMMAND,
+            RUN_COMMAND,
+            SAVE_PRESET_COMMAND,
+        ])
+    )?;
+    writeln!(wtr, "  \"options\": [\n{}\n  ]", options.join(",\n"))?;
+    writeln!(wtr, "}}")?;
+    Ok(())
+}
+
+/// Parses the command-line arguments, exiting with a usage error if they're invalid.
+/// An unknown aggregation function gets reported along with the closest valid name.
+fn parse_args(args: &[String]) -> ArgMatches<'static> {
+    match build_app().get_matches_from_safe(args) {
+        Ok(matches) => matches,
+        Err(err) => match (&err.kind, &err.info) {
+            (ErrorKind::InvalidValue, Some(info)) if info.len() == 2 && info[0] == "aggfunc" => {
+                if json_errors(args) {
+                    exit_with_error(&CsvCliError::InvalidConfiguration(unknown_aggfunc_message(
+                        &info[1],
+                    )));
+                }
+                eprintln!("error: {}", unknown_aggfunc_message(&info[1]));
+                process::exit(CONFIG_EXIT_CODE);
+            }
+            _ => exit_with_usage_error(err),
+        },
+    }
+}
+
+/// Exits after a command-line argument couldn't be parsed (or after printing the help
+/// message, which clap also treats as an error)
+fn exit_with_usage_error(err: clap::Error) -> ! {
+    if err.use_stderr() && json_errors(&env::args().collect::<Vec<_>>()) {
+        // the first line of the message is the error, and the rest is the usage
+        let message = err.message.lines().next().unwrap_or_default();
+        let message = message.strip_prefix("error: ").unwrap_or(message);
+        exit_with_error(&CsvCliError::InvalidConfiguration(message.to_string()));
+    }
+    if err.use_stderr() {
+        eprintln!("{}", err.message);
+        process::exit(CONFIG_EXIT_CODE);
+    }
+    err.exit()
+}
+
+/// The message for an aggregation function that doesn't exist, suggesting the closest
+/// function (if there's one that's close enough to be a likely typo)
+fn unknown_aggfunc_message(aggfunc: &str) -> String {
+    let closest = ALLOWED_AGGFUNCS
+        .iter()
+        .map(|name| (edit_distance(&aggfunc.to_lowercase(), name), *name))
+        .min();
+    match closest {
+        Some((distance, name)) if distance <= 2 && distance < aggfunc.len() => format!(
+            "unknown aggregation `{}`; did you mean `{}`?",
+            aggfunc, name
+        ),
+        _ => format!(
+            "unknown aggregation `{}`; it needs to be one of {}",
+            aggfunc,
+            ALLOWED_AGGFUNCS.join(", ")
+        ),
+    }
+}
+
+/// The (Levenshtein) edit distance between two strings: the number of characters you'd need
+/// to insert, delete, or replace to turn one into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let replace = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Asks for the aggregation function when the command starts with the name of a file instead
+/// (as in `clipivot sales.csv -r region`), as long as the user can answer
+fn prompt_for_missing_aggfunc(args: &[String]) -> CsvCliResult<Option<String>> {
+    match args.get(1) {
+        Some(first)
+            if !ALLOWED_AGGFUNCS.contains(&first.as_str())
+                && Path::new(first).is_file()
+                && interactive::can_prompt() =>
+        {
+            interactive::prompt_for_aggfunc(first).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Replaces `run <preset>` with the arguments saved in the preset, keeping any arguments
+/// that come after the name of the preset (which can override the ones in the preset)
+fn expand_preset(args: Vec<String>) -> CsvCliResult<Vec<String>> {
+    if args.get(1).map(String::as_str) != Some(RUN_COMMAND) {
+        return Ok(args);
+    }
+    let name = args.get(2).ok_or_else(|| {
+        CsvCliError::InvalidConfiguration(format!(
+            "`{}` needs the name of a preset, like `clipivot {} monthly-sales`",
+            RUN_COMMAND, RUN_COMMAND
+        ))
+    })?;
+    let extra_args = &args[3..];
+    let path = config_path(extra_args).ok_or_else(|| {
+        CsvCliError::InvalidConfiguration(
+            "Could not find a config file to read presets from".into(),
+        )
+    })?;
+    let preset = Config::from_path(&path)?.preset(name)?.ok_or_else(|| {
+        CsvCliError::InvalidConfiguration(format!(
+            "There is no preset named `{}` in the config file `{}`",
+            name,
+            path.display()
+        ))
+    })?;
+    Ok(args[..1]
+        .iter()
+        .cloned()
+        .chain(preset)
+        .chain(extra_args.iter().cloned())
+        .collect())
+}
+
+/// The configuration file that presets get read from or saved to: the one passed with
+/// `--config` (if there is one) or the default one
+fn config_path(args: &[String]) -> Option<PathBuf> {
+    let passed = args.iter().enumerate().find_map(|(idx, arg)| {
+        if arg == "--config" {
+            args.get(idx + 1).cloned()
+        } else {
+            arg.strip_prefix("--config=").map(String::from)
+        }
+    });
+    passed.map(PathBuf::from).or_else(Config::default_path)
+}
+
+/// Saves the arguments to `save-preset <name>` as a preset in the configuration file.
+/// (A `--config FILE` among the arguments picks the file the preset gets saved to,
+/// instead of becoming part of the preset.)
+pub fn save_preset(args: &[String]) -> CsvCliResult<PathBuf> {
+    let (name, preset_args) = match args.split_first() {
+        Some((name, preset_args)) if !preset_args.is_empty() => (name, preset_args),
+        _ => {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` needs the name of a preset and the arguments to save, like \
+                 `clipivot {} monthly-sales sum sales.csv -r region -v amount`",
+                SAVE_PRESET_COMMAND, SAVE_PRESET_COMMAND
+            )))
+        }
+    };
+    let path = config_path(preset_args).ok_or_else(|| {
+        CsvCliError::InvalidConfiguration("Could not find a place to save the config file".into())
+    })?;
+    let mut saved_args = Vec::new();
+    let mut remaining = preset_args.iter();
+    while let Some(arg) = remaining.next() {
+        match arg.as_str() {
+            "--config" => {
+                remaining.next();
+            }
+            _ if arg.starts_with("--config=") => {}
+            _ => saved_args.push(arg.clone()),
+        }
+    }
+    // checks that the preset can be run before saving it
+    let full_args = iter::once("clipivot".to_string()).chain(saved_args.iter().cloned());
+    build_app()
+        .get_matches_from_safe(full_args)
+        .map_err(|err| CsvCliError::InvalidConfiguration(err.message))?;
+    config::save_preset(&path, name, &saved_args)?;
+    Ok(path)
+}
+
+/// Converts the environment variables that set options (like `CSVPIVOT_DELIMITER`) into
+/// command-line arguments, skipping any options that were passed on the command line
+fn get_env_args(matches: &ArgMatches) -> CsvCliResult<Vec<String>> {
+    let mut args = Vec::new();
+    for (_, env_var, flag, takes_value, overrides) in CONFIG_OPTIONS.iter() {
+        let value = match env::var(env_var) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => continue,
+            Err(env::VarError::NotUnicode(_)) => {
+                return Err(CsvCliError::InvalidConfiguration(format!(
+                    "The environment variable `{}` needs to be valid UTF-8",
+                    env_var
+                )))
+            }
+        };
+        if overrides.iter().any(|name| matches.is_present(name)) {
+            continue;
+        }
+        match (value.to_ascii_lowercase().as_str(), *takes_value) {
+            (_, true) => {
+                args.push(flag.to_string());
+                args.push(value);
+            }
+            ("1", false) | ("true", false) | ("yes", false) => args.push(flag.to_string()),
+            ("", false) | ("0", false) | ("false", false) | ("no", false) => {}
+            _ => {
+                return Err(CsvCliError::InvalidConfiguration(format!(
+                    "The environment variable `{}` needs to be true or false",
+                    env_var
+                )))
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Reads the configuration file and converts its settings into command-line arguments
+fn get_config_args(matches: &ArgMatches) -> CsvCliResult<Vec<String>> {
+    if matches.is_present("noconfig") {
+        return Ok(vec![]);
+    }
+    let config = match (matches.value_of("config"), Config::default_path()) {
+        (Some(path), _) => Config::from_path(path)?,
+        (None, Some(path)) if path.exists() => Config::from_path(path)?,
+        (None, _) => return Ok(vec![]),
+    };
+    let mut args = Vec::new();
+    for (key, value) in config.top_level() {
+        let (_, _, flag, takes_value, overrides) = CONFIG_OPTIONS
+            .iter()
+            .find(|(name, _, _, _, _)| *name == key)
+            .ok_or_else(|| {
+                CsvCliError::InvalidConfiguration(format!(
+                    "`{}` is not a setting you can put in a config file. Expected one of {}",
+                    key,
+                    CONFIG_OPTIONS
+                        .iter()
+                        .map(|(name, _, _, _, _)| *name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ))
+            })?;
+        if overrides.iter().any(|name| matches.is_present(name)) {
+            continue;
+        }
+        match (value, *takes_value) {
+            (ConfigValue::Bool(true), false) => args.push(flag.to_string()),
+            (ConfigValue::Bool(false), false) => {}
+            (value, true) if value.as_arg().is_some() => {
+                args.push(flag.to_string());
+                args.extend(value.as_arg());
+            }
+            _ => {
+                let expected = if *takes_value {
+                    "a string"
+                } else {
+                    "true or false"
+                };
+                return Err(CsvCliError::InvalidConfiguration(format!(
+                    "The setting `{}` in the config file needs to be {}",
+                    key, expected
+                )));
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Writes a completion script for `shell` (one of bash, zsh, fish, or powershell)
+/// to standard output, generated from the definition of the command-line interface
+pub fn write_completions(shell: &str) -> CsvCliResult<()> {
+    let shell: Shell = shell.parse().map_err(|_| {
+        CsvCliError::InvalidConfiguration(format!(
+            "`{}` needs to be one of {}",
+            shell,
+            Shell::variants().join(", ")
+        ))
+    })?;
+    let mut script = Vec::new();
+    build_app().gen_completions_to("clipivot", shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    // only the zsh script completes the values of the positional arguments,
+    // so the names of the functions get added to the other scripts by hand
+    let mut commands: Vec<&str> = ALLOWED_AGGFUNCS.to_vec();
+    commands.extend(&[
+        PIVOT_COMMAND,
+        AGG_COMMAND,
+        MELT_COMMAND,
+        DESCRIBE_COMMAND,
+        VALIDATE_COMMAND,
+        COMPLETIONS_COMMAND,
+        INTERACTIVE_COMMAND,
+        RUN_COMMAND,
+        SAVE_PRESET_COMMAND,
+    ]);
+    let script = match shell {
+        Shell::Bash => script.replacen("<aggfunc>", &commands.join(" "), 1),
+        Shell::Fish => format!(
+            "{}complete -c clipivot -n \"__fish_use_subcommand\" -f -a \"{}\"\n",
+            script,
+            commands.join(" ")
+        ),
+        Shell::PowerShell => {
+            let quoted: Vec<String> = commands.iter().map(|cmd| format!("'{}', ", cmd)).collect();
+            // the first list that isn't empty holds the completions of the top-level command
+            script.replacen(
+                "$completions = @('",
+                &format!("$completions = @({}'", quoted.concat()),
+                1,
+            )
+        }
+        _ => script.into_owned(),
+    };
+    print!("{}", script);
+    Ok(())
+}
+
+/// Runs the pivot table over and over (for `clipivot bench`), writing the timing of each run
+/// and a summary of the runs to standard error. Standard input and synthetic data can only be
+/// generated (or read) once, so they're held in memory, while files get read again on each run.
+fn run_benchmark<T, I, O>(
     arg_matches: &ArgMatches,
-    parsing_strategy: ParsingStrategy,
+    pivot: &Pivot,
+    parser: ValueParser<I>,
 ) -> CsvCliResult<()>
 where
-    T: Accumulate<I, O>,
-    I: std::str::FromStr,
-    O: std::fmt::Display,
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
 {
-    // fn run_and_init<T: Accumulate<I,O>, I: std::str::FromStr, O: std::fmt::Display>(arg_matches: &ArgMatches) -> CsvCliResult<()> {
+    let settings = pivot.settings();
+    let runs = match arg_matches.value_of("runs") {
+        Some(runs) => parse_count_arg(runs, "--runs")?.max(1),
+        None => bench::DEFAULT_RUNS,
+    };
     let filename = arg_matches.value_of("filename");
-    let delim_values = if arg_matches.is_present("tab") {
-        Some(r"\t")
-    } else {
-        arg_matches.value_of("delim")
+    let input = match (arg_matches.value_of("synthetic"), filename) {
+        (Some(num), _) => Some(bench::synthetic_data(parse_count_arg(num, "--synthetic")?)),
+        (None, Some(_)) => None,
+        (None, None) => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Some(data)
+        }
     };
-    let settings =
-        CsvSettings::parse_new(&filename, delim_values, !arg_matches.is_present("noheader"))?;
-    if let Some(filepath) = filename {
-        let rdr = settings.get_reader_from_path(filepath)?;
-        agg_from_reader::<T, I, O, std::fs::File>(arg_matches, &settings, parsing_strategy, rdr)?;
-    } else {
-        let rdr = settings.get_reader_from_stdin();
-        agg_from_reader::<T, I, O, io::Stdin>(arg_matches, &settings, parsing_strategy, rdr)?;
+    let mut timings = Vec::new();
+    for run in 1..=runs {
+        let timing = match (&input, filename) {
+            (Some(data), _) => {
+                time_run::<T, I, O, &[u8]>(pivot, settings.get_reader(&data[..]), parser.clone())?
+            }
+            (None, Some(filepath)) => time_run::<T, I, O, fs::File>(
+                pivot,
+                settings.get_reader_from_path(filepath)?,
+                parser.clone(),
+            )?,
+            (None, None) => unreachable!("standard input gets read into memory"),
+        };
+        eprintln!("Run {}: {}", run, timing);
+        timings.push(timing);
     }
+    eprintln!("{}", bench::summarize(&timings));
     Ok(())
 }
 
-fn agg_from_reader<T, I, O, R>(
-    arg_matches: &ArgMatches,
-    settings: &CsvSettings,
-    parsing_strategy: ParsingStrategy,
+/// Runs the pivot table once, writing the table to nowhere, and times each phase of the run
+fn time_run<T, I, O, R>(
+    pivot: &Pivot,
     mut reader: csv::Reader<R>,
-) -> CsvCliResult<()>
+    parser: ValueParser<I>,
+) -> CsvCliResult<RunTiming>
 where
-    T: Accumulate<I, O>,
-    I: std::str::FromStr,
-    O: std::fmt::Display,
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
     R: io::Read,
 {
+    let start = Instant::now();
     let headers = reader.headers()?;
-    let mut agg = get_aggregator::<T, I, O>(
-        arg_matches,
-        &settings,
-        parsing_strategy,
-        &headers.iter().collect(),
-    )?;
+    let mut agg =
+        pivot.aggregator::<T, I, O>(pivot.settings(), &headers.iter().collect(), parser)?;
+    let configured = Instant::now();
     agg.aggregate(&mut reader)?;
-    agg.write_results(&mut csv::Writer::from_writer(io::stdout()))?;
-    Ok(())
+    let aggregated = Instant::now();
+    agg.write_results(&mut csv::Writer::from_writer(io::sink()))?;
+    Ok(RunTiming {
+        bytes: reader.position().byte(),
+        records: agg.record_counts().read,
+        configure: configured - start,
+        aggregate: aggregated - configured,
+        write: aggregated.elapsed(),
+    })
 }
 
-fn get_aggregator<T, I, O>(
+/// Reads the input the way the pivot table would (for `clipivot validate`), without writing the
+/// table, and writes a report to standard output: the number of records, the first few records
+/// that couldn't be aggregated (with the reason for each), and the type of each column. Exits
+/// with the exit code for parsing errors if any record couldn't be aggregated. Standard input
+/// gets held in memory, since it gets read a second time to find the types of the columns.
+fn validate<T, I, O>(
     arg_matches: &ArgMatches,
-    settings: &CsvSettings,
-    parsing_strategy: ParsingStrategy,
-    headers: &Vec<&str>,
-) -> CsvCliResult<Aggregator<T, I, O>>
+    pivot: &Pivot,
+    parser: ValueParser<I>,
+) -> CsvCliResult<()>
 where
-    T: Accumulate<I, O>,
-    I: std::str::FromStr,
-    O: std::fmt::Display,
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
 {
-    let str_indexes = arg_matches
-        .values_of("rows")
-        .map_or(vec![], |v| v.collect());
-    let index_cols = settings.get_field_indexes(&str_indexes, headers)?;
-    let str_cols = arg_matches
-        .values_of("columns")
-        .map_or(vec![], |v| v.collect());
-    let column_cols = settings.get_field_indexes(&str_cols, headers)?;
-    let values_col = settings.get_field_index(arg_matches.value_of("value").unwrap(), headers)?;
-    let skip_null = arg_matches.is_present("empty");
+    let settings = pivot.settings();
+    let filename = arg_matches.value_of("filename");
+    let input = match filename {
+        Some(_) => None,
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Some(data)
+        }
+    };
+    let (agg, summaries) = match (&input, filename) {
+        (Some(data), _) => (
+            check_records::<T, I, O, &[u8]>(pivot, settings.get_reader(&data[..]), parser)?,
+            describe::describe_skipping_bad_records(&mut settings.get_reader(&data[..]))?,
+        ),
+        (None, Some(filepath)) => (
+            check_records::<T, I, O, fs::File>(
+                pivot,
+                settings.get_reader_from_path(filepath)?,
+                parser,
+            )?,
+            describe::describe_skipping_bad_records(&mut settings.get_reader_from_path(filepath)?)?,
+        ),
+        (None, None) => unreachable!("standard input gets read into memory"),
+    };
+    let counts = agg.record_counts();
+    let bad_records = agg.bad_records();
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    let mut wtr = io::BufWriter::new(io::stdout());
+    writeln!(wtr, "Read {} record{}", counts.read, plural(counts.read))?;
+    if counts.bad_records == 0 {
+        writeln!(wtr, "Every record can be aggregated")?;
+    } else {
+        writeln!(
+            wtr,
+            "{} record{} can't be aggregated:",
+            counts.bad_records,
+            plural(counts.bad_records)
+        )?;
+        for (line, err) in &bad_records {
+            writeln!(wtr, "  line {}: {}", line, err)?;
+        }
+        if counts.bad_records > bad_records.len() {
+            writeln!(
+                wtr,
+                "  (and {} more)",
+                counts.bad_records - bad_records.len()
+            )?;
+        }
+    }
+    writeln!(wtr, "Column types:")?;
+    for summary in &summaries {
+        writeln!(wtr, "  {}: {}", summary.name, summary.column_type().name())?;
+    }
+    wtr.flush()?;
+    if counts.bad_records > 0 {
+        process::exit(PARSING_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// Aggregates the records of the input (for `clipivot validate`), skipping the ones that can't
+/// be aggregated, and writes the table to nowhere so that the cells get computed
+fn check_records<T, I, O, R>(
+    pivot: &Pivot,
+    mut reader: csv::Reader<R>,
+    parser: ValueParser<I>,
+) -> CsvCliResult<Aggregator<T, I, O>>
+where
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
+    R: io::Read,
+{
+    let headers = reader.headers()?;
+    let mut agg = pivot
+        .aggregator::<T, I, O>(pivot.settings(), &headers.iter().collect(), parser)?
+        .with_bad_records_kept(VALIDATE_REPORT_LIMIT);
+    agg.aggregate(&mut reader)?;
+    agg.write_results(&mut csv::Writer::from_writer(io::sink()))?;
+    Ok(agg)
+}
+
+/// Checks the arguments for combinations that are probably mistakes (but aren't errors),
+/// returning a warning for each one
+fn argument_warnings(arg_matches: &ArgMatches) -> Vec<String> {
+    // the functions whose cells can be added up into a meaningful total
+    let summable = ["count", "sum"];
+    let aggfunc = arg_matches.value_of("aggfunc").unwrap_or_default();
+    let mut warnings = Vec::new();
+    for (arg, flag) in &[("percent", "--percent"), ("accumulate", "--accumulate")] {
+        if arg_matches.is_present(arg) && !summable.contains(&aggfunc) {
+            warnings.push(format!(
+                "{} adds up the cells of the table, which usually isn't meaningful for `{}` (as opposed to count or sum)",
+                flag, aggfunc
+            ));
+        }
+    }
+    if !arg_matches.is_present("bench") {
+        for (arg, flag) in &[("runs", "--runs"), ("synthetic", "--synthetic")] {
+            if arg_matches.is_present(arg) {
+                warnings.push(format!("{} only applies to `clipivot bench`", flag));
+            }
+        }
+    }
+    if arg_matches.is_present("seed")
+        && !arg_matches.is_present("sample")
+        && !arg_matches.is_present("samplen")
+    {
+        warnings.push("--seed only applies to --sample and --sample-n".to_string());
+    }
+    if arg_matches.is_present("approx") && !["median", "mode"].contains(&aggfunc) {
+        warnings.push(format!(
+            "--approx only changes median and mode, so `{}` is computed exactly",
+            aggfunc
+        ));
+    }
+    warnings
+}
+
+/// Gets the settings for reading the input from the filename, delimiter, and header arguments
+fn get_reader_settings(arg_matches: &ArgMatches) -> CsvCliResult<CsvSettings> {
+    let delim_values = if arg_matches.is_present("tab") {
+        Some(r"\t")
+    } else {
+        arg_matches.value_of("delim")
+    };
+    CsvSettings::parse_new(
+        &arg_matches.value_of("filename"),
+        delim_values,
+        !arg_matches.is_present("noheader"),
+    )
+}
+
+/// Runs `clipivot melt` or `clipivot describe`, given all of the command-line arguments
+/// (starting with the name of the program)
+pub fn run_subcommand(args: &[String]) -> CsvCliResult<()> {
+    let subcommand = args.get(1).map_or("", String::as_str);
+    let app = match subcommand {
+        MELT_COMMAND => build_melt_app(),
+        DESCRIBE_COMMAND => build_describe_app(),
+        _ => {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` is not a subcommand",
+                subcommand
+            )))
+        }
+    };
+    let arg_matches = app
+        .get_matches_from_safe(&args[1..])
+        .unwrap_or_else(|err| exit_with_usage_error(err));
+    let settings = get_reader_settings(&arg_matches)?;
+    match arg_matches.value_of("filename") {
+        Some(filepath) => {
+            let rdr = settings.get_reader_from_path(filepath)?;
+            run_subcommand_from_reader(subcommand, &arg_matches, &settings, rdr)
+        }
+        None => {
+            let rdr = settings.get_reader_from_stdin();
+            run_subcommand_from_reader(subcommand, &arg_matches, &settings, rdr)
+        }
+    }
+}
+
+/// A CSV writer for standard output, delimited with `delimiter`
+fn stdout_writer(delimiter: u8) -> csv::Writer<io::BufWriter<io::StdoutLock<'static>>> {
+    csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(io::BufWriter::with_capacity(
+            OUTPUT_BUFFER_SIZE,
+            io::stdout().lock(),
+        ))
+}
+
+fn run_subcommand_from_reader<R: io::Read>(
+    subcommand: &str,
+    arg_matches: &ArgMatches,
+    settings: &CsvSettings,
+    mut rdr: csv::Reader<R>,
+) -> CsvCliResult<()> {
+    let mut wtr = stdout_writer(b',');
+    if subcommand == DESCRIBE_COMMAND {
+        return describe::write_summaries(&describe::describe(&mut rdr)?, &mut wtr);
+    }
+    let headers = rdr.headers()?.clone();
+    let headers: Vec<&str> = headers.iter().collect();
+    let get_indexes = |arg: &str| {
+        let names = arg_matches.values_of(arg).map_or(vec![], |v| v.collect());
+        settings.get_field_indexes(&names, &headers)
+    };
+    let id_cols = get_indexes("id")?;
+    let value_cols = if arg_matches.is_present("vars") {
+        get_indexes("vars")?
+    } else {
+        (0..headers.len())
+            .filter(|idx| !id_cols.contains(idx))
+            .collect()
+    };
+    melt::melt(
+        &mut rdr,
+        &mut wtr,
+        &id_cols,
+        &value_cols,
+        arg_matches
+            .value_of("varname")
+            .unwrap_or(melt::DEFAULT_VARIABLE_NAME),
+        arg_matches
+            .value_of("valuename")
+            .unwrap_or(melt::DEFAULT_VALUE_NAME),
+        arg_matches.is_present("empty"),
+    )
+}
+
+fn agg_from_reader<T, I, O, R>(
+    arg_matches: &ArgMatches,
+    pivot: &Pivot,
+    mut reader: csv::Reader<R>,
+    parser: ValueParser<I>,
+) -> CsvCliResult<()>
+where
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
+    R: io::Read,
+{
+    let start = Instant::now();
+    let settings = pivot.settings();
+    if arg_matches.is_present("chi2") {
+        check_chi2(arg_matches)?;
+    }
+    let headers = reader.headers()?;
+    let mut agg = pivot.aggregator::<T, I, O>(settings, &headers.iter().collect(), parser)?;
+    if let Some(total_bytes) = get_progress_size(arg_matches) {
+        agg = agg.with_progress_bar(total_bytes);
+    }
+    let filenames: Vec<&str> = arg_matches
+        .values_of("filename")
+        .map_or(Vec::new(), |values| values.collect());
+    let threads = get_threads(arg_matches, &filenames)?;
+    agg = agg.with_threads(threads);
+    let index = if arg_matches.is_present("dryrun") || filenames.len() > 1 {
+        None
+    } else {
+        get_index(arg_matches, settings)?
+    };
+    if let Some(index) = &index {
+        agg = agg.with_total_records(index.num_records(settings.has_header()));
+    }
+    let warnings = agg
+        .configuration_warnings(headers)
+        .into_iter()
+        .chain(argument_warnings(arg_matches));
+    for warning in warnings {
+        logging::warn(format_args!("Warning: {}", warning));
+    }
+    if let Some(dialect) = arg_matches.value_of("tosql") {
+        return write_sql(arg_matches, &mut agg, &mut reader, dialect.parse()?);
+    }
+    if arg_matches.is_present("dryrun") {
+        let mut stdout = io::stdout();
+        write_reader_plan(arg_matches, pivot, &mut stdout)?;
+        return agg.write_plan(&mut reader, &mut stdout, DRY_RUN_RECORDS);
+    }
+    if logging::enabled(Level::Info) {
+        let mut plan = Vec::new();
+        write_reader_plan(arg_matches, pivot, &mut plan)?;
+        for line in String::from_utf8_lossy(&plan).lines() {
+            logging::log(Level::Info, format_args!("{}", line));
+        }
+        logging::log(Level::Info, format_args!("Threads: {}", threads));
+    }
+    if logging::enabled(Level::Debug) {
+        let mut fields = Vec::new();
+        agg.write_fields(reader.headers()?, &mut fields)?;
+        for line in String::from_utf8_lossy(&fields).lines() {
+            logging::log(Level::Debug, format_args!("{}", line));
+        }
+    }
+    let configured = Instant::now();
+    logging::log(
+        Level::Info,
+        format_args!(
+            "Configured the pivot table in {}",
+            format_duration(configured - start)
+        ),
+    );
+    let state = match arg_matches.value_of("state") {
+        Some(path) => {
+            check_conflicts(
+                arg_matches,
+                "--state",
+                &[
+                    ("twopass", "--two-pass"),
+                    ("sample", "sampling"),
+                    ("samplen", "sampling"),
+                ],
+            )?;
+            Some((path, state_signature(arg_matches)))
+        }
+        None => None,
+    };
+    let checkpoints = get_checkpoints(arg_matches, &filenames)?;
+    let mut resume_at = None;
+    if let Some((path, filename, signature)) = &checkpoints {
+        let interval = match arg_matches.value_of("checkpointinterval") {
+            Some(secs) => parse_count_arg(secs, "--checkpoint-interval")?,
+            None => DEFAULT_CHECKPOINT_INTERVAL,
+        };
+        agg = agg.with_checkpoints(
+            PathBuf::from(path),
+            signature,
+            Duration::from_secs(interval as u64),
+        );
+        if arg_matches.is_present("resume") {
+            match fs::File::open(path) {
+                Ok(file) => {
+                    let position = agg.resume(io::BufReader::new(file), signature)?;
+                    logging::log(
+                        Level::Info,
+                        format_args!(
+                            "Resuming from the checkpoint in {} at byte {}",
+                            path,
+                            position.byte()
+                        ),
+                    );
+                    resume_at = Some((*filename, position));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    logging::warn(format_args!(
+                        "Warning: There isn't a checkpoint at {} yet, so the file gets read from the start",
+                        path
+                    ));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    // a checkpoint already includes the state it was started from
+    if let (Some((path, signature)), None) = (&state, &resume_at) {
+        load_state_file(&mut agg, path, signature)?;
+    }
+    if filenames.len() > 1 {
+        if arg_matches.is_present("twopass") {
+            return Err(CsvCliError::InvalidConfiguration(
+                "--two-pass only works with a single file".to_string(),
+            ));
+        }
+        let readers = filenames
+            .iter()
+            .map(|filename| settings.get_reader_from_path(filename))
+            .collect::<csv::Result<Vec<_>>>()?;
+        agg.aggregate_files(readers).map_err(|(file_idx, err)| {
+            // standard error only holds the JSON object with --errors json
+            if let (Some(file_idx), false) =
+                (file_idx, json_errors(&env::args().collect::<Vec<_>>()))
+            {
+                eprintln!("Failed to aggregate {}", filenames[file_idx]);
+            }
+            err
+        })?;
+    } else if let Some((filename, position)) = resume_at {
+        let mut resumed = settings.get_reader_from_path(filename)?;
+        resumed.seek(position)?;
+        agg.aggregate(&mut resumed)?;
+    } else {
+        if arg_matches.is_present("twopass") {
+            let filename = arg_matches.value_of("filename").ok_or_else(|| {
+                CsvCliError::InvalidConfiguration(
+                    "--two-pass has to read the input twice, so it can't read from standard input"
+                        .to_string(),
+                )
+            })?;
+            agg.scan_keys(&mut settings.get_reader_from_path(filename)?)?;
+        }
+        match get_chunks(arg_matches, settings, index, threads)? {
+            Some((filename, chunks)) => agg
+                .aggregate_chunks(&mut reader, chunks)
+                .map_err(|err| with_file_line(filename, err))?,
+            None => agg.aggregate(&mut reader)?,
+        }
+    }
+    if let Some((sampled, total)) = agg.sample_size() {
+        logging::warn(format_args!("Sampled {} of {} records", sampled, total));
+    }
+    let aggregated = Instant::now();
+    let counts = agg.record_counts();
+    logging::log(
+        Level::Info,
+        format_args!(
+            "Read {} records in {}",
+            counts.read,
+            format_duration(aggregated - configured)
+        ),
+    );
+    logging::log(
+        Level::Info,
+        format_args!(
+            "Aggregated {} records, skipping {} that didn't meet the filters, {} whose keys were dropped, and {} empty values",
+            counts.aggregated, counts.filtered, counts.dropped_keys, counts.empty_values
+        ),
+    );
+    if counts.nan_values > 0 {
+        logging::warn(format_args!(
+            "Skipped {} NaN or infinite values",
+            counts.nan_values
+        ));
+    }
+    if counts.bad_records > 0 {
+        let lines: Vec<String> = agg.bad_record_lines().iter().map(u64::to_string).collect();
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let first = if lines.len() < counts.bad_records {
+            format!("the first {} ", lines.len())
+        } else {
+            String::new()
+        };
+        logging::warn(format_args!(
+            "Skipped {} record{} that couldn't be parsed ({}on line{} {})",
+            counts.bad_records,
+            plural(counts.bad_records),
+            first,
+            plural(lines.len()),
+            lines.join(", ")
+        ));
+    }
+    if agg.spilled() && !arg_matches.is_present("maxcells") {
+        logging::warn(format_args!(
+            "Warning: The table went over the memory budget from --max-memory, so its cells were spilled to disk and the rows will come out sorted"
+        ));
+    }
+    if let Some((path, signature)) = &state {
+        save_state_file(&agg, path, signature)?;
+    }
+    let table_size = agg.table_size();
+    let memory_estimate = agg.memory_estimate();
+    let cell_counts = if arg_matches.is_present("chi2") {
+        Some(agg.cell_counts())
+    } else {
+        None
+    };
+    let delimiter = agg.out_locale().map_or(b',', |locale| locale.delimiter());
+    agg.write_results(&mut stdout_writer(delimiter))?;
+    // the run finished, so there's nothing left to resume
+    if let Some((path, _, _)) = &checkpoints {
+        // along with a checkpoint that was being written when an earlier run got interrupted
+        for path in [path.to_string(), format!("{}.tmp", path)] {
+            if Path::new(&path).exists() {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    logging::log(
+        Level::Info,
+        format_args!(
+            "Wrote the results in {}",
+            format_duration(aggregated.elapsed())
+        ),
+    );
+    if arg_matches.is_present("stats") {
+        let peak_memory = logging::peak_memory().map_or("unknown".to_string(), format_bytes);
+        eprintln!("Records read: {}", counts.read);
+        eprintln!("Records skipped: {}", counts.read - counts.aggregated);
+        eprintln!("Row keys: {}", table_size.rows);
+        eprintln!("Column keys: {}", table_size.columns);
+        eprintln!("Populated cells: {}", table_size.cells);
+        eprintln!("Wall time: {}", format_duration(start.elapsed()));
+        eprintln!("Peak memory: {}", peak_memory);
+        eprintln!(
+            "Estimated table memory: {}",
+            format_bytes(memory_estimate as u64)
+        );
+    }
+    if let Some(counts) = cell_counts {
+        write_chi_square(&counts);
+    }
+    Ok(())
+}
+
+/// Checks that the pivot table is a table of counts with row and column fields, which is what
+/// `--chi2` tests
+fn check_chi2(arg_matches: &ArgMatches) -> CsvCliResult<()> {
+    if arg_matches.value_of("aggfunc") != Some("count") {
+        return Err(CsvCliError::InvalidConfiguration(
+            "--chi2 tests a table of counts, so it only works with `count`".to_string(),
+        ));
+    }
+    if !arg_matches.is_present("rows") || !arg_matches.is_present("columns") {
+        return Err(CsvCliError::InvalidConfiguration(
+            "--chi2 tests whether the rows and columns are related, so it needs both row fields (-r) and column fields (-c)".to_string(),
+        ));
+    }
+    // the cells that get spilled to disk aren't in memory to be tested
+    check_conflicts(
+        arg_matches,
+        "--chi2",
+        &[("maxcells", "--max-cells"), ("maxmemory", "--max-memory")],
+    )
+}
+
+/// Writes the chi-square test of a table of counts (for `--chi2`) to standard error
+fn write_chi_square(counts: &[Vec<f64>]) {
+    let chi2 = match ChiSquare::from_counts(counts) {
+        Some(chi2) => chi2,
+        None => {
+            logging::warn(format_args!(
+                "Warning: --chi2 needs at least two rows and two columns with records, so the table couldn't be tested"
+            ));
+            return;
+        }
+    };
+    eprintln!("{}", chi2);
+    if chi2.small_cells > 0 {
+        logging::warn(format_args!(
+            "Warning: {} of the {} cells are expected to have fewer than {} records, so the p-value might not be reliable",
+            chi2.small_cells,
+            chi2.cells,
+            stats::MIN_EXPECTED_COUNT
+        ));
+    }
+}
+
+/// The index of the input file (see the `index` module), if it has one that can be used.
+/// Indexes that are out of date or don't line up with the file get ignored, with a warning.
+fn get_index(
+    arg_matches: &ArgMatches,
+    settings: &CsvSettings,
+) -> CsvCliResult<Option<CsvIndex<fs::File>>> {
+    let filename = match arg_matches.value_of("filename") {
+        Some(filename) => filename,
+        None => return Ok(None),
+    };
+    let problem = match CsvIndex::open(filename) {
+        Ok(None) => return Ok(None),
+        Ok(Some(mut index)) => match index_matches(settings, filename, &mut index) {
+            Ok(true) => return Ok(Some(index)),
+            Ok(false) => "it doesn't line up with the records of the file".to_string(),
+            Err(err) => err.to_string(),
+        },
+        Err(err) => err.to_string(),
+    };
+    logging::warn(format_args!(
+        "Warning: Ignoring the index of {} because {}",
+        filename, problem
+    ));
+    Ok(None)
+}
+
+/// Whether the first record of the file starts where its index says it does, which catches
+/// indexes of a different file, or of the file with a different header setting
+fn index_matches(
+    settings: &CsvSettings,
+    filename: &str,
+    index: &mut CsvIndex<fs::File>,
+) -> CsvCliResult<bool> {
+    let mut rdr = settings.get_reader_from_path(filename)?;
+    let mut record = csv::ByteRecord::new();
+    let num_records = index.num_records(settings.has_header());
+    if !rdr.read_byte_record(&mut record)? {
+        return Ok(num_records == 0);
+    }
+    let start = index.offset(0, settings.has_header())?;
+    Ok(num_records > 0 && record.position().map(|pos| pos.byte()) == Some(start))
+}
+
+/// The chunks of a file, each with a reader that can read it
+type FileChunks = Vec<(Chunk, csv::Reader<fs::File>)>;
+
+/// Splits the input file into a chunk for each thread, with a reader for each chunk, if it
+/// has an index and the records get aggregated on more than one thread
+fn get_chunks<'a>(
+    arg_matches: &'a ArgMatches,
+    settings: &CsvSettings,
+    index: Option<CsvIndex<fs::File>>,
+    threads: usize,
+) -> CsvCliResult<Option<(&'a str, FileChunks)>> {
+    let (filename, mut index) = match (arg_matches.value_of("filename"), index) {
+        (Some(filename), Some(index)) => (filename, index),
+        _ => return Ok(None),
+    };
+    if threads <= 1 {
+        return Ok(None);
+    }
+    let chunks = index.chunks(settings.has_header(), threads)?;
+    // a chunk that doesn't start at the beginning of a line means the file has changed
+    // since it was indexed
+    let mut file = fs::File::open(filename)?;
+    for chunk in chunks.iter().filter(|chunk| chunk.byte > 0) {
+        let mut before = [0];
+        file.seek(io::SeekFrom::Start(chunk.byte - 1))?;
+        file.read_exact(&mut before)?;
+        if before[0] != b'\n' && before[0] != b'\r' {
+            logging::warn(format_args!(
+                "Warning: Ignoring the index of {} because it doesn't line up with the records of the file",
+                filename
+            ));
+            return Ok(None);
+        }
+    }
+    let chunks = chunks
+        .into_iter()
+        .map(|chunk| Ok((chunk, settings.get_reader_from_path(filename)?)))
+        .collect::<csv::Result<Vec<_>>>()?;
+    Ok(Some((filename, chunks)))
+}
+
+/// Replaces the line number of an error parsing a record that was read from the middle of
+/// `filename` (with an index) with the line the record is on in the whole file
+fn with_file_line(filename: &str, mut err: CsvCliError) -> CsvCliError {
+    if let CsvCliError::ParsingError {
+        position: Some(position),
+        ..
+    } = &mut err
+    {
+        let line = fs::File::open(filename).and_then(|file| index::line_at(file, position.byte()));
+        if let Ok(line) = line {
+            position.set_line(line);
+        }
+    }
+    err
+}
+
+/// Describes the pivot table a state file (from `--state`) belongs to, by its function and its
+/// fields, so the state of one pivot table doesn't get merged into another
+fn state_signature(arg_matches: &ArgMatches) -> String {
+    let mut parts = vec![arg_matches
+        .value_of("aggfunc")
+        .unwrap_or_default()
+        .to_string()];
+    for (arg, option) in [("approx", "--approx"), ("numeric", "-N")] {
+        if arg_matches.is_present(arg) {
+            parts.push(option.to_string());
+        }
+    }
+    let fields = [
+        ("format", "-F"),
+        ("rows", "-r"),
+        ("columns", "-c"),
+        ("value", "-v"),
+        ("valueexpr", "--value-expr"),
+    ];
+    for (arg, option) in fields {
+        if let Some(values) = arg_matches.values_of(arg) {
+            parts.push(format!(
+                "{} {}",
+                option,
+                values.collect::<Vec<_>>().join(",")
+            ));
+        }
+    }
+    parts.join(" ")
+}
+
+/// Returns an error if `option` was combined with any of `conflicts`, given as the names of
+/// their arguments along with how to describe them
+fn check_conflicts(
+    arg_matches: &ArgMatches,
+    option: &str,
+    conflicts: &[(&str, &str)],
+) -> CsvCliResult<()> {
+    match conflicts
+        .iter()
+        .find(|(arg, _)| arg_matches.is_present(arg))
+    {
+        Some((_, conflict)) => Err(CsvCliError::InvalidConfiguration(format!(
+            "{} can't be combined with {}",
+            option, conflict
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Sets up the checkpoints for `--checkpoint`, returning the path of the checkpoint file and the
+/// signature of the checkpoints (which includes the input file, since a checkpoint only makes
+/// sense for the file it was saved from)
+fn get_checkpoints<'a>(
+    arg_matches: &'a ArgMatches,
+    filenames: &[&'a str],
+) -> CsvCliResult<Option<(&'a str, &'a str, String)>> {
+    let path = match arg_matches.value_of("checkpoint") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let filename = match filenames {
+        [filename] => *filename,
+        _ => {
+            return Err(CsvCliError::InvalidConfiguration(
+                "--checkpoint needs to read from a single file, so it can pick up where it left off in that file"
+                    .to_string(),
+            ))
+        }
+    };
+    check_conflicts(
+        arg_matches,
+        "--checkpoint",
+        &[
+            ("twopass", "--two-pass"),
+            ("sample", "sampling"),
+            ("samplen", "sampling"),
+            ("maxcells", "--max-cells"),
+            ("maxmemory", "--max-memory"),
+        ],
+    )?;
+    if let Some(num) = arg_matches.value_of("threads") {
+        if parse_threads(num)? > 1 {
+            return Err(CsvCliError::InvalidConfiguration(
+                "--checkpoint reads the file on a single thread, so it can't be combined with --threads".to_string(),
+            ));
+        }
+    }
+    let signature = format!("{} on {}", state_signature(arg_matches), filename);
+    Ok(Some((path, filename, signature)))
+}
+
+/// Merges the state an earlier run saved to `path` into the aggregator, if there is one
+fn load_state_file<T, I, O>(
+    agg: &mut Aggregator<T, I, O>,
+    path: &str,
+    signature: &str,
+) -> CsvCliResult<()>
+where
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
+{
+    match fs::File::open(path) {
+        Ok(file) => agg.load_state(io::BufReader::new(file), signature),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Saves the state of the aggregator to `path`. The state gets written to a temporary file that
+/// then replaces the old state, so a run that fails partway through leaves the old state intact.
+fn save_state_file<T, I, O>(
+    agg: &Aggregator<T, I, O>,
+    path: &str,
+    signature: &str,
+) -> CsvCliResult<()>
+where
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
+{
+    let tmp_path = format!("{}.tmp", path);
+    agg.save_state(io::BufWriter::new(fs::File::create(&tmp_path)?), signature)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// The size of the input file, if a progress bar should be drawn while it gets read
+fn get_progress_size(arg_matches: &ArgMatches) -> Option<u64> {
+    let filenames = arg_matches.values_of("filename")?;
+    let show = match arg_matches.value_of("progress") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => io::stderr().is_terminal() && !arg_matches.is_present("quiet"),
+    };
+    if !show || arg_matches.is_present("dryrun") {
+        return None;
+    }
+    filenames
+        .map(|filename| fs::metadata(filename).ok().map(|metadata| metadata.len()))
+        .sum()
+}
+
+/// Describes how the input gets read, for `--dry-run`
+fn write_reader_plan<W: io::Write>(
+    arg_matches: &ArgMatches,
+    pivot: &Pivot,
+    wtr: &mut W,
+) -> CsvCliResult<()> {
+    let settings = pivot.settings();
+    let filename = arg_matches.value_of("filename");
+    let input = arg_matches
+        .values_of("filename")
+        .map_or("standard input".to_string(), |values| {
+            values.collect::<Vec<_>>().join(", ")
+        });
+    writeln!(wtr, "Input: {}", input)?;
+    let delimiter = match settings.delimiter() {
+        b'\t' => "tab".to_string(),
+        byte => format!("`{}`", byte as char),
+    };
+    let source = if arg_matches.is_present("tab") || arg_matches.is_present("delim") {
+        "set with -t or --delim"
+    } else if filename.is_some() && settings.delimiter() == b'\t' {
+        "detected from the file extension"
+    } else {
+        "the default"
+    };
+    writeln!(wtr, "Delimiter: {} ({})", delimiter, source)?;
+    writeln!(wtr, "Encoding: UTF-8")?;
+    let has_header = if settings.has_header() { "yes" } else { "no" };
+    writeln!(wtr, "Header row: {}", has_header)?;
+    let values = match pivot.parsing_strategy() {
+        ParsingStrategy::Text => "text",
+        ParsingStrategy::Numeric => "numbers",
+        ParsingStrategy::Date => "dates",
+        _ => "values",
+    };
+    writeln!(
+        wtr,
+        "Function: {} (parsing values as {})",
+        arg_matches.value_of("aggfunc").unwrap_or_default(),
+        values
+    )?;
+    Ok(())
+}
+
+/// Writes the query that computes the pivot table (for `--to-sql`), after reading the columns
+/// of the pivot table from the input
+fn write_sql<T, I, O, R>(
+    arg_matches: &ArgMatches,
+    agg: &mut Aggregator<T, I, O>,
+    reader: &mut csv::Reader<R>,
+    dialect: Dialect,
+) -> CsvCliResult<()>
+where
+    T: Accumulate<I, O> + Sync,
+    I: std::str::FromStr + Clone + Send,
+    O: WriteCell,
+    R: io::Read,
+{
+    let unsupported = list_options(&build_app())
+        .into_iter()
+        .find(|option| arg_matches.is_present(option.name) && !SQL_OPTIONS.contains(&option.name));
+    if let Some(option) = unsupported {
+        return Err(CsvCliError::InvalidConfiguration(format!(
+            "{} can't be written as SQL, so it can't be combined with --to-sql",
+            option.long.or(option.short).unwrap_or_default()
+        )));
+    }
+    let filenames: Vec<&str> = arg_matches
+        .values_of("filename")
+        .map_or(Vec::new(), |values| values.collect());
+    if filenames.len() > 1 {
+        return Err(CsvCliError::InvalidConfiguration(
+            "--to-sql can only read the columns of the pivot table from a single file".to_string(),
+        ));
+    }
+    let table = match (arg_matches.value_of("sqltable"), filenames.first()) {
+        (Some(table), _) => table.to_string(),
+        (None, Some(filename)) => Path::new(filename)
+            .file_stem()
+            .map_or("input".to_string(), |stem| {
+                stem.to_string_lossy().into_owned()
+            }),
+        (None, None) => "input".to_string(),
+    };
+    let query = agg.scan_crosstab(reader)?.to_sql(
+        arg_matches.value_of("aggfunc").unwrap_or_default(),
+        &table,
+        dialect,
+    )?;
+    io::stdout().write_all(query.as_bytes())?;
+    Ok(())
+}
+
+/// Parses a non-negative integer passed to one of the command-line options
+fn parse_count_arg(num: &str, option: &str) -> CsvCliResult<usize> {
+    num.trim().parse().map_err(|_| {
+        CsvCliError::InvalidConfiguration(format!(
+            "Could not parse `{}` as a whole number for {}",
+            num, option
+        ))
+    })
+}
+
+/// Parses a number of bytes, like `2G` or `512MB` (where a kilobyte is 1,024 bytes)
+fn parse_size_arg(size: &str, option: &str) -> CsvCliResult<usize> {
+    let trimmed = size.trim().to_uppercase();
+    let without_bytes = trimmed.strip_suffix('B').unwrap_or(&trimmed);
+    let (num, unit) = match without_bytes.char_indices().last() {
+        Some((idx, 'K')) => (&without_bytes[..idx], 1 << 10),
+        Some((idx, 'M')) => (&without_bytes[..idx], 1 << 20),
+        Some((idx, 'G')) => (&without_bytes[..idx], 1 << 30),
+        Some((idx, 'T')) => (&without_bytes[..idx], 1u64 << 40),
+        _ => (without_bytes, 1),
+    };
+    match num.trim().parse::<f64>() {
+        Ok(num) if num.is_finite() && num >= 0. => {
+            Ok((num * unit as f64).min(usize::MAX as f64) as usize)
+        }
+        _ => Err(CsvCliError::InvalidConfiguration(format!(
+            "Could not parse `{}` as a size (like 512M or 2G) for {}",
+            size, option
+        ))),
+    }
+}
+
+/// Parses the number of threads for `--threads`, where 0 means one thread per core
+fn parse_threads(num: &str) -> CsvCliResult<usize> {
+    match parse_count_arg(num, "--threads")? {
+        0 => Ok(available_threads()),
+        threads => Ok(threads),
+    }
+}
+
+/// The number of threads the records get aggregated on. Without `--threads`, several files get
+/// one thread per core, and so does a single large file (up to `MAX_DEFAULT_THREADS`), unless
+/// something needs the records to be aggregated in order on a single thread: a checkpoint, or
+/// `--approx`, whose result can depend on how the records get split up (since it forgets values
+/// depending on the order it sees them). Otherwise, the number of cores could change the output.
+/// (`mode` gives ties to the value that got there first wherever the records were read, so
+/// it can be split up like everything else.)
+fn get_threads(arg_matches: &ArgMatches, filenames: &[&str]) -> CsvCliResult<usize> {
+    if let Some(num) = arg_matches.value_of("threads") {
+        return parse_threads(num);
+    }
+    if filenames.len() > 1 {
+        return Ok(available_threads());
+    }
+    if arg_matches.is_present("approx") || arg_matches.is_present("checkpoint") {
+        return Ok(1);
+    }
+    let large = filenames.first().is_some_and(|filename| {
+        fs::metadata(filename).is_ok_and(|metadata| metadata.len() >= DEFAULT_THREADS_MIN_BYTES)
+    });
+    Ok(if large {
+        available_threads().min(MAX_DEFAULT_THREADS)
+    } else {
+        1
+    })
+}
+
+/// One thread for each core
+fn available_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+/// The cells that a transform moving across the columns (or down the rows) works within
+fn get_direction_group(direction: &str) -> CellGroup {
+    match direction {
+        "columns" => CellGroup::Row,
+        _ => CellGroup::Column,
+    }
+}
+
+/// The cells that a transform comparing each cell with the rest of its row (or column) works within
+fn get_within_group(within: &str) -> CellGroup {
+    match within {
+        "rows" => CellGroup::Row,
+        _ => CellGroup::Column,
+    }
+}
+
+/// The order of the rows or columns when none of the options for sorting them were used,
+/// which is either set by `--order` or falls back to `fallback`
+fn default_order(arg_matches: &ArgMatches, fallback: OutputOrder) -> OutputOrder {
+    match arg_matches.value_of("order") {
+        Some("input") => OutputOrder::IndexOrder,
+        Some(_) => OutputOrder::Ascending,
+        None => fallback,
+    }
+}
+
+/// Parses the order passed to `--sort-rows` or `--sort-cols`
+fn get_sort_order(order: &str) -> OutputOrder {
+    match order {
+        "desc" => OutputOrder::Descending,
+        "by-total-asc" => OutputOrder::ByTotalAscending,
+        "by-total-desc" => OutputOrder::ByTotalDescending,
+        _ => OutputOrder::Ascending,
+    }
+}
+
+/// Reads a custom ordering of the rows or columns from a comma-separated list
+/// (like `--row-order`) or from a file with one value per line (like `--row-order-file`)
+fn get_custom_order(
+    arg_matches: &ArgMatches,
+    list_arg: &str,
+    file_arg: &str,
+) -> CsvCliResult<Option<OutputOrder>> {
+    if let Some(list) = arg_matches.value_of(list_arg) {
+        let order = list.split(',').map(|val| val.trim().to_string()).collect();
+        return Ok(Some(OutputOrder::Custom(order)));
+    }
+    match arg_matches.value_of(file_arg) {
+        Some(path) => {
+            let order = std::fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect();
+            Ok(Some(OutputOrder::Custom(order)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parses the --sample and --sample-n options
+fn get_sample(arg_matches: &ArgMatches) -> CsvCliResult<Option<Sample>> {
+    if let Some(fraction) = arg_matches.value_of("sample") {
+        return match fraction.trim().parse::<f64>() {
+            Ok(num) if num > 0.0 && num <= 1.0 => Ok(Some(Sample::Fraction(num))),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "--sample needs to be a number greater than 0 and no more than 1, not `{}`",
+                fraction
+            ))),
+        };
+    }
+    match arg_matches.value_of("samplen") {
+        Some(num) => match parse_count_arg(num, "--sample-n")? {
+            0 => Err(CsvCliError::InvalidConfiguration(
+                "--sample-n needs to be at least 1".to_string(),
+            )),
+            size => Ok(Some(Sample::Size(size))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The label for the values that get grouped together into a catch-all category
+fn get_other_label(arg_matches: &ArgMatches) -> String {
+    arg_matches
+        .value_of("otherlabel")
+        .unwrap_or(DEFAULT_OTHER_LABEL)
+        .to_string()
+}
+
+/// How `max`, `min`, `minmax`, and `range` read their values, from `-N` and `-F`
+fn get_value_type(arg_matches: &ArgMatches, function: Agg) -> CsvCliResult<Option<ValueType>> {
+    let numeric = arg_matches.is_present("numeric");
+    let format = arg_matches.value_of("format");
+    match function {
+        Agg::Max | Agg::Min | Agg::MinMax => match (numeric, format) {
+            (true, Some(_)) => Err(CsvCliError::InvalidConfiguration(
+                "You can only enter one of the -N and -F flags/options".to_string(),
+            )),
+            (true, None) => Ok(Some(ValueType::Numbers)),
+            (false, Some(fmt)) => Ok(Some(ValueType::Dates(fmt.to_string()))),
+            (false, None) => Ok(None),
+        },
+        Agg::Range => Ok(format.map(|fmt| ValueType::Dates(fmt.to_string()))),
+        _ => Ok(None),
+    }
+}
+
+/// Describes the pivot table the command-line arguments ask for
+fn get_pivot_builder(arg_matches: &ArgMatches) -> CsvCliResult<PivotBuilder> {
+    let function: Agg = arg_matches
+        .value_of("aggfunc")
+        .unwrap_or_default()
+        .parse()?;
+    let settings = get_reader_settings(arg_matches)?;
+    let mut builder = PivotBuilder::new()
+        .aggregate(function)
+        .approx(arg_matches.is_present("approx"))
+        .delimiter(settings.delimiter())
+        .has_headers(settings.has_header())
+        .strict_headers(arg_matches.is_present("strictheaders"));
+    if let Some(value_type) = get_value_type(arg_matches, function)? {
+        builder = builder.value_type(value_type);
+    }
+    let values_of = |arg: &str| arg_matches.values_of(arg).into_iter().flatten();
+    for name in values_of("rows") {
+        builder = builder.rows(name);
+    }
+    for pattern in values_of("rowsregex") {
+        builder = builder.rows_matching(pattern);
+    }
+    for spec in values_of("rowsextract") {
+        builder = builder.rows_extract(spec);
+    }
+    for expr in values_of("rowsexpr") {
+        builder = builder.rows_expr(expr);
+    }
+    for name in values_of("columns") {
+        builder = builder.columns(name);
+    }
+    for pattern in values_of("colsregex") {
+        builder = builder.columns_matching(pattern);
+    }
+    for spec in values_of("colsextract") {
+        builder = builder.columns_extract(spec);
+    }
+    for expr in values_of("colsexpr") {
+        builder = builder.columns_expr(expr);
+    }
+    if let Some(steps) = arg_matches.value_of("normalizekeys") {
+        builder = builder.normalize_keys(steps);
+    }
+    for spec in values_of("by") {
+        builder = builder.date_part(spec);
+    }
+    for spec in values_of("bin") {
+        builder = builder.bin(spec);
+    }
+    for spec in values_of("recode") {
+        builder = builder.recode(spec);
+    }
+    builder = builder.unmapped(match arg_matches.value_of("unmapped").unwrap_or("keep") {
+        "drop" => UnmappedPolicy::Drop,
+        "other" => UnmappedPolicy::Other(get_other_label(arg_matches)),
+        _ => UnmappedPolicy::Keep,
+    });
+    if let Some(spec) = arg_matches.value_of("timeseries") {
+        builder = builder.timeseries(spec);
+    }
+    if let Some(format) = arg_matches.value_of("format") {
+        builder = builder.key_date_format(format);
+    }
+    match arg_matches.value_of("value") {
+        Some(ALL_OTHER_VALUES) => builder = builder.other_values(),
+        Some(value) => builder = builder.values(value),
+        None => {}
+    }
+    if let Some(expr) = arg_matches.value_of("valueexpr") {
+        builder = builder.value_expr(expr);
+    }
+    builder = builder.skip_empty(arg_matches.is_present("empty"));
+    if let Some(values) = arg_matches.value_of("nullvalues") {
+        builder = builder.null_values(values.split(',').map(String::from).collect());
+    }
     let row_ordering_pair = (
         arg_matches.is_present("ascrow"),
         arg_matches.is_present("descrow"),
@@ -190,7 +2778,7 @@ where
         )),
         (true, false) => Ok(OutputOrder::Ascending),
         (false, true) => Ok(OutputOrder::Descending),
-        (false, false) => Ok(OutputOrder::IndexOrder),
+        (false, false) => Ok(default_order(arg_matches, OutputOrder::IndexOrder)),
     }?;
     let column_ordering_pair = (
         arg_matches.is_present("indexcol"),
@@ -202,93 +2790,239 @@ where
         )),
         (true, false) => Ok(OutputOrder::IndexOrder),
         (false, true) => Ok(OutputOrder::Descending),
-        (false, false) => Ok(OutputOrder::Ascending),
+        (false, false) => Ok(default_order(arg_matches, OutputOrder::Ascending)),
     }?;
-    let agg = Aggregator::new(
-        index_cols,
-        column_cols,
-        values_col,
-        skip_null,
-        row_order,
-        column_order,
-        parsing_strategy,
-    );
-    Ok(agg)
+    let row_order = arg_matches
+        .value_of("sortrows")
+        .map_or(row_order, get_sort_order);
+    let row_order = get_custom_order(arg_matches, "roworder", "roworderfile")?.unwrap_or(row_order);
+    let column_order = arg_matches
+        .value_of("sortcols")
+        .map_or(column_order, get_sort_order);
+    let column_order =
+        get_custom_order(arg_matches, "colorder", "colorderfile")?.unwrap_or(column_order);
+    builder = builder
+        .row_order(row_order)
+        .column_order(column_order)
+        .comparator(
+            arg_matches
+                .value_of("sorttype")
+                .map_or(Ok(Comparator::default()), str::parse)?,
+        )
+        .row_labels(values_of("rowlabel").map(String::from).collect())
+        .header_prefix(arg_matches.value_of("headerprefix").unwrap_or(""))
+        .multi_header(arg_matches.is_present("multiheader"))
+        .totals(arg_matches.is_present("totals"))
+        .subtotals(arg_matches.is_present("subtotals"))
+        .drop_empty(arg_matches.is_present("dropempty"))
+        .sparse(arg_matches.is_present("sparse"));
+    if let Some(separator) = arg_matches.value_of("keysep") {
+        builder = builder.key_separator(separator);
+    }
+    for filter in values_of("filter") {
+        builder = builder.filter(filter);
+    }
+    for filter in values_of("filterany") {
+        builder = builder.filter_any(filter);
+    }
+    for spec in values_of("filterregex") {
+        builder = builder.filter_regex(spec);
+    }
+    for spec in values_of("where") {
+        builder = builder.filter_where(spec);
+    }
+    if let Some(column) = arg_matches.value_of("datecol") {
+        builder = builder.date_column(column);
+    }
+    if let Some(since) = arg_matches.value_of("since") {
+        builder = builder.since(since);
+    }
+    if let Some(until) = arg_matches.value_of("until") {
+        builder = builder.until(until);
+    }
+    if let Some(label) = arg_matches.value_of("otherlabel") {
+        builder = builder.other_label(label);
+    }
+    if let Some(num) = arg_matches.value_of("toprows") {
+        builder = builder.top_rows(parse_count_arg(num, "--top-rows")?);
+    }
+    if let Some(num) = arg_matches.value_of("mincount") {
+        let as_other = arg_matches.value_of("smallgroups") == Some("other");
+        builder = builder.min_count(parse_count_arg(num, "--min-count")?, as_other);
+    }
+    let decimals = match arg_matches.value_of("percentdecimals") {
+        Some(num) => parse_count_arg(num, "--percent-decimals")? as u32,
+        None => DEFAULT_PERCENT_DECIMALS,
+    };
+    if let Some(of) = arg_matches.value_of("percent") {
+        builder =
+            builder.cell_transform(CellTransform::Percent(of.parse::<CellGroup>()?, decimals));
+    }
+    if let Some(column) = arg_matches.value_of("baseline") {
+        let scale = match arg_matches.value_of("baselinescale") {
+            Some(num) => parse_count_arg(num, "--baseline-scale")? as u64,
+            None => 1,
+        };
+        builder = builder.cell_transform(CellTransform::Baseline(
+            column.to_string(),
+            Decimal::from(scale),
+            BASELINE_DECIMALS,
+        ));
+    }
+    if let Some(direction) = arg_matches.value_of("diff") {
+        let group = get_direction_group(direction);
+        builder = builder.cell_transform(if arg_matches.is_present("pctchange") {
+            CellTransform::PercentChange(group, decimals)
+        } else {
+            CellTransform::Diff(group)
+        });
+    }
+    if let Some(direction) = arg_matches.value_of("accumulate") {
+        builder = builder.cell_transform(CellTransform::Accumulate(get_direction_group(direction)));
+    }
+    if let Some(within) = arg_matches.value_of("zscore") {
+        builder = builder.cell_transform(CellTransform::ZScore(
+            get_within_group(within),
+            ZSCORE_DECIMALS,
+        ));
+    }
+    if let Some(within) = arg_matches.value_of("rank") {
+        let ties = arg_matches
+            .value_of("rankties")
+            .map_or(Ok(RankTies::Min), str::parse)?;
+        builder = builder.cell_transform(CellTransform::Rank(get_within_group(within), ties));
+    }
+    if let Some(threshold) = arg_matches.value_of("suppressbelow") {
+        let threshold = parse_count_arg(threshold, "--suppress-below")?;
+        builder = builder.output_recode(RecodeRule::suppress_below(threshold, SUPPRESSED_LABEL));
+    }
+    if let Some(rules) = arg_matches.value_of("outputrecode") {
+        for rule in RecodeRule::parse_list(rules)? {
+            builder = builder.output_recode(rule);
+        }
+    }
+    if let Some(locale) = arg_matches.value_of("outlocale") {
+        builder = builder.out_locale(locale.parse()?);
+    }
+    if let Some(having) = arg_matches.value_of("having") {
+        builder = builder.having(having);
+    }
+    match arg_matches.value_of("onfiltererror") {
+        Some("skip") => builder = builder.filter_error_policy(FilterErrorPolicy::Skip),
+        Some("keep") => builder = builder.filter_error_policy(FilterErrorPolicy::Keep),
+        _ => {}
+    }
+    match arg_matches.value_of("nan") {
+        Some("skip") => builder = builder.nan_policy(NanPolicy::Skip),
+        Some("propagate") => builder = builder.nan_policy(NanPolicy::Propagate),
+        _ => {}
+    }
+    builder = builder.skip_bad_records(arg_matches.is_present("skipbadrecords"));
+    if let Some(num) = arg_matches.value_of("maxerrors") {
+        builder = builder.max_bad_records(parse_count_arg(num, "--max-errors")?);
+    }
+    if let Some(path) = arg_matches.value_of("badrecords") {
+        builder = builder.bad_records(path);
+    }
+    if let Some(num) = arg_matches.value_of("limit") {
+        builder = builder.limit(parse_count_arg(num, "--limit")?);
+    }
+    if let Some(num) = arg_matches.value_of("threads") {
+        builder = builder.threads(parse_threads(num)?);
+    }
+    if let Some(num) = arg_matches.value_of("hintgroups") {
+        builder = builder.group_hint(parse_count_arg(num, "--hint-groups")?);
+    }
+    if let Some(num) = arg_matches.value_of("maxcells") {
+        builder = builder.max_cells(parse_count_arg(num, "--max-cells")?);
+    }
+    if let Some(size) = arg_matches.value_of("maxmemory") {
+        builder = builder.max_memory(parse_size_arg(size, "--max-memory")?);
+    }
+    if let Some(sample) = get_sample(arg_matches)? {
+        builder = builder.sample(sample);
+    }
+    if let Some(seed) = arg_matches.value_of("seed") {
+        builder = builder.seed(seed.parse().map_err(|_| {
+            CsvCliError::InvalidConfiguration(format!(
+                "--seed needs to be a whole number between 0 and {}, not `{}`",
+                u64::MAX,
+                seed
+            ))
+        })?);
+    }
+    Ok(builder)
 }
 
-pub fn run() -> CsvCliResult<()> {
-    match CLI_ARGS.value_of("aggfunc").unwrap() {
-        "count" => run_and_init::<Count<String>, String, usize>(&CLI_ARGS, ParsingStrategy::Text),
-        "countunique" => {
-            run_and_init::<CountUnique<String>, String, usize>(&CLI_ARGS, ParsingStrategy::Text)
-        }
-        "mode" => run_and_init::<Mode<String>, String, String>(&CLI_ARGS, ParsingStrategy::Text),
-        "max" if (CLI_ARGS.is_present("numeric") && CLI_ARGS.is_present("format")) => {
-            Err(CsvCliError::InvalidConfiguration(
-                "You can only enter one of the -N and -F flags/options".to_string(),
-            ))
+/// Builds the pivot table the command-line arguments ask for. In a terminal, a function that
+/// needs a values column but didn't get one asks for it, once it has read the header row.
+fn get_pivot(arg_matches: &ArgMatches) -> CsvCliResult<Pivot> {
+    let builder = get_pivot_builder(arg_matches)?;
+    let filename = arg_matches.value_of("filename");
+    let builder = match filename {
+        Some(filename) if builder.needs_values() && interactive::can_prompt() => {
+            let settings = get_reader_settings(arg_matches)?;
+            let headers: Vec<String> = settings
+                .get_reader_from_path(filename)?
+                .headers()?
+                .iter()
+                .map(String::from)
+                .collect();
+            let aggfunc = arg_matches.value_of("aggfunc").unwrap_or_default();
+            let field = interactive::prompt_for_values_field(aggfunc, &headers)?;
+            builder.values(&field)
         }
-        "max" if CLI_ARGS.is_present("numeric") => {
-            run_and_init::<Maximum<f64>, f64, f64>(&CLI_ARGS, ParsingStrategy::Numeric)
-        }
-        "max" if CLI_ARGS.is_present("format") => run_and_init::<
-            Maximum<CustomDateObject>,
-            CustomDateObject,
-            CustomDateObject,
-        >(&CLI_ARGS, ParsingStrategy::Date),
-        "max" => run_and_init::<Maximum<String>, String, String>(&CLI_ARGS, ParsingStrategy::Text),
-        "mean" => run_and_init::<Mean, DecimalWrapper, DecimalWrapper>(
-            &CLI_ARGS,
-            ParsingStrategy::Numeric,
-        ),
-        "median" => run_and_init::<Median, DecimalWrapper, DecimalWrapper>(
-            &CLI_ARGS,
-            ParsingStrategy::Numeric,
-        ),
-        "min" if (CLI_ARGS.is_present("numeric") && CLI_ARGS.is_present("format")) => {
-            Err(CsvCliError::InvalidConfiguration(
-                "You can only enter one of the -N and -F flags/options".to_string(),
-            ))
+        _ => builder,
+    };
+    builder.build()
+}
+
+/// Runs the pivot table (or `bench` or `validate`), with the types `Pivot::dispatch` picks
+/// for its function
+struct CliRun<'a> {
+    arg_matches: &'a ArgMatches<'a>,
+    pivot: &'a Pivot,
+}
+
+impl PivotTask for CliRun<'_> {
+    type Output = ();
+
+    fn run<T, I, O>(self, parser: ValueParser<I>) -> CsvCliResult<()>
+    where
+        T: Accumulate<I, O> + Sync + 'static,
+        I: std::str::FromStr + Clone + Send + 'static,
+        O: WriteCell + 'static,
+    {
+        let (arg_matches, pivot) = (self.arg_matches, self.pivot);
+        if arg_matches.is_present("bench") {
+            return run_benchmark::<T, I, O>(arg_matches, pivot, parser);
         }
-        "min" if CLI_ARGS.is_present("numeric") => {
-            run_and_init::<Minimum<f64>, f64, f64>(&CLI_ARGS, ParsingStrategy::Numeric)
-        }
-        "min" if CLI_ARGS.is_present("format") => run_and_init::<
-            Minimum<CustomDateObject>,
-            CustomDateObject,
-            CustomDateObject,
-        >(&CLI_ARGS, ParsingStrategy::Date),
-        "min" => run_and_init::<Minimum<String>, String, String>(&CLI_ARGS, ParsingStrategy::Text),
-        "minmax" if (CLI_ARGS.is_present("numeric") && CLI_ARGS.is_present("format")) => {
-            Err(CsvCliError::InvalidConfiguration(
-                "You can only enter one of the -N and -F flags/options".to_string(),
-            ))
+        if arg_matches.is_present("validate") {
+            return validate::<T, I, O>(arg_matches, pivot, parser);
         }
-        "minmax" if CLI_ARGS.is_present("numeric") => {
-            run_and_init::<MinMax<f64>, f64, String>(&CLI_ARGS, ParsingStrategy::Numeric)
-        }
-        "minmax" if CLI_ARGS.is_present("format") => run_and_init::<
-            MinMax<CustomDateObject>,
-            CustomDateObject,
-            String,
-        >(&CLI_ARGS, ParsingStrategy::Date),
-        "minmax" => {
-            run_and_init::<MinMax<String>, String, String>(&CLI_ARGS, ParsingStrategy::Text)
-        }
-        "range" if CLI_ARGS.is_present("format") => run_and_init::<
-            Range<CustomDateObject, f64>,
-            CustomDateObject,
-            f64,
-        >(&CLI_ARGS, ParsingStrategy::Date),
-        "range" => run_and_init::<Range<DecimalWrapper, Decimal>, DecimalWrapper, Decimal>(
-            &CLI_ARGS,
-            ParsingStrategy::Numeric,
-        ),
-        "stddev" => run_and_init::<StdDev, f64, f64>(&CLI_ARGS, ParsingStrategy::Numeric),
-        "sum" => run_and_init::<Sum<DecimalWrapper>, DecimalWrapper, DecimalWrapper>(
-            &CLI_ARGS,
-            ParsingStrategy::Numeric,
-        ),
-        _ => unreachable!(),
+        let settings = pivot.settings();
+        if let Some(filepath) = arg_matches.value_of("filename") {
+            let rdr = settings.get_reader_from_path(filepath)?;
+            agg_from_reader::<T, I, O, std::fs::File>(arg_matches, pivot, rdr, parser)
+        } else {
+            let rdr = settings.get_reader_from_stdin();
+            agg_from_reader::<T, I, O, io::Stdin>(arg_matches, pivot, rdr, parser)
+        }
+    }
+}
+
+pub fn run() -> CsvCliResult<()> {
+    logging::set_verbosity(CLI_ARGS.occurrences_of("verbose") as usize);
+    logging::set_quiet(CLI_ARGS.is_present("quiet"));
+    if CLI_ARGS.is_present("listaggregations") {
+        return write_aggregations(&mut io::stdout());
+    }
+    if CLI_ARGS.is_present("capabilities") {
+        return write_capabilities(&mut io::stdout());
     }
+    let pivot = get_pivot(&CLI_ARGS)?;
+    pivot.dispatch(CliRun {
+        arg_matches: &CLI_ARGS,
+        pivot: &pivot,
+    })
 }
@@ -71,6 +71,17 @@
 //! - --option col1,col2
 //! - --option col1 col2
 //! - --option col1 --option col2
+//! - --option 0-1
+//!
+//! Numeric ranges work like they do in `xsv select`, so `3-1` selects the fourth, third,
+//! and second columns (in that order) and `2-` selects every column from the third onward.
+//!
+//! Names can also contain shell-style wildcards, so `score_*` selects every column whose name
+//! starts with `score_`. (Wrap a name in quotes if it contains a literal `*` or `?`.)
+//!
+//! Finally, prefixing a selection with `!` excludes it. On its own, `!col1,!col3` selects
+//! every column *except* the ones named `col1` and `col3`, while `0-3,!col2` selects the first four
+//! columns aside from `col2`.
 //!
 //! In order to convert the user's selection into a list of unsigned integers
 //! so your program can more easily retrieve the value of a given row at a column the user selected,
@@ -96,17 +107,23 @@
 //! returns the first row of your file regardless of whether or not the file has a header row,
 //! you don't need to change a line of code to get it to work.
 use crate::errors::{CsvCliError, CsvCliResult};
+use crate::keys::{KeyField, RECORD_NUMBER_FIELD};
+use crate::logging;
+use regex::Regex;
 use std::fs;
 use std::io;
 
 /// The core struct of the settings module, providing general settings and utilities for
 /// writing CSV command-line tools.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CsvSettings {
     /// The column separator (e.g. '\t' for TSV files, ',' for CSV, etc.)
     delimiter: u8,
     /// Whether or not the CSV file has a field separator
     has_header: bool,
+    /// Whether column names have to match headers exactly, or whether they can
+    /// differ in case and surrounding whitespace
+    strict_headers: bool,
 }
 
 impl Default for CsvSettings {
@@ -114,6 +131,7 @@ impl Default for CsvSettings {
         CsvSettings {
             delimiter: b',',
             has_header: true,
+            strict_headers: false,
         }
     }
 }
@@ -126,14 +144,49 @@ impl CsvSettings {
         delim: Option<&str>,
         has_header: bool,
     ) -> CsvCliResult<CsvSettings> {
-        let delimiter = CsvSettings::parse_delimiter(&fname, delim)?;
+        let delimiter = CsvSettings::parse_delimiter(fname, delim)?;
         let settings = CsvSettings {
             delimiter,
             has_header,
+            strict_headers: false,
         };
         Ok(settings)
     }
 
+    /// Creates a new `CsvSettings` struct from a delimiter that's already a single byte.
+    pub fn new(delimiter: u8, has_header: bool) -> CsvSettings {
+        CsvSettings {
+            delimiter,
+            has_header,
+            strict_headers: false,
+        }
+    }
+
+    /// Requires column names to exactly match the names in the header row.
+    ///
+    /// By default, if a column name doesn't exactly match any header, `CsvSettings` falls back
+    /// to matching headers case-insensitively and ignoring surrounding whitespace, since exported
+    /// headers frequently carry stray spaces or inconsistent capitalization.
+    pub fn with_strict_headers(mut self, strict_headers: bool) -> Self {
+        self.strict_headers = strict_headers;
+        self
+    }
+
+    /// The byte that separates fields
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Whether the first row of the file is a header row
+    pub fn has_header(&self) -> bool {
+        self.has_header
+    }
+
+    /// Whether column names have to exactly match the names in the header row
+    pub fn strict_headers(&self) -> bool {
+        self.strict_headers
+    }
+
     /// Returns a `csv::Reader` object from a filepath, returning an error if the file doesn't exist.
     pub fn get_reader_from_path(&self, filename: &str) -> csv::Result<csv::Reader<fs::File>> {
         csv::ReaderBuilder::new()
@@ -145,11 +198,17 @@ impl CsvSettings {
 
     /// Returns a `csv::Reader` object from standard input.
     pub fn get_reader_from_stdin(&self) -> csv::Reader<io::Stdin> {
+        self.get_reader(io::stdin())
+    }
+
+    /// Returns a `csv::Reader` object from anything that implements `Read` (like data that's
+    /// already in memory).
+    pub fn get_reader<R: io::Read>(&self, rdr: R) -> csv::Reader<R> {
         csv::ReaderBuilder::new()
             .delimiter(self.delimiter)
             .trim(csv::Trim::All)
             .has_headers(self.has_header)
-            .from_reader(io::stdin())
+            .from_reader(rdr)
     }
 
     /// Parses the 1-byte value of a delimiter, for parsing as a CSV
@@ -186,7 +245,7 @@ impl CsvSettings {
     /// Returns a single index where a single string appears. Allows you to validate a single column, rather
     /// than multiple columns.
     pub fn get_field_index(&self, colname: &str, headers: &Vec<&str>) -> CsvCliResult<usize> {
-        let infered_num = match self.get_numeric_index(&colname) {
+        let infered_num = match self.get_numeric_index(colname) {
             Some(num) if num < headers.len() => Ok(Some(num)),
             Some(_num) => Err(CsvCliError::InvalidConfiguration(format!(
                 "Could not properly configure. Column selection needs to be between 0 and `{}`",
@@ -201,7 +260,7 @@ impl CsvSettings {
         if let Some(num) = infered_num {
             return Ok(num);
         }
-        let str_idx = self.get_string_index(&colname, headers)?;
+        let str_idx = self.get_string_index(colname, headers)?;
         Ok(str_idx)
     }
 
@@ -215,16 +274,158 @@ impl CsvSettings {
         headers: &Vec<&str>,
     ) -> CsvCliResult<Vec<usize>> {
         let mut output_vec = Vec::new();
+        let mut excluded = Vec::new();
         for user_input in user_defs {
             let all_cols = self.split_arg_string(user_input);
             for colname in all_cols {
-                let idx = self.get_field_index(&colname, headers)?;
-                output_vec.push(idx);
+                let trimmed = colname.trim_start();
+                if let Some(negated) = trimmed.strip_prefix('!') {
+                    excluded.extend(self.get_selection_indexes(negated, headers)?);
+                } else {
+                    output_vec.extend(self.get_selection_indexes(&colname, headers)?);
+                }
             }
         }
+        if excluded.is_empty() {
+            return Ok(output_vec);
+        }
+        // something like `!id,!timestamp` on its own means "every column except these"
+        if output_vec.is_empty() {
+            output_vec = (0..headers.len()).collect();
+        }
+        output_vec.retain(|idx| !excluded.contains(idx));
         Ok(output_vec)
     }
 
+    /// Like `get_field_indexes`, but for row and column fields, which can also include
+    /// the record number (the `@index` pseudo-column) alongside the columns of the file.
+    pub fn get_key_fields(
+        &self,
+        user_defs: &Vec<&str>,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Vec<KeyField>> {
+        let mut names = Vec::new();
+        // the number of column descriptions appearing before `@index`
+        let mut record_number_pos = None;
+        for user_input in user_defs {
+            for colname in self.split_arg_string(user_input) {
+                if colname.trim() == RECORD_NUMBER_FIELD {
+                    record_number_pos.get_or_insert(names.len());
+                } else {
+                    names.push(colname);
+                }
+            }
+        }
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut fields: Vec<KeyField> = self
+            .get_field_indexes(&names, headers)?
+            .into_iter()
+            .map(KeyField::column)
+            .collect();
+        if let Some(pos) = record_number_pos {
+            let num_before = self
+                .get_field_indexes(&names[..pos].to_vec(), headers)?
+                .len();
+            fields.insert(num_before.min(fields.len()), KeyField::record_number());
+        }
+        Ok(fields)
+    }
+
+    /// Returns the indexes described by a single item in a list of columns
+    /// (either a numeric range or a single field).
+    fn get_selection_indexes(
+        &self,
+        colname: &str,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Vec<usize>> {
+        if let Some((start, end)) = self.get_numeric_range(colname) {
+            return self.expand_range(start, end, headers);
+        }
+        if is_glob_pattern(colname) && self.has_header {
+            return self.get_glob_indexes(colname.trim(), headers);
+        }
+        Ok(vec![self.get_field_index(colname, headers)?])
+    }
+
+    /// Returns the indexes of every header matching a shell-style wildcard pattern
+    /// (where `*` matches any number of characters and `?` matches a single character).
+    fn get_glob_indexes(&self, pattern: &str, headers: &[&str]) -> CsvCliResult<Vec<usize>> {
+        let matches: Vec<usize> = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| {
+                glob_match(pattern, field)
+                    || (!self.strict_headers
+                        && glob_match(&normalize_header(pattern), &normalize_header(field)))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if matches.is_empty() {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "The pattern `{}` did not match any columns in the header row",
+                pattern
+            )));
+        }
+        Ok(matches)
+    }
+
+    /// Converts a numeric range (with an optional end) into the list of indexes it describes.
+    /// Like `xsv select`, this allows ranges like `3-1` to appear in reverse order and
+    /// open-ended ranges like `2-` to run through the final column.
+    fn expand_range(
+        &self,
+        start: usize,
+        end: Option<usize>,
+        headers: &[&str],
+    ) -> CsvCliResult<Vec<usize>> {
+        let end = end.unwrap_or_else(|| headers.len().saturating_sub(1));
+        if start >= headers.len() || end >= headers.len() {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "Could not properly configure. Column ranges need to be between 0 and `{}`",
+                headers.len()
+            )));
+        }
+        if start <= end {
+            Ok((start..=end).collect())
+        } else {
+            Ok((end..=start).rev().collect())
+        }
+    }
+
+    /// Returns the indexes of every header matching a regular expression, in the order
+    /// in which they appear in the header row. This is mostly useful for wide files
+    /// (like surveys) where dozens of columns share a common prefix or suffix.
+    pub fn get_regex_indexes(
+        &self,
+        pattern: &str,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Vec<usize>> {
+        if !self.has_header {
+            return Err(CsvCliError::InvalidConfiguration(
+                "Columns must be numeric if you don't have a header".to_string(),
+            ));
+        }
+        let re = Regex::new(pattern).map_err(|err| {
+            CsvCliError::InvalidConfiguration(format!(
+                "Could not parse the regular expression `{}`: {}",
+                pattern, err
+            ))
+        })?;
+        let matches: Vec<usize> = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| re.is_match(field))
+            .map(|(i, _)| i)
+            .collect();
+        if matches.is_empty() {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "The regular expression `{}` did not match any columns in the header row",
+                pattern
+            )));
+        }
+        Ok(matches)
+    }
+
     fn split_arg_string(&self, combined_cols: &str) -> Vec<String> {
         let mut split_strings = Vec::new();
         // quote_char represents whether or not we're inside quotes
@@ -256,7 +457,7 @@ impl CsvSettings {
         // ignore leading whitespace
         let parsed_str = colname.trim();
         // because of `unwrap` at the end here, we need to check for empty string
-        if parsed_str == "" {
+        if parsed_str.is_empty() {
             return None;
         }
         for char in parsed_str.chars() {
@@ -267,6 +468,18 @@ impl CsvSettings {
         Some(parsed_str.parse().unwrap())
     }
 
+    /// Parses strings like `0-2` or `3-` into a start index and an (optional) end index.
+    fn get_numeric_range(&self, colname: &str) -> Option<(usize, Option<usize>)> {
+        let mut parts = colname.trim().splitn(2, '-');
+        let start = self.get_numeric_index(parts.next()?)?;
+        let end_str = parts.next()?;
+        if end_str.trim().is_empty() {
+            return Some((start, None));
+        }
+        let end = self.get_numeric_index(end_str)?;
+        Some((start, Some(end)))
+    }
+
     fn get_string_index(&self, colname: &str, headers: &Vec<&str>) -> CsvCliResult<usize> {
         // same implementation here as in `split_arg_string`
         let mut quote_char = None;
@@ -300,16 +513,60 @@ impl CsvSettings {
             }
         }
         // TODO Figure out the best way to handle this; deserializing and reserializing isn't great
-        if expected_order.is_empty() {
+        let explicit_order = !expected_order.is_empty();
+        if !explicit_order {
             expected_order = "0".to_string();
         }
-        let order = expected_order.parse::<usize>().or_else(|_| {
-            Err(CsvCliError::InvalidConfiguration(format!(
+        let order = expected_order.parse::<usize>().map_err(|_| {
+            CsvCliError::InvalidConfiguration(format!(
                 "Could not convert column name `{}`. Hint: consider enclosing the column in quotes",
                 colname
-            )))
+            ))
         })?;
-        self.find_index_from_expected(&expected_header, order, headers)
+        let idx = self.find_index_from_expected(&expected_header, order, headers)?;
+        if !explicit_order {
+            if let Some(warning) = self.describe_ambiguity(&expected_header, idx, headers) {
+                logging::warn(format_args!("Warning: {}", warning));
+            }
+        }
+        Ok(idx)
+    }
+
+    /// Describes the problem if a column name (without a bracketed position) matches more than one header.
+    fn describe_ambiguity(
+        &self,
+        expected_header: &str,
+        idx: usize,
+        headers: &[&str],
+    ) -> Option<String> {
+        let num_matches = if headers[idx] == expected_header {
+            headers
+                .iter()
+                .filter(|&&field| field == expected_header)
+                .count()
+        } else {
+            let normalized = normalize_header(expected_header);
+            headers
+                .iter()
+                .filter(|field| normalize_header(field) == normalized)
+                .count()
+        };
+        if num_matches <= 1 {
+            return None;
+        }
+        let hint = if num_matches == 2 {
+            format!("Use `{}[1]` to select the other one.", expected_header)
+        } else {
+            format!(
+                "Use `{0}[1]` through `{0}[{1}]` to select one of the others.",
+                expected_header,
+                num_matches - 1
+            )
+        };
+        Some(format!(
+            "`{}` matches {} columns in the header row, so the first one (column {}) is being used. {}",
+            expected_header, num_matches, idx, hint
+        ))
     }
 
     fn find_index_from_expected(
@@ -318,20 +575,160 @@ impl CsvSettings {
         expected_order: usize,
         headers: &Vec<&str>,
     ) -> CsvCliResult<usize> {
-        let mut count = 0;
-        for (i, field) in headers.iter().enumerate() {
-            if &expected_header == field {
-                if count == expected_order {
-                    return Ok(i);
+        let exact_match = find_nth_match(headers, expected_order, |field| expected_header == field);
+        if let Some(idx) = exact_match {
+            return Ok(idx);
+        }
+        if !self.strict_headers {
+            let normalized = normalize_header(expected_header);
+            let loose_match = find_nth_match(headers, expected_order, |field| {
+                normalize_header(field) == normalized
+            });
+            if let Some(idx) = loose_match {
+                return Ok(idx);
+            }
+        }
+        // a position past the last of the matching headers shouldn't suggest the name itself
+        let num_matches = if self.strict_headers {
+            headers
+                .iter()
+                .filter(|&&field| field == expected_header)
+                .count()
+        } else {
+            let normalized = normalize_header(expected_header);
+            headers
+                .iter()
+                .filter(|field| normalize_header(field) == normalized)
+                .count()
+        };
+        if num_matches > 0 {
+            let columns = if num_matches == 1 {
+                format!("1 column, so use `{}[0]`", expected_header)
+            } else {
+                format!(
+                    "{} columns, so use `{1}[0]` through `{1}[{2}]`",
+                    num_matches,
+                    expected_header,
+                    num_matches - 1
+                )
+            };
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "Could not find `{}[{}]` in header row: `{}` only matches {}",
+                expected_header, expected_order, expected_header, columns
+            )));
+        }
+        let msg = match closest_match(expected_header, headers) {
+            Some(suggestion) => format!(
+                "Could not find `{}` in header row; did you mean `{}`?",
+                expected_header, suggestion
+            ),
+            None => format!("Could not find `{}` in header row", expected_header),
+        };
+        Err(CsvCliError::InvalidConfiguration(msg))
+    }
+}
+
+/// Whether a column name should be treated as a wildcard pattern. Quoted names never are,
+/// so you can still select a column with a literal `*` in its name by quoting it.
+fn is_glob_pattern(colname: &str) -> bool {
+    let trimmed = colname.trim();
+    !(trimmed.starts_with('\'') || trimmed.starts_with('"'))
+        && (trimmed.contains('*') || trimmed.contains('?'))
+}
+
+/// Matches text against a shell-style wildcard pattern.
+// adapted from the iterative algorithm in https://research.swtch.com/glob
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p_idx, mut t_idx) = (0, 0);
+    // where to restart if we hit a mismatch after a `*`
+    let mut restart: Option<(usize, usize)> = None;
+    while p_idx < pattern.len() || t_idx < text.len() {
+        if p_idx < pattern.len() {
+            match pattern[p_idx] {
+                '*' => {
+                    restart = Some((p_idx, t_idx + 1));
+                    p_idx += 1;
+                    continue;
                 }
-                count += 1;
+                '?' if t_idx < text.len() => {
+                    p_idx += 1;
+                    t_idx += 1;
+                    continue;
+                }
+                c if t_idx < text.len() && c == text[t_idx] => {
+                    p_idx += 1;
+                    t_idx += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match restart {
+            Some((next_p, next_t)) if next_t <= text.len() => {
+                p_idx = next_p;
+                t_idx = next_t;
             }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Returns the index of the `n`th (0-indexed) header matching a condition.
+fn find_nth_match<F>(headers: &[&str], n: usize, matches: F) -> Option<usize>
+where
+    F: Fn(&str) -> bool,
+{
+    headers
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| matches(field))
+        .map(|(i, _)| i)
+        .nth(n)
+}
+
+/// Returns the candidate that's closest to `target` (by edit distance, ignoring case), as long as it's
+/// close enough to plausibly be a typo. This is used to suggest fixes in error messages.
+pub fn closest_match<'a>(target: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let target = normalize_header(target);
+    // allow roughly one typo for every three characters
+    let max_distance = (target.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|candidate| {
+            (
+                edit_distance(&target, &normalize_header(candidate)),
+                *candidate,
+            )
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Computes the Levenshtein distance between two strings, using the two-row version of the
+/// [Wagner-Fischer algorithm](https://en.wikipedia.org/wiki/Wagner%E2%80%93Fischer_algorithm).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut prev_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut cur_row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = prev_row[j] + if a_char == *b_char { 0 } else { 1 };
+            let deletion = prev_row[j + 1] + 1;
+            let insertion = cur_row[j] + 1;
+            cur_row.push(substitution.min(deletion).min(insertion));
         }
-        Err(CsvCliError::InvalidConfiguration(format!(
-            "Could not find `{}` in header row",
-            expected_header
-        )))
+        prev_row = cur_row;
     }
+    prev_row[b_chars.len()]
+}
+
+/// Converts a header into a case- and whitespace-insensitive form.
+fn normalize_header(header: &str) -> String {
+    header.trim().to_lowercase()
 }
 
 #[cfg(test)]
@@ -396,7 +793,7 @@ mod tests {
             let settings = CsvSettings::default();
             assert_eq!(settings.get_string_index(&s, &header).unwrap(), 0);
             let header = vec![s.as_ref(), s.as_ref()];
-            let new_str = format!("{}{}", s, "[1]".to_string());
+            let new_str = format!("{}{}", s, "[1]");
             assert_eq!(settings.get_string_index(&new_str, &header).unwrap(), 1);
         }
     }
@@ -484,6 +881,167 @@ mod tests {
         assert!(settings.get_numeric_index("").is_none());
     }
 
+    #[test]
+    fn test_numeric_ranges() {
+        let settings = CsvSettings::default();
+        let header = vec!["a", "b", "c", "d", "e", "f"];
+        assert_eq!(
+            settings.get_field_indexes(&vec!["0-2"], &header).unwrap(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            settings.get_field_indexes(&vec!["3-1"], &header).unwrap(),
+            vec![3, 2, 1]
+        );
+        assert_eq!(
+            settings.get_field_indexes(&vec!["4-"], &header).unwrap(),
+            vec![4, 5]
+        );
+        assert_eq!(
+            settings.get_field_indexes(&vec!["0,3,5"], &header).unwrap(),
+            vec![0, 3, 5]
+        );
+        assert_eq!(
+            settings.get_field_indexes(&vec!["a,2-3"], &header).unwrap(),
+            vec![0, 2, 3]
+        );
+        assert!(settings.get_field_indexes(&vec!["4-6"], &header).is_err());
+        // a non-numeric name with a hyphen is still treated as a name
+        assert!(settings.get_numeric_range("a-b").is_none());
+    }
+
+    #[test]
+    fn test_ambiguous_headers() {
+        let header = vec!["amount", "state", "amount", "Amount"];
+        let settings = CsvSettings::default();
+        let warning = settings.describe_ambiguity("amount", 0, &header).unwrap();
+        assert!(warning.contains("matches 2 columns"));
+        assert!(warning.contains("Use `amount[1]` to select the other one."));
+        assert!(settings.describe_ambiguity("state", 1, &header).is_none());
+        assert_eq!(settings.get_field_index("amount[1]", &header).unwrap(), 2);
+        let err = settings.get_field_index("amount[3]", &header).unwrap_err();
+        assert!(err.to_string().contains(
+            "Could not find `amount[3]` in header row: `amount` only matches 3 columns, so use `amount[0]` through `amount[2]`"
+        ));
+        let err = settings.get_field_index("state[1]", &header).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only matches 1 column, so use `state[0]`"));
+        assert!(!err.to_string().contains("did you mean"));
+    }
+
+    #[test]
+    fn test_glob_selection() {
+        assert!(glob_match("score_*", "score_math"));
+        assert!(glob_match("*_2019", "sales_2019"));
+        assert!(glob_match("q?", "q1"));
+        assert!(!glob_match("q?", "q10"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+        let settings = CsvSettings::default();
+        let header = vec!["id", "score_math", "Score_Reading", "score*"];
+        assert_eq!(
+            settings
+                .get_field_indexes(&vec!["score_*"], &header)
+                .unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            settings
+                .get_field_indexes(&vec!["'score*'"], &header)
+                .unwrap(),
+            vec![3]
+        );
+        assert!(settings.get_field_indexes(&vec!["zip*"], &header).is_err());
+    }
+
+    #[test]
+    fn test_typo_suggestions() {
+        assert_eq!(edit_distance("amout", "amount"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        let header = vec!["id", "amount", "state"];
+        assert_eq!(closest_match("amout", &header), Some("amount"));
+        assert_eq!(closest_match("Stat", &header), Some("state"));
+        assert_eq!(closest_match("zipcode", &header), None);
+        let settings = CsvSettings::default();
+        let err = settings.get_field_index("amout", &header).unwrap_err();
+        assert!(err.to_string().contains("did you mean `amount`?"));
+    }
+
+    #[test]
+    fn test_loose_header_matching() {
+        let header = vec!["Amount ", "STATE", "state"];
+        let settings = CsvSettings::default();
+        assert_eq!(settings.get_field_index("amount", &header).unwrap(), 0);
+        // exact matches take priority over loose ones
+        assert_eq!(settings.get_field_index("state", &header).unwrap(), 2);
+        assert_eq!(settings.get_field_index("State[1]", &header).unwrap(), 2);
+        let strict = CsvSettings::default().with_strict_headers(true);
+        assert!(strict.get_field_index("amount", &header).is_err());
+        assert_eq!(strict.get_field_index("STATE", &header).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let settings = CsvSettings::default();
+        let header = vec!["id", "timestamp", "state", "amount"];
+        assert_eq!(
+            settings
+                .get_field_indexes(&vec!["!id,!timestamp"], &header)
+                .unwrap(),
+            vec![2, 3]
+        );
+        assert_eq!(
+            settings
+                .get_field_indexes(&vec!["0-2", "!1"], &header)
+                .unwrap(),
+            vec![0, 2]
+        );
+        assert_eq!(
+            settings.get_field_indexes(&vec!["!0-1"], &header).unwrap(),
+            vec![2, 3]
+        );
+        assert!(settings
+            .get_field_indexes(&vec!["!nonexistent"], &header)
+            .is_err());
+    }
+
+    #[test]
+    fn test_regex_indexes() {
+        let settings = CsvSettings::default();
+        let header = vec!["id", "demo_age", "demo_sex", "q1", "demo_zip"];
+        assert_eq!(
+            settings.get_regex_indexes("^demo_", &header).unwrap(),
+            vec![1, 2, 4]
+        );
+        assert!(settings.get_regex_indexes("^nothing", &header).is_err());
+        assert!(settings.get_regex_indexes("(unclosed", &header).is_err());
+        let no_header_set = CsvSettings::parse_new(&None, None, false).unwrap();
+        assert!(no_header_set.get_regex_indexes("^demo_", &header).is_err());
+    }
+
+    #[test]
+    fn test_key_fields_with_record_number() {
+        let settings = CsvSettings::default();
+        let header = vec!["a", "b", "c"];
+        assert_eq!(
+            settings
+                .get_key_fields(&vec!["0-1,@index", "c"], &header)
+                .unwrap(),
+            vec![
+                KeyField::column(0),
+                KeyField::column(1),
+                KeyField::record_number(),
+                KeyField::column(2)
+            ]
+        );
+        assert_eq!(
+            settings.get_key_fields(&vec!["@index"], &header).unwrap(),
+            vec![KeyField::record_number()]
+        );
+    }
+
     #[test]
     fn test_no_header_doesnt_parse() {
         let no_header_set = CsvSettings::parse_new(&None, None, false).unwrap();
@@ -0,0 +1,494 @@
+//! The module for reading configuration files.
+//!
+//! A configuration file sets defaults for the command-line options (like the delimiter or the
+//! date format), so a team can share the same settings across projects. Configuration files
+//! are written in a subset of [TOML](https://toml.io): `key = value` pairs, where values are
+//! strings, booleans, numbers, or arrays of those, grouped into `[sections]`. Keys inside of
+//! a section are stored with the name of the section in front of them (so `args` in the
+//! section `[presets.monthly]` becomes `presets.monthly.args`).
+//!
+//! By default, the configuration file gets read from `clipivot/config.toml` inside of
+//! `$XDG_CONFIG_HOME` (or `~/.config`, if that isn't set).
+use crate::errors::{CsvCliError, CsvCliResult};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A value in a configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Text(String),
+    Bool(bool),
+    /// A number, stored as it was written
+    Number(String),
+    List(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    /// The value as an argument on the command line, if it's a string or a number
+    pub fn as_arg(&self) -> Option<String> {
+        match self {
+            ConfigValue::Text(text) => Some(text.clone()),
+            ConfigValue::Number(num) => Some(num.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The settings in a configuration file, in the order they appear
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    entries: Vec<(String, ConfigValue)>,
+}
+
+impl Config {
+    /// Reads a configuration file
+    pub fn from_path<P: AsRef<Path>>(path: P) -> CsvCliResult<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| {
+            CsvCliError::InvalidConfiguration(format!(
+                "Could not read the config file `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        Config::parse(&text).map_err(|err| {
+            CsvCliError::InvalidConfiguration(format!(
+                "Could not parse the config file `{}`: {}",
+                path.display(),
+                err
+            ))
+        })
+    }
+
+    /// The location of the default configuration file, whether or not it exists
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_dir.join("clipivot").join("config.toml"))
+    }
+
+    /// Parses the text of a configuration file
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut entries = Vec::new();
+        let mut section = String::new();
+        let mut lines = text.lines().enumerate();
+        while let Some((line_num, line)) = lines.next() {
+            let describe = |err: String| format!("line {}: {}", line_num + 1, err);
+            let trimmed = strip_comment(line).trim().to_string();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if trimmed.starts_with('[') {
+                let name = trimmed
+                    .strip_prefix('[')
+                    .and_then(|name| name.strip_suffix(']'))
+                    .ok_or_else(|| describe("expected a section header like `[name]`".into()))?;
+                section = split_unquoted(name, '.')
+                    .map(|key| parse_key(key.trim()))
+                    .collect::<Result<Vec<String>, String>>()
+                    .map_err(describe)?
+                    .join(".");
+                continue;
+            }
+            // quoted keys can have an `=` in them, so the value starts after the first one outside
+            // of a string
+            let pos = find_unquoted(&trimmed, '=')
+                .ok_or_else(|| describe("expected a setting like `key = value`".into()))?;
+            let key = parse_key(trimmed[..pos].trim()).map_err(describe)?;
+            let mut raw_value = trimmed[pos + 1..].trim().to_string();
+            // arrays can continue onto the next lines, until their brackets are balanced
+            while raw_value.starts_with('[') && !is_balanced(&raw_value) {
+                match lines.next() {
+                    Some((_, next)) => {
+                        raw_value.push(' ');
+                        raw_value.push_str(strip_comment(next).trim());
+                    }
+                    None => return Err(describe("this array is never closed".into())),
+                }
+            }
+            let (value, rest) = parse_value(&raw_value).map_err(describe)?;
+            if !rest.trim().is_empty() {
+                return Err(describe(format!("unexpected text `{}`", rest.trim())));
+            }
+            let key = if section.is_empty() {
+                key
+            } else {
+                format!("{}.{}", section, key)
+            };
+            entries.push((key, value));
+        }
+        Ok(Config { entries })
+    }
+
+    /// The value of a setting (like `delim` or `presets.monthly.args`), if it's set.
+    /// If a setting appears more than once, the last one wins.
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// The arguments saved in the preset `name` (under `[presets.name]`), if there is one
+    pub fn preset(&self, name: &str) -> CsvCliResult<Option<Vec<String>>> {
+        let args = match self.get(&format!("presets.{}.args", name)) {
+            Some(args) => args,
+            None => return Ok(None),
+        };
+        let invalid = || {
+            CsvCliError::InvalidConfiguration(format!(
+                "The arguments of the preset `{}` need to be an array of strings",
+                name
+            ))
+        };
+        match args {
+            ConfigValue::List(items) => items
+                .iter()
+                .map(|item| item.as_arg().ok_or_else(invalid))
+                .collect::<CsvCliResult<Vec<String>>>()
+                .map(Some),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The settings that aren't inside of a section
+    pub fn top_level(&self) -> impl Iterator<Item = (&str, &ConfigValue)> {
+        self.entries
+            .iter()
+            .filter(|(name, _)| !name.contains('.'))
+            .map(|(name, value)| (name.as_str(), value))
+    }
+}
+
+/// Adds a preset to the end of a configuration file, creating the file
+/// (and its directory) if it doesn't exist
+pub fn save_preset(path: &Path, name: &str, args: &[String]) -> CsvCliResult<()> {
+    let name = parse_key(name).map_err(|_| {
+        CsvCliError::InvalidConfiguration(format!(
+            "`{}` is not a valid name for a preset. Names can only have letters, numbers, `-`, and `_`",
+            name
+        ))
+    })?;
+    let config = if path.exists() {
+        Config::from_path(path)?
+    } else {
+        Config::default()
+    };
+    if config.preset(&name).ok().flatten().is_some() {
+        return Err(CsvCliError::InvalidConfiguration(format!(
+            "There is already a preset named `{}` in `{}`",
+            name,
+            path.display()
+        )));
+    }
+    let quoted: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+    let preset = format!("\n[presets.{}]\nargs = [{}]\n", name, quoted.join(", "));
+    let write_preset = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(preset.as_bytes())
+    };
+    write_preset().map_err(|err| {
+        CsvCliError::InvalidConfiguration(format!(
+            "Could not write to the config file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+/// Writes text as a basic (double-quoted) string
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Removes a comment (starting with `#`) from a line, unless the `#` is inside of a string
+fn strip_comment(line: &str) -> &str {
+    match find_unquoted(line, '#') {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// The position of the first `target` in `text` that isn't inside of a string
+fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, c) in text.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, c) if c == target => return Some(idx),
+            _ => {}
+        }
+        escaped = false;
+    }
+    None
+}
+
+/// Splits `text` at each `sep` that isn't inside of a string
+fn split_unquoted(text: &str, sep: char) -> impl Iterator<Item = &str> {
+    let mut rest = Some(text);
+    std::iter::from_fn(move || {
+        let text = rest?;
+        match find_unquoted(text, sep) {
+            Some(idx) => {
+                rest = Some(&text[idx + sep.len_utf8()..]);
+                Some(&text[..idx])
+            }
+            None => {
+                rest = None;
+                Some(text)
+            }
+        }
+    })
+}
+
+/// Whether the brackets in an array are balanced (ignoring any brackets inside of strings)
+fn is_balanced(text: &str) -> bool {
+    let mut depth = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '"' | '\'' => match parse_string(rest) {
+                Ok((_, after)) => {
+                    rest = after;
+                    continue;
+                }
+                Err(_) => return false,
+            },
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => {}
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    depth <= 0
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    if key.starts_with('"') || key.starts_with('\'') {
+        return match parse_string(key)? {
+            (key, "") => Ok(key),
+            _ => Err(format!("could not parse the key `{}`", key)),
+        };
+    }
+    let is_bare = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if key.is_empty() || !key.chars().all(is_bare) {
+        return Err(format!("`{}` is not a valid key", key));
+    }
+    Ok(key.to_string())
+}
+
+/// Parses the value at the start of `text`, returning it along with the rest of the text
+fn parse_value(text: &str) -> Result<(ConfigValue, &str), String> {
+    let text = text.trim_start();
+    if text.starts_with('"') || text.starts_with('\'') {
+        let (value, rest) = parse_string(text)?;
+        return Ok((ConfigValue::Text(value), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((ConfigValue::List(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected a `,` or `]` in the array".to_string());
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    match word {
+        "true" => Ok((ConfigValue::Bool(true), rest)),
+        "false" => Ok((ConfigValue::Bool(false), rest)),
+        _ if word.replace('_', "").parse::<f64>().is_ok() => {
+            Ok((ConfigValue::Number(word.replace('_', "")), rest))
+        }
+        _ => Err(format!(
+            "could not parse `{}` (strings need to be quoted)",
+            word
+        )),
+    }
+}
+
+/// Parses a basic ("...") or literal ('...') string at the start of `text`,
+/// returning it along with the rest of the text
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    let quote = text.chars().next().unwrap_or('"');
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            _ if c == quote => return Ok((value, &text[idx + 1..])),
+            '\\' if quote == '"' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some(c) => return Err(format!("unknown escape sequence `\\{}`", c)),
+                    None => break,
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+    Err("this string is never closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> ConfigValue {
+        ConfigValue::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+# shared settings
+delim = ";"   # semicolons
+date-format = '%d/%m/%Y'
+no-header = true
+percent-decimals = 2
+label = "a \"quoted\" # label"
+
+[presets.monthly]
+args = ["sum", "--rows", "region",
+        "--cols", "month"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.get("delim"), Some(&text(";")));
+        assert_eq!(config.get("date-format"), Some(&text("%d/%m/%Y")));
+        assert_eq!(config.get("no-header"), Some(&ConfigValue::Bool(true)));
+        assert_eq!(
+            config.get("percent-decimals"),
+            Some(&ConfigValue::Number("2".to_string()))
+        );
+        assert_eq!(config.get("label"), Some(&text("a \"quoted\" # label")));
+        assert_eq!(
+            config.get("presets.monthly.args"),
+            Some(&ConfigValue::List(vec![
+                text("sum"),
+                text("--rows"),
+                text("region"),
+                text("--cols"),
+                text("month")
+            ]))
+        );
+        let top_level: Vec<&str> = config.top_level().map(|(key, _)| key).collect();
+        assert_eq!(
+            top_level,
+            vec![
+                "delim",
+                "date-format",
+                "no-header",
+                "percent-decimals",
+                "label"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_presets() {
+        let config = Config::parse(
+            "[presets.monthly]\nargs = [\"sum\", \"-r\", \"region\"]\n[presets.bad]\nargs = true",
+        )
+        .unwrap();
+        assert_eq!(
+            config.preset("monthly").unwrap(),
+            Some(vec![
+                "sum".to_string(),
+                "-r".to_string(),
+                "region".to_string()
+            ])
+        );
+        assert_eq!(config.preset("missing").unwrap(), None);
+        assert!(config.preset("bad").is_err());
+        let args = vec!["count".to_string(), "-d".to_string(), "\t\"\\".to_string()];
+        let quoted: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+        let saved = Config::parse(&format!("[presets.tabs]\nargs = [{}]", quoted.join(", ")));
+        assert_eq!(saved.unwrap().preset("tabs").unwrap(), Some(args));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Config::parse("delim = ;")
+            .unwrap_err()
+            .starts_with("line 1"));
+        assert!(Config::parse("\ndelim").unwrap_err().starts_with("line 2"));
+        assert!(Config::parse("delim = \"abc").is_err());
+        assert!(Config::parse("args = [\"a\",").is_err());
+        assert!(Config::parse("[unclosed").is_err());
+        assert!(Config::parse("a b = 1").is_err());
+        assert!(Config::parse("a = 1 2").is_err());
+    }
+
+    #[test]
+    fn test_quoted_equals_signs() {
+        let config = Config::parse(
+            r#"
+"a=b" = "c=d"
+'x = y' = 'z = w'
+filter = "amount >= 100"
+
+[presets."q=1.5"]
+args = ["count", "--filter", "region == 'a=b'"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.get("a=b"), Some(&text("c=d")));
+        assert_eq!(config.get("x = y"), Some(&text("z = w")));
+        assert_eq!(config.get("filter"), Some(&text("amount >= 100")));
+        assert_eq!(
+            config.preset("q=1.5").unwrap(),
+            Some(vec![
+                "count".to_string(),
+                "--filter".to_string(),
+                "region == 'a=b'".to_string()
+            ])
+        );
+        assert!(Config::parse("\"a=b = 1").is_err());
+    }
+}
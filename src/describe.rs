@@ -0,0 +1,298 @@
+//! The module for summarizing the columns of a file, with `clipivot describe`.
+//!
+//! Before making a pivot table out of an unfamiliar file, it helps to know what's in each of
+//! its columns: whether it holds numbers, dates, or text, how many of its values are empty,
+//! how many distinct values it has (which tells you whether it makes sense as a row or column
+//! field), and the range of its values. `describe` reads the whole file once and writes one
+//! record per column with that information.
+use crate::aggregation::EMPTY_VALUES;
+use crate::errors::CsvCliResult;
+use crate::keys::parse_period;
+use crate::parsing::DecimalWrapper;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io;
+
+/// The header of the summary `describe` writes
+const SUMMARY_HEADER: [&str; 8] = [
+    "field", "type", "count", "empty", "unique", "min", "max", "mean",
+];
+
+/// The kind of data a column holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    /// Every non-empty value is a number
+    Number,
+    /// Every non-empty value is a date or a period (like `2020-03` or `Mar 2020`)
+    Date,
+    /// Anything else
+    Text,
+    /// The column doesn't have any non-empty values
+    Empty,
+}
+
+impl ColumnType {
+    /// The name of the type, as it's written in the summary
+    pub fn name(self) -> &'static str {
+        match self {
+            ColumnType::Number => "number",
+            ColumnType::Date => "date",
+            ColumnType::Text => "text",
+            ColumnType::Empty => "empty",
+        }
+    }
+}
+
+/// A summary of the values of a single column
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSummary {
+    pub name: String,
+    /// The number of non-empty values
+    pub count: usize,
+    /// The number of empty values (like "" or "NA")
+    pub empty: usize,
+    uniques: HashSet<String>,
+    all_numbers: bool,
+    all_dates: bool,
+    sum: Decimal,
+    /// The smallest and largest numbers in the column
+    number_range: Option<(Decimal, Decimal)>,
+    /// The first and last values of the column, compared as dates (if they're all dates)
+    /// or as text
+    text_range: Option<(String, String)>,
+}
+
+impl ColumnSummary {
+    fn new(name: String) -> ColumnSummary {
+        ColumnSummary {
+            name,
+            count: 0,
+            empty: 0,
+            uniques: HashSet::new(),
+            all_numbers: true,
+            all_dates: true,
+            sum: Decimal::new(0, 0),
+            number_range: None,
+            text_range: None,
+        }
+    }
+
+    fn add(&mut self, value: &str) {
+        if EMPTY_VALUES.contains(value.to_ascii_lowercase().as_str()) {
+            self.empty += 1;
+            return;
+        }
+        self.count += 1;
+        if !self.uniques.contains(value) {
+            self.uniques.insert(value.to_string());
+        }
+        match (self.all_numbers, value.parse::<DecimalWrapper>()) {
+            (true, Ok(num)) => {
+                self.sum += num.item;
+                self.number_range = match self.number_range {
+                    None => Some((num.item, num.item)),
+                    Some((min, max)) => Some((min.min(num.item), max.max(num.item))),
+                };
+            }
+            _ => self.all_numbers = false,
+        }
+        self.all_dates = self.all_dates && parse_period(value).is_some();
+        let all_dates = self.all_dates;
+        let compare = |a: &str, b: &str| match (all_dates, parse_period(a), parse_period(b)) {
+            (true, Some(a), Some(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        self.text_range = match self.text_range.take() {
+            None => Some((value.to_string(), value.to_string())),
+            Some((min, max)) => {
+                let min = if compare(value, &min) == Ordering::Less {
+                    value.to_string()
+                } else {
+                    min
+                };
+                let max = if compare(value, &max) == Ordering::Greater {
+                    value.to_string()
+                } else {
+                    max
+                };
+                Some((min, max))
+            }
+        };
+    }
+
+    /// The kind of data the column holds
+    pub fn column_type(&self) -> ColumnType {
+        match (self.count, self.all_numbers, self.all_dates) {
+            (0, _, _) => ColumnType::Empty,
+            (_, true, _) => ColumnType::Number,
+            (_, _, true) => ColumnType::Date,
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// The number of distinct non-empty values
+    pub fn unique(&self) -> usize {
+        self.uniques.len()
+    }
+
+    /// The smallest and largest values, compared according to the type of the column
+    pub fn range(&self) -> Option<(String, String)> {
+        match (self.column_type(), self.number_range) {
+            (ColumnType::Number, Some((min, max))) => Some((min.to_string(), max.to_string())),
+            _ => self.text_range.clone(),
+        }
+    }
+
+    /// The mean of the column, if it holds numbers
+    pub fn mean(&self) -> Option<Decimal> {
+        match self.column_type() {
+            ColumnType::Number => Some(self.sum / Decimal::from(self.count as u64)),
+            _ => None,
+        }
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        let (min, max) = self.range().unwrap_or_default();
+        vec![
+            self.name.clone(),
+            self.column_type().name().to_string(),
+            self.count.to_string(),
+            self.empty.to_string(),
+            self.unique().to_string(),
+            min,
+            max,
+            self.mean().map_or(String::new(), |mean| mean.to_string()),
+        ]
+    }
+}
+
+/// Summarizes every column of `rdr`. If the input doesn't have a header row, the columns
+/// are named by their (0-indexed) positions.
+pub fn describe<R: io::Read>(rdr: &mut csv::Reader<R>) -> CsvCliResult<Vec<ColumnSummary>> {
+    summarize(rdr, false)
+}
+
+/// Summarizes every column of `rdr` like `describe`, but skips the records that have a
+/// different number of fields than the first one instead of stopping with an error (for
+/// `clipivot validate`, which reports those records on its own)
+pub fn describe_skipping_bad_records<R: io::Read>(
+    rdr: &mut csv::Reader<R>,
+) -> CsvCliResult<Vec<ColumnSummary>> {
+    summarize(rdr, true)
+}
+
+fn summarize<R: io::Read>(
+    rdr: &mut csv::Reader<R>,
+    skip_bad_records: bool,
+) -> CsvCliResult<Vec<ColumnSummary>> {
+    let mut summaries: Vec<ColumnSummary> = if rdr.has_headers() {
+        rdr.headers()?
+            .iter()
+            .map(|name| ColumnSummary::new(name.to_string()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut record = csv::StringRecord::new();
+    loop {
+        match rdr.read_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            // the reader moves on to the next record after this error
+            Err(ref err)
+                if skip_bad_records
+                    && matches!(err.kind(), csv::ErrorKind::UnequalLengths { .. }) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        }
+        while summaries.len() < record.len() {
+            summaries.push(ColumnSummary::new(summaries.len().to_string()));
+        }
+        for (summary, value) in summaries.iter_mut().zip(record.iter()) {
+            summary.add(value);
+        }
+    }
+    Ok(summaries)
+}
+
+/// Writes the summaries of the columns as a CSV, with one record per column
+pub fn write_summaries<W: io::Write>(
+    summaries: &[ColumnSummary],
+    wtr: &mut csv::Writer<W>,
+) -> CsvCliResult<()> {
+    wtr.write_record(SUMMARY_HEADER)?;
+    for summary in summaries {
+        wtr.write_record(summary.to_record())?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let data = "id,amount,month,name,notes\n\
+                    1,10.5,Feb 2020,b,\n\
+                    2,NA,Jan 2020,a,\n\
+                    3,-2,Jan 2021,b,\n";
+        let summaries = describe(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        let types: Vec<ColumnType> = summaries.iter().map(|s| s.column_type()).collect();
+        assert_eq!(
+            types,
+            vec![
+                ColumnType::Number,
+                ColumnType::Number,
+                ColumnType::Date,
+                ColumnType::Text,
+                ColumnType::Empty
+            ]
+        );
+        let amount = &summaries[1];
+        assert_eq!((amount.count, amount.empty, amount.unique()), (2, 1, 2));
+        assert_eq!(amount.range(), Some(("-2".to_string(), "10.5".to_string())));
+        assert_eq!(amount.mean().unwrap().to_string(), "4.25");
+        // dates are compared chronologically, rather than alphabetically
+        assert_eq!(
+            summaries[2].range(),
+            Some(("Jan 2020".to_string(), "Jan 2021".to_string()))
+        );
+        assert_eq!(summaries[3].unique(), 2);
+        assert_eq!(summaries[4].range(), None);
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        write_summaries(&summaries[..2], &mut wtr).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+            "field,type,count,empty,unique,min,max,mean\n\
+             id,number,3,0,3,1,3,2\n\
+             amount,number,2,1,2,-2,10.5,4.25\n"
+        );
+    }
+
+    #[test]
+    fn test_describe_without_headers() {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("a,1\nb,2\n".as_bytes());
+        let summaries = describe(&mut rdr).unwrap();
+        let names: Vec<&str> = summaries.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["0", "1"]);
+    }
+
+    #[test]
+    fn test_describe_skipping_bad_records() {
+        let data = "name,amount\na,1\nb,2,3\nc,x\n";
+        assert!(describe(&mut csv::Reader::from_reader(data.as_bytes())).is_err());
+        let summaries =
+            describe_skipping_bad_records(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].count, 2);
+        assert_eq!(summaries[1].column_type(), ColumnType::Text);
+    }
+}
@@ -22,9 +22,20 @@
 //! ```
 //! to see the full line that caused you to run into an error.
 //!
+//! Each kind of error also has its own exit code, so scripts can tell why `clipivot` failed
+//! without reading its error message:
+//!
+//! | Exit code | Error |
+//! |-----------|-------|
+//! | 2 | An invalid configuration (including invalid command-line arguments) |
+//! | 3 | A value that couldn't be parsed |
+//! | 4 | An IO error, like a file that doesn't exist |
+//! | 5 | A malformed CSV file (like a record with the wrong number of fields) |
+//!
 
 extern crate csv;
 
+use std::borrow::Cow;
 use std::fmt;
 use std::io;
 use std::result;
@@ -33,6 +44,19 @@ use std::result;
 // from https://github.com/BurntSushi/rust-csv/blob/master/src/error.rs
 pub type CsvCliResult<T> = result::Result<T, CsvCliError>;
 
+/// The exit code for invalid configurations, including invalid command-line arguments
+pub const CONFIG_EXIT_CODE: i32 = 2;
+/// The exit code for values that couldn't be parsed
+pub const PARSING_EXIT_CODE: i32 = 3;
+/// The exit code for IO errors
+pub const IO_EXIT_CODE: i32 = 4;
+/// The exit code for malformed CSV files
+pub const CSV_EXIT_CODE: i32 = 5;
+
+/// The most characters of a value that get shown in an error message (since the value can be
+/// an entire record)
+pub const MAX_VALUE_CHARS: usize = 100;
+
 /// The type of CSV error
 #[derive(Debug)]
 pub enum CsvCliError {
@@ -63,7 +87,146 @@ pub enum CsvCliError {
         /// The general error message. This is specific to the type of error, so failures to parse
         /// data as datetimes will tell you they failed to parse datetimes, etc.
         err: String,
+        /// The column holding the string that failed to parse, if it came from a single column
+        /// (boxed, so that results don't get any bigger)
+        column: Option<Box<ErrorColumn>>,
+        /// Where the record is in the input (its line number and byte offset), so you can find
+        /// it without counting records
+        position: Option<csv::Position>,
     },
+    /// Errors combining the cells that several threads (or files) computed for the same row and
+    /// column, like sums that only overflow once they get added together.
+    ///
+    /// These don't come from any one record, so they name the cell instead.
+    MergeError {
+        /// The row and column of the cell, like `` row `east`, column `total` ``
+        cell: String,
+        /// The error from combining the cells
+        err: String,
+        /// The values column of the cell
+        column: Option<Box<ErrorColumn>>,
+    },
+}
+
+/// The column that a string that failed to parse came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorColumn {
+    /// The name of the column, if the input has a header row
+    pub name: Option<String>,
+    /// The position of the column in the record (where the first column is 0)
+    pub index: usize,
+}
+
+impl CsvCliError {
+    /// The exit code of the process when it fails because of this error
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            CsvCliError::CsvError(ref err) => match err.kind() {
+                csv::ErrorKind::Io(_) => IO_EXIT_CODE,
+                _ => CSV_EXIT_CODE,
+            },
+            CsvCliError::InvalidConfiguration(_) => CONFIG_EXIT_CODE,
+            CsvCliError::Io(_) => IO_EXIT_CODE,
+            CsvCliError::ParsingError { .. } | CsvCliError::MergeError { .. } => PARSING_EXIT_CODE,
+        }
+    }
+}
+
+impl CsvCliError {
+    /// The kind of error (`csv`, `configuration`, `io`, or `parsing`), as it gets named in
+    /// JSON error reports
+    pub fn class(&self) -> &'static str {
+        match *self {
+            CsvCliError::CsvError(ref err) => match err.kind() {
+                csv::ErrorKind::Io(_) => "io",
+                _ => "csv",
+            },
+            CsvCliError::InvalidConfiguration(_) => "configuration",
+            CsvCliError::Io(_) => "io",
+            CsvCliError::ParsingError { .. } | CsvCliError::MergeError { .. } => "parsing",
+        }
+    }
+
+    /// Describes the error as a (single-line) JSON object, for programs that run `clipivot`
+    /// and want to show where it failed. The object always has the error's `class`, its
+    /// `message` (the same text it gets displayed with), and its `exit_code`. The `record`,
+    /// `value` (truncated like it is in the message), `reason`, `column`, `column_index`, `line`,
+    /// and `byte` of the record the error is about are `null` when they aren't known.
+    pub fn to_json(&self) -> String {
+        let (mut record, mut value, mut reason, mut column, mut column_index, mut position) =
+            (None, None, None, None, None, None);
+        match self {
+            CsvCliError::ParsingError {
+                line_num,
+                str_to_parse,
+                err,
+                column: err_column,
+                position: err_position,
+            } => {
+                record = Some(*line_num as u64);
+                value = Some(truncate_value(str_to_parse));
+                reason = Some(err.as_str());
+                column = err_column.as_ref().and_then(|col| col.name.as_deref());
+                column_index = err_column.as_ref().map(|col| col.index as u64);
+                position = err_position.as_ref();
+            }
+            CsvCliError::MergeError {
+                err,
+                column: err_column,
+                ..
+            } => {
+                reason = Some(err.as_str());
+                column = err_column.as_ref().and_then(|col| col.name.as_deref());
+                column_index = err_column.as_ref().map(|col| col.index as u64);
+            }
+            CsvCliError::CsvError(err) => {
+                position = err.position();
+                record = position.map(csv::Position::record);
+            }
+            _ => {}
+        }
+        let string_or_null = |text: Option<&str>| text.map_or("null".to_string(), json_string);
+        let number_or_null = |num: Option<u64>| num.map_or("null".to_string(), |n| n.to_string());
+        format!(
+            "{{\"class\": {}, \"message\": {}, \"exit_code\": {}, \"record\": {}, \"value\": {}, \"reason\": {}, \"column\": {}, \"column_index\": {}, \"line\": {}, \"byte\": {}}}",
+            json_string(self.class()),
+            json_string(&self.to_string()),
+            self.exit_code(),
+            number_or_null(record),
+            string_or_null(value.as_deref()),
+            string_or_null(reason),
+            string_or_null(column),
+            number_or_null(column_index),
+            number_or_null(position.map(csv::Position::line)),
+            number_or_null(position.map(csv::Position::byte)),
+        )
+    }
+}
+
+/// Cuts a value that failed to parse down to `MAX_VALUE_CHARS` characters (followed by `...`)
+fn truncate_value(value: &str) -> Cow<'_, str> {
+    match value.char_indices().nth(MAX_VALUE_CHARS) {
+        Some((end, _)) => Cow::Owned(format!("{}...", &value[..end])),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// Quotes and escapes a string for JSON
+pub(crate) fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
 impl fmt::Display for CsvCliError {
@@ -79,11 +242,45 @@ impl fmt::Display for CsvCliError {
                 ref line_num,
                 ref str_to_parse,
                 ref err,
-            } => write!(
-                f,
-                "Could not parse record `{}` with index {}: {}",
-                str_to_parse, line_num, err
-            ),
+                ref column,
+                ref position,
+            } => {
+                write!(
+                    f,
+                    "Could not parse record `{}` with index {}",
+                    truncate_value(str_to_parse),
+                    line_num
+                )?;
+                let mut context = Vec::new();
+                if let Some(column) = column {
+                    if let Some(name) = &column.name {
+                        context.push(format!("column `{}`", name));
+                    }
+                    context.push(format!("field {}", column.index));
+                }
+                if let Some(position) = position {
+                    context.push(format!("line {}", position.line()));
+                    context.push(format!("byte {}", position.byte()));
+                }
+                if !context.is_empty() {
+                    write!(f, " ({})", context.join(", "))?;
+                }
+                write!(f, ": {}", err)
+            }
+            CsvCliError::MergeError {
+                ref cell,
+                ref err,
+                ref column,
+            } => {
+                write!(f, "Could not combine the cell in {}", cell)?;
+                if let Some(column) = column {
+                    match &column.name {
+                        Some(name) => write!(f, " of the values column `{}`", name)?,
+                        None => write!(f, " of the values in field {}", column.index)?,
+                    }
+                }
+                write!(f, " from several threads or files: {}", err)
+            }
         }
     }
 }
@@ -0,0 +1,655 @@
+//! A small expression language for filtering records before they get aggregated
+//! and for computing values from the fields of a record.
+//!
+//! An expression like `state == "CA" && amount > 100` gets parsed once (with the column names
+//! resolved to their positions in the header row) and then evaluated against each record.
+//! Expressions support:
+//!
+//! - column references, either as bare names (`amount`), as names wrapped in backticks
+//!   (`` `order date` ``) for names that aren't simple identifiers, or as 0-indexed positions (`$3`)
+//! - string literals (`"CA"` or `'CA'`), numbers (`100`, `2.5`, `1e3`), and `true` and `false`
+//! - text concatenation with `~`, as in `city ~ ", " ~ state`
+//! - the arithmetic operators `+`, `-`, `*`, and `/`, which always treat their operands as numbers
+//! - the comparison operators `==`, `!=`, `<`, `<=`, `>`, and `>=`
+//! - regular expression matches, like `product =~ "^Widget"`
+//! - the boolean operators `&&`, `||`, and `!`, along with parentheses for grouping
+//!
+//! Comparisons are numeric whenever both sides can be parsed as numbers and textual otherwise,
+//! so `amount > 100` compares amounts as numbers while `state == "CA"` compares strings.
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::keys::parse_date;
+use crate::record::Record;
+use chrono::NaiveDateTime;
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// A value produced while evaluating an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(Decimal),
+    Text(String),
+    Date(NaiveDateTime),
+}
+
+impl Value {
+    /// Converts the value to a number, parsing text if necessary
+    fn as_number(&self) -> Result<Decimal, String> {
+        match self {
+            Value::Number(num) => Ok(*num),
+            Value::Text(text) => {
+                parse_number(text).ok_or_else(|| format!("Could not parse `{}` as a number", text))
+            }
+            other => Err(format!("Expected a number but found `{}`", other)),
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            other => Err(format!("Expected true or false but found `{}`", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(num) => write!(f, "{}", num),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Date(dt) => write!(f, "{}", dt),
+        }
+    }
+}
+
+/// Parses a number the same way the numeric aggregation functions do
+fn parse_number(text: &str) -> Option<Decimal> {
+    let trimmed = text.trim();
+    Decimal::from_str(trimmed)
+        .or_else(|_| Decimal::from_scientific(trimmed))
+        .ok()
+}
+
+/// A binary operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Concat,
+}
+
+/// A compiled regular expression (which, unlike `Regex`, can be compared with `==`)
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// A parsed expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    /// The value of a column, by its 0-indexed position
+    Column(usize),
+    Not(Box<Expr>),
+    /// The negative of a number
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    /// Whether the text of an expression matches a regular expression
+    Matches(Box<Expr>, Pattern),
+    /// Parses the text of an expression as a date, trying each of the formats in turn
+    ParseDate(Box<Expr>, Vec<String>),
+}
+
+impl Expr {
+    /// Parses an expression, using `resolve` to convert column names into positions
+    pub fn parse<F>(input: &str, resolve: F) -> CsvCliResult<Expr>
+    where
+        F: Fn(&str) -> CsvCliResult<usize>,
+    {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            input,
+            tokens,
+            pos: 0,
+            resolve,
+        };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some((token, pos)) => {
+                Err(parser.error(&format!("unexpected {}", token.describe()), *pos))
+            }
+        }
+    }
+
+    /// Evaluates the expression for a record, returning an error message if it can't be evaluated
+    /// (for instance, because a value that needs to be a number isn't)
+    pub fn eval<R: Record>(&self, record: &R) -> Result<Value, String> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Column(idx) => record.field(*idx).map(|val| Value::Text(val.to_string())),
+            Expr::Not(inner) => Ok(Value::Bool(!inner.eval(record)?.as_bool()?)),
+            Expr::Negate(inner) => Ok(Value::Number(-inner.eval(record)?.as_number()?)),
+            Expr::Matches(inner, Pattern(re)) => {
+                Ok(Value::Bool(re.is_match(&inner.eval(record)?.to_string())))
+            }
+            Expr::ParseDate(inner, formats) => {
+                let text = inner.eval(record)?.to_string();
+                parse_date(text.trim(), formats)
+                    .map(Value::Date)
+                    .ok_or_else(|| format!("Could not parse `{}` as a date", text))
+            }
+            Expr::Binary(lhs, BinaryOp::Concat, rhs) => Ok(Value::Text(format!(
+                "{}{}",
+                lhs.eval(record)?,
+                rhs.eval(record)?
+            ))),
+            Expr::Binary(lhs, BinaryOp::And, rhs) => Ok(Value::Bool(
+                lhs.eval(record)?.as_bool()? && rhs.eval(record)?.as_bool()?,
+            )),
+            Expr::Binary(lhs, BinaryOp::Or, rhs) => Ok(Value::Bool(
+                lhs.eval(record)?.as_bool()? || rhs.eval(record)?.as_bool()?,
+            )),
+            Expr::Binary(
+                lhs,
+                op @ (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div),
+                rhs,
+            ) => {
+                let a = lhs.eval(record)?.as_number()?;
+                let b = rhs.eval(record)?.as_number()?;
+                let result = match op {
+                    BinaryOp::Add => a.checked_add(b),
+                    BinaryOp::Sub => a.checked_sub(b),
+                    BinaryOp::Mul => a.checked_mul(b),
+                    _ if b == Decimal::new(0, 0) => {
+                        return Err(format!("Cannot divide `{}` by zero", a))
+                    }
+                    _ => a.checked_div(b),
+                };
+                result
+                    .map(Value::Number)
+                    .ok_or_else(|| format!("The result of `{}` and `{}` is too large", a, b))
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                let ordering = compare(&lhs.eval(record)?, &rhs.eval(record)?)?;
+                let result = match op {
+                    BinaryOp::Eq => ordering == Ordering::Equal,
+                    BinaryOp::Ne => ordering != Ordering::Equal,
+                    BinaryOp::Lt => ordering == Ordering::Less,
+                    BinaryOp::Le => ordering != Ordering::Greater,
+                    BinaryOp::Gt => ordering == Ordering::Greater,
+                    BinaryOp::Ge => ordering != Ordering::Less,
+                    _ => unreachable!(),
+                };
+                Ok(Value::Bool(result))
+            }
+        }
+    }
+
+    /// Evaluates the expression as a condition, which needs to be either true or false
+    pub fn matches<R: Record>(&self, record: &R) -> Result<bool, String> {
+        self.eval(record)?.as_bool()
+    }
+}
+
+/// Compares two values, numerically if both of them are numbers (or text that parses as a number)
+fn compare(lhs: &Value, rhs: &Value) -> Result<Ordering, String> {
+    match (lhs, rhs) {
+        (Value::Bool(a), Value::Bool(b)) => Ok(a.cmp(b)),
+        (Value::Date(a), Value::Date(b)) => Ok(a.cmp(b)),
+        (Value::Text(a), Value::Text(b)) => match (parse_number(a), parse_number(b)) {
+            (Some(a), Some(b)) => Ok(a.cmp(&b)),
+            _ => Ok(a.cmp(b)),
+        },
+        (Value::Number(_), _) | (_, Value::Number(_)) => {
+            Ok(lhs.as_number()?.cmp(&rhs.as_number()?))
+        }
+        _ => Err(format!("Cannot compare `{}` with `{}`", lhs, rhs)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    QuotedIdent(String),
+    Position(usize),
+    Str(String),
+    Number(Decimal),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(name) | Token::QuotedIdent(name) => format!("column `{}`", name),
+            Token::Position(idx) => format!("column `${}`", idx),
+            Token::Str(s) => format!("string \"{}\"", s),
+            Token::Number(num) => format!("number `{}`", num),
+            Token::Op(op) => format!("`{}`", op),
+            Token::LParen => "`(`".to_string(),
+            Token::RParen => "`)`".to_string(),
+        }
+    }
+}
+
+const OPERATORS: [&str; 15] = [
+    "=~", "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "~",
+];
+
+/// Describes a problem with an expression, pointing at the position where it occurred
+fn parse_error(input: &str, message: &str, pos: usize) -> CsvCliError {
+    CsvCliError::InvalidConfiguration(format!(
+        "Could not parse the expression `{}`: {} at position {}",
+        input, message, pos
+    ))
+}
+
+fn tokenize(input: &str) -> CsvCliResult<Vec<(Token, usize)>> {
+    let chars: Vec<(usize, char)> = input.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (pos, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let rest = &input[pos..];
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push((Token::Op(op), pos));
+            i += op.chars().count();
+            continue;
+        }
+        match c {
+            '(' => {
+                tokens.push((Token::LParen, pos));
+                i += 1;
+            }
+            ')' => {
+                tokens.push((Token::RParen, pos));
+                i += 1;
+            }
+            '"' | '\'' | '`' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(parse_error(input, "unterminated quote", pos)),
+                        Some(&(_, '\\')) if c != '`' && i + 1 < chars.len() => {
+                            text.push(chars[i + 1].1);
+                            i += 2;
+                        }
+                        Some(&(_, ch)) if ch == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&(_, ch)) => {
+                            text.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                let token = if c == '`' {
+                    Token::QuotedIdent(text)
+                } else {
+                    Token::Str(text)
+                };
+                tokens.push((token, pos));
+            }
+            '$' => {
+                let start = i + 1;
+                let mut end = start;
+                while end < chars.len() && chars[end].1.is_ascii_digit() {
+                    end += 1;
+                }
+                let digits: String = chars[start..end].iter().map(|(_, ch)| ch).collect();
+                let idx = digits
+                    .parse()
+                    .map_err(|_| parse_error(input, "expected a column number after `$`", pos))?;
+                tokens.push((Token::Position(idx), pos));
+                i = end;
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut end = i;
+                while end < chars.len() {
+                    let ch = chars[end].1;
+                    let is_exponent_sign =
+                        (ch == '-' || ch == '+') && matches!(chars[end - 1].1, 'e' | 'E');
+                    if !(ch.is_ascii_alphanumeric() || ch == '.' || is_exponent_sign) {
+                        break;
+                    }
+                    end += 1;
+                }
+                let end_pos = chars.get(end).map_or(input.len(), |(p, _)| *p);
+                let literal = &input[pos..end_pos];
+                let num = parse_number(literal).ok_or_else(|| {
+                    parse_error(input, &format!("`{}` is not a number", literal), pos)
+                })?;
+                tokens.push((Token::Number(num), pos));
+                i = end;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = i;
+                while end < chars.len() && (chars[end].1.is_alphanumeric() || chars[end].1 == '_') {
+                    end += 1;
+                }
+                let word: String = chars[i..end].iter().map(|(_, ch)| ch).collect();
+                tokens.push((Token::Ident(word), pos));
+                i = end;
+            }
+            _ => {
+                return Err(parse_error(
+                    input,
+                    &format!("unexpected character `{}`", c),
+                    pos,
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a, F> {
+    input: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    resolve: F,
+}
+
+impl<'a, F> Parser<'a, F>
+where
+    F: Fn(&str) -> CsvCliResult<usize>,
+{
+    fn peek(&self) -> Option<&(Token, usize)> {
+        self.tokens.get(self.pos)
+    }
+
+    fn error(&self, message: &str, pos: usize) -> CsvCliError {
+        parse_error(self.input, message, pos)
+    }
+
+    /// Consumes the next token if it's one of the given operators
+    fn eat_op(&mut self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some((Token::Op(op), _)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_or(&mut self) -> CsvCliResult<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat_op(&["||"]).is_some() {
+            expr = Expr::Binary(Box::new(expr), BinaryOp::Or, Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> CsvCliResult<Expr> {
+        let mut expr = self.parse_comparison()?;
+        while self.eat_op(&["&&"]).is_some() {
+            expr = Expr::Binary(
+                Box::new(expr),
+                BinaryOp::And,
+                Box::new(self.parse_comparison()?),
+            );
+        }
+        Ok(expr)
+    }
+
+    fn parse_comparison(&mut self) -> CsvCliResult<Expr> {
+        let lhs = self.parse_concat()?;
+        if self.eat_op(&["=~"]).is_some() {
+            return match self.tokens.get(self.pos).cloned() {
+                Some((Token::Str(pattern), pos)) => {
+                    self.pos += 1;
+                    let re = Regex::new(&pattern).map_err(|e| {
+                        self.error(&format!("invalid regular expression ({})", e), pos)
+                    })?;
+                    Ok(Expr::Matches(Box::new(lhs), Pattern(re)))
+                }
+                Some((token, pos)) => Err(self.error(
+                    &format!(
+                        "expected a quoted regular expression but found {}",
+                        token.describe()
+                    ),
+                    pos,
+                )),
+                None => Err(self.error("expected a regular expression", self.input.len())),
+            };
+        }
+        let op = match self.eat_op(&["==", "!=", "<=", ">=", "<", ">"]) {
+            Some("==") => BinaryOp::Eq,
+            Some("!=") => BinaryOp::Ne,
+            Some("<=") => BinaryOp::Le,
+            Some(">=") => BinaryOp::Ge,
+            Some("<") => BinaryOp::Lt,
+            Some(">") => BinaryOp::Gt,
+            _ => return Ok(lhs),
+        };
+        Ok(Expr::Binary(
+            Box::new(lhs),
+            op,
+            Box::new(self.parse_concat()?),
+        ))
+    }
+
+    fn parse_concat(&mut self) -> CsvCliResult<Expr> {
+        let mut expr = self.parse_additive()?;
+        while self.eat_op(&["~"]).is_some() {
+            expr = Expr::Binary(
+                Box::new(expr),
+                BinaryOp::Concat,
+                Box::new(self.parse_additive()?),
+            );
+        }
+        Ok(expr)
+    }
+
+    fn parse_additive(&mut self) -> CsvCliResult<Expr> {
+        let mut expr = self.parse_multiplicative()?;
+        while let Some(op) = self.eat_op(&["+", "-"]) {
+            let op = if op == "+" {
+                BinaryOp::Add
+            } else {
+                BinaryOp::Sub
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.parse_multiplicative()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_multiplicative(&mut self) -> CsvCliResult<Expr> {
+        let mut expr = self.parse_unary()?;
+        while let Some(op) = self.eat_op(&["*", "/"]) {
+            let op = if op == "*" {
+                BinaryOp::Mul
+            } else {
+                BinaryOp::Div
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> CsvCliResult<Expr> {
+        if self.eat_op(&["!"]).is_some() {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_op(&["-"]).is_some() {
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> CsvCliResult<Expr> {
+        let (token, pos) = match self.tokens.get(self.pos) {
+            Some(token) => token.clone(),
+            None => {
+                return Err(self.error("unexpected end of expression", self.input.len()));
+            }
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(num) => Ok(Expr::Literal(Value::Number(num))),
+            Token::Str(s) => Ok(Expr::Literal(Value::Text(s))),
+            Token::Ident(word) if word == "true" => Ok(Expr::Literal(Value::Bool(true))),
+            Token::Ident(word) if word == "false" => Ok(Expr::Literal(Value::Bool(false))),
+            Token::Ident(name) | Token::QuotedIdent(name) => {
+                Ok(Expr::Column((self.resolve)(&name)?))
+            }
+            Token::Position(idx) => Ok(Expr::Column(idx)),
+            Token::LParen => {
+                let expr = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some((Token::RParen, _)) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    Some((token, pos)) => Err(self.error(
+                        &format!("expected `)` but found {}", token.describe()),
+                        *pos,
+                    )),
+                    None => Err(self.error("missing `)`", self.input.len())),
+                }
+            }
+            token => Err(self.error(
+                &format!("expected a value but found {}", token.describe()),
+                pos,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::StringRecord;
+
+    fn parse(input: &str) -> CsvCliResult<Expr> {
+        let headers = ["state", "amount", "order date"];
+        Expr::parse(input, |name| {
+            headers
+                .iter()
+                .position(|h| *h == name)
+                .ok_or_else(|| CsvCliError::InvalidConfiguration(format!("no column {}", name)))
+        })
+    }
+
+    #[test]
+    fn test_filter_expressions() {
+        let record = StringRecord::from(vec!["CA", "150", "2020-01-02"]);
+        let check = |input: &str| parse(input).unwrap().matches(&record).unwrap();
+        assert!(check("state == \"CA\" && amount > 100"));
+        assert!(!check("state == 'CA' && amount > 1000"));
+        assert!(check("state != \"NY\" || amount < 0"));
+        assert!(check("!(amount <= 100)"));
+        assert!(check("$1 >= 1.5e2"));
+        assert!(check("`order date` >= \"2020-01-01\""));
+        // numeric strings compare as numbers, so "150" > "20"
+        assert!(check("amount > \"20\""));
+        assert!(check("state =~ \"^C\" && !(state =~ '^CAL')"));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let record = StringRecord::from(vec!["NY", "150", ""]);
+        let expr = parse("state == \"CA\" && amount > 100 || amount > 120").unwrap();
+        assert!(expr.matches(&record).unwrap());
+        let grouped = parse("state == \"CA\" && (amount > 100 || amount > 120)").unwrap();
+        assert!(!grouped.matches(&record).unwrap());
+    }
+
+    #[test]
+    fn test_dates() {
+        let formats = vec!["%Y-%m-%d".to_string()];
+        let date = |s: &str| chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let since = Expr::Binary(
+            Box::new(Expr::ParseDate(Box::new(Expr::Column(2)), formats)),
+            BinaryOp::Ge,
+            Box::new(Expr::Literal(Value::Date(
+                date("2020-01-01").and_hms(0, 0, 0),
+            ))),
+        );
+        let record = |d: &str| StringRecord::from(vec!["CA", "1", d]);
+        assert!(since.matches(&record("2020-01-02")).unwrap());
+        assert!(!since.matches(&record("2019-12-31")).unwrap());
+        assert!(since.matches(&record("not a date")).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let record = StringRecord::from(vec!["CA", "150", "2.5"]);
+        let eval = |input: &str| parse(input).unwrap().eval(&record).unwrap().to_string();
+        assert_eq!(eval("amount * $2"), "375.0");
+        assert_eq!(eval("amount - 50 / 2 * 4"), "50");
+        assert_eq!(eval("(amount - 50) / -4"), "-25");
+        assert_eq!(eval("amount + 1e2"), "250");
+        assert!(parse("amount * 2 > 299 && amount - 1 < 150")
+            .unwrap()
+            .matches(&record)
+            .unwrap());
+        assert!(parse("amount / 0").unwrap().eval(&record).is_err());
+        assert!(parse("state * 2").unwrap().eval(&record).is_err());
+        assert!(parse("amount *").is_err());
+    }
+
+    #[test]
+    fn test_concatenation() {
+        let record = StringRecord::from(vec!["CA", "150", "2.5"]);
+        let eval = |input: &str| parse(input).unwrap().eval(&record).unwrap().to_string();
+        assert_eq!(eval("state ~ \", \" ~ amount"), "CA, 150");
+        assert_eq!(eval("state ~ amount + 1"), "CA151");
+        assert!(parse("state ~ \"A\" == \"CAA\"")
+            .unwrap()
+            .matches(&record)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        let record = StringRecord::from(vec!["CA", "n/a", ""]);
+        assert!(parse("amount > 100").unwrap().matches(&record).is_err());
+        assert!(parse("state").unwrap().matches(&record).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for bad in &[
+            "state ==",
+            "state == \"CA",
+            "(amount > 1",
+            "amount > 1 1",
+            "amount @ 2",
+            "country == \"US\"",
+            "state =~ amount",
+            "state =~ \"(\"",
+        ] {
+            assert!(parse(bad).is_err(), "{} should not parse", bad);
+        }
+        let err = parse("amount > > 1").unwrap_err().to_string();
+        assert!(err.contains("position 9"), "{}", err);
+    }
+}
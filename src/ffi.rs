@@ -0,0 +1,527 @@
+//! The module for embedding `clipivot` in programs that aren't written in Rust, with the `ffi`
+//! feature.
+//!
+//! It exposes a small C API over the `pivot` module, which `include/clipivot.h` declares. A
+//! program creates a builder with `clipivot_builder_new`, describes the pivot table with the
+//! `clipivot_builder_*` functions, and runs it on a file (`clipivot_run_path`) or on a buffer of
+//! CSV data (`clipivot_run_buffer`). Running it returns a result, which holds either the table
+//! (which `clipivot_result_csv` and `clipivot_result_json` return as text) or an error message
+//! (`clipivot_result_error`). Everything `clipivot` allocates gets freed by the matching
+//! `clipivot_*_free` function.
+//!
+//! Building the library needs the `cdylib` crate type, which isn't part of the default build:
+//!
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! The JSON a result returns mirrors `pivot::Table`: an object with `row_fields`,
+//! `column_fields`, `value_fields`, `rows`, `columns`, and `cells`, where each cell is a number,
+//! a string (for dates and text), or `null` for an empty cell.
+//!
+//! A panic can't unwind into C, so every function catches them. A run that panics returns a
+//! result holding the panic's message as its error, and the other functions return the same
+//! thing they return for invalid arguments (-1 or null).
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::output::Value;
+use crate::pivot::{PivotBuilder, Table};
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::fmt::Write;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// The result of running a pivot table: the table, or the message of the error that stopped it
+pub struct PivotResult(Result<Table, CString>);
+
+/// Reads a string from C, returning `None` if it's null or isn't valid UTF-8
+unsafe fn read_str<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    CStr::from_ptr(text).to_str().ok()
+}
+
+/// Turns text into a C string, dropping any null bytes (which C strings can't hold)
+fn to_c_string(text: String) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+/// Calls `f`, returning `on_panic` instead if it panics
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// The message a panic was started with
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("an unknown error", String::as_str),
+    }
+}
+
+/// Applies `f` to the builder, returning 0 if it worked and -1 if the builder was null (or if
+/// `f` panicked, which leaves the builder the way it was)
+unsafe fn update(
+    builder: *mut PivotBuilder,
+    f: impl FnOnce(PivotBuilder) -> PivotBuilder,
+) -> c_int {
+    match builder.as_mut() {
+        Some(builder) => {
+            let original = builder.clone();
+            match panic::catch_unwind(AssertUnwindSafe(|| f(std::mem::take(builder)))) {
+                Ok(updated) => {
+                    *builder = updated;
+                    0
+                }
+                Err(_) => {
+                    *builder = original;
+                    -1
+                }
+            }
+        }
+        None => -1,
+    }
+}
+
+/// Applies `f` to the builder and a string argument, returning -1 if either one is invalid
+unsafe fn update_with_str(
+    builder: *mut PivotBuilder,
+    text: *const c_char,
+    f: impl FnOnce(PivotBuilder, &str) -> PivotBuilder,
+) -> c_int {
+    match read_str(text) {
+        Some(text) => update(builder, |builder| f(builder, text)),
+        None => -1,
+    }
+}
+
+/// Creates a builder for a pivot table, which `clipivot_builder_free` frees
+#[no_mangle]
+pub extern "C" fn clipivot_builder_new() -> *mut PivotBuilder {
+    catch_panic(ptr::null_mut(), || {
+        Box::into_raw(Box::new(PivotBuilder::new()))
+    })
+}
+
+/// Adds a row field to the builder. Returns 0 if it worked, and -1 otherwise.
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`, and `name` needs to be null or a
+/// null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_rows(
+    builder: *mut PivotBuilder,
+    name: *const c_char,
+) -> c_int {
+    update_with_str(builder, name, PivotBuilder::rows)
+}
+
+/// Adds a column field to the builder. Returns 0 if it worked, and -1 otherwise.
+///
+/// # Safety
+/// The same as `clipivot_builder_rows`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_columns(
+    builder: *mut PivotBuilder,
+    name: *const c_char,
+) -> c_int {
+    update_with_str(builder, name, PivotBuilder::columns)
+}
+
+/// Adds a values column to the builder. Returns 0 if it worked, and -1 otherwise.
+///
+/// # Safety
+/// The same as `clipivot_builder_rows`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_values(
+    builder: *mut PivotBuilder,
+    name: *const c_char,
+) -> c_int {
+    update_with_str(builder, name, PivotBuilder::values)
+}
+
+/// Sets the aggregation function by its name on the command line (like `sum` or
+/// `countunique`). Returns 0 if it worked, and -1 otherwise (including for an unknown function).
+///
+/// # Safety
+/// The same as `clipivot_builder_rows`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_aggregate(
+    builder: *mut PivotBuilder,
+    function: *const c_char,
+) -> c_int {
+    match read_str(function).and_then(|function| catch_panic(None, || function.parse().ok())) {
+        Some(function) => update(builder, |builder| builder.aggregate(function)),
+        None => -1,
+    }
+}
+
+/// Sets the byte that separates fields. Returns 0 if it worked, and -1 otherwise.
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_delimiter(
+    builder: *mut PivotBuilder,
+    delimiter: u8,
+) -> c_int {
+    update(builder, |builder| builder.delimiter(delimiter))
+}
+
+/// Sets whether the first row is a header row (0 for no, anything else for yes). Returns 0 if
+/// it worked, and -1 otherwise.
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_has_headers(
+    builder: *mut PivotBuilder,
+    has_headers: c_int,
+) -> c_int {
+    update(builder, |builder| builder.has_headers(has_headers != 0))
+}
+
+/// Sets whether empty values get skipped (0 for no, anything else for yes), like `-e`. Returns
+/// 0 if it worked, and -1 otherwise.
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_skip_empty(
+    builder: *mut PivotBuilder,
+    skip_empty: c_int,
+) -> c_int {
+    update(builder, |builder| builder.skip_empty(skip_empty != 0))
+}
+
+/// Frees a builder
+///
+/// # Safety
+/// `builder` needs to be null or come from `clipivot_builder_new`, and can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_builder_free(builder: *mut PivotBuilder) {
+    if !builder.is_null() {
+        catch_panic((), || drop(Box::from_raw(builder)));
+    }
+}
+
+/// Runs `read` on a copy of the builder, turning its error (or its panic) into an error result
+fn run(
+    builder: Option<&PivotBuilder>,
+    read: impl FnOnce(PivotBuilder) -> CsvCliResult<Table>,
+) -> *mut PivotResult {
+    let table = match builder {
+        Some(builder) => match panic::catch_unwind(AssertUnwindSafe(|| read(builder.clone()))) {
+            Ok(table) => table.map_err(|err| to_c_string(err.to_string())),
+            Err(payload) => Err(to_c_string(format!(
+                "clipivot stopped because of a bug: {}",
+                panic_message(&*payload)
+            ))),
+        },
+        None => Err(to_c_string("The builder is null".to_string())),
+    };
+    Box::into_raw(Box::new(PivotResult(table)))
+}
+
+/// Runs the pivot table on the file at `path`. The builder can be run again, and the result
+/// always needs to be freed with `clipivot_result_free`, even if it holds an error.
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`, and `path` needs to be null or a
+/// null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_run_path(
+    builder: *const PivotBuilder,
+    path: *const c_char,
+) -> *mut PivotResult {
+    let path = read_str(path);
+    run(builder.as_ref(), |builder| match path {
+        Some(path) => builder.build()?.read_path(path),
+        None => Err(CsvCliError::InvalidConfiguration(
+            "The path is null or isn't valid UTF-8".to_string(),
+        )),
+    })
+}
+
+/// Runs the pivot table on `len` bytes of CSV data
+///
+/// # Safety
+/// `builder` needs to come from `clipivot_builder_new`, and `data` needs to point to at least
+/// `len` bytes (or be null, if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_run_buffer(
+    builder: *const PivotBuilder,
+    data: *const u8,
+    len: usize,
+) -> *mut PivotResult {
+    let data = if data.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+    run(builder.as_ref(), |builder| builder.build()?.read(data))
+}
+
+/// The error message of a result, or null if the pivot table was computed. The message lives
+/// as long as the result.
+///
+/// # Safety
+/// `result` needs to come from `clipivot_run_path` or `clipivot_run_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_result_error(result: *const PivotResult) -> *const c_char {
+    match result.as_ref() {
+        Some(PivotResult(Err(err))) => err.as_ptr(),
+        _ => ptr::null(),
+    }
+}
+
+/// The table of a result as CSV, or null if the result holds an error. The string needs to be
+/// freed with `clipivot_string_free`.
+///
+/// # Safety
+/// `result` needs to come from `clipivot_run_path` or `clipivot_run_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_result_csv(result: *const PivotResult) -> *mut c_char {
+    let table = match result.as_ref() {
+        Some(PivotResult(Ok(table))) => table,
+        _ => return ptr::null_mut(),
+    };
+    catch_panic(ptr::null_mut(), || {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        let text = table
+            .write_csv(&mut wtr)
+            .ok()
+            .and_then(|_| wtr.into_inner().ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match text {
+            Some(text) => to_c_string(text).into_raw(),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// The table of a result as JSON, or null if the result holds an error. The string needs to be
+/// freed with `clipivot_string_free`.
+///
+/// # Safety
+/// `result` needs to come from `clipivot_run_path` or `clipivot_run_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_result_json(result: *const PivotResult) -> *mut c_char {
+    match result.as_ref() {
+        Some(PivotResult(Ok(table))) => catch_panic(ptr::null_mut(), || {
+            to_c_string(table_json(table)).into_raw()
+        }),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Frees a result
+///
+/// # Safety
+/// `result` needs to be null or come from `clipivot_run_path` or `clipivot_run_buffer`, and
+/// can't be used afterwards (along with its error message).
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_result_free(result: *mut PivotResult) {
+    if !result.is_null() {
+        catch_panic((), || drop(Box::from_raw(result)));
+    }
+}
+
+/// Frees a string from `clipivot_result_csv` or `clipivot_result_json`
+///
+/// # Safety
+/// `text` needs to be null or come from one of those functions, and can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn clipivot_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        catch_panic((), || drop(CString::from_raw(text)));
+    }
+}
+
+fn write_json_str(buf: &mut String, text: &str) {
+    buf.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+fn write_json_list<T>(buf: &mut String, items: &[T], mut write_item: impl FnMut(&mut String, &T)) {
+    buf.push('[');
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            buf.push(',');
+        }
+        write_item(buf, item);
+    }
+    buf.push(']');
+}
+
+fn write_json_strs(buf: &mut String, items: &[String]) {
+    write_json_list(buf, items, |buf, item| write_json_str(buf, item));
+}
+
+fn write_json_cell(buf: &mut String, cell: &Option<Value>) {
+    match cell {
+        None => buf.push_str("null"),
+        Some(Value::Float(num)) if !num.is_finite() => write_json_str(buf, &num.to_string()),
+        Some(value @ (Value::Int(_) | Value::Float(_) | Value::Decimal(_))) => {
+            buf.push_str(&value.to_string())
+        }
+        Some(value) => write_json_str(buf, &value.to_string()),
+    }
+}
+
+/// The table as a JSON object
+fn table_json(table: &Table) -> String {
+    let mut buf = String::new();
+    buf.push_str("{\"row_fields\":");
+    write_json_strs(&mut buf, &table.row_fields);
+    buf.push_str(",\"column_fields\":");
+    write_json_strs(&mut buf, &table.column_fields);
+    buf.push_str(",\"value_fields\":");
+    write_json_strs(&mut buf, &table.value_fields);
+    buf.push_str(",\"rows\":");
+    write_json_list(&mut buf, &table.rows, |buf, key| write_json_strs(buf, key));
+    buf.push_str(",\"columns\":");
+    write_json_list(&mut buf, &table.columns, |buf, key| {
+        write_json_strs(buf, key)
+    });
+    buf.push_str(",\"cells\":");
+    write_json_list(&mut buf, &table.cells, |buf, row| {
+        write_json_list(buf, row, write_json_cell)
+    });
+    buf.push('}');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &str = "region;product;amount\nwest;a;1.5\neast;\"b\"\"\";2\nwest;a;3\n";
+
+    fn c_str(text: &str) -> CString {
+        CString::new(text).unwrap()
+    }
+
+    unsafe fn take_string(text: *mut c_char) -> String {
+        assert!(!text.is_null());
+        let string = CStr::from_ptr(text).to_str().unwrap().to_string();
+        clipivot_string_free(text);
+        string
+    }
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let builder = clipivot_builder_new();
+            assert_eq!(clipivot_builder_rows(builder, c_str("region").as_ptr()), 0);
+            assert_eq!(
+                clipivot_builder_columns(builder, c_str("product").as_ptr()),
+                0
+            );
+            assert_eq!(
+                clipivot_builder_values(builder, c_str("amount").as_ptr()),
+                0
+            );
+            assert_eq!(
+                clipivot_builder_aggregate(builder, c_str("sum").as_ptr()),
+                0
+            );
+            assert_eq!(clipivot_builder_delimiter(builder, b';'), 0);
+            assert_eq!(clipivot_builder_skip_empty(builder, 1), 0);
+            let result = clipivot_run_buffer(builder, DATA.as_ptr(), DATA.len());
+            assert!(clipivot_result_error(result).is_null());
+            assert_eq!(
+                take_string(clipivot_result_csv(result)),
+                "region,a,\"b\"\"\"\nwest,4.5,\neast,,2\n"
+            );
+            assert_eq!(
+                take_string(clipivot_result_json(result)),
+                "{\"row_fields\":[\"region\"],\"column_fields\":[\"product\"],\
+                 \"value_fields\":[\"amount\"],\"rows\":[[\"west\"],[\"east\"]],\
+                 \"columns\":[[\"a\"],[\"b\\\"\"]],\"cells\":[[4.5,null],[null,2]]}"
+            );
+            clipivot_result_free(result);
+
+            // a builder can be run again, and errors end up in the result
+            let result = clipivot_run_path(builder, c_str("/no/such/file.csv").as_ptr());
+            assert!(!clipivot_result_error(result).is_null());
+            assert!(clipivot_result_csv(result).is_null());
+            assert!(clipivot_result_json(result).is_null());
+            clipivot_result_free(result);
+            clipivot_builder_free(builder);
+        }
+    }
+
+    #[test]
+    fn test_ffi_errors() {
+        unsafe {
+            let builder = clipivot_builder_new();
+            assert_eq!(
+                clipivot_builder_aggregate(builder, c_str("average").as_ptr()),
+                -1
+            );
+            assert_eq!(clipivot_builder_rows(builder, ptr::null()), -1);
+            assert_eq!(
+                clipivot_builder_rows(ptr::null_mut(), c_str("region").as_ptr()),
+                -1
+            );
+            // a pivot table without a function can't be built
+            let result = clipivot_run_buffer(builder, DATA.as_ptr(), DATA.len());
+            let err = CStr::from_ptr(clipivot_result_error(result));
+            assert!(err.to_str().unwrap().contains("aggregation function"));
+            clipivot_result_free(result);
+            clipivot_builder_free(builder);
+
+            let result = clipivot_run_buffer(ptr::null(), ptr::null(), 0);
+            assert!(!clipivot_result_error(result).is_null());
+            clipivot_result_free(result);
+            clipivot_builder_free(ptr::null_mut());
+            clipivot_result_free(ptr::null_mut());
+            clipivot_string_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_ffi_panics() {
+        unsafe {
+            // a panic while running ends up in the result, rather than unwinding into C
+            let builder = PivotBuilder::new();
+            let result = run(Some(&builder), |_| panic!("the table broke"));
+            let err = CStr::from_ptr(clipivot_result_error(result));
+            assert_eq!(
+                err.to_str().unwrap(),
+                "clipivot stopped because of a bug: the table broke"
+            );
+            clipivot_result_free(result);
+            let result = run(Some(&builder), |_| panic!("{} broke", "the table"));
+            let err = CStr::from_ptr(clipivot_result_error(result));
+            assert!(err.to_str().unwrap().ends_with("the table broke"));
+            clipivot_result_free(result);
+
+            // a builder that panics while being updated keeps its old settings
+            let builder = clipivot_builder_new();
+            assert_eq!(clipivot_builder_rows(builder, c_str("region").as_ptr()), 0);
+            assert_eq!(update(builder, |_| panic!("the builder broke")), -1);
+            assert_eq!(*builder, PivotBuilder::new().rows("region"));
+            clipivot_builder_free(builder);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! The module for hashing the keys of the cells, rows, and columns of a pivot table.
+//!
+//! The standard library's hasher (SipHash) is built to resist inputs that were crafted to
+//! collide, which makes it fairly slow for the short keys a pivot table looks up for every
+//! record. `FxHasher` is the same simple multiply-and-rotate hash the Rust compiler uses for its
+//! own tables: it's much faster, and the worst a crafted file could do is slow `clipivot` down.
+use indexmap::set::IndexSet;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The constant every word of the input gets multiplied by
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+/// A fast, non-cryptographic hasher
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FxHasher {
+    hash: u64,
+}
+
+impl FxHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut word = [0; 8];
+            word[..rest.len()].copy_from_slice(rest);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(u64::from(i));
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add_to_hash(u64::from(i));
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// Builds `FxHasher`s for maps and sets
+pub type FastHashBuilder = BuildHasherDefault<FxHasher>;
+/// A `HashMap` that uses `FxHasher`
+pub type FastHashMap<K, V> = HashMap<K, V, FastHashBuilder>;
+/// An `IndexSet` that uses `FxHasher`
+pub type FastIndexSet<T> = IndexSet<T, FastHashBuilder>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hash;
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = FxHasher::default();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_hashes() {
+        let key = vec!["https://example.com/a".to_string(), "CA".to_string()];
+        assert_eq!(hash(&key), hash(&key.clone()));
+        assert_ne!(
+            hash(&key),
+            hash(&vec!["https://example.com/b".to_string(), "CA".to_string()])
+        );
+        // the boundary between the parts of a key matters
+        assert_ne!(
+            hash(&vec!["ab".to_string(), "c".to_string()]),
+            hash(&vec!["a".to_string(), "bc".to_string()])
+        );
+        assert_ne!(
+            hash(&(0usize, 1usize, 0usize)),
+            hash(&(1usize, 0usize, 0usize))
+        );
+
+        let mut map: FastHashMap<(usize, usize), usize> = FastHashMap::default();
+        for i in 0..1000 {
+            map.insert((i, i % 7), i);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map[&(350, 0)], 350);
+    }
+}
@@ -0,0 +1,205 @@
+//! The module for reading index files, like the ones `xsv index` creates.
+//!
+//! An index file has the name of the file it indexes plus `.idx` (so `data.csv.idx` for
+//! `data.csv`). It holds the byte offset where every record of the file starts (including the
+//! header row) as big-endian 64-bit integers, followed by the number of records. When there's an
+//! index next to the input, `clipivot` uses it to know how many records the file has before reading
+//! it, so the progress bar can count records. And with `--threads`, it splits the file into chunks
+//! that start on record boundaries, so each thread can parse its own chunk of the file instead of
+//! waiting on a single thread to parse all of it.
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// The number of bytes in each entry of an index
+const ENTRY_SIZE: u64 = 8;
+
+/// A run of consecutive records in a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunk {
+    /// The byte offset where the first record of the chunk starts
+    pub byte: u64,
+    /// The position of the first record of the chunk (not counting the header row)
+    pub first_record: usize,
+    /// The number of records in the chunk
+    pub num_records: usize,
+}
+
+/// An index of the records of a file
+#[derive(Debug)]
+pub struct CsvIndex<R> {
+    rdr: R,
+    /// The number of records in the index (including the header row, if there is one)
+    num_entries: u64,
+}
+
+/// The path of the index for `filename`
+pub fn index_path(filename: &str) -> PathBuf {
+    PathBuf::from(format!("{}.idx", filename))
+}
+
+impl CsvIndex<File> {
+    /// Opens the index of `filename`, if it has one. Returns an error if the index is older than
+    /// the file (since the file could have changed after it was indexed) or isn't a valid index.
+    pub fn open(filename: &str) -> io::Result<Option<CsvIndex<File>>> {
+        let path = index_path(filename);
+        let index_modified = match fs::metadata(&path) {
+            Ok(metadata) => metadata.modified()?,
+            Err(_) => return Ok(None),
+        };
+        if index_modified < fs::metadata(filename)?.modified()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is older than {}", path.display(), filename),
+            ));
+        }
+        CsvIndex::new(File::open(path)?).map(Some)
+    }
+}
+
+impl<R: Read + Seek> CsvIndex<R> {
+    /// Reads an index, returning an error if it isn't a valid index
+    pub fn new(mut rdr: R) -> io::Result<CsvIndex<R>> {
+        let len = rdr.seek(SeekFrom::End(0))?;
+        if len < ENTRY_SIZE || len % ENTRY_SIZE != 0 {
+            return Err(invalid_index());
+        }
+        let num_entries = len / ENTRY_SIZE - 1;
+        let mut index = CsvIndex { rdr, num_entries };
+        if index.entry(num_entries)? != num_entries {
+            return Err(invalid_index());
+        }
+        Ok(index)
+    }
+
+    /// The number of records in the file, not counting the header row (if it has one)
+    pub fn num_records(&self, has_header: bool) -> usize {
+        self.num_entries.saturating_sub(u64::from(has_header)) as usize
+    }
+
+    /// The byte offset where the record at position `record` starts (not counting the header
+    /// row, if the file has one)
+    pub fn offset(&mut self, record: usize, has_header: bool) -> io::Result<u64> {
+        let entry = record as u64 + u64::from(has_header);
+        if entry >= self.num_entries {
+            return Err(invalid_index());
+        }
+        self.entry(entry)
+    }
+
+    /// Splits the records of the file into at most `num_chunks` chunks of (nearly) the same size
+    pub fn chunks(&mut self, has_header: bool, num_chunks: usize) -> io::Result<Vec<Chunk>> {
+        let num_records = self.num_records(has_header);
+        let chunk_size = num_records.div_ceil(num_chunks.max(1)).max(1);
+        let mut chunks = Vec::new();
+        for first_record in (0..num_records).step_by(chunk_size) {
+            chunks.push(Chunk {
+                byte: self.offset(first_record, has_header)?,
+                first_record,
+                num_records: chunk_size.min(num_records - first_record),
+            });
+        }
+        Ok(chunks)
+    }
+
+    fn entry(&mut self, entry: u64) -> io::Result<u64> {
+        self.rdr.seek(SeekFrom::Start(entry * ENTRY_SIZE))?;
+        let mut bytes = [0; ENTRY_SIZE as usize];
+        self.rdr.read_exact(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+fn invalid_index() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the index doesn't match the format `xsv index` writes",
+    )
+}
+
+/// The line number (starting at 1) of the byte at offset `byte` of `rdr`, for error messages
+/// about records that were read from the middle of a file
+pub fn line_at<R: Read>(rdr: R, byte: u64) -> io::Result<u64> {
+    let mut rdr = BufReader::new(rdr.take(byte));
+    let mut lines = 1;
+    loop {
+        let buf = rdr.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(lines);
+        }
+        lines += buf.iter().filter(|&&b| b == b'\n').count() as u64;
+        let len = buf.len();
+        rdr.consume(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn index_of(offsets: &[u64]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for offset in offsets {
+            bytes.extend(&offset.to_be_bytes());
+        }
+        bytes.extend(&(offsets.len() as u64).to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_chunks() {
+        let data = "state,total\nCA,1\nNY,2\nCA,3\nTX,4\nNY,5\n";
+        let offsets: Vec<u64> = data
+            .match_indices('\n')
+            .map(|(i, _)| i as u64 + 1)
+            .take(5)
+            .collect();
+        let mut index = CsvIndex::new(Cursor::new(index_of(
+            &std::iter::once(0).chain(offsets).collect::<Vec<_>>(),
+        )))
+        .unwrap();
+        assert_eq!(index.num_records(true), 5);
+        assert_eq!(index.num_records(false), 6);
+        assert_eq!(index.offset(0, true).unwrap(), 12);
+        assert!(index.offset(5, true).is_err());
+        assert_eq!(
+            index.chunks(true, 2).unwrap(),
+            vec![
+                Chunk {
+                    byte: 12,
+                    first_record: 0,
+                    num_records: 3
+                },
+                Chunk {
+                    byte: 27,
+                    first_record: 3,
+                    num_records: 2
+                },
+            ]
+        );
+        assert_eq!(&data[27..], "TX,4\nNY,5\n");
+        assert_eq!(index.chunks(true, 10).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        assert!(CsvIndex::new(Cursor::new(Vec::new())).is_err());
+        assert!(CsvIndex::new(Cursor::new(vec![0; 12])).is_err());
+        // the count at the end doesn't match the number of offsets
+        let mut bytes = index_of(&[0, 12]);
+        bytes.extend(&0u64.to_be_bytes());
+        assert!(CsvIndex::new(Cursor::new(bytes)).is_err());
+        let mut empty = CsvIndex::new(Cursor::new(index_of(&[]))).unwrap();
+        assert_eq!(empty.num_records(true), 0);
+        assert!(empty.chunks(true, 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_line_at() {
+        let data = "a,b\n1,\"two\nlines\"\n3,4\n";
+        assert_eq!(line_at(data.as_bytes(), 0).unwrap(), 1);
+        assert_eq!(line_at(data.as_bytes(), 4).unwrap(), 2);
+        assert_eq!(line_at(data.as_bytes(), 18).unwrap(), 4);
+    }
+}
@@ -0,0 +1,368 @@
+//! The module for exploring a file interactively, with `clipivot interactive FILE`.
+//!
+//! Instead of typing out a full command, the user picks the aggregation function and the
+//! row, column, and values fields from the headers of the file in a series of prompts. After
+//! each choice of fields, `clipivot` shows a preview of the pivot table, so the user can try
+//! out different fields until the table looks right. Once the user is happy with the table,
+//! `clipivot` prints the equivalent (non-interactive) command, so the table can be recreated
+//! in a script.
+//!
+//! The prompts and previews get written to standard error, so that only the final command gets
+//! written to standard output (as in `cmd=$(clipivot interactive sales.csv)`).
+//!
+//! The same prompts also fill in required arguments that are missing from a regular command
+//! (like the values column of `clipivot sum sales.csv`), as long as `clipivot` is running in a
+//! terminal. When the input or the errors are redirected, missing arguments are still errors.
+use crate::cli::ALLOWED_AGGFUNCS;
+use crate::cli_settings::CsvSettings;
+use crate::errors::{CsvCliError, CsvCliResult};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// The name of the pseudo-subcommand that explores a file interactively
+pub const INTERACTIVE_COMMAND: &str = "interactive";
+
+/// The maximum number of lines of a preview
+const PREVIEW_LINES: usize = 10;
+
+/// The choices the user has made so far
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Choices {
+    aggfunc: String,
+    rows: Vec<String>,
+    columns: Vec<String>,
+    values: Option<String>,
+}
+
+impl Choices {
+    /// The arguments of the equivalent non-interactive command (not including the
+    /// name of the program)
+    fn to_args(&self, filename: &str) -> Vec<String> {
+        let mut args = vec![self.aggfunc.clone(), filename.to_string()];
+        for row in &self.rows {
+            args.extend(vec!["-r".to_string(), row.clone()]);
+        }
+        for column in &self.columns {
+            args.extend(vec!["-c".to_string(), column.clone()]);
+        }
+        if let Some(values) = &self.values {
+            args.extend(vec!["-v".to_string(), values.clone()]);
+        }
+        args
+    }
+}
+
+/// Explores `filename` interactively, using the `clipivot` executable at the current path
+/// to compute the previews, and returns the equivalent command
+pub fn explore_file(filename: &str) -> CsvCliResult<String> {
+    let settings = CsvSettings::parse_new(&Some(filename), None, true)?;
+    let mut rdr = settings.get_reader_from_path(filename)?;
+    let headers: Vec<String> = rdr.headers()?.iter().map(String::from).collect();
+    let exe = std::env::current_exe()?;
+    let preview = |args: &[String]| -> CsvCliResult<String> {
+        let output = std::process::Command::new(&exe).args(args).output()?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(CsvCliError::InvalidConfiguration(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ))
+        }
+    };
+    let stdin = io::stdin();
+    explore(
+        filename,
+        &headers,
+        &mut stdin.lock(),
+        &mut io::stderr(),
+        preview,
+    )
+}
+
+/// Prompts the user (reading their answers from `input` and writing the prompts to `output`)
+/// until they've settled on a pivot table, computing each preview with `preview`, and returns
+/// the equivalent command
+fn explore<R, W, F>(
+    filename: &str,
+    headers: &[String],
+    input: &mut R,
+    output: &mut W,
+    preview: F,
+) -> CsvCliResult<String>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn(&[String]) -> CsvCliResult<String>,
+{
+    writeln!(output, "The fields in {} are:", filename)?;
+    write_headers(output, headers)?;
+    loop {
+        let choices = choose(headers, input, output)?;
+        let args = choices.to_args(filename);
+        writeln!(output)?;
+        match preview(&args) {
+            Ok(table) => {
+                for line in table.lines().take(PREVIEW_LINES) {
+                    writeln!(output, "{}", line)?;
+                }
+                if table.lines().count() > PREVIEW_LINES {
+                    writeln!(output, "...")?;
+                }
+            }
+            Err(err) => writeln!(output, "{}", err)?,
+        }
+        writeln!(output)?;
+        let answer = prompt(output, input, "Keep this pivot table? [y/N]")?;
+        if answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes") {
+            let command: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+            return Ok(format!("clipivot {}", command.join(" ")));
+        }
+    }
+}
+
+/// Asks for the aggregation function and the fields of a pivot table
+fn choose<R: BufRead, W: Write>(
+    headers: &[String],
+    input: &mut R,
+    output: &mut W,
+) -> CsvCliResult<Choices> {
+    let aggfunc = choose_aggfunc(input, output)?;
+    let rows = choose_fields(headers, input, output, "Row fields", true)?;
+    let columns = choose_fields(headers, input, output, "Column fields", true)?;
+    // only count works without a values field
+    let values = choose_fields(headers, input, output, "Values field", aggfunc == "count")?;
+    Ok(Choices {
+        aggfunc,
+        rows,
+        columns,
+        values: values.into_iter().next(),
+    })
+}
+
+/// Asks for the aggregation function, which defaults to count
+fn choose_aggfunc<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> CsvCliResult<String> {
+    let question = format!(
+        "Aggregation function ({}) [count]:",
+        ALLOWED_AGGFUNCS.join(", ")
+    );
+    loop {
+        let answer = prompt(output, input, &question)?;
+        match answer.as_str() {
+            "" => return Ok("count".to_string()),
+            func if ALLOWED_AGGFUNCS.contains(&func) => return Ok(func.to_string()),
+            func => writeln!(output, "`{}` is not an aggregation function", func)?,
+        }
+    }
+}
+
+/// Whether missing arguments can be asked for: standard input and standard error both need to
+/// be terminals, so the answers don't get mixed up with piped-in data or logged output
+pub fn can_prompt() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Asks for the aggregation function of a command that only named a file
+pub fn prompt_for_aggfunc(filename: &str) -> CsvCliResult<String> {
+    let mut output = io::stderr();
+    writeln!(
+        output,
+        "Which function should aggregate the records of {}?",
+        filename
+    )?;
+    choose_aggfunc(&mut io::stdin().lock(), &mut output)
+}
+
+/// Asks for the values column of a function that needs one, returning the name of the column
+pub fn prompt_for_values_field(aggfunc: &str, headers: &[String]) -> CsvCliResult<String> {
+    ask_for_values_field(aggfunc, headers, &mut io::stdin().lock(), &mut io::stderr())
+}
+
+fn ask_for_values_field<R: BufRead, W: Write>(
+    aggfunc: &str,
+    headers: &[String],
+    input: &mut R,
+    output: &mut W,
+) -> CsvCliResult<String> {
+    writeln!(
+        output,
+        "Which column should `{}` aggregate? The fields are:",
+        aggfunc
+    )?;
+    write_headers(output, headers)?;
+    let fields = choose_fields(headers, input, output, "Values field", false)?;
+    Ok(fields.into_iter().next().unwrap_or_default())
+}
+
+/// Lists the headers of a file, along with their indexes
+fn write_headers<W: Write>(output: &mut W, headers: &[String]) -> io::Result<()> {
+    for (idx, header) in headers.iter().enumerate() {
+        writeln!(output, "  {}: {}", idx, header)?;
+    }
+    Ok(())
+}
+
+/// Asks for a comma-separated list of fields (given as names or as indexes), asking again
+/// until every field is in the headers. A single field is allowed for the values field.
+fn choose_fields<R: BufRead, W: Write>(
+    headers: &[String],
+    input: &mut R,
+    output: &mut W,
+    name: &str,
+    optional: bool,
+) -> CsvCliResult<Vec<String>> {
+    let single = name == "Values field";
+    let hint = match (single, optional) {
+        (true, true) => "a name or number, blank for none",
+        (true, false) => "a name or number",
+        (false, _) => "comma-separated names or numbers, blank for none",
+    };
+    loop {
+        let answer = prompt(output, input, &format!("{} ({}):", name, hint))?;
+        if answer.is_empty() {
+            if optional {
+                return Ok(vec![]);
+            }
+            writeln!(output, "You need to pick a values field for this function")?;
+            continue;
+        }
+        let fields: Result<Vec<String>, String> = answer
+            .split(',')
+            .map(|field| find_field(headers, field.trim()))
+            .collect();
+        match fields {
+            Ok(ref fields) if single && fields.len() > 1 => {
+                writeln!(output, "You can only pick one values field")?
+            }
+            Ok(fields) => return Ok(fields),
+            Err(field) => writeln!(output, "Could not find `{}` in the header row", field)?,
+        }
+    }
+}
+
+/// Finds a field by its name or by its index, returning its name
+fn find_field(headers: &[String], field: &str) -> Result<String, String> {
+    if let Some(header) = headers.iter().find(|header| header.as_str() == field) {
+        return Ok(header.clone());
+    }
+    match field.parse::<usize>().ok().and_then(|idx| headers.get(idx)) {
+        Some(header) => Ok(header.clone()),
+        None => Err(field.to_string()),
+    }
+}
+
+/// Writes a question and reads the (trimmed) answer, returning an error if the input ends
+fn prompt<R: BufRead, W: Write>(
+    output: &mut W,
+    input: &mut R,
+    question: &str,
+) -> io::Result<String> {
+    write!(output, "{} ", question)?;
+    output.flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The input ended before you finished picking a pivot table",
+        ));
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Quotes an argument for a POSIX shell, if it needs to be quoted
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> Vec<String> {
+        vec![
+            "region".to_string(),
+            "month".to_string(),
+            "sales amount".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_find_field() {
+        assert_eq!(find_field(&headers(), "month"), Ok("month".to_string()));
+        assert_eq!(find_field(&headers(), "2"), Ok("sales amount".to_string()));
+        assert_eq!(find_field(&headers(), "day"), Err("day".to_string()));
+        assert_eq!(find_field(&headers(), "3"), Err("3".to_string()));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("sales.csv"), "sales.csv");
+        assert_eq!(shell_quote("sales amount"), "'sales amount'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_ask_for_values_field() {
+        let mut output = Vec::new();
+        let field = ask_for_values_field(
+            "sum",
+            &headers(),
+            &mut "\nnope\n2\n".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(field, "sales amount");
+        let output = String::from_utf8(output).unwrap();
+        assert!(output
+            .starts_with("Which column should `sum` aggregate? The fields are:\n  0: region\n"));
+        assert!(output.contains("You need to pick a values field"));
+        assert!(output.contains("Could not find `nope` in the header row"));
+    }
+
+    #[test]
+    fn test_explore() {
+        // tries count by region, rejects it, then picks the sum of sales by region and month
+        let answers = "\nregion\n\n\nn\nsum\n0\nmonth,nope\n1\n\n2\ny\n";
+        let mut output = Vec::new();
+        let previews = std::cell::RefCell::new(Vec::new());
+        let command = explore(
+            "sales.csv",
+            &headers(),
+            &mut answers.as_bytes(),
+            &mut output,
+            |args: &[String]| {
+                previews.borrow_mut().push(args.to_vec());
+                Ok("region,total\nEast,1\n".to_string())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            command,
+            "clipivot sum sales.csv -r region -c month -v 'sales amount'"
+        );
+        assert_eq!(previews.borrow().len(), 2);
+        assert_eq!(
+            previews.borrow()[0],
+            vec!["count", "sales.csv", "-r", "region"]
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  2: sales amount"));
+        assert!(output.contains("Could not find `nope` in the header row"));
+        assert!(output.contains("You need to pick a values field"));
+        assert!(output.contains("East,1"));
+        // running out of input is an error, rather than an endless loop
+        let mut output = Vec::new();
+        let result = explore(
+            "sales.csv",
+            &headers(),
+            &mut "sum\n".as_bytes(),
+            &mut output,
+            |_: &[String]| Ok(String::new()),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,673 @@
+//! The module for deriving the grouping keys (the values in the row and column fields)
+//! from each record.
+//!
+//! Most of the time, a grouping key is simply the value of a column. But a key can also
+//! come from the pseudo-column `@index`, which holds the 0-indexed number of each record
+//! (matching the record numbers in error messages), so you can pivot a file by chunks of records.
+//! Keys can also be computed from an `Expr`, like `city ~ ", " ~ state`.
+//! Each field can also apply a series of `KeyTransform`s to its values (like pulling a capture group
+//! out of a regular expression) before they get used as keys.
+//!
+//! Fields that truncate dates keep a `ParseCache` of the keys their raw values turned into, since
+//! parsing a date with each of the formats in turn is slow and most files have many records for
+//! each date.
+use crate::cache::ParseCache;
+use crate::expression::Expr;
+use crate::record::Record;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The name of the pseudo-column holding the record number
+pub const RECORD_NUMBER_FIELD: &str = "@index";
+
+/// Where the value of a grouping key comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    /// A column of the CSV file (identified by its 0-indexed position)
+    Column(usize),
+    /// The 0-indexed number of the record
+    RecordNumber,
+    /// The result of an expression, along with the text of the expression (which names the field)
+    Expression(Expr, String),
+}
+
+/// The date formats that `--by` (and the date filters) try when the user hasn't set a format with `-F`
+pub const DEFAULT_KEY_DATE_FORMATS: [&str; 5] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d",
+    "%m/%d/%Y",
+];
+
+/// The period a date gets truncated to with `--by`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatePart {
+    /// e.g. `2019`
+    Year,
+    /// e.g. `2019-03`
+    Month,
+    /// The ISO week, e.g. `2019-W09`
+    Week,
+    /// e.g. `2019-03-04`
+    Day,
+    /// The day of the week, e.g. `Monday`
+    Weekday,
+    /// e.g. `2019-03-04 13:00`
+    Hour,
+}
+
+impl FromStr for DatePart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "year" => Ok(DatePart::Year),
+            "month" => Ok(DatePart::Month),
+            "week" => Ok(DatePart::Week),
+            "day" => Ok(DatePart::Day),
+            "weekday" => Ok(DatePart::Weekday),
+            "hour" => Ok(DatePart::Hour),
+            _ => Err(format!(
+                "`{}` is not a date part. Expected one of year, month, week, day, weekday, or hour",
+                s
+            )),
+        }
+    }
+}
+
+impl DatePart {
+    fn format(self, dt: &NaiveDateTime) -> String {
+        match self {
+            DatePart::Year => dt.format("%Y").to_string(),
+            DatePart::Month => dt.format("%Y-%m").to_string(),
+            DatePart::Week => {
+                let week = dt.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            DatePart::Day => dt.format("%Y-%m-%d").to_string(),
+            DatePart::Weekday => dt.format("%A").to_string(),
+            DatePart::Hour => dt.format("%Y-%m-%d %H:00").to_string(),
+        }
+    }
+}
+
+/// Parses a date (or datetime) with the first of the formats that works
+pub fn parse_date(value: &str, formats: &[String]) -> Option<NaiveDateTime> {
+    formats.iter().find_map(|fmt| {
+        NaiveDateTime::parse_from_str(value, fmt)
+            .or_else(|_| NaiveDate::parse_from_str(value, fmt).map(|d| d.and_hms(0, 0, 0)))
+            .ok()
+    })
+}
+
+/// Parses a date, or a period like a month (`2019-03` or `Mar 2019`), a year (`2019`), an ISO week
+/// (`2019-W09`), or a day of the week (`Monday`) into the moment the period starts. This understands
+/// the labels `--by` produces, along with the dates in `DEFAULT_KEY_DATE_FORMATS`, so that they can
+/// be sorted chronologically. (Days of the week start on Monday.)
+pub fn parse_period(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    let formats: Vec<String> = DEFAULT_KEY_DATE_FORMATS
+        .iter()
+        .map(|fmt| fmt.to_string())
+        .collect();
+    if let Some(dt) = parse_date(value, &formats) {
+        return Some(dt);
+    }
+    let start_of_day = |date: NaiveDate| date.and_hms(0, 0, 0);
+    if value.len() == 4 && value.chars().all(|c| c.is_ascii_digit()) {
+        return NaiveDate::from_ymd_opt(value.parse().ok()?, 1, 1).map(start_of_day);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(&format!("{}-1", value), "%G-W%V-%u") {
+        return Some(start_of_day(date));
+    }
+    let month_formats = ["%Y-%m", "%m/%Y", "%b-%Y", "%b %Y", "%B-%Y", "%B %Y"];
+    for fmt in &month_formats {
+        let with_day = format!("01 {}", value);
+        if let Ok(date) = NaiveDate::parse_from_str(&with_day, &format!("%d {}", fmt)) {
+            return Some(start_of_day(date));
+        }
+    }
+    let weekday: chrono::Weekday = value.parse().ok()?;
+    // 2018 started on a Monday
+    NaiveDate::from_ymd_opt(2018, 1, 1 + weekday.num_days_from_monday()).map(start_of_day)
+}
+
+/// The buckets that `--bin` sorts numbers into
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bins {
+    /// Buckets of equal width, starting at 0 (e.g. `[10,20)`)
+    Width(Decimal),
+    /// Buckets between each pair of (increasing) edges, so the edges `0,18,35` produce
+    /// `[0,18)` and `[18,35)`. Values outside the edges become `<0` or `>=35`.
+    Edges(Vec<Decimal>),
+}
+
+impl FromStr for Bins {
+    type Err = String;
+
+    /// Parses either `width=N` or a comma-separated list of edges
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_num = |num: &str| {
+            Decimal::from_str(num.trim())
+                .map_err(|_| format!("Could not parse `{}` as a bin boundary", num.trim()))
+        };
+        if let Some(width) = s.trim().strip_prefix("width=") {
+            let width = parse_num(width)?;
+            if width <= Decimal::new(0, 0) {
+                return Err("The width of the bins needs to be positive".to_string());
+            }
+            return Ok(Bins::Width(width));
+        }
+        let edges = s.split(',').map(parse_num).collect::<Result<Vec<_>, _>>()?;
+        if edges.len() < 2 || edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!(
+                "Expected `{}` to be width=N or at least two increasing numbers (e.g. 0,18,35)",
+                s
+            ));
+        }
+        Ok(Bins::Edges(edges))
+    }
+}
+
+impl Bins {
+    fn label(&self, value: Decimal) -> String {
+        match self {
+            Bins::Width(width) => {
+                let start = (value / width).floor() * width;
+                format!("[{},{})", start.normalize(), (start + width).normalize())
+            }
+            Bins::Edges(edges) => {
+                let last = edges[edges.len() - 1];
+                if value < edges[0] {
+                    return format!("<{}", edges[0]);
+                }
+                if value >= last {
+                    return format!(">={}", last);
+                }
+                let pos = edges.iter().rposition(|edge| *edge <= value).unwrap();
+                format!("[{},{})", edges[pos], edges[pos + 1])
+            }
+        }
+    }
+}
+
+/// A simple cleanup step for `--normalize-keys`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Removes leading and trailing whitespace
+    Trim,
+    /// Converts the value to lowercase
+    Lower,
+    /// Converts the value to uppercase
+    Upper,
+    /// Replaces each run of whitespace with a single space
+    CollapseSpaces,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "trim" => Ok(Normalization::Trim),
+            "lower" => Ok(Normalization::Lower),
+            "upper" => Ok(Normalization::Upper),
+            "collapse-spaces" => Ok(Normalization::CollapseSpaces),
+            _ => Err(format!(
+                "`{}` is not a normalization. Expected one of trim, lower, upper, or collapse-spaces",
+                s
+            )),
+        }
+    }
+}
+
+impl Normalization {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Normalization::Trim => value.trim().to_string(),
+            Normalization::Lower => value.to_lowercase(),
+            Normalization::Upper => value.to_uppercase(),
+            Normalization::CollapseSpaces => {
+                let mut collapsed = String::with_capacity(value.len());
+                let mut prev_space = false;
+                for c in value.chars() {
+                    if c.is_whitespace() {
+                        if !prev_space {
+                            collapsed.push(' ');
+                        }
+                        prev_space = true;
+                    } else {
+                        collapsed.push(c);
+                        prev_space = false;
+                    }
+                }
+                collapsed
+            }
+        }
+    }
+}
+
+/// What to do with values that don't appear in a `--recode` lookup table
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnmappedPolicy {
+    /// Leave the value as it is
+    Keep,
+    /// Skip the records with unmapped values
+    Drop,
+    /// Replace the value with a catch-all label
+    Other(String),
+}
+
+/// What to do with values that aren't dates in any of the formats a field truncates dates with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonDatePolicy {
+    /// Leave the value as it is, so that truncating every field (with `--by month`) doesn't
+    /// change fields like `region`
+    Keep,
+    /// Fail with an error naming the value, so that dates in an unexpected format don't end up
+    /// as keys of their own among the periods (empty values are still left as they are)
+    Fail,
+}
+
+/// A transformation applied to the values of a field before they get used as keys
+#[derive(Debug, Clone)]
+pub enum KeyTransform {
+    /// Replaces the value with the text in the regular expression's first capture group
+    /// (or the whole match, if it doesn't have any groups). Values that don't match become empty.
+    Extract(Regex),
+    /// Truncates a date to a period, trying each of the formats in turn. The policy decides
+    /// what happens to values that aren't dates.
+    DatePart(DatePart, Vec<String>, NonDatePolicy),
+    /// Sorts numbers into buckets. Values that aren't numbers are left alone.
+    Bin(Bins),
+    /// Replaces values with the labels they map to in a lookup table
+    Recode(HashMap<String, String>, UnmappedPolicy),
+    /// Cleans up values with a series of normalizations, in order
+    Normalize(Vec<Normalization>),
+}
+
+impl KeyTransform {
+    /// Transforms a value, returning `None` if the record should be skipped, or an error message
+    /// if the value can't be transformed
+    fn apply(&self, value: String) -> Result<Option<String>, String> {
+        let transformed = match self {
+            KeyTransform::Extract(re) => re
+                .captures(&value)
+                .and_then(|caps| caps.get(1).or_else(|| caps.get(0)))
+                .map_or(String::new(), |m| m.as_str().to_string()),
+            KeyTransform::DatePart(part, formats, policy) => {
+                match (parse_date(value.trim(), formats), policy) {
+                    (Some(dt), _) => part.format(&dt),
+                    (None, NonDatePolicy::Fail) if !value.trim().is_empty() => {
+                        return Err(format!(
+                            "`{}` isn't a date in any of the formats {}",
+                            value,
+                            formats.join(", ")
+                        ))
+                    }
+                    (None, _) => value,
+                }
+            }
+            KeyTransform::Bin(bins) => match Decimal::from_str(value.trim()) {
+                Ok(num) => bins.label(num),
+                Err(_) => value,
+            },
+            KeyTransform::Recode(lookup, policy) => match (lookup.get(&value), policy) {
+                (Some(label), _) => label.clone(),
+                (None, UnmappedPolicy::Keep) => value,
+                (None, UnmappedPolicy::Drop) => return Ok(None),
+                (None, UnmappedPolicy::Other(label)) => label.clone(),
+            },
+            KeyTransform::Normalize(steps) => {
+                steps.iter().fold(value, |value, step| step.apply(&value))
+            }
+        };
+        Ok(Some(transformed))
+    }
+}
+
+impl PartialEq for KeyTransform {
+    fn eq(&self, other: &KeyTransform) -> bool {
+        match (self, other) {
+            (KeyTransform::Extract(a), KeyTransform::Extract(b)) => a.as_str() == b.as_str(),
+            (
+                KeyTransform::DatePart(a, a_fmts, a_policy),
+                KeyTransform::DatePart(b, b_fmts, b_policy),
+            ) => a == b && a_fmts == b_fmts && a_policy == b_policy,
+            (KeyTransform::Bin(a), KeyTransform::Bin(b)) => a == b,
+            (KeyTransform::Recode(a, a_policy), KeyTransform::Recode(b, b_policy)) => {
+                a == b && a_policy == b_policy
+            }
+            (KeyTransform::Normalize(a), KeyTransform::Normalize(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// The key a field computes for a record (see `KeyField::get_key`)
+type KeyResult = Result<Option<String>, String>;
+
+/// A single row or column field
+#[derive(Debug, Clone)]
+pub struct KeyField {
+    source: KeySource,
+    transforms: Vec<KeyTransform>,
+    /// The keys that recent raw values turned into (or the errors they ran into), for fields
+    /// that truncate dates
+    cache: Option<RefCell<ParseCache<KeyResult>>>,
+}
+
+impl PartialEq for KeyField {
+    fn eq(&self, other: &KeyField) -> bool {
+        // the cache doesn't change the keys the field computes
+        self.source == other.source && self.transforms == other.transforms
+    }
+}
+
+impl KeyField {
+    /// A field that takes its values from a column
+    pub fn column(idx: usize) -> KeyField {
+        KeyField {
+            source: KeySource::Column(idx),
+            transforms: Vec::new(),
+            cache: None,
+        }
+    }
+
+    /// A field that takes its values from the record number
+    pub fn record_number() -> KeyField {
+        KeyField {
+            source: KeySource::RecordNumber,
+            transforms: Vec::new(),
+            cache: None,
+        }
+    }
+
+    /// Adds a transformation to the end of the field's list of transformations
+    pub fn with_transform(mut self, transform: KeyTransform) -> Self {
+        if let KeyTransform::DatePart(..) = transform {
+            self.cache = Some(RefCell::new(ParseCache::new()));
+        }
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn source(&self) -> &KeySource {
+        &self.source
+    }
+
+    /// The position of the column the field reads from, if it reads from a column
+    pub fn column_index(&self) -> Option<usize> {
+        match self.source {
+            KeySource::Column(idx) => Some(idx),
+            _ => None,
+        }
+    }
+
+    /// The name of the field, given the header row of the file
+    pub fn name(&self, headers: &csv::StringRecord) -> String {
+        match &self.source {
+            KeySource::Column(idx) => headers.get(*idx).unwrap_or_default().to_string(),
+            KeySource::RecordNumber => RECORD_NUMBER_FIELD.to_string(),
+            KeySource::Expression(_, text) => text.clone(),
+        }
+    }
+
+    /// The column the field takes its values from, if it uses the column's values as they are
+    /// (without any transformations)
+    pub fn plain_column(&self) -> Option<usize> {
+        match (&self.source, self.transforms.is_empty()) {
+            (KeySource::Column(idx), true) => Some(*idx),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of the key for a given record, or `None` if the record
+    /// should be skipped (because of a `--recode` lookup table that drops unmapped values).
+    /// Returns an error message if the field's expression can't be evaluated for the record,
+    /// or if its date isn't a date (for a field that fails on values that aren't dates).
+    pub fn get_key<R: Record>(
+        &self,
+        record: &R,
+        line_num: usize,
+    ) -> Result<Option<String>, String> {
+        let value = match &self.source {
+            KeySource::Column(idx) => record.field(*idx)?.to_string(),
+            KeySource::RecordNumber => line_num.to_string(),
+            KeySource::Expression(expr, _) => expr.eval(record)?.to_string(),
+        };
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.transform(value),
+        };
+        if let Some(key) = cache.borrow_mut().get(&value) {
+            return key;
+        }
+        let key = self.transform(value.clone());
+        cache.borrow_mut().insert(&value, key.clone());
+        key
+    }
+
+    fn transform(&self, value: String) -> Result<Option<String>, String> {
+        let mut value = value;
+        for transform in &self.transforms {
+            value = match transform.apply(value)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(value))
+    }
+}
+
+impl From<usize> for KeyField {
+    fn from(idx: usize) -> KeyField {
+        KeyField::column(idx)
+    }
+}
+
+impl From<KeySource> for KeyField {
+    fn from(source: KeySource) -> KeyField {
+        KeyField {
+            source,
+            transforms: Vec::new(),
+            cache: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::StringRecord;
+
+    #[test]
+    fn test_get_key() {
+        let record = StringRecord::from(vec!["a", "b"]);
+        assert_eq!(
+            KeyField::column(1).get_key(&record, 5).unwrap().unwrap(),
+            "b"
+        );
+        assert_eq!(
+            KeyField::record_number()
+                .get_key(&record, 5)
+                .unwrap()
+                .unwrap(),
+            "5"
+        );
+        let headers = StringRecord::from(vec!["x", "y"]);
+        assert_eq!(KeyField::from(0).name(&headers), "x");
+        assert_eq!(KeyField::record_number().name(&headers), "@index");
+        let label = Expr::Binary(
+            Box::new(Expr::Column(1)),
+            crate::expression::BinaryOp::Concat,
+            Box::new(Expr::Column(0)),
+        );
+        let expr_field = KeyField::from(KeySource::Expression(label, "y ~ x".to_string()));
+        assert_eq!(expr_field.get_key(&record, 5).unwrap().unwrap(), "ba");
+        assert_eq!(expr_field.name(&headers), "y ~ x");
+    }
+
+    #[test]
+    fn test_extract() {
+        let domain = KeyField::column(0).with_transform(KeyTransform::Extract(
+            Regex::new("^https?://([^/]+)").unwrap(),
+        ));
+        let key = |val: &str| {
+            domain
+                .get_key(&StringRecord::from(vec![val]), 0)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(key("https://example.com/page"), "example.com");
+        assert_eq!(key("ftp://example.com"), "");
+        let year = KeyField::column(0)
+            .with_transform(KeyTransform::Extract(Regex::new("[0-9]{4}").unwrap()));
+        assert_eq!(
+            year.get_key(&StringRecord::from(vec!["FY2019"]), 0)
+                .unwrap()
+                .unwrap(),
+            "2019"
+        );
+    }
+
+    #[test]
+    fn test_date_parts() {
+        let formats: Vec<String> = DEFAULT_KEY_DATE_FORMATS
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let key = |part: &str, val: &str| {
+            KeyField::column(0)
+                .with_transform(KeyTransform::DatePart(
+                    part.parse().unwrap(),
+                    formats.clone(),
+                    NonDatePolicy::Keep,
+                ))
+                .get_key(&StringRecord::from(vec![val]), 0)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(key("year", "2019-03-04 13:45:00"), "2019");
+        assert_eq!(key("month", "2019-03-04"), "2019-03");
+        assert_eq!(key("week", "2019-03-04"), "2019-W10");
+        assert_eq!(key("week", "2021-01-01"), "2020-W53");
+        assert_eq!(key("day", "03/04/2019"), "2019-03-04");
+        assert_eq!(key("weekday", "2019-03-04"), "Monday");
+        assert_eq!(key("hour", "2019-03-04T13:45:00"), "2019-03-04 13:00");
+        assert_eq!(key("Month", "not a date"), "not a date");
+        assert!("quarter".parse::<DatePart>().is_err());
+        let strict = KeyField::column(0).with_transform(KeyTransform::DatePart(
+            DatePart::Month,
+            vec!["%Y-%m-%d".to_string()],
+            NonDatePolicy::Fail,
+        ));
+        let key = |val: &str| strict.get_key(&StringRecord::from(vec![val]), 0);
+        assert_eq!(key("2020-01-04"), Ok(Some("2020-01".to_string())));
+        assert_eq!(
+            key("13/45/2020"),
+            Err("`13/45/2020` isn't a date in any of the formats %Y-%m-%d".to_string())
+        );
+        // the error gets cached along with the keys
+        assert!(key("13/45/2020").is_err());
+        assert_eq!(key(""), Ok(Some(String::new())));
+    }
+
+    #[test]
+    fn test_date_cache() {
+        let lookup: HashMap<String, String> = vec![("2019-03", "March")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let field = KeyField::column(0)
+            .with_transform(KeyTransform::DatePart(
+                DatePart::Month,
+                vec!["%Y-%m-%d".to_string()],
+                NonDatePolicy::Keep,
+            ))
+            .with_transform(KeyTransform::Recode(lookup, UnmappedPolicy::Drop));
+        let fresh = field.clone();
+        let key = |val: &str| field.get_key(&StringRecord::from(vec![val]), 0).unwrap();
+        for _ in 0..3 {
+            // the second and third times, the keys come from the cache
+            assert_eq!(key("2019-03-04"), Some("March".to_string()));
+            assert_eq!(key("2019-03-05"), Some("March".to_string()));
+            assert_eq!(key("2019-04-01"), None);
+        }
+        assert_eq!(field, fresh);
+        assert!(KeyField::column(0).cache.is_none());
+    }
+
+    #[test]
+    fn test_bins() {
+        let key = |bins: &str, val: &str| {
+            KeyField::column(0)
+                .with_transform(KeyTransform::Bin(bins.parse().unwrap()))
+                .get_key(&StringRecord::from(vec![val]), 0)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(key("width=10", "23"), "[20,30)");
+        assert_eq!(key("width=10", "-3"), "[-10,0)");
+        assert_eq!(key("width=0.5", "1.7"), "[1.5,2)");
+        assert_eq!(key("0,18,35,65", "18"), "[18,35)");
+        assert_eq!(key("0,18,35,65", "17.9"), "[0,18)");
+        assert_eq!(key("0,18,35,65", "65"), ">=65");
+        assert_eq!(key("0,18,35,65", "-1"), "<0");
+        assert_eq!(key("0,18", "unknown"), "unknown");
+        assert!("width=0".parse::<Bins>().is_err());
+        assert!("10".parse::<Bins>().is_err());
+        assert!("10,5".parse::<Bins>().is_err());
+    }
+
+    #[test]
+    fn test_recode() {
+        let lookup: HashMap<String, String> = vec![("39049", "Central"), ("39035", "Northeast")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let key = |policy: UnmappedPolicy, val: &str| {
+            KeyField::column(0)
+                .with_transform(KeyTransform::Recode(lookup.clone(), policy))
+                .get_key(&StringRecord::from(vec![val]), 0)
+                .unwrap()
+        };
+        assert_eq!(
+            key(UnmappedPolicy::Drop, "39049"),
+            Some("Central".to_string())
+        );
+        assert_eq!(
+            key(UnmappedPolicy::Keep, "39001"),
+            Some("39001".to_string())
+        );
+        assert_eq!(key(UnmappedPolicy::Drop, "39001"), None);
+        assert_eq!(
+            key(UnmappedPolicy::Other("Other".to_string()), "39001"),
+            Some("Other".to_string())
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let steps: Vec<Normalization> = ["trim", "lower", "collapse-spaces"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let field = KeyField::column(0).with_transform(KeyTransform::Normalize(steps));
+        let key = |val: &str| {
+            field
+                .get_key(&StringRecord::from(vec![val]), 0)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(key("  New   York "), "new york");
+        assert_eq!(key("new york"), "new york");
+        assert_eq!(Normalization::Upper.apply("ohio"), "OHIO");
+        assert!("titlecase".parse::<Normalization>().is_err());
+    }
+}
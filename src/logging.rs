@@ -0,0 +1,122 @@
+//! The module for writing diagnostic messages (with `--verbose`) to standard error.
+//!
+//! Messages are only written if the verbosity is at least as high as the level of the message,
+//! so by default nothing gets written. `--verbose` writes `Info` messages (like how the input
+//! gets read and how many records were skipped), and `--verbose --verbose` also writes `Debug`
+//! messages (like the fields that were selected). Since the messages go to standard error,
+//! standard output only ever holds the pivot table.
+//!
+//! Warnings (about things that aren't errors, but might not be what the user meant) get written
+//! regardless of the verbosity, unless `--quiet` was passed.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(0);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// How detailed a message is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// A summary of what happened, like the number of records that were read
+    Info = 1,
+    /// The details of how a pivot table was configured
+    Debug = 2,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Info => write!(f, "info"),
+            Level::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+/// Sets the verbosity: the number of times `--verbose` was passed
+pub fn set_verbosity(verbosity: usize) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+/// Whether messages of a given level get written
+pub fn enabled(level: Level) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as usize
+}
+
+/// Writes a message to standard error, if messages of its level are enabled
+pub fn log(level: Level, message: fmt::Arguments) {
+    if enabled(level) {
+        eprintln!("[{}] {}", level, message);
+    }
+}
+
+/// Stops warnings from getting written (for `--quiet`)
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether warnings get written
+pub fn warnings_enabled() -> bool {
+    !QUIET.load(Ordering::Relaxed)
+}
+
+/// Writes a warning (or another non-fatal message) to standard error, unless warnings are off
+pub fn warn(message: fmt::Arguments) {
+    if warnings_enabled() {
+        eprintln!("{}", message);
+    }
+}
+
+/// The peak memory usage of the process (its high-water mark), in bytes. This is only
+/// available on Linux, where it's read from `/proc/self/status`.
+pub fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_peak_memory(&status)
+}
+
+/// Reads the `VmHWM` line of `/proc/self/status`, which holds the peak memory usage in kB
+fn parse_peak_memory(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Formats a duration in milliseconds, for timing each phase of a run
+pub fn format_duration(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        set_verbosity(0);
+        assert!(!enabled(Level::Info));
+        set_verbosity(1);
+        assert!(enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+        set_verbosity(2);
+        assert!(enabled(Level::Debug));
+        set_verbosity(0);
+        assert!(warnings_enabled());
+        set_quiet(true);
+        assert!(!warnings_enabled());
+        set_quiet(false);
+        assert_eq!(format_duration(Duration::from_micros(1300)), "1.3ms");
+    }
+
+    #[test]
+    fn test_parse_peak_memory() {
+        let status =
+            "Name:\tclipivot\nVmPeak:\t  10000 kB\nVmHWM:\t    2048 kB\nVmRSS:\t 1024 kB\n";
+        assert_eq!(parse_peak_memory(status), Some(2048 * 1024));
+        assert_eq!(parse_peak_memory("Name:\tclipivot\n"), None);
+    }
+}
@@ -1,14 +1,52 @@
-use std::process;
+use std::env;
 
 use clipivot::cli;
-use clipivot::parsing;
+use clipivot::interactive;
 fn main() {
-    if let Some(date_format) = cli::CLI_ARGS.value_of("format") {
-        parsing::set_date_format(date_format.to_string());
+    // `clipivot completions <shell>`, `clipivot man`, `clipivot melt`, `clipivot describe`,
+    // `clipivot interactive <file>`, and `clipivot save-preset <name> ...` are handled before
+    // the regular arguments get parsed, since they don't take an aggregation function
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some(cli::COMPLETIONS_COMMAND) {
+        let shell = args.get(2).map_or("", String::as_str);
+        if let Err(err) = cli::write_completions(shell) {
+            cli::exit_with_error(&err);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some(cli::MAN_COMMAND) {
+        if let Err(err) = cli::write_man_page() {
+            cli::exit_with_error(&err);
+        }
+        return;
+    }
+    if let Some(cli::MELT_COMMAND) | Some(cli::DESCRIBE_COMMAND) = args.get(1).map(String::as_str) {
+        if let Err(err) = cli::run_subcommand(&args) {
+            cli::exit_with_error(&err);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some(interactive::INTERACTIVE_COMMAND) {
+        let filename = args.get(2).map_or("", String::as_str);
+        match interactive::explore_file(filename) {
+            Ok(command) => println!("{}", command),
+            Err(err) => {
+                cli::exit_with_error(&err);
+            }
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some(cli::SAVE_PRESET_COMMAND) {
+        match cli::save_preset(&args[2..]) {
+            Ok(path) => eprintln!("Saved the preset `{}` to {}", args[2], path.display()),
+            Err(err) => {
+                cli::exit_with_error(&err);
+            }
+        }
+        return;
     }
 
     if let Err(err) = cli::run() {
-        eprintln!("{}", err);
-        process::exit(1);
+        cli::exit_with_error(&err);
     }
 }
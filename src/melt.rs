@@ -0,0 +1,127 @@
+//! The module for "melting" a wide table into a long one, with `clipivot melt`.
+//!
+//! Melting is the opposite of creating a pivot table: instead of spreading the values of a
+//! field across columns, it takes a set of columns and stacks them on top of each other. Each
+//! record of the input becomes one record per melted column, holding the record's ID columns
+//! (which are kept as they are), the name of the melted column, and the column's value. So
+//!
+//! ```text
+//! region,jan,feb
+//! East,10,20
+//! ```
+//!
+//! melted with `region` as the ID column becomes
+//!
+//! ```text
+//! region,variable,value
+//! East,jan,10
+//! East,feb,20
+//! ```
+//!
+//! Melting the output of a pivot table gets back the long table it was made from, except for the
+//! empty cells of the groups that didn't have any records, which `skip_empty` leaves out.
+use crate::aggregation::EMPTY_VALUES;
+use crate::errors::CsvCliResult;
+use std::io;
+
+/// The default name of the column holding the names of the melted columns
+pub const DEFAULT_VARIABLE_NAME: &str = "variable";
+/// The default name of the column holding the values of the melted columns
+pub const DEFAULT_VALUE_NAME: &str = "value";
+
+/// Melts the records of `rdr`, keeping the columns in `id_cols` and stacking the columns in
+/// `value_cols`, and writes the long table to `wtr`. If the input doesn't have a header row,
+/// the melted columns are named by their (0-indexed) positions. With `skip_empty`, the values
+/// that are empty (like "" or "NA") don't get a record.
+pub fn melt<R, W>(
+    rdr: &mut csv::Reader<R>,
+    wtr: &mut csv::Writer<W>,
+    id_cols: &[usize],
+    value_cols: &[usize],
+    variable_name: &str,
+    value_name: &str,
+    skip_empty: bool,
+) -> CsvCliResult<()>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let headers = if rdr.has_headers() {
+        rdr.headers()?.clone()
+    } else {
+        csv::StringRecord::new()
+    };
+    let name = |idx: usize| match headers.get(idx) {
+        Some(header) => header.to_string(),
+        None => idx.to_string(),
+    };
+    let mut header: Vec<String> = id_cols.iter().map(|&idx| name(idx)).collect();
+    header.push(variable_name.to_string());
+    header.push(value_name.to_string());
+    wtr.write_record(&header)?;
+    let variables: Vec<String> = value_cols.iter().map(|&idx| name(idx)).collect();
+    let mut record = csv::StringRecord::new();
+    while rdr.read_record(&mut record)? {
+        let ids: Vec<&str> = id_cols
+            .iter()
+            .map(|&idx| record.get(idx).unwrap_or_default())
+            .collect();
+        for (variable, &idx) in variables.iter().zip(value_cols) {
+            let value = record.get(idx).unwrap_or_default();
+            if skip_empty && EMPTY_VALUES.contains(value.to_ascii_lowercase().as_str()) {
+                continue;
+            }
+            let mut melted = ids.clone();
+            melted.push(variable);
+            melted.push(value);
+            wtr.write_record(&melted)?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn melt_str(data: &str, has_headers: bool, id_cols: &[usize], value_cols: &[usize]) -> String {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(has_headers)
+            .from_reader(data.as_bytes());
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        melt(
+            &mut rdr, &mut wtr, id_cols, value_cols, "month", "sales", false,
+        )
+        .unwrap();
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_melt() {
+        let data = "region,jan,feb\nEast,10,20\nWest,,5\n";
+        assert_eq!(
+            melt_str(data, true, &[0], &[1, 2]),
+            "region,month,sales\nEast,jan,10\nEast,feb,20\nWest,jan,\nWest,feb,5\n"
+        );
+        assert_eq!(
+            melt_str(data, true, &[], &[2]),
+            "month,sales\nfeb,20\nfeb,5\n"
+        );
+        assert_eq!(
+            melt_str("East,10,20\n", false, &[0], &[1, 2]),
+            "0,month,sales\nEast,1,10\nEast,2,20\n"
+        );
+    }
+
+    #[test]
+    fn test_melt_skip_empty() {
+        let mut rdr = csv::Reader::from_reader("region,jan,feb\nEast,10,NA\nWest,,5\n".as_bytes());
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        melt(&mut rdr, &mut wtr, &[0], &[1, 2], "month", "sales", true).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+            "region,month,sales\nEast,jan,10\nWest,feb,5\n"
+        );
+    }
+}
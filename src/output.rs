@@ -0,0 +1,1173 @@
+//! The module for transforming the pivot table once everything has been aggregated.
+//!
+//! The aggregator writes its results into a `PivotTable`, whose value cells can then be
+//! transformed by a series of `CellTransform`s (like converting each cell into a percentage
+//! of its row's total) before the table gets written out. Transforms only touch cells that
+//! hold numbers, leaving empty and non-numeric cells alone.
+//!
+//! The computed values get turned into text with `WriteCell`, which appends them to a buffer
+//! that can be reused from one cell to the next. Integers and floating-point numbers get
+//! formatted with `itoa` and `ryu`, which are much faster than going through `format!` when a
+//! table has thousands of columns.
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::parsing::{CustomDateObject, DecimalWrapper};
+use chrono::NaiveDateTime;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// A pivot table whose cells have been computed, but not yet written
+#[derive(Debug, Clone, PartialEq)]
+pub struct PivotTable {
+    /// The header rows of the table
+    pub headers: Vec<Vec<String>>,
+    /// The rows of the table, each of which starts with its index values
+    pub rows: Vec<Vec<String>>,
+    /// The number of index columns at the start of each row
+    pub num_index_columns: usize,
+    /// The number of values columns that get aggregated in every (row, column) pair.
+    /// The cells for each values column are interleaved, so transforms that work across
+    /// an entire row only combine the cells from the same values column.
+    pub num_value_slots: usize,
+    /// The key of each output column (joined into a single string), in the order the columns appear
+    pub column_keys: Vec<String>,
+}
+
+impl PivotTable {
+    /// The number of value cells in each row
+    fn num_cells(&self) -> usize {
+        self.rows
+            .first()
+            .map_or(0, |row| row.len() - self.num_index_columns)
+    }
+
+    /// The numeric value of a cell, if it has one
+    fn get_number(&self, row: usize, cell: usize) -> Option<Decimal> {
+        parse_number(&self.rows[row][self.num_index_columns + cell])
+    }
+
+    fn set_cell(&mut self, row: usize, cell: usize, value: String) {
+        self.rows[row][self.num_index_columns + cell] = value;
+    }
+
+    /// The error for a transformation whose result (`what`) got too large for a number in the
+    /// given row. The error's `line_num` is the row's position in the table, since the table
+    /// doesn't know which records its rows came from.
+    fn overflow_error(&self, row: usize, what: &str) -> CsvCliError {
+        CsvCliError::ParsingError {
+            line_num: row,
+            str_to_parse: self.rows[row][..self.num_index_columns].join(" "),
+            err: format!(
+                "{} overflowed (it's more than {} or less than -{})",
+                what,
+                Decimal::max_value(),
+                Decimal::max_value()
+            ),
+            column: None,
+            position: None,
+        }
+    }
+
+    /// Groups the positions of the value cells into the sets that get transformed together
+    fn groups(&self, group: CellGroup) -> Vec<Vec<(usize, usize)>> {
+        let num_cells = self.num_cells();
+        let slots = self.num_value_slots.max(1);
+        match group {
+            CellGroup::Row => (0..self.rows.len())
+                .flat_map(|row| {
+                    (0..slots).map(move |slot| {
+                        (slot..num_cells)
+                            .step_by(slots)
+                            .map(|cell| (row, cell))
+                            .collect()
+                    })
+                })
+                .collect(),
+            CellGroup::Column => (0..num_cells)
+                .map(|cell| (0..self.rows.len()).map(|row| (row, cell)).collect())
+                .collect(),
+            CellGroup::Table => (0..slots)
+                .map(|slot| {
+                    (0..self.rows.len())
+                        .flat_map(|row| {
+                            (slot..num_cells)
+                                .step_by(slots)
+                                .map(move |cell| (row, cell))
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces each numeric cell with the result of comparing it to the cell before it in its group
+    /// (or with an empty cell, if the previous cell isn't a number or the comparison returns `None`).
+    /// The comparison fails with the name of its result if the result overflows.
+    fn compare_to_previous<F>(&mut self, group: CellGroup, compare: F) -> CsvCliResult<()>
+    where
+        F: Fn(Decimal, Decimal) -> Result<Option<String>, &'static str>,
+    {
+        for group in self.groups(group) {
+            let nums: Vec<Option<Decimal>> = group
+                .iter()
+                .map(|&(row, cell)| self.get_number(row, cell))
+                .collect();
+            for (i, &(row, cell)) in group.iter().enumerate() {
+                let num = match nums[i] {
+                    Some(num) => num,
+                    None => continue,
+                };
+                let prev = if i == 0 { None } else { nums[i - 1] };
+                let result = match prev {
+                    Some(prev) => {
+                        compare(prev, num).map_err(|what| self.overflow_error(row, what))?
+                    }
+                    None => None,
+                };
+                self.set_cell(row, cell, result.unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the rows and columns whose value cells are all empty or zero. (A column only
+    /// gets removed if the cells of every values column in it are empty.)
+    pub fn drop_empty(&mut self) {
+        let is_empty =
+            |cell: &str| cell.trim().is_empty() || parse_number(cell) == Some(Decimal::new(0, 0));
+        let num_index_columns = self.num_index_columns;
+        self.rows
+            .retain(|row| !row[num_index_columns..].iter().all(|cell| is_empty(cell)));
+        let slots = self.num_value_slots.max(1);
+        let num_columns = self.num_cells() / slots;
+        let kept: Vec<bool> = (0..num_columns)
+            .map(|col| {
+                self.rows.iter().any(|row| {
+                    let start = num_index_columns + col * slots;
+                    !row[start..start + slots].iter().all(|cell| is_empty(cell))
+                })
+            })
+            .collect();
+        let keep_cell = |i: usize| i < num_index_columns || kept[(i - num_index_columns) / slots];
+        for record in self.headers.iter_mut().chain(self.rows.iter_mut()) {
+            *record = record
+                .drain(..)
+                .enumerate()
+                .filter(|(i, _)| keep_cell(*i))
+                .map(|(_, cell)| cell)
+                .collect();
+        }
+        self.column_keys = self
+            .column_keys
+            .drain(..)
+            .zip(&kept)
+            .filter(|(_, keep)| **keep)
+            .map(|(key, _)| key)
+            .collect();
+    }
+
+    /// Replaces every value cell that matches one of the rules with that rule's replacement,
+    /// using the first rule that matches
+    pub fn recode(&mut self, rules: &[RecodeRule]) {
+        let num_index_columns = self.num_index_columns;
+        for row in &mut self.rows {
+            for cell in row.iter_mut().skip(num_index_columns) {
+                if let Some(rule) = rules.iter().find(|rule| rule.matches(cell)) {
+                    *cell = rule.replacement.clone();
+                }
+            }
+        }
+    }
+
+    /// Writes the numbers in the value cells with the separators of `locale`
+    pub fn localize(&mut self, locale: &NumberLocale) {
+        let num_index_columns = self.num_index_columns;
+        for row in &mut self.rows {
+            for cell in row.iter_mut().skip(num_index_columns) {
+                locale.format_cell(cell);
+            }
+        }
+    }
+
+    /// Converts the table into a list of records, starting with the header rows
+    pub fn into_records(self) -> Vec<Vec<String>> {
+        let mut records = self.headers;
+        records.extend(self.rows);
+        records
+    }
+}
+
+/// Parses the text of a cell as a number
+fn parse_number(text: &str) -> Option<Decimal> {
+    let trimmed = text.trim();
+    Decimal::from_str(trimmed)
+        .or_else(|_| Decimal::from_scientific(trimmed))
+        .ok()
+}
+
+/// The set of cells that a transform works within (for instance, the cells whose total
+/// a cell gets compared against when it's converted into a percentage)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellGroup {
+    /// The cells in the same row, from left to right
+    Row,
+    /// The cells in the same column, from top to bottom
+    Column,
+    /// Every cell in the table
+    Table,
+}
+
+impl FromStr for CellGroup {
+    type Err = CsvCliError;
+
+    fn from_str(s: &str) -> CsvCliResult<CellGroup> {
+        match s {
+            "row" => Ok(CellGroup::Row),
+            "column" => Ok(CellGroup::Column),
+            "total" => Ok(CellGroup::Table),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` needs to be one of row, column, or total",
+                s
+            ))),
+        }
+    }
+}
+
+/// A transformation of the cells of a finished pivot table
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellTransform {
+    /// Converts each cell into a percentage of its row, column, or table's total,
+    /// rounded to the given number of decimal places
+    Percent(CellGroup, u32),
+    /// Replaces each cell with the running total of the cells up to it in its row or column.
+    /// (Empty cells that come after a number get filled in with the running total.)
+    Accumulate(CellGroup),
+    /// Divides each cell by the cell in the same row of the named baseline column,
+    /// multiplying the ratio by the given scale (e.g. 100 for index numbers) and rounding it
+    /// to the given number of decimal places
+    Baseline(String, Decimal, u32),
+    /// Replaces each cell with its difference from the previous cell in its row or column
+    Diff(CellGroup),
+    /// Replaces each cell with its percent change from the previous cell in its row or column,
+    /// rounded to the given number of decimal places
+    PercentChange(CellGroup, u32),
+    /// Replaces each cell with its z-score within its row or column (the number of sample
+    /// standard deviations it lies from the mean), rounded to the given number of decimal places
+    ZScore(CellGroup, u32),
+    /// Replaces each cell with its rank within its row or column, where the largest number is ranked 1
+    Rank(CellGroup, RankTies),
+}
+
+/// How cells with the same value get ranked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RankTies {
+    /// Tied cells share the lowest of their ranks, skipping the ranks after them (1, 2, 2, 4)
+    Min,
+    /// Tied cells share the same rank, without skipping any ranks (1, 2, 2, 3)
+    Dense,
+    /// Tied cells get the average of their ranks (1, 2.5, 2.5, 4)
+    Average,
+}
+
+impl FromStr for RankTies {
+    type Err = CsvCliError;
+
+    fn from_str(s: &str) -> CsvCliResult<RankTies> {
+        match s {
+            "min" => Ok(RankTies::Min),
+            "dense" => Ok(RankTies::Dense),
+            "average" => Ok(RankTies::Average),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` needs to be one of min, dense, or average",
+                s
+            ))),
+        }
+    }
+}
+
+impl CellTransform {
+    /// Transforms the cells of a table. This is an error if a result gets too large for a
+    /// number (like the total of cells close to the largest number a cell can hold).
+    pub fn apply(&self, table: &mut PivotTable) -> CsvCliResult<()> {
+        match self {
+            CellTransform::Percent(of, decimals) => {
+                for group in table.groups(*of) {
+                    let mut total = Decimal::new(0, 0);
+                    for &(row, cell) in &group {
+                        if let Some(num) = table.get_number(row, cell) {
+                            total = total
+                                .checked_add(num)
+                                .ok_or_else(|| table.overflow_error(row, "The total"))?;
+                        }
+                    }
+                    for (row, cell) in group {
+                        if let Some(num) = table.get_number(row, cell) {
+                            let percent = if total == Decimal::new(0, 0) {
+                                String::new()
+                            } else {
+                                let percent = percent_of(num, total)
+                                    .ok_or_else(|| table.overflow_error(row, "The percentage"))?;
+                                format_decimals(percent, *decimals)
+                            };
+                            table.set_cell(row, cell, percent);
+                        }
+                    }
+                }
+            }
+            CellTransform::Accumulate(group) => {
+                for group in table.groups(*group) {
+                    let mut total = None;
+                    for (row, cell) in group {
+                        let is_empty = table.rows[row][table.num_index_columns + cell].is_empty();
+                        match (table.get_number(row, cell), total) {
+                            (Some(num), None) => total = Some(num),
+                            (Some(num), Some(sum)) => {
+                                let sum = sum.checked_add(num).ok_or_else(|| {
+                                    table.overflow_error(row, "The running total")
+                                })?;
+                                total = Some(sum);
+                            }
+                            // empty cells carry the running total, once there is one
+                            (None, Some(_)) if is_empty => {}
+                            (None, _) => continue,
+                        }
+                        if let Some(sum) = total {
+                            table.set_cell(row, cell, sum.to_string());
+                        }
+                    }
+                }
+            }
+            CellTransform::Baseline(column, scale, decimals) => {
+                let col_idx = match table.column_keys.iter().position(|key| key == column) {
+                    Some(idx) => idx,
+                    None => return Ok(()),
+                };
+                let slots = table.num_value_slots.max(1);
+                for row in 0..table.rows.len() {
+                    // the baseline cells get replaced too, so they need to be read first
+                    let bases: Vec<Option<Decimal>> = (0..slots)
+                        .map(|slot| table.get_number(row, col_idx * slots + slot))
+                        .collect();
+                    for cell in 0..table.num_cells() {
+                        if let Some(num) = table.get_number(row, cell) {
+                            let ratio = match bases[cell % slots] {
+                                Some(base) if base != Decimal::new(0, 0) => {
+                                    let ratio = scaled_ratio(num, base, *scale)
+                                        .ok_or_else(|| table.overflow_error(row, "The ratio"))?;
+                                    format_decimals(ratio, *decimals)
+                                }
+                                _ => String::new(),
+                            };
+                            table.set_cell(row, cell, ratio);
+                        }
+                    }
+                }
+            }
+            CellTransform::Diff(group) => table.compare_to_previous(*group, |prev, num| {
+                let diff = num.checked_sub(prev).ok_or("The difference")?;
+                Ok(Some(diff.to_string()))
+            })?,
+            CellTransform::PercentChange(group, decimals) => {
+                table.compare_to_previous(*group, |prev, num| {
+                    if prev == Decimal::new(0, 0) {
+                        return Ok(None);
+                    }
+                    let change = num
+                        .checked_sub(prev)
+                        .and_then(|diff| percent_of(diff, prev))
+                        .ok_or("The percent change")?;
+                    Ok(Some(format_decimals(change, *decimals)))
+                })?
+            }
+            CellTransform::ZScore(group, decimals) => {
+                for group in table.groups(*group) {
+                    let nums: Vec<((usize, usize), f64)> = group
+                        .into_iter()
+                        .filter_map(|(row, cell)| {
+                            let num = table.get_number(row, cell)?.to_string().parse().ok()?;
+                            Some(((row, cell), num))
+                        })
+                        .collect();
+                    let count = nums.len() as f64;
+                    let mean = nums.iter().map(|(_, num)| num).sum::<f64>() / count;
+                    let variance = nums
+                        .iter()
+                        .map(|(_, num)| (num - mean).powi(2))
+                        .sum::<f64>()
+                        / (count - 1.);
+                    let stddev = variance.sqrt();
+                    for ((row, cell), num) in nums {
+                        // a z-score is meaningless without at least two distinct values
+                        let zscore = if stddev > 0. {
+                            format_float((num - mean) / stddev, *decimals)
+                        } else {
+                            String::new()
+                        };
+                        table.set_cell(row, cell, zscore);
+                    }
+                }
+            }
+            CellTransform::Rank(group, ties) => {
+                for group in table.groups(*group) {
+                    let (positions, nums): (Vec<(usize, usize)>, Vec<Decimal>) = group
+                        .into_iter()
+                        .filter_map(|(row, cell)| {
+                            table.get_number(row, cell).map(|num| ((row, cell), num))
+                        })
+                        .unzip();
+                    for ((row, cell), rank) in positions.into_iter().zip(rank(&nums, *ties)) {
+                        table.set_cell(row, cell, rank.to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rule for recoding the cells of the output, like `0=-` or `>1000=***`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecodeRule {
+    condition: RecodeCondition,
+    replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RecodeCondition {
+    /// Matches cells with this text (or, if it's a number, cells with the same numeric value)
+    Equals(String),
+    LessThan(Decimal),
+    LessThanOrEqual(Decimal),
+    GreaterThan(Decimal),
+    GreaterThanOrEqual(Decimal),
+}
+
+impl RecodeRule {
+    /// Creates a rule replacing the cells with numbers less than `threshold`,
+    /// for suppressing counts that are too small to publish
+    pub fn suppress_below(threshold: usize, replacement: &str) -> RecodeRule {
+        RecodeRule {
+            condition: RecodeCondition::LessThan(Decimal::from(threshold as u64)),
+            replacement: replacement.to_string(),
+        }
+    }
+
+    /// Parses a comma-separated list of rules, like `0=-,>1000=***`
+    pub fn parse_list(rules: &str) -> CsvCliResult<Vec<RecodeRule>> {
+        rules.split(',').map(str::parse).collect()
+    }
+
+    fn matches(&self, cell: &str) -> bool {
+        let num = parse_number(cell);
+        match (&self.condition, num) {
+            (RecodeCondition::Equals(text), num) => {
+                text == cell.trim() || (num.is_some() && num == parse_number(text))
+            }
+            (RecodeCondition::LessThan(limit), Some(num)) => num < *limit,
+            (RecodeCondition::LessThanOrEqual(limit), Some(num)) => num <= *limit,
+            (RecodeCondition::GreaterThan(limit), Some(num)) => num > *limit,
+            (RecodeCondition::GreaterThanOrEqual(limit), Some(num)) => num >= *limit,
+            (_, None) => false,
+        }
+    }
+}
+
+impl FromStr for RecodeRule {
+    type Err = CsvCliError;
+
+    fn from_str(s: &str) -> CsvCliResult<RecodeRule> {
+        let invalid = || {
+            CsvCliError::InvalidConfiguration(format!(
+                "Could not parse the recoding rule `{}`. Rules need to look like `VALUE=REPLACEMENT` or `>NUMBER=REPLACEMENT`",
+                s
+            ))
+        };
+        let (op, rest) = ["<=", ">=", "<", ">"]
+            .iter()
+            .find(|op| s.trim_start().starts_with(*op))
+            .map_or(("", s), |op| (*op, &s.trim_start()[op.len()..]));
+        let pos = rest.find('=').ok_or_else(invalid)?;
+        let (value, replacement) = (rest[..pos].trim(), rest[pos + 1..].to_string());
+        let num = || parse_number(value).ok_or_else(invalid);
+        let condition = match op {
+            "<" => RecodeCondition::LessThan(num()?),
+            "<=" => RecodeCondition::LessThanOrEqual(num()?),
+            ">" => RecodeCondition::GreaterThan(num()?),
+            ">=" => RecodeCondition::GreaterThanOrEqual(num()?),
+            _ => RecodeCondition::Equals(value.to_string()),
+        };
+        Ok(RecodeRule {
+            condition,
+            replacement,
+        })
+    }
+}
+
+/// The locales numbers can be written for (with `--out-locale`), along with their decimal and
+/// grouping separators. A locale that isn't listed with its region falls back to its language.
+const NUMBER_LOCALES: [(&str, char, char); 24] = [
+    ("en", '.', ','),
+    ("ja", '.', ','),
+    ("ko", '.', ','),
+    ("zh", '.', ','),
+    ("de", ',', '.'),
+    ("de-CH", '.', '\u{2019}'),
+    ("da", ',', '.'),
+    ("el", ',', '.'),
+    ("es", ',', '.'),
+    ("id", ',', '.'),
+    ("it", ',', '.'),
+    ("nl", ',', '.'),
+    ("pt", ',', '.'),
+    ("pt-PT", ',', '\u{a0}'),
+    ("tr", ',', '.'),
+    ("cs", ',', '\u{a0}'),
+    ("fi", ',', '\u{a0}'),
+    ("fr", ',', '\u{202f}'),
+    ("fr-CH", '.', '\u{202f}'),
+    ("nb", ',', '\u{a0}'),
+    ("pl", ',', '\u{a0}'),
+    ("ru", ',', '\u{a0}'),
+    ("sv", ',', '\u{a0}'),
+    ("uk", ',', '\u{a0}'),
+];
+
+/// How the numbers of the output get written for a locale, like `1.234,5` for `de-DE`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumberLocale {
+    /// The character between the whole part of a number and its decimal places
+    pub decimal: char,
+    /// The character between each group of three digits of the whole part
+    pub grouping: char,
+}
+
+impl NumberLocale {
+    /// The delimiter the output gets written with: a semicolon when numbers use a decimal
+    /// comma (the way spreadsheets in those locales expect), and a comma otherwise
+    pub fn delimiter(&self) -> u8 {
+        if self.decimal == ',' {
+            b';'
+        } else {
+            b','
+        }
+    }
+
+    /// Rewrites the text of a cell with the separators of the locale if it's a number (like
+    /// `-1234.5`, or a percentage like `12.5%`), leaving any other cell alone
+    pub fn format_cell(&self, cell: &mut String) {
+        let number = cell.strip_suffix('%').unwrap_or(cell);
+        let unsigned = number.strip_prefix('-').unwrap_or(number);
+        let (whole, decimals) = match unsigned.find('.') {
+            Some(pos) => (&unsigned[..pos], Some(&unsigned[pos + 1..])),
+            None => (unsigned, None),
+        };
+        let is_digits = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || !decimals.map_or(true, is_digits) {
+            return;
+        }
+        let mut formatted = String::with_capacity(cell.len() + whole.len() / 3 * 3);
+        if number.len() > unsigned.len() {
+            formatted.push('-');
+        }
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                formatted.push(self.grouping);
+            }
+            formatted.push(digit);
+        }
+        if let Some(decimals) = decimals {
+            formatted.push(self.decimal);
+            formatted.push_str(decimals);
+        }
+        if number.len() < cell.len() {
+            formatted.push('%');
+        }
+        *cell = formatted;
+    }
+}
+
+impl FromStr for NumberLocale {
+    type Err = CsvCliError;
+
+    /// Parses a locale like `de-DE` (or `de_DE`, or just `de`)
+    fn from_str(s: &str) -> CsvCliResult<NumberLocale> {
+        let tag = s.trim().replace('_', "-");
+        let language = tag.split('-').next().unwrap_or_default();
+        NUMBER_LOCALES
+            .iter()
+            .find(|(name, _, _)| name.eq_ignore_ascii_case(&tag))
+            .or_else(|| {
+                NUMBER_LOCALES
+                    .iter()
+                    .find(|(name, _, _)| name.eq_ignore_ascii_case(language))
+            })
+            .map(|&(_, decimal, grouping)| NumberLocale { decimal, grouping })
+            .ok_or_else(|| {
+                let languages: Vec<&str> = NUMBER_LOCALES
+                    .iter()
+                    .map(|(name, _, _)| *name)
+                    .filter(|name| !name.contains('-'))
+                    .collect();
+                CsvCliError::InvalidConfiguration(format!(
+                    "`{}` isn't a locale whose numbers clipivot knows how to write. The locale needs to start with one of {}",
+                    s,
+                    languages.join(", ")
+                ))
+            })
+    }
+}
+
+/// Ranks a list of numbers from largest to smallest
+fn rank(nums: &[Decimal], ties: RankTies) -> Vec<Decimal> {
+    let mut sorted = nums.to_vec();
+    sorted.sort_by(|a, b| b.cmp(a));
+    sorted.dedup();
+    nums.iter()
+        .map(|num| {
+            let num_greater = nums.iter().filter(|other| *other > num).count();
+            let num_tied = nums.iter().filter(|other| *other == num).count();
+            match ties {
+                RankTies::Min => Decimal::from(num_greater as u64 + 1),
+                RankTies::Dense => {
+                    Decimal::from(sorted.iter().position(|other| other == num).unwrap() as u64 + 1)
+                }
+                RankTies::Average => {
+                    // the average of the ranks from num_greater + 1 to num_greater + num_tied
+                    Decimal::from((2 * num_greater + num_tied + 1) as u64) / Decimal::from(2)
+                }
+            }
+        })
+        .collect()
+}
+
+/// `part` as a percentage of `whole`, or `None` if it's too large for a number
+fn percent_of(part: Decimal, whole: Decimal) -> Option<Decimal> {
+    scaled_ratio(part, whole, Decimal::from(100))
+}
+
+/// The ratio of `part` to `whole` multiplied by `scale`, or `None` if it's too large for a number.
+/// (Parts that are too large to multiply by `scale` get divided by `whole` first, losing a little
+/// precision.)
+fn scaled_ratio(part: Decimal, whole: Decimal, scale: Decimal) -> Option<Decimal> {
+    part.checked_mul(scale)
+        .and_then(|part| part.checked_div(whole))
+        .or_else(|| part.checked_div(whole)?.checked_mul(scale))
+}
+
+/// Formats a number with exactly `decimals` decimal places
+fn format_decimals(num: Decimal, decimals: u32) -> String {
+    format!("{:.*}", decimals as usize, num.round_dp(decimals))
+}
+
+/// Formats a floating-point number with exactly `decimals` decimal places
+fn format_float(num: f64, decimals: u32) -> String {
+    let scale = 10f64.powi(decimals as i32);
+    // adding zero turns negative zero into zero, so tiny negative numbers don't print as `-0.00`
+    let rounded = (num * scale).round() / scale + 0.;
+    format!("{:.*}", decimals as usize, rounded)
+}
+
+/// A computed value that can be written into a cell of the output
+pub trait WriteCell: fmt::Display {
+    /// Appends the text of the value to `buf`
+    fn write_cell(&self, buf: &mut String) {
+        // writing to a `String` can't fail
+        let _ = write!(buf, "{}", self);
+    }
+
+    /// The text of the value
+    fn to_cell(&self) -> String {
+        let mut buf = String::new();
+        self.write_cell(&mut buf);
+        buf
+    }
+
+    /// The value itself, before it gets turned into text
+    fn to_value(&self) -> Value;
+}
+
+impl WriteCell for usize {
+    fn write_cell(&self, buf: &mut String) {
+        buf.push_str(itoa::Buffer::new().format(*self));
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+}
+
+impl WriteCell for f64 {
+    /// Writes the same text as `Display`, which never uses scientific notation and leaves off
+    /// the decimal point of whole numbers (so `3` rather than `ryu`'s `3.0`). When a number is
+    /// exactly halfway between the two shortest ways of writing it (which takes 17 digits),
+    /// `ryu` rounds the last digit to even and `Display` rounds it up, so those go through `Display`.
+    fn write_cell(&self, buf: &mut String) {
+        let mut ryu_buf = ryu::Buffer::new();
+        let text = ryu_buf.format(*self);
+        if text.contains('e') || text.bytes().filter(u8::is_ascii_digit).count() >= 17 {
+            let _ = write!(buf, "{}", self);
+        } else {
+            buf.push_str(text.strip_suffix(".0").unwrap_or(text));
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Float(*self)
+    }
+}
+
+impl WriteCell for String {
+    fn write_cell(&self, buf: &mut String) {
+        buf.push_str(self);
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Text(self.clone())
+    }
+}
+
+impl WriteCell for Decimal {
+    fn to_value(&self) -> Value {
+        Value::Decimal(*self)
+    }
+}
+
+impl WriteCell for DecimalWrapper {
+    fn to_value(&self) -> Value {
+        Value::Decimal(self.item)
+    }
+}
+
+impl WriteCell for CustomDateObject {
+    fn to_value(&self) -> Value {
+        Value::Date(self.datetime())
+    }
+}
+
+/// The typed value of a computed cell. Its text (from `Display` or `WriteCell`) is the same
+/// text `clipivot` writes for the cell.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A count of records or values
+    Int(i64),
+    /// A number computed with floating-point arithmetic (like a standard deviation)
+    Float(f64),
+    /// A number computed exactly (like a sum or a mean)
+    Decimal(Decimal),
+    /// A date and time
+    Date(NaiveDateTime),
+    /// Text, like the largest value of a text column or the range `minmax` writes
+    Text(String),
+}
+
+impl Value {
+    /// The value as a floating-point number, if it's a number
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(num) => Some(*num as f64),
+            Value::Float(num) => Some(*num),
+            Value::Decimal(num) => num.to_f64(),
+            Value::Date(_) | Value::Text(_) => None,
+        }
+    }
+
+    /// The value as text, if it's text
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_cell())
+    }
+}
+
+impl WriteCell for Value {
+    fn write_cell(&self, buf: &mut String) {
+        match self {
+            Value::Int(num) => buf.push_str(itoa::Buffer::new().format(*num)),
+            Value::Float(num) => num.write_cell(buf),
+            Value::Decimal(num) => num.write_cell(buf),
+            Value::Date(date) => CustomDateObject::from(*date).write_cell(buf),
+            Value::Text(text) => buf.push_str(text),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn table(rows: &[&[&str]], num_value_slots: usize) -> PivotTable {
+        PivotTable {
+            headers: vec![],
+            rows: rows
+                .iter()
+                .map(|row| row.iter().map(|s| s.to_string()).collect())
+                .collect(),
+            num_index_columns: 1,
+            num_value_slots,
+            column_keys: (0..(rows[0].len() - 1) / num_value_slots)
+                .map(|i| i.to_string())
+                .collect(),
+        }
+    }
+
+    fn cells(table: &PivotTable) -> Vec<Vec<&str>> {
+        table
+            .rows
+            .iter()
+            .map(|row| row[1..].iter().map(String::as_str).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_percent() {
+        let base = table(&[&["a", "1", "3"], &["b", "4", ""]], 1);
+        let percent = |of: CellGroup| {
+            let mut t = base.clone();
+            CellTransform::Percent(of, 1).apply(&mut t).unwrap();
+            t
+        };
+        assert_eq!(
+            cells(&percent(CellGroup::Row)),
+            vec![vec!["25.0", "75.0"], vec!["100.0", ""]]
+        );
+        assert_eq!(
+            cells(&percent(CellGroup::Column)),
+            vec![vec!["20.0", "100.0"], vec!["80.0", ""]]
+        );
+        assert_eq!(
+            cells(&percent(CellGroup::Table)),
+            vec![vec!["12.5", "37.5"], vec!["50.0", ""]]
+        );
+        // the cells of each values column only get compared with each other
+        let mut multi = table(&[&["a", "1", "10", "3", "30"]], 2);
+        CellTransform::Percent(CellGroup::Row, 0)
+            .apply(&mut multi)
+            .unwrap();
+        assert_eq!(cells(&multi), vec![vec!["25", "25", "75", "75"]]);
+        // numbers too large to multiply by 100 still get a percentage, but totals that are too
+        // large for a number are an error
+        let max = Decimal::max_value().to_string();
+        let mut large = table(&[&["a", &max, "0"]], 1);
+        CellTransform::Percent(CellGroup::Row, 0)
+            .apply(&mut large)
+            .unwrap();
+        assert_eq!(cells(&large), vec![vec!["100", "0"]]);
+        let mut overflowing = table(&[&["a", &max, "1"]], 1);
+        let err = CellTransform::Percent(CellGroup::Row, 0)
+            .apply(&mut overflowing)
+            .unwrap_err();
+        assert!(err.to_string().contains("The total overflowed"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let base = table(&[&["a", "4", "5", "", "2"], &["b", "0", "3", "6", "x"]], 1);
+        let mut diff = base.clone();
+        CellTransform::Diff(CellGroup::Row)
+            .apply(&mut diff)
+            .unwrap();
+        assert_eq!(
+            cells(&diff),
+            vec![vec!["", "1", "", ""], vec!["", "3", "3", "x"]]
+        );
+        let mut change = base.clone();
+        CellTransform::PercentChange(CellGroup::Row, 1)
+            .apply(&mut change)
+            .unwrap();
+        assert_eq!(
+            cells(&change),
+            vec![vec!["", "25.0", "", ""], vec!["", "", "100.0", "x"]]
+        );
+        let mut down = base;
+        CellTransform::Diff(CellGroup::Column)
+            .apply(&mut down)
+            .unwrap();
+        assert_eq!(
+            cells(&down),
+            vec![vec!["", "", "", ""], vec!["-4", "-2", "", "x"]]
+        );
+        let max = Decimal::max_value().to_string();
+        let min = format!("-{}", max);
+        let mut overflowing = table(&[&["a", &min, "1"]], 1);
+        let err = CellTransform::Diff(CellGroup::Row)
+            .apply(&mut overflowing)
+            .unwrap_err();
+        assert!(err.to_string().contains("The difference overflowed"));
+        let mut overflowing = table(&[&["a", "-1", &max]], 1);
+        let err = CellTransform::PercentChange(CellGroup::Row, 1)
+            .apply(&mut overflowing)
+            .unwrap_err();
+        assert!(err.to_string().contains("The percent change overflowed"));
+    }
+
+    #[test]
+    fn test_baseline() {
+        let base = table(&[&["a", "4", "5", "2"], &["b", "0", "3", "x"]], 1);
+        let mut ratios = base.clone();
+        CellTransform::Baseline("0".to_string(), Decimal::new(1, 0), 2)
+            .apply(&mut ratios)
+            .unwrap();
+        assert_eq!(
+            cells(&ratios),
+            vec![vec!["1.00", "1.25", "0.50"], vec!["", "", "x"]]
+        );
+        let mut index = base;
+        CellTransform::Baseline("1".to_string(), Decimal::from(100), 0)
+            .apply(&mut index)
+            .unwrap();
+        assert_eq!(
+            cells(&index),
+            vec![vec!["80", "100", "40"], vec!["0", "100", "x"]]
+        );
+        // each values column gets compared with the same values column in the baseline
+        let mut multi = table(&[&["a", "2", "10", "3", "5"]], 2);
+        CellTransform::Baseline("1".to_string(), Decimal::new(1, 0), 1)
+            .apply(&mut multi)
+            .unwrap();
+        assert_eq!(cells(&multi), vec![vec!["0.7", "2.0", "1.0", "1.0"]]);
+        // a ratio too large for a number is an error
+        let max = Decimal::max_value().to_string();
+        let mut overflowing = table(&[&["a", &max, "0.001"]], 1);
+        let err = CellTransform::Baseline("1".to_string(), Decimal::new(1, 0), 0)
+            .apply(&mut overflowing)
+            .unwrap_err();
+        assert!(err.to_string().contains("The ratio overflowed"));
+        let mut scaled = table(&[&["a", &max, "1000"]], 1);
+        CellTransform::Baseline("1".to_string(), Decimal::from(100), 0)
+            .apply(&mut scaled)
+            .unwrap();
+        assert_eq!(
+            cells(&scaled),
+            vec![vec!["7922816251426433759354395034", "100"]]
+        );
+    }
+
+    #[test]
+    fn test_rank() {
+        let base = table(
+            &[
+                &["a", "5", "9", "", "5", "1"],
+                &["b", "2", "1", "4", "x", "3"],
+            ],
+            1,
+        );
+        let ranks = |group: CellGroup, ties: RankTies| {
+            let mut t = base.clone();
+            CellTransform::Rank(group, ties).apply(&mut t).unwrap();
+            t
+        };
+        assert_eq!(
+            cells(&ranks(CellGroup::Row, RankTies::Min)),
+            vec![vec!["2", "1", "", "2", "4"], vec!["3", "4", "1", "x", "2"]]
+        );
+        assert_eq!(
+            cells(&ranks(CellGroup::Row, RankTies::Dense))[0],
+            vec!["2", "1", "", "2", "3"]
+        );
+        assert_eq!(
+            cells(&ranks(CellGroup::Row, RankTies::Average))[0],
+            vec!["2.5", "1", "", "2.5", "4"]
+        );
+        assert_eq!(
+            cells(&ranks(CellGroup::Column, RankTies::Min)),
+            vec![vec!["1", "1", "", "1", "2"], vec!["2", "2", "1", "x", "1"]]
+        );
+    }
+
+    #[test]
+    fn test_zscore() {
+        let base = table(&[&["a", "2", "4", "6", ""], &["b", "3", "3", "x", "1"]], 1);
+        let mut across = base.clone();
+        CellTransform::ZScore(CellGroup::Row, 2)
+            .apply(&mut across)
+            .unwrap();
+        assert_eq!(
+            cells(&across),
+            vec![
+                vec!["-1.00", "0.00", "1.00", ""],
+                vec!["0.58", "0.58", "x", "-1.15"]
+            ]
+        );
+        let mut down = base;
+        CellTransform::ZScore(CellGroup::Column, 1)
+            .apply(&mut down)
+            .unwrap();
+        assert_eq!(
+            cells(&down),
+            vec![vec!["-0.7", "0.7", "", ""], vec!["0.7", "-0.7", "x", ""]]
+        );
+    }
+
+    #[test]
+    fn test_drop_empty() {
+        let mut t = table(
+            &[
+                &["a", "1", "", "0"],
+                &["b", "0.0", "", ""],
+                &["c", "", "", "2"],
+            ],
+            1,
+        );
+        t.headers = vec![vec!["", "x", "y", "z"]
+            .into_iter()
+            .map(String::from)
+            .collect()];
+        t.drop_empty();
+        assert_eq!(t.headers, vec![vec!["", "x", "z"]]);
+        assert_eq!(cells(&t), vec![vec!["1", "0"], vec!["", "2"]]);
+        assert_eq!(t.column_keys, vec!["0", "2"]);
+        // columns are only dropped when all of their values columns are empty
+        let mut multi = table(&[&["a", "1", "", "", "", "0", "x"]], 2);
+        multi.drop_empty();
+        assert_eq!(cells(&multi), vec![vec!["1", "", "0", "x"]]);
+    }
+
+    #[test]
+    fn test_recode() {
+        let rules = RecodeRule::parse_list("0=-,>=1000=***,<3=<3,=n/a").unwrap();
+        let mut t = table(
+            &[
+                &["a", "0.0", "1000", "2", "5"],
+                &["b", "", "x", "-1", "999"],
+            ],
+            1,
+        );
+        t.recode(&rules);
+        assert_eq!(
+            cells(&t),
+            vec![vec!["-", "***", "<3", "5"], vec!["n/a", "x", "<3", "999"]]
+        );
+        let mut suppressed = table(&[&["a", "1", "4", "5"]], 1);
+        suppressed.recode(&[RecodeRule::suppress_below(5, "*")]);
+        assert_eq!(cells(&suppressed), vec![vec!["*", "*", "5"]]);
+        assert!(RecodeRule::parse_list("0").is_err());
+        assert!(RecodeRule::parse_list(">a=b").is_err());
+    }
+
+    #[test]
+    fn test_localize() {
+        let german: NumberLocale = "de-DE".parse().unwrap();
+        assert_eq!(german.delimiter(), b';');
+        let mut t = table(
+            &[&[
+                "2019",
+                "1234567.5",
+                "-1000",
+                "12.5%",
+                "999",
+                "2020-03-01",
+                "1e5",
+            ]],
+            1,
+        );
+        t.localize(&german);
+        assert_eq!(
+            cells(&t),
+            vec![vec![
+                "1.234.567,5",
+                "-1.000",
+                "12,5%",
+                "999",
+                "2020-03-01",
+                "1e5"
+            ]]
+        );
+        // the index values stay the way they were read
+        assert_eq!(t.rows[0][0], "2019");
+        let swiss: NumberLocale = "de_CH".parse().unwrap();
+        assert_eq!((swiss.decimal, swiss.grouping), ('.', '\u{2019}'));
+        let english: NumberLocale = "EN-us".parse().unwrap();
+        assert_eq!(english.delimiter(), b',');
+        assert!("xx-XX".parse::<NumberLocale>().is_err());
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let base = table(&[&["a", "1", "", "2.5"], &["b", "4", "1", "1"]], 1);
+        let mut across = base.clone();
+        CellTransform::Accumulate(CellGroup::Row)
+            .apply(&mut across)
+            .unwrap();
+        assert_eq!(
+            cells(&across),
+            vec![vec!["1", "1", "3.5"], vec!["4", "5", "6"]]
+        );
+        let mut down = base;
+        CellTransform::Accumulate(CellGroup::Column)
+            .apply(&mut down)
+            .unwrap();
+        assert_eq!(
+            cells(&down),
+            vec![vec!["1", "", "2.5"], vec!["5", "1", "3.5"]]
+        );
+        let max = Decimal::max_value().to_string();
+        let mut overflowing = table(&[&["a", &max, "1"], &["b", "1", "1"]], 1);
+        let err = CellTransform::Accumulate(CellGroup::Row)
+            .apply(&mut overflowing)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Could not parse record `a` with index 0: The running total overflowed (it's more \
+                 than {} or less than -{})",
+                max, max
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_cell() {
+        let mut buf = String::new();
+        12_345usize.write_cell(&mut buf);
+        "x".to_string().write_cell(&mut buf);
+        assert_eq!(buf, "12345x");
+        for &num in &[3., -0., 0.1, 1e21, 1e-7, -2.5e15, f64::NAN, f64::INFINITY] {
+            assert_eq!(num.to_cell(), num.to_string());
+        }
+        assert_eq!(Decimal::new(-1050, 2).to_cell(), "-10.50");
+    }
+
+    #[test]
+    fn test_value() {
+        // a value has the same text as what it was computed from
+        let date: CustomDateObject = "2020-01-03 04:05:06".parse().unwrap();
+        assert_eq!(date.to_value(), Value::Date(date.datetime()));
+        assert_eq!(date.to_value().to_string(), date.to_cell());
+        for value in &[
+            7usize.to_value(),
+            2.5f64.to_value(),
+            1e21f64.to_value(),
+            2058829022785317.2f64.to_value(),
+            Decimal::new(-1050, 2).to_value(),
+            "x".to_string().to_value(),
+        ] {
+            assert_eq!(value.to_cell(), value.to_string());
+        }
+        assert_eq!(7usize.to_value().to_cell(), "7");
+        assert_eq!(Decimal::new(-1050, 2).to_value().to_cell(), "-10.50");
+        assert_eq!(Value::Decimal(Decimal::new(-1050, 2)).as_f64(), Some(-10.5));
+        assert_eq!(Value::Int(3).as_f64(), Some(3.));
+        assert_eq!(date.to_value().as_f64(), None);
+        assert_eq!(Value::Text("x".to_string()).as_str(), Some("x"));
+    }
+
+    proptest! {
+        #[test]
+        fn test_floats_match_display(num in proptest::num::f64::ANY) {
+            assert_eq!(num.to_cell(), num.to_string());
+        }
+    }
+}
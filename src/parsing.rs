@@ -8,6 +8,13 @@
 //! and because I wanted to return the number of days between datetimes for range (overwriting `std::ops::Sub`).
 //! And decimal has a way of parsing values in scientific notation and parsing normal numbers. So I added
 //! the scientific notation parsing to the implementation of `FromStr`.
+//!
+//! Parsing the values is most of the work of a `sum` or `mean` over a wide numeric file, so the
+//! implementation of `FromStr` for `DecimalWrapper` first tries a fast path that handles plain
+//! integers and decimals (like `-12` or `3.50`) without allocating, and only falls back to
+//! `rust_decimal`'s more general parser for everything else.
+use crate::aggfunc::{CheckedAdd, CheckedSub};
+use crate::spill::Spill;
 use chrono::{NaiveDate, NaiveDateTime};
 use lazy_static::lazy_static;
 use rust_decimal::Decimal;
@@ -22,11 +29,81 @@ lazy_static! {
 /// Sets `INPUT_DATE_FORMAT` so that date parsing can work with `std::str::FromStr
 ///
 /// Keep in mind that as this sets a mutable global variable, any changes to this
-/// function could affect other code you write.
+/// function could affect other code you write. An `Aggregator` with a `ValueParser` from
+/// `ValueParser::dates` uses its own format instead.
 pub fn set_date_format(s: String) {
     *INPUT_DATE_FORMAT.lock().unwrap() = s;
 }
 
+/// Parses the values an `Aggregator` reads into the input type of its accumulators.
+///
+/// By default, that's the type's `FromStr` implementation, which for `CustomDateObject` uses
+/// the global `INPUT_DATE_FORMAT`. `ValueParser::dates` carries its own format instead, so
+/// aggregators reading dates in different formats can run at the same time.
+pub struct ValueParser<I> {
+    parse: fn(&str, &str) -> Option<I>,
+    date_format: Option<String>,
+}
+
+impl<I: std::str::FromStr> Default for ValueParser<I> {
+    fn default() -> Self {
+        ValueParser {
+            parse: |s, _| s.parse().ok(),
+            date_format: None,
+        }
+    }
+}
+
+impl ValueParser<CustomDateObject> {
+    /// Parses dates (or datetimes) with a `strftime` format, like `%Y-%m-%d`
+    pub fn dates(format: &str) -> Self {
+        ValueParser {
+            parse: |s, format| CustomDateObject::parse_with_format(s, format).ok(),
+            date_format: Some(format.to_string()),
+        }
+    }
+}
+
+impl<I> ValueParser<I> {
+    /// Parses a value, returning `None` if it isn't a value of the input type
+    pub fn parse(&self, s: &str) -> Option<I> {
+        (self.parse)(s, self.date_format.as_deref().unwrap_or_default())
+    }
+
+    /// The format dates get parsed with
+    pub fn date_format(&self) -> String {
+        match &self.date_format {
+            Some(format) => format.clone(),
+            None => INPUT_DATE_FORMAT.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl<I> Clone for ValueParser<I> {
+    fn clone(&self) -> Self {
+        ValueParser {
+            parse: self.parse,
+            date_format: self.date_format.clone(),
+        }
+    }
+}
+
+impl<I> fmt::Debug for ValueParser<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueParser")
+            .field("date_format", &self.date_format)
+            .finish()
+    }
+}
+
+impl<I> PartialEq for ValueParser<I> {
+    /// Parsers are equal if they parse dates the same way (since a parser with a date
+    /// format always parses dates)
+    fn eq(&self, other: &Self) -> bool {
+        self.date_format == other.date_format
+    }
+}
+
 /// A light wrapper over `rust_decimal::Decimal`.
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct DecimalWrapper {
@@ -37,15 +114,58 @@ impl std::str::FromStr for DecimalWrapper {
     type Err = rust_decimal::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(item) = parse_plain_decimal(s) {
+            return Ok(DecimalWrapper { item });
+        }
         Decimal::from_str(s)
             .or_else(|_| Decimal::from_scientific(s))
             .map(|v| DecimalWrapper { item: v })
     }
 }
 
+/// The most digits `parse_plain_decimal` reads, so the digits always fit in an `i64`
+const MAX_PLAIN_DIGITS: usize = 18;
+
+/// Parses a number that's just an optional sign followed by digits and at most one decimal point
+/// (with at least one digit and no more than `MAX_PLAIN_DIGITS` of them), giving the same result
+/// as `Decimal::from_str`. Anything else (including negative zero, which `Decimal::new` can't
+/// represent) returns `None`, so it can go through the general parser.
+fn parse_plain_decimal(s: &str) -> Option<Decimal> {
+    let bytes = s.as_bytes();
+    let (negative, digits) = match bytes.first() {
+        Some(b'-') => (true, &bytes[1..]),
+        Some(b'+') => (false, &bytes[1..]),
+        _ => (false, bytes),
+    };
+    let mut mantissa: i64 = 0;
+    let mut num_digits = 0;
+    let mut scale = None;
+    for &byte in digits {
+        match byte {
+            b'0'..=b'9' => {
+                num_digits += 1;
+                if num_digits > MAX_PLAIN_DIGITS {
+                    return None;
+                }
+                mantissa = mantissa * 10 + i64::from(byte - b'0');
+                if let Some(scale) = scale.as_mut() {
+                    *scale += 1;
+                }
+            }
+            b'.' if scale.is_none() => scale = Some(0),
+            _ => return None,
+        }
+    }
+    if num_digits == 0 || (negative && mantissa == 0) {
+        return None;
+    }
+    let mantissa = if negative { -mantissa } else { mantissa };
+    Some(Decimal::new(mantissa, scale.unwrap_or(0)))
+}
+
 impl fmt::Display for DecimalWrapper {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.item.to_string())
+        write!(f, "{}", self.item)
     }
 }
 
@@ -59,6 +179,12 @@ impl std::ops::Sub for DecimalWrapper {
     }
 }
 
+impl CheckedSub<Decimal> for DecimalWrapper {
+    fn checked_sub(self, other: Self) -> Option<Decimal> {
+        self.item.checked_sub(other.item)
+    }
+}
+
 // necessary for Sum
 impl std::ops::AddAssign for DecimalWrapper {
     fn add_assign(&mut self, other: Self) {
@@ -68,6 +194,14 @@ impl std::ops::AddAssign for DecimalWrapper {
     }
 }
 
+impl CheckedAdd for DecimalWrapper {
+    fn checked_add(self, other: Self) -> Option<Self> {
+        self.item
+            .checked_add(other.item)
+            .map(|item| DecimalWrapper { item })
+    }
+}
+
 impl std::ops::Add for DecimalWrapper {
     type Output = DecimalWrapper;
 
@@ -97,16 +231,8 @@ impl std::str::FromStr for CustomDateObject {
     type Err = chrono::format::ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // need to borrow as mutable to avoid moving the value
-        // https://stackoverflow.com/questions/62248219/rust-accessing-option-from-mutex
-        let first_pass = NaiveDateTime::parse_from_str(s, &*INPUT_DATE_FORMAT.lock().unwrap());
-        let parsed_dt = if first_pass.is_err() {
-            NaiveDate::parse_from_str(s, &*INPUT_DATE_FORMAT.lock().unwrap())
-                .map(|v| v.and_hms(0, 0, 0))
-        } else {
-            first_pass
-        }?;
-        Ok(CustomDateObject(parsed_dt))
+        let format = INPUT_DATE_FORMAT.lock().unwrap().clone();
+        CustomDateObject::parse_with_format(s, &format)
     }
 }
 
@@ -122,6 +248,48 @@ impl std::ops::Sub for CustomDateObject {
     }
 }
 
+impl CheckedSub<f64> for CustomDateObject {
+    /// The number of days between two dates, which always fits
+    fn checked_sub(self, other: Self) -> Option<f64> {
+        Some(self - other)
+    }
+}
+
+impl Spill for CustomDateObject {
+    fn save(&self, fields: &mut Vec<String>) {
+        // the output format leaves out fractions of a second, so this saves the exact timestamp
+        fields.push(self.0.timestamp().to_string());
+        fields.push(self.0.timestamp_subsec_nanos().to_string());
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        let secs = fields.next()?.parse().ok()?;
+        let nanos = fields.next()?.parse().ok()?;
+        NaiveDateTime::from_timestamp_opt(secs, nanos).map(CustomDateObject)
+    }
+}
+
+impl CustomDateObject {
+    /// Parses a datetime with a `strftime` format, or a date (at midnight) if the format
+    /// doesn't have a time
+    pub fn parse_with_format(s: &str, format: &str) -> Result<Self, chrono::format::ParseError> {
+        let parsed_dt = NaiveDateTime::parse_from_str(s, format)
+            .or_else(|_| NaiveDate::parse_from_str(s, format).map(|v| v.and_hms(0, 0, 0)))?;
+        Ok(CustomDateObject(parsed_dt))
+    }
+
+    /// The date and time that were parsed
+    pub fn datetime(&self) -> NaiveDateTime {
+        self.0
+    }
+}
+
+impl From<NaiveDateTime> for CustomDateObject {
+    fn from(datetime: NaiveDateTime) -> CustomDateObject {
+        CustomDateObject(datetime)
+    }
+}
+
 impl fmt::Display for CustomDateObject {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0.format(&OUTPUT_DATE_FORMAT))
@@ -133,6 +301,7 @@ mod tests {
     use super::*;
     use chrono::NaiveDate;
     use proptest::prelude::*;
+    use std::str::FromStr;
 
     #[test]
     fn test_date_subtraction() {
@@ -173,11 +342,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_plain_decimals() {
+        let parse = |s: &str| s.parse::<DecimalWrapper>().map(|d| d.to_string());
+        assert_eq!(parse("12").unwrap(), "12");
+        assert_eq!(parse("-3.50").unwrap(), "-3.50");
+        assert_eq!(parse("+.25").unwrap(), "0.25");
+        assert_eq!(parse("7.").unwrap(), "7");
+        assert_eq!(
+            parse("-0.0").unwrap(),
+            Decimal::from_str("-0.0").unwrap().to_string()
+        );
+        // numbers the fast path doesn't handle still get parsed
+        assert_eq!(parse("1_000").unwrap(), "1000");
+        assert_eq!(
+            parse("1234567890123456789012").unwrap(),
+            "1234567890123456789012"
+        );
+        assert_eq!(parse("1e3").unwrap(), "1000");
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("-").is_err());
+        assert!(parse("").is_err());
+        assert_eq!(parse_plain_decimal("."), None);
+        assert_eq!(parse_plain_decimal("12a"), None);
+    }
+
     proptest! {
+        #[test]
+        fn test_plain_decimals_match(s in "[+-]?[0-9]{0,10}(\\.[0-9]{0,10})?") {
+            if let Some(fast) = parse_plain_decimal(&s) {
+                let slow = Decimal::from_str(&s).unwrap();
+                assert_eq!(fast, slow);
+                assert_eq!(fast.to_string(), slow.to_string());
+            }
+        }
         #[test]
         fn test_date_parsing(year in 1900..=2020i32, month in 1..=12u32, day in 1..=28u32, hour in 0..=23u32, minute in 0..=59u32, second in 0..=59u32) {
             let dt = CustomDateObject(NaiveDate::from_ymd(year, month, day).and_hms(hour, minute, second));
-            let _ex = set_date_format("%Y-%m-%d %H:%M:%S".to_string());
+            set_date_format("%Y-%m-%d %H:%M:%S".to_string());
             let deser_ser : CustomDateObject = dt.to_string().parse().unwrap();
             assert_eq!(dt, deser_ser);
         }
@@ -0,0 +1,1420 @@
+//! The module for building pivot tables from other Rust programs, without going through the
+//! command line.
+//!
+//! A `PivotBuilder` describes the table: the aggregation function, the columns that make up
+//! its rows, columns, and values, how the rows and columns get sorted, and how the input gets
+//! parsed. `build` checks that the pieces fit together and returns a `Pivot`, which can then
+//! read any number of inputs. Each one becomes a `Table`, whose rows and columns are keys and
+//! whose cells are the typed `Value`s of each aggregation (numbers, dates, or text), which
+//! turn into the same text `clipivot` would write for them with `to_string`.
+//! Or, with the `stream` methods, each one becomes an iterator of `PivotRow`s, for programs
+//! that hand the rows off somewhere else one at a time.
+//!
+//! The builder has a method for each of the options of the command line, which builds its
+//! pivot tables the same way. The options that change the cells of the finished table (like
+//! `totals` or `cell_transform`) only apply when the table gets written out with `write`.
+//!
+//! ```
+//! use clipivot::output::Value;
+//! use clipivot::pivot::{Agg, PivotBuilder};
+//!
+//! let data = "region;product;amount\nwest;a;1\neast;b;2\nwest;b;3\nwest;a;4\n";
+//! let pivot = PivotBuilder::new()
+//!     .rows("region")
+//!     .columns("product")
+//!     .values("amount")
+//!     .aggregate(Agg::Sum)
+//!     .delimiter(b';')
+//!     .build()
+//!     .unwrap();
+//! let table = pivot.read(data.as_bytes()).unwrap();
+//! assert_eq!(table.rows, vec![vec!["west"], vec!["east"]]);
+//! let cell = table.get(&["west"], &["a"]).unwrap();
+//! assert_eq!(cell.as_f64(), Some(5.0));
+//! assert_eq!(cell.to_string(), "5");
+//! assert_eq!(table.get(&["east"], &["a"]), None);
+//! ```
+use crate::aggfunc::{
+    Accumulate, ApproxMedian, ApproxMode, Count, CountUnique, Maximum, Mean, Median, MinMax,
+    Minimum, Mode, Range, StdDev, Sum,
+};
+use crate::aggregation::{
+    join_key, Aggregator, FilterErrorPolicy, NanPolicy, OutputOrder, ParsingStrategy, Sample,
+    FIELD_SEPARATOR,
+};
+use crate::cli_settings::CsvSettings;
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::keys::UnmappedPolicy;
+use crate::output::{CellTransform, NumberLocale, RecodeRule, Value, WriteCell};
+use crate::parsing::{CustomDateObject, DecimalWrapper, ValueParser};
+use crate::sorting::Comparator;
+use options::Options;
+pub(crate) use options::DEFAULT_OTHER_LABEL;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+mod options;
+#[cfg(feature = "serde")]
+mod serialize;
+
+/// The aggregation function that combines the values of each cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Agg {
+    Count,
+    CountUnique,
+    Max,
+    Mean,
+    Median,
+    Min,
+    MinMax,
+    Mode,
+    Range,
+    StdDev,
+    Sum,
+}
+
+impl FromStr for Agg {
+    type Err = CsvCliError;
+
+    /// Parses the name of a function, as it's written on the command line (like `countunique`)
+    fn from_str(s: &str) -> CsvCliResult<Agg> {
+        match s {
+            "count" => Ok(Agg::Count),
+            "countunique" => Ok(Agg::CountUnique),
+            "max" => Ok(Agg::Max),
+            "mean" => Ok(Agg::Mean),
+            "median" => Ok(Agg::Median),
+            "min" => Ok(Agg::Min),
+            "minmax" => Ok(Agg::MinMax),
+            "mode" => Ok(Agg::Mode),
+            "range" => Ok(Agg::Range),
+            "stddev" => Ok(Agg::StdDev),
+            "sum" => Ok(Agg::Sum),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` isn't one of the aggregation functions",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Agg {
+    /// Writes the name of the function, as it's written on the command line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Agg::Count => "count",
+            Agg::CountUnique => "countunique",
+            Agg::Max => "max",
+            Agg::Mean => "mean",
+            Agg::Median => "median",
+            Agg::Min => "min",
+            Agg::MinMax => "minmax",
+            Agg::Mode => "mode",
+            Agg::Range => "range",
+            Agg::StdDev => "stddev",
+            Agg::Sum => "sum",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How `max`, `min`, `minmax`, and `range` read their values (like `-N` and `-F` on the
+/// command line). The other functions always read their values the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueType {
+    /// Compares values as text (the default for `max`, `min`, and `minmax`)
+    Text,
+    /// Parses values as numbers (the default for `range`)
+    Numbers,
+    /// Parses values as dates with a `strftime` format, like `%Y-%m-%d`
+    Dates(String),
+}
+
+/// The way a function reads its values, making sure it can read them the way it was told to
+fn value_type(function: Agg, value_type: Option<ValueType>) -> CsvCliResult<ValueType> {
+    let value_type = match value_type {
+        None if function == Agg::Range => return Ok(ValueType::Numbers),
+        None => return Ok(ValueType::Text),
+        Some(value_type) => value_type,
+    };
+    match (function, &value_type) {
+        (Agg::Max | Agg::Min | Agg::MinMax, _)
+        | (Agg::Range, ValueType::Numbers | ValueType::Dates(_)) => Ok(value_type),
+        (function, _) => Err(CsvCliError::InvalidConfiguration(format!(
+            "{:?} can't read its values as {:?}",
+            function, value_type
+        ))),
+    }
+}
+
+/// The way a function with a value type parses its values
+fn parsing_strategy(function: Agg, value_type: &ValueType) -> ParsingStrategy {
+    match (function, value_type) {
+        (Agg::Count | Agg::CountUnique | Agg::Mode, _) => ParsingStrategy::Text,
+        (Agg::Mean | Agg::Median | Agg::StdDev | Agg::Sum, _) => ParsingStrategy::Numeric,
+        (_, ValueType::Dates(_)) => ParsingStrategy::Date,
+        (Agg::Range, _) | (_, ValueType::Numbers) => ParsingStrategy::Numeric,
+        (_, ValueType::Text) => ParsingStrategy::Text,
+    }
+}
+
+/// Describes a pivot table, one piece at a time. Once every piece is in place, `build` turns
+/// it into a `Pivot`.
+///
+/// Anything that names a column (like the pattern of `filter_regex`) only gets checked once
+/// the header row of the input has been read, when the `Pivot` reads it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PivotBuilder {
+    function: Option<Agg>,
+    rows: Vec<String>,
+    columns: Vec<String>,
+    values: Vec<String>,
+    value_type: Option<ValueType>,
+    skip_empty: bool,
+    row_order: OutputOrder,
+    column_order: OutputOrder,
+    threads: usize,
+    delimiter: u8,
+    has_headers: bool,
+    strict_headers: bool,
+    options: Options,
+}
+
+impl Default for PivotBuilder {
+    fn default() -> Self {
+        PivotBuilder::new()
+    }
+}
+
+impl PivotBuilder {
+    /// A pivot table with a single row and column (named `total`), reading a comma-separated
+    /// file with a header row. It still needs an aggregation function.
+    pub fn new() -> PivotBuilder {
+        PivotBuilder {
+            function: None,
+            rows: Vec::new(),
+            columns: Vec::new(),
+            values: Vec::new(),
+            value_type: None,
+            skip_empty: false,
+            row_order: OutputOrder::IndexOrder,
+            column_order: OutputOrder::Ascending,
+            threads: 1,
+            delimiter: b',',
+            has_headers: true,
+            strict_headers: false,
+            options: Options::default(),
+        }
+    }
+
+    /// Adds a column to the fields that make up the rows, by its name (or, for a file without
+    /// a header row, its 0-indexed position). Calling this again adds another field. Like `-r`,
+    /// this can also be a comma-separated list, a range, or `@index` for the record number.
+    pub fn rows(mut self, name: &str) -> Self {
+        self.rows.push(name.to_string());
+        self
+    }
+
+    /// Adds a column to the fields that make up the columns
+    pub fn columns(mut self, name: &str) -> Self {
+        self.columns.push(name.to_string());
+        self
+    }
+
+    /// Adds the columns whose names match a regular expression to the row fields
+    pub fn rows_matching(mut self, pattern: &str) -> Self {
+        self.options.rows_regex.push(pattern.to_string());
+        self
+    }
+
+    /// Adds the columns whose names match a regular expression to the column fields
+    pub fn columns_matching(mut self, pattern: &str) -> Self {
+        self.options.columns_regex.push(pattern.to_string());
+        self
+    }
+
+    /// Adds a row field holding the first capture group of a regular expression
+    /// (like `email:@(.+)$`), or the whole match if it doesn't have one
+    pub fn rows_extract(mut self, spec: &str) -> Self {
+        self.options.rows_extract.push(spec.to_string());
+        self
+    }
+
+    /// Adds a column field holding a regex capture, like `rows_extract`
+    pub fn columns_extract(mut self, spec: &str) -> Self {
+        self.options.columns_extract.push(spec.to_string());
+        self
+    }
+
+    /// Adds a row field computed from an expression, like `lower(region)`
+    pub fn rows_expr(mut self, expr: &str) -> Self {
+        self.options.rows_expr.push(expr.to_string());
+        self
+    }
+
+    /// Adds a column field computed from an expression
+    pub fn columns_expr(mut self, expr: &str) -> Self {
+        self.options.columns_expr.push(expr.to_string());
+        self
+    }
+
+    /// Cleans up the keys of the row and column fields with a comma-separated list of steps
+    /// (like `trim,lower`)
+    pub fn normalize_keys(mut self, steps: &str) -> Self {
+        self.options.normalize_keys = Some(steps.to_string());
+        self
+    }
+
+    /// Truncates the dates of the row and column fields to a period (like `month`), or only
+    /// those of a single field (like `order_date:month`)
+    pub fn date_part(mut self, spec: &str) -> Self {
+        self.options.date_parts.push(spec.to_string());
+        self
+    }
+
+    /// Groups the numbers of a row or column field into buckets (like `salary:0,50000,100000`)
+    pub fn bin(mut self, spec: &str) -> Self {
+        self.options.bins.push(spec.to_string());
+        self
+    }
+
+    /// Replaces the keys of the row and column fields with the labels in a lookup table (a
+    /// CSV file mapping its first column to its second), or only those of a single field
+    /// (like `state:states.csv`)
+    pub fn recode(mut self, spec: &str) -> Self {
+        self.options.recodes.push(spec.to_string());
+        self
+    }
+
+    /// Sets what happens to keys that a lookup table from `recode` doesn't have
+    pub fn unmapped(mut self, policy: UnmappedPolicy) -> Self {
+        self.options.unmapped = Some(policy);
+        self
+    }
+
+    /// Adds a column field with the dates of a column, truncated to a period (like
+    /// `order_date:month`), with a column for every period between the first and last dates
+    pub fn timeseries(mut self, spec: &str) -> Self {
+        self.options.timeseries = Some(spec.to_string());
+        self
+    }
+
+    /// Sets the format of the dates outside of the values column (in date parts, time series,
+    /// and `since` and `until`), which otherwise get tried against a few common formats
+    pub fn key_date_format(mut self, format: &str) -> Self {
+        self.options.key_date_format = Some(format.to_string());
+        self
+    }
+
+    /// Adds a values column. Every function but `count` needs at least one (or a value
+    /// expression, or `other_values`).
+    pub fn values(mut self, name: &str) -> Self {
+        self.values.push(name.to_string());
+        self
+    }
+
+    /// Uses every column that isn't a row or column field as a values column
+    pub fn other_values(mut self) -> Self {
+        self.options.other_values = true;
+        self
+    }
+
+    /// Aggregates the result of an expression (like `price * quantity`), instead of a column
+    pub fn value_expr(mut self, expr: &str) -> Self {
+        self.options.value_expr = Some(expr.to_string());
+        self
+    }
+
+    /// Sets the function that aggregates the values
+    pub fn aggregate(mut self, function: Agg) -> Self {
+        self.function = Some(function);
+        self
+    }
+
+    /// Sets how `max`, `min`, `minmax`, and `range` read their values
+    pub fn value_type(mut self, value_type: ValueType) -> Self {
+        self.value_type = Some(value_type);
+        self
+    }
+
+    /// Computes `median` and `mode` with a fixed amount of memory per cell (see `--approx`).
+    /// The other functions are always exact.
+    pub fn approx(mut self, approx: bool) -> Self {
+        self.options.approx = approx;
+        self
+    }
+
+    /// Skips empty values (like `-e`), rather than aggregating them
+    pub fn skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// Skips the values that match one of `values` (case-insensitively), along with empty values
+    pub fn null_values(mut self, values: Vec<String>) -> Self {
+        self.options.null_values = Some(values);
+        self
+    }
+
+    /// Sets the order of the rows, which defaults to the order they first appear in
+    pub fn row_order(mut self, order: OutputOrder) -> Self {
+        self.row_order = order;
+        self
+    }
+
+    /// Sets the order of the columns, which defaults to sorted (ascending) order
+    pub fn column_order(mut self, order: OutputOrder) -> Self {
+        self.column_order = order;
+        self
+    }
+
+    /// Sets how keys get compared when the rows or columns get sorted
+    pub fn comparator(mut self, comparator: Comparator) -> Self {
+        self.options.comparator = comparator;
+        self
+    }
+
+    /// Sets the string that joins the keys of several column fields into a header
+    pub fn key_separator(mut self, separator: &str) -> Self {
+        self.options.key_separator = Some(separator.to_string());
+        self
+    }
+
+    /// Sets the labels of the row fields in the header row
+    pub fn row_labels(mut self, labels: Vec<String>) -> Self {
+        self.options.row_labels = labels;
+        self
+    }
+
+    /// Puts a prefix in front of the header of each column
+    pub fn header_prefix(mut self, prefix: &str) -> Self {
+        self.options.header_prefix = prefix.to_string();
+        self
+    }
+
+    /// Writes a header row for each column field, rather than joining their keys
+    pub fn multi_header(mut self, multi_header: bool) -> Self {
+        self.options.multi_header = multi_header;
+        self
+    }
+
+    /// Adds a total row and column
+    pub fn totals(mut self, totals: bool) -> Self {
+        self.options.totals = totals;
+        self
+    }
+
+    /// Adds a subtotal row for each group of the first row fields
+    pub fn subtotals(mut self, subtotals: bool) -> Self {
+        self.options.subtotals = subtotals;
+        self
+    }
+
+    /// Drops the rows and columns whose cells are all empty or zero
+    pub fn drop_empty(mut self, drop_empty: bool) -> Self {
+        self.options.drop_empty = drop_empty;
+        self
+    }
+
+    /// Writes a row for each cell, rather than a table
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.options.sparse = sparse;
+        self
+    }
+
+    /// Only aggregates the records where an expression (like `amount > 100`) is true.
+    /// Calling this again adds another condition that has to hold.
+    pub fn filter(mut self, expr: &str) -> Self {
+        self.options.filters.push(expr.to_string());
+        self
+    }
+
+    /// Adds a condition to a set of conditions, where a record only needs one of them to hold
+    pub fn filter_any(mut self, expr: &str) -> Self {
+        self.options.any_filters.push(expr.to_string());
+        self
+    }
+
+    /// Only aggregates the records whose column matches a regular expression (like
+    /// `product:^Widget`)
+    pub fn filter_regex(mut self, spec: &str) -> Self {
+        self.options.regex_filters.push(spec.to_string());
+        self
+    }
+
+    /// Only aggregates the records whose column compares to a number (like `amount>=1000`)
+    pub fn filter_where(mut self, spec: &str) -> Self {
+        self.options.numeric_filters.push(spec.to_string());
+        self
+    }
+
+    /// Sets the date column that `since` and `until` check
+    pub fn date_column(mut self, name: &str) -> Self {
+        self.options.date_column = Some(name.to_string());
+        self
+    }
+
+    /// Only aggregates the records whose date column is on or after a date
+    pub fn since(mut self, date: &str) -> Self {
+        self.options.since = Some(date.to_string());
+        self
+    }
+
+    /// Only aggregates the records whose date column is on or before a date
+    pub fn until(mut self, date: &str) -> Self {
+        self.options.until = Some(date.to_string());
+        self
+    }
+
+    /// Sets what happens to the records whose filters can't be evaluated
+    pub fn filter_error_policy(mut self, policy: FilterErrorPolicy) -> Self {
+        self.options.filter_error_policy = Some(policy);
+        self
+    }
+
+    /// Sets what happens to values that aren't numbers (like `NaN`)
+    pub fn nan_policy(mut self, policy: NanPolicy) -> Self {
+        self.options.nan_policy = Some(policy);
+        self
+    }
+
+    /// Skips the records that can't be aggregated, rather than stopping at the first one
+    pub fn skip_bad_records(mut self, skip: bool) -> Self {
+        self.options.skip_bad_records = skip;
+        self
+    }
+
+    /// Stops once more than `max` records can't be aggregated
+    pub fn max_bad_records(mut self, max: usize) -> Self {
+        self.options.max_bad_records = Some(max);
+        self
+    }
+
+    /// Writes the records that can't be aggregated to the file at `path`
+    pub fn bad_records(mut self, path: &str) -> Self {
+        self.options.bad_records = Some(path.to_string());
+        self
+    }
+
+    /// Sets the label of the catch-all row for `top_rows`, `min_count`, and unmapped keys,
+    /// which defaults to `Other`
+    pub fn other_label(mut self, label: &str) -> Self {
+        self.options.other_label = Some(label.to_string());
+        self
+    }
+
+    /// Only keeps the `num_rows` rows with the largest totals, grouping the rest together
+    pub fn top_rows(mut self, num_rows: usize) -> Self {
+        self.options.top_rows = Some(num_rows);
+        self
+    }
+
+    /// Drops the rows with fewer than `min_count` records, or groups them together if
+    /// `as_other` is true
+    pub fn min_count(mut self, min_count: usize, as_other: bool) -> Self {
+        self.options.min_count = Some((min_count, as_other));
+        self
+    }
+
+    /// Only keeps the rows where a condition on their aggregate (`value`) and their number of
+    /// records (`count`) holds, like `value > 100 && count >= 5`
+    pub fn having(mut self, condition: &str) -> Self {
+        self.options.having = Some(condition.to_string());
+        self
+    }
+
+    /// Adds a transformation of the finished cells, like a percent of each column's total.
+    /// The transformations get applied in the order they were added.
+    pub fn cell_transform(mut self, transform: CellTransform) -> Self {
+        self.options.cell_transforms.push(transform);
+        self
+    }
+
+    /// Adds a rule for relabeling the finished cells
+    pub fn output_recode(mut self, rule: RecodeRule) -> Self {
+        self.options.recode_rules.push(rule);
+        self
+    }
+
+    /// Writes the numbers of the cells with a locale's separators
+    pub fn out_locale(mut self, locale: NumberLocale) -> Self {
+        self.options.out_locale = Some(locale);
+        self
+    }
+
+    /// Stops reading after `limit` records
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.options.limit = Some(limit);
+        self
+    }
+
+    /// Only aggregates a random sample of the records
+    pub fn sample(mut self, sample: Sample) -> Self {
+        self.options.sample = Some(sample);
+        self
+    }
+
+    /// Sets the seed of the random sample, so it's the same each time
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.options.seed = Some(seed);
+        self
+    }
+
+    /// Aggregates the records on `threads` threads (see `Aggregator::with_threads`)
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets the number of rows and columns to expect (see `Aggregator::with_group_hint`)
+    pub fn group_hint(mut self, groups: usize) -> Self {
+        self.options.group_hint = Some(groups);
+        self
+    }
+
+    /// Spills the cells to disk once there are more than `max_cells` of them
+    pub fn max_cells(mut self, max_cells: usize) -> Self {
+        self.options.max_cells = Some(max_cells);
+        self
+    }
+
+    /// Spills the cells to disk once they take up more than `bytes` of memory
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.options.max_memory = Some(bytes);
+        self
+    }
+
+    /// Sets the byte that separates fields, which defaults to a comma. Like the delimiter of
+    /// the command line, it has to be an ASCII character.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row of the input is a header row, which it is by default
+    pub fn has_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Requires column names to exactly match the names in the header row
+    /// (see `CsvSettings::with_strict_headers`)
+    pub fn strict_headers(mut self, strict_headers: bool) -> Self {
+        self.strict_headers = strict_headers;
+        self
+    }
+
+    /// Whether the pivot table still needs a values column before it can be built
+    pub(crate) fn needs_values(&self) -> bool {
+        self.values.is_empty()
+            && !self.options.other_values
+            && self.options.value_expr.is_none()
+            && self.function != Some(Agg::Count)
+    }
+
+    /// Checks that the pieces of the pivot table fit together and returns the `Pivot`
+    pub fn build(self) -> CsvCliResult<Pivot> {
+        let function = self.function.ok_or_else(|| {
+            CsvCliError::InvalidConfiguration(
+                "The pivot table needs an aggregation function".to_string(),
+            )
+        })?;
+        if self.needs_values() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "You need to select a values column (or a value expression) for every function except count"
+                    .to_string(),
+            ));
+        }
+        if !self.delimiter.is_ascii() {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "The delimiter has to be an ASCII character, not the byte {:#04x}",
+                self.delimiter
+            )));
+        }
+        self.options.validate()?;
+        let value_type = value_type(function, self.value_type)?;
+        Ok(Pivot {
+            function,
+            parsing_strategy: parsing_strategy(function, &value_type),
+            value_type,
+            rows: self.rows,
+            columns: self.columns,
+            values: self.values,
+            skip_empty: self.skip_empty,
+            row_order: self.row_order,
+            column_order: self.column_order,
+            threads: self.threads,
+            settings: CsvSettings::new(self.delimiter, self.has_headers)
+                .with_strict_headers(self.strict_headers),
+            options: self.options,
+        })
+    }
+}
+
+/// Something to do with the accumulator, input, and output types of a pivot table's function,
+/// which `Pivot::dispatch` picks out (so the library and the command line pick them the same way),
+/// along with the parser for its values
+pub(crate) trait PivotTask {
+    type Output;
+
+    fn run<T, I, O>(self, parser: ValueParser<I>) -> CsvCliResult<Self::Output>
+    where
+        T: Accumulate<I, O> + Sync + 'static,
+        I: FromStr + Clone + Send + 'static,
+        O: WriteCell + 'static;
+}
+
+/// Reads the records of a `csv::Reader` into the rows of a pivot table
+struct StreamCsv<'a, R> {
+    pivot: &'a Pivot,
+    rdr: &'a mut csv::Reader<R>,
+}
+
+impl<R: io::Read> PivotTask for StreamCsv<'_, R> {
+    type Output = Rows;
+
+    fn run<T, I, O>(self, parser: ValueParser<I>) -> CsvCliResult<Rows>
+    where
+        T: Accumulate<I, O> + Sync + 'static,
+        I: FromStr + Clone + Send + 'static,
+        O: WriteCell + 'static,
+    {
+        let mut agg: Aggregator<T, I, O> = self.pivot.reader_aggregator(self.rdr, parser)?;
+        agg.aggregate(self.rdr)?;
+        agg.into_rows()
+    }
+}
+
+/// Reads the records of a `csv::Reader` and writes the finished table, the way `clipivot` would
+struct WriteCsv<'a, R, W: io::Write> {
+    pivot: &'a Pivot,
+    rdr: &'a mut csv::Reader<R>,
+    wtr: &'a mut csv::Writer<W>,
+}
+
+impl<R: io::Read, W: io::Write> PivotTask for WriteCsv<'_, R, W> {
+    type Output = ();
+
+    fn run<T, I, O>(self, parser: ValueParser<I>) -> CsvCliResult<()>
+    where
+        T: Accumulate<I, O> + Sync + 'static,
+        I: FromStr + Clone + Send + 'static,
+        O: WriteCell + 'static,
+    {
+        let mut agg: Aggregator<T, I, O> = self.pivot.reader_aggregator(self.rdr, parser)?;
+        agg.aggregate(self.rdr)?;
+        agg.write_results(self.wtr)
+    }
+}
+
+/// A pivot table that's ready to read its records
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pivot {
+    function: Agg,
+    value_type: ValueType,
+    parsing_strategy: ParsingStrategy,
+    rows: Vec<String>,
+    columns: Vec<String>,
+    values: Vec<String>,
+    skip_empty: bool,
+    row_order: OutputOrder,
+    column_order: OutputOrder,
+    threads: usize,
+    settings: CsvSettings,
+    options: Options,
+}
+
+impl Pivot {
+    /// Reads the records of `rdr` (with the pivot table's delimiter and header settings) and
+    /// returns the pivot table they make up
+    pub fn read<R: io::Read>(&self, rdr: R) -> CsvCliResult<Table> {
+        self.stream(rdr)?.into_table()
+    }
+
+    /// Reads the file at `path` and returns the pivot table its records make up
+    pub fn read_path(&self, path: &str) -> CsvCliResult<Table> {
+        self.stream_path(path)?.into_table()
+    }
+
+    /// Reads the records of a `csv::Reader` that was already set up (so its own settings, like
+    /// its delimiter, get used instead of the pivot table's) and returns the pivot table they
+    /// make up
+    pub fn read_csv<R: io::Read>(&self, rdr: &mut csv::Reader<R>) -> CsvCliResult<Table> {
+        self.stream_csv(rdr)?.into_table()
+    }
+
+    /// Reads the records of `rdr` like `read`, but returns the rows of the pivot table as an
+    /// iterator, which computes the cells of each row as it gets to it
+    pub fn stream<R: io::Read>(&self, rdr: R) -> CsvCliResult<Rows> {
+        self.stream_csv(&mut self.settings.get_reader(rdr))
+    }
+
+    /// Reads the file at `path` like `read_path`, returning the rows as an iterator
+    pub fn stream_path(&self, path: &str) -> CsvCliResult<Rows> {
+        self.stream_csv(&mut self.settings.get_reader_from_path(path)?)
+    }
+
+    /// Reads the records of a `csv::Reader` like `read_csv`, returning the rows as an iterator
+    pub fn stream_csv<R: io::Read>(&self, rdr: &mut csv::Reader<R>) -> CsvCliResult<Rows> {
+        self.dispatch(StreamCsv { pivot: self, rdr })
+    }
+
+    /// Reads the records of `rdr` and writes the finished pivot table to `wtr`, the way
+    /// `clipivot` would. Unlike `read` and `stream`, this can write a table with totals,
+    /// cell transformations, or recoded cells.
+    pub fn write<R: io::Read, W: io::Write>(
+        &self,
+        rdr: R,
+        wtr: &mut csv::Writer<W>,
+    ) -> CsvCliResult<()> {
+        self.write_csv(&mut self.settings.get_reader(rdr), wtr)
+    }
+
+    /// Reads the records of a `csv::Reader` like `read_csv`, writing the table like `write`
+    pub fn write_csv<R: io::Read, W: io::Write>(
+        &self,
+        rdr: &mut csv::Reader<R>,
+        wtr: &mut csv::Writer<W>,
+    ) -> CsvCliResult<()> {
+        self.dispatch(WriteCsv {
+            pivot: self,
+            rdr,
+            wtr,
+        })
+    }
+
+    /// Sets up an aggregator for the records of `rdr`, after reading its header row
+    fn reader_aggregator<T, I, O, R>(
+        &self,
+        rdr: &mut csv::Reader<R>,
+        parser: ValueParser<I>,
+    ) -> CsvCliResult<Aggregator<T, I, O>>
+    where
+        T: Accumulate<I, O> + Sync,
+        I: FromStr + Clone + Send,
+        O: WriteCell,
+        R: io::Read,
+    {
+        // the reader decides whether there's a header row, since `read_csv` can get one that
+        // was set up differently from the pivot table
+        let settings = CsvSettings::new(self.settings.delimiter(), rdr.has_headers())
+            .with_strict_headers(self.settings.strict_headers());
+        let headers: Vec<String> = rdr.headers()?.iter().map(String::from).collect();
+        self.aggregator(
+            &settings,
+            &headers.iter().map(String::as_str).collect(),
+            parser,
+        )
+    }
+
+    /// The settings for reading the input
+    pub(crate) fn settings(&self) -> &CsvSettings {
+        &self.settings
+    }
+
+    /// The way the function parses its values
+    pub(crate) fn parsing_strategy(&self) -> ParsingStrategy {
+        self.parsing_strategy
+    }
+
+    /// Runs `task` with the accumulator, input, and output types of the function
+    pub(crate) fn dispatch<P: PivotTask>(&self, task: P) -> CsvCliResult<P::Output> {
+        // each pivot table parses dates with its own format, so tables with different formats
+        // can be read at the same time
+        let dates = |format: &str| ValueParser::dates(format);
+        let approx = self.options.approx;
+        match (self.function, &self.value_type) {
+            (Agg::Count, _) => task.run::<Count<String>, _, _>(ValueParser::default()),
+            (Agg::CountUnique, _) => task.run::<CountUnique<String>, _, _>(ValueParser::default()),
+            (Agg::Mode, _) if approx => {
+                task.run::<ApproxMode<String>, _, _>(ValueParser::default())
+            }
+            (Agg::Mode, _) => task.run::<Mode<String>, _, _>(ValueParser::default()),
+            (Agg::Mean, _) => task.run::<Mean, _, _>(ValueParser::default()),
+            (Agg::Median, _) if approx => task.run::<ApproxMedian, _, _>(ValueParser::default()),
+            (Agg::Median, _) => task.run::<Median, _, _>(ValueParser::default()),
+            (Agg::StdDev, _) => task.run::<StdDev, _, _>(ValueParser::default()),
+            (Agg::Sum, _) => task.run::<Sum<DecimalWrapper>, _, _>(ValueParser::default()),
+            (Agg::Max, ValueType::Text) => {
+                task.run::<Maximum<String>, _, _>(ValueParser::default())
+            }
+            (Agg::Max, ValueType::Numbers) => {
+                task.run::<Maximum<f64>, _, _>(ValueParser::default())
+            }
+            (Agg::Max, ValueType::Dates(format)) => {
+                task.run::<Maximum<CustomDateObject>, _, _>(dates(format))
+            }
+            (Agg::Min, ValueType::Text) => {
+                task.run::<Minimum<String>, _, _>(ValueParser::default())
+            }
+            (Agg::Min, ValueType::Numbers) => {
+                task.run::<Minimum<f64>, _, _>(ValueParser::default())
+            }
+            (Agg::Min, ValueType::Dates(format)) => {
+                task.run::<Minimum<CustomDateObject>, _, _>(dates(format))
+            }
+            (Agg::MinMax, ValueType::Text) => {
+                task.run::<MinMax<String>, _, String>(ValueParser::default())
+            }
+            (Agg::MinMax, ValueType::Numbers) => {
+                task.run::<MinMax<f64>, _, String>(ValueParser::default())
+            }
+            (Agg::MinMax, ValueType::Dates(format)) => {
+                task.run::<MinMax<CustomDateObject>, _, String>(dates(format))
+            }
+            (Agg::Range, ValueType::Dates(format)) => {
+                task.run::<Range<CustomDateObject, f64>, CustomDateObject, f64>(dates(format))
+            }
+            (Agg::Range, _) => task.run::<Range<DecimalWrapper, Decimal>, DecimalWrapper, Decimal>(
+                ValueParser::default(),
+            ),
+        }
+    }
+
+    /// Sets up an aggregator for an input with `headers` that parses its values with `parser`,
+    /// resolving the columns the pivot table refers to
+    pub(crate) fn aggregator<T, I, O>(
+        &self,
+        settings: &CsvSettings,
+        headers: &Vec<&str>,
+        parser: ValueParser<I>,
+    ) -> CsvCliResult<Aggregator<T, I, O>>
+    where
+        T: Accumulate<I, O> + Sync,
+        I: FromStr + Clone + Send,
+        O: WriteCell,
+    {
+        let (row_fields, column_fields) =
+            self.options
+                .key_fields(&self.rows, &self.columns, settings, headers)?;
+        let values = self.options.value_columns(
+            &self.values,
+            &[&row_fields, &column_fields],
+            settings,
+            headers,
+        )?;
+        let agg = Aggregator::new(
+            vec![],
+            vec![],
+            values,
+            self.skip_empty,
+            self.row_order.clone(),
+            self.column_order.clone(),
+            self.parsing_strategy,
+        )
+        .with_row_fields(row_fields)
+        .with_column_fields(column_fields)
+        .with_threads(self.threads)
+        .with_value_parser(parser);
+        self.options
+            .configure(agg, self.function, settings, headers)
+    }
+}
+
+/// A pivot table whose cells have been computed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    /// The names of the columns that make up the rows (empty for a file without a header row)
+    pub row_fields: Vec<String>,
+    /// The names of the columns that make up the columns
+    pub column_fields: Vec<String>,
+    /// The names of the values columns
+    pub value_fields: Vec<String>,
+    /// The key of each row, in order. A table without any row fields has a single row, `total`.
+    pub rows: Vec<Vec<String>>,
+    /// The key of each column, in order
+    pub columns: Vec<Vec<String>>,
+    /// The number of cells in each (row, column) pair: one for each values column
+    pub num_value_slots: usize,
+    /// The cells of each row, going through the values columns of each column in turn
+    /// (so `cells[row][column * num_value_slots + value]`). Cells without any values are `None`.
+    pub cells: Vec<Vec<Option<Value>>>,
+}
+
+impl Table {
+    /// The cell of a row and column (by their positions) for the values column at position
+    /// `value`, if it has a value
+    pub fn cell(&self, row: usize, column: usize, value: usize) -> Option<&Value> {
+        self.cells
+            .get(row)?
+            .get(column * self.num_value_slots + value)?
+            .as_ref()
+    }
+
+    /// The cell of the first values column for a row and column (by their keys)
+    pub fn get(&self, row: &[&str], column: &[&str]) -> Option<&Value> {
+        let position = |keys: &[Vec<String>], key: &[&str]| keys.iter().position(|k| k == key);
+        self.cell(
+            position(&self.rows, row)?,
+            position(&self.columns, column)?,
+            0,
+        )
+    }
+
+    /// Writes the table as CSV, with the same header row and cells `clipivot` would write for
+    /// a plain pivot table
+    pub fn write_csv<W: io::Write>(&self, wtr: &mut csv::Writer<W>) -> CsvCliResult<()> {
+        let mut header = self.row_fields.clone();
+        if header.is_empty() {
+            header.push(String::new());
+        }
+        for column in &self.columns {
+            let label = join_key(column, FIELD_SEPARATOR);
+            for value_idx in 0..self.num_value_slots {
+                header.push(match self.value_fields.get(value_idx) {
+                    Some(name) if self.num_value_slots > 1 && self.column_fields.is_empty() => {
+                        name.clone()
+                    }
+                    Some(name) if self.num_value_slots > 1 => format!("{}_{}", label, name),
+                    _ => label.clone(),
+                });
+            }
+        }
+        wtr.write_record(&header)?;
+        let mut record = Vec::new();
+        for (key, cells) in self.rows.iter().zip(&self.cells) {
+            record.clear();
+            record.extend(key.iter().cloned());
+            record.extend(
+                cells
+                    .iter()
+                    .map(|cell| cell.as_ref().map(Value::to_string).unwrap_or_default()),
+            );
+            wtr.write_record(&record)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// One row of a pivot table
+#[derive(Debug, Clone, PartialEq)]
+pub struct PivotRow {
+    /// The key of the row (`total` if the table doesn't have any row fields)
+    pub key: Vec<String>,
+    /// The cells of the row, going through the values columns of each column in turn, like a
+    /// row of `Table::cells`
+    pub cells: Vec<Option<Value>>,
+}
+
+/// The rows of a finished pivot table, in order. All of the records have already been read, but
+/// each row's cells only get computed once the iterator gets to it, so the rows can be handed
+/// off one at a time without holding the whole table.
+pub struct Rows {
+    /// The names of the columns that make up the rows
+    pub row_fields: Vec<String>,
+    /// The names of the columns that make up the columns
+    pub column_fields: Vec<String>,
+    /// The names of the values columns
+    pub value_fields: Vec<String>,
+    /// The key of each column, in order
+    pub columns: Vec<Vec<String>>,
+    /// The number of cells in each (row, column) pair: one for each values column
+    pub num_value_slots: usize,
+    rows: Box<dyn Iterator<Item = CsvCliResult<PivotRow>>>,
+}
+
+impl Rows {
+    pub(crate) fn new(
+        row_fields: Vec<String>,
+        column_fields: Vec<String>,
+        value_fields: Vec<String>,
+        columns: Vec<Vec<String>>,
+        num_value_slots: usize,
+        rows: Box<dyn Iterator<Item = CsvCliResult<PivotRow>>>,
+    ) -> Rows {
+        Rows {
+            row_fields,
+            column_fields,
+            value_fields,
+            columns,
+            num_value_slots,
+            rows,
+        }
+    }
+
+    /// Collects the rest of the rows into a `Table`
+    pub fn into_table(mut self) -> CsvCliResult<Table> {
+        let mut rows = Vec::new();
+        let mut cells = Vec::new();
+        for row in &mut self.rows {
+            let row = row?;
+            rows.push(row.key);
+            cells.push(row.cells);
+        }
+        Ok(Table {
+            row_fields: self.row_fields,
+            column_fields: self.column_fields,
+            value_fields: self.value_fields,
+            rows,
+            columns: self.columns,
+            num_value_slots: self.num_value_slots,
+            cells,
+        })
+    }
+}
+
+impl Iterator for Rows {
+    type Item = CsvCliResult<PivotRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rows.next()
+    }
+}
+
+impl fmt::Debug for Rows {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rows")
+            .field("row_fields", &self.row_fields)
+            .field("column_fields", &self.column_fields)
+            .field("value_fields", &self.value_fields)
+            .field("columns", &self.columns)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    const DATA: &str = "region,product,amount,date\n\
+        west,a,9,2020-01-03\n\
+        east,b,2,2020-02-01\n\
+        west,b,,2020-01-05\n\
+        west,a,10,2019-12-31\n";
+
+    fn aggregate(builder: PivotBuilder) -> CsvCliResult<Table> {
+        builder.build()?.read(DATA.as_bytes())
+    }
+
+    #[test]
+    fn test_pivot() {
+        let table = aggregate(
+            PivotBuilder::new()
+                .rows("region")
+                .columns("product")
+                .aggregate(Agg::Count),
+        )
+        .unwrap();
+        assert_eq!(table.row_fields, vec!["region"]);
+        assert_eq!(table.column_fields, vec!["product"]);
+        assert_eq!(table.columns, vec![vec!["a"], vec!["b"]]);
+        assert_eq!(
+            table.cells,
+            vec![
+                vec![Some(Value::Int(2)), Some(Value::Int(1))],
+                vec![None, Some(Value::Int(1))]
+            ]
+        );
+
+        // text gets compared alphabetically, and numbers numerically
+        let max = |value_type| {
+            aggregate(
+                PivotBuilder::new()
+                    .rows("region")
+                    .values("amount")
+                    .aggregate(Agg::Max)
+                    .value_type(value_type)
+                    .skip_empty(true),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            max(ValueType::Text).get(&["west"], &["total"]),
+            Some(&Value::Text("9".to_string()))
+        );
+        assert_eq!(
+            max(ValueType::Numbers).get(&["west"], &["total"]),
+            Some(&Value::Float(10.))
+        );
+        let text = aggregate(
+            PivotBuilder::new()
+                .rows("region")
+                .values("amount")
+                .aggregate(Agg::Min)
+                .skip_empty(true),
+        )
+        .unwrap();
+        assert_eq!(
+            text.get(&["west"], &["total"]).and_then(Value::as_str),
+            Some("10")
+        );
+
+        let dates = |function| {
+            aggregate(
+                PivotBuilder::new()
+                    .values("date")
+                    .aggregate(function)
+                    .value_type(ValueType::Dates("%Y-%m-%d".to_string())),
+            )
+            .unwrap()
+        };
+        let latest = NaiveDate::from_ymd(2020, 2, 1).and_hms(0, 0, 0);
+        assert_eq!(dates(Agg::Max).cell(0, 0, 0), Some(&Value::Date(latest)));
+        assert_eq!(dates(Agg::Range).cell(0, 0, 0), Some(&Value::Float(32.)));
+
+        let several = aggregate(
+            PivotBuilder::new()
+                .values("amount")
+                .values("2")
+                .aggregate(Agg::Sum)
+                .skip_empty(true),
+        )
+        .unwrap();
+        assert_eq!(several.value_fields, vec!["amount", "amount"]);
+        assert_eq!(
+            several.cell(0, 0, 1),
+            Some(&Value::Decimal(Decimal::new(21, 0)))
+        );
+        assert_eq!(several.cell(0, 0, 1).unwrap().to_string(), "21");
+        assert_eq!(several.rows, vec![vec!["total"]]);
+    }
+
+    #[test]
+    fn test_date_formats() {
+        // each pivot table parses dates with its own format, even while others are being read
+        let latest = |format: &'static str, data: &'static str| {
+            std::thread::spawn(move || {
+                let pivot = PivotBuilder::new()
+                    .values("date")
+                    .aggregate(Agg::Max)
+                    .value_type(ValueType::Dates(format.to_string()))
+                    .build()
+                    .unwrap();
+                (0..50)
+                    .map(|_| {
+                        pivot
+                            .read(data.as_bytes())
+                            .map(|table| table.cell(0, 0, 0).cloned())
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        let iso = latest("%Y-%m-%d", "date\n2020-01-03\n2020-02-01\n");
+        let us = latest("%m/%d/%Y", "date\n01/03/2020\n02/01/2020\n");
+        let expected = Value::Date(NaiveDate::from_ymd(2020, 2, 1).and_hms(0, 0, 0));
+        for handle in [iso, us] {
+            for table in handle.join().unwrap() {
+                assert_eq!(table.unwrap(), Some(expected.clone()));
+            }
+        }
+        let err = PivotBuilder::new()
+            .values("date")
+            .aggregate(Agg::Max)
+            .value_type(ValueType::Dates("%m/%d/%Y".to_string()))
+            .build()
+            .unwrap()
+            .read("date\n2020-01-03\n".as_bytes())
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Could not parse as date with %m/%d/%Y format"));
+    }
+
+    #[test]
+    fn test_pivot_settings() {
+        let data = DATA.replace(',', ";");
+        let pivot = PivotBuilder::new()
+            .rows("region")
+            .aggregate(Agg::Count)
+            .delimiter(b';')
+            .build()
+            .unwrap();
+        let table = pivot.read(data.as_bytes()).unwrap();
+        assert_eq!(table.get(&["west"], &["total"]), Some(&Value::Int(3)));
+        // a reader that was already set up keeps its own delimiter
+        let mut rdr = csv::Reader::from_reader(DATA.as_bytes());
+        assert_eq!(pivot.read_csv(&mut rdr).unwrap(), table);
+
+        let no_header = PivotBuilder::new()
+            .rows("0")
+            .aggregate(Agg::Count)
+            .has_headers(false)
+            .build()
+            .unwrap();
+        let table = no_header.read(DATA.as_bytes()).unwrap();
+        assert_eq!(table.rows, vec![vec!["region"], vec!["west"], vec!["east"]]);
+        let named = PivotBuilder::new()
+            .rows("region")
+            .aggregate(Agg::Count)
+            .has_headers(false)
+            .build()
+            .unwrap();
+        assert!(named.read(DATA.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let pivot = PivotBuilder::new()
+            .rows("region")
+            .columns("product")
+            .aggregate(Agg::Count)
+            .build()
+            .unwrap();
+        let mut rows = pivot.stream(DATA.as_bytes()).unwrap();
+        assert_eq!(rows.columns, vec![vec!["a"], vec!["b"]]);
+        assert_eq!(
+            rows.next().unwrap().unwrap(),
+            PivotRow {
+                key: vec!["west".to_string()],
+                cells: vec![Some(Value::Int(2)), Some(Value::Int(1))],
+            }
+        );
+        assert_eq!(rows.next().unwrap().unwrap().key, vec!["east"]);
+        assert!(rows.next().is_none());
+        assert_eq!(
+            pivot.stream(DATA.as_bytes()).unwrap().into_table().unwrap(),
+            pivot.read(DATA.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pivot_options() {
+        // the options of the command line work the same way through the builder
+        let doubled = PivotBuilder::new()
+            .rows("region")
+            .value_expr("amount * 2")
+            .aggregate(Agg::Sum)
+            .filter("product == 'a'");
+        let table = aggregate(doubled.clone()).unwrap();
+        assert_eq!(table.rows, vec![vec!["west"]]);
+        assert_eq!(table.get(&["west"], &["total"]).unwrap().to_string(), "38");
+        // the totals only show up in the written table
+        let totals = doubled.totals(true).build().unwrap();
+        assert!(totals.read(DATA.as_bytes()).is_err());
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        totals.write(DATA.as_bytes(), &mut wtr).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+            "region,total\nwest,38\nTotal,38\n"
+        );
+        let others = aggregate(
+            PivotBuilder::new()
+                .rows("region")
+                .columns("product")
+                .other_values()
+                .aggregate(Agg::Count),
+        )
+        .unwrap();
+        assert_eq!(others.value_fields, vec!["amount", "date"]);
+        let months = aggregate(
+            PivotBuilder::new()
+                .rows("date")
+                .date_part("month")
+                .aggregate(Agg::Count)
+                .row_order(OutputOrder::Ascending)
+                .since("2020-01-01")
+                .date_column("date"),
+        )
+        .unwrap();
+        assert_eq!(months.rows, vec![vec!["2020-01"], vec!["2020-02"]]);
+        let approx = |approx| {
+            aggregate(
+                PivotBuilder::new()
+                    .values("amount")
+                    .aggregate(Agg::Median)
+                    .skip_empty(true)
+                    .approx(approx),
+            )
+            .unwrap()
+        };
+        assert_eq!(approx(true), approx(false));
+
+        assert!(PivotBuilder::new()
+            .value_expr("amount")
+            .aggregate(Agg::Sum)
+            .build()
+            .is_ok());
+        assert!(PivotBuilder::new()
+            .aggregate(Agg::Count)
+            .key_separator("")
+            .build()
+            .is_err());
+        assert!(PivotBuilder::new()
+            .aggregate(Agg::Count)
+            .since("2020-01-01")
+            .build()
+            .is_err());
+        // the columns only get checked once there's a header row
+        let pivot = PivotBuilder::new()
+            .aggregate(Agg::Count)
+            .filter_regex("nope:^a")
+            .build()
+            .unwrap();
+        assert!(pivot.read(DATA.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let write = |builder: PivotBuilder| {
+            let mut wtr = csv::Writer::from_writer(Vec::new());
+            aggregate(builder).unwrap().write_csv(&mut wtr).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        assert_eq!(
+            write(
+                PivotBuilder::new()
+                    .rows("region")
+                    .columns("product")
+                    .aggregate(Agg::Count)
+            ),
+            "region,a,b\nwest,2,1\neast,,1\n"
+        );
+        assert_eq!(
+            write(
+                PivotBuilder::new()
+                    .values("amount")
+                    .values("2")
+                    .aggregate(Agg::Count)
+            ),
+            ",amount,amount\ntotal,4,4\n"
+        );
+        assert_eq!(
+            write(
+                PivotBuilder::new()
+                    .columns("region")
+                    .values("amount")
+                    .values("product")
+                    .aggregate(Agg::Max)
+            ),
+            ",east_amount,east_product,west_amount,west_product\ntotal,2,b,9,b\n"
+        );
+    }
+
+    #[test]
+    fn test_pivot_errors() {
+        assert!(PivotBuilder::new().rows("region").build().is_err());
+        // a delimiter past ASCII can't be written the way it gets read
+        assert!(PivotBuilder::new()
+            .aggregate(Agg::Count)
+            .delimiter(0xa7)
+            .build()
+            .is_err());
+        assert!(PivotBuilder::new()
+            .rows("region")
+            .aggregate(Agg::Sum)
+            .build()
+            .is_err());
+        assert!(PivotBuilder::new()
+            .values("amount")
+            .aggregate(Agg::Mean)
+            .value_type(ValueType::Dates("%Y-%m-%d".to_string()))
+            .build()
+            .is_err());
+        assert!(aggregate(
+            PivotBuilder::new()
+                .rows("nope")
+                .values("amount")
+                .aggregate(Agg::Count)
+        )
+        .is_err());
+        // the empty value isn't a number
+        assert!(aggregate(PivotBuilder::new().values("amount").aggregate(Agg::Sum)).is_err());
+        assert_eq!("countunique".parse::<Agg>().unwrap(), Agg::CountUnique);
+        assert!("average".parse::<Agg>().is_err());
+        assert_eq!(Agg::StdDev.to_string().parse::<Agg>().unwrap(), Agg::StdDev);
+    }
+}
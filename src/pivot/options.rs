@@ -0,0 +1,640 @@
+//! The parts of a pivot table beyond its fields and function, which cover everything the
+//! command line can describe: derived keys, filters, cell transforms, limits, and so on.
+//!
+//! Anything that refers to a column (like `region:^West` for a regex filter) is kept as the
+//! text it was given in, since it can only be resolved once the header row of the input has
+//! been read. `Options::configure` does that for each input, turning the options into the
+//! settings of an `Aggregator`.
+use super::Agg;
+use crate::aggfunc::Accumulate;
+use crate::aggregation::{
+    Aggregator, FilterErrorPolicy, NanPolicy, Sample, HAVING_COUNT, HAVING_VALUE,
+};
+use crate::cli_settings::CsvSettings;
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::expression::{BinaryOp, Expr, Pattern, Value};
+use crate::keys::{
+    parse_date, Bins, DatePart, KeyField, KeySource, KeyTransform, NonDatePolicy, Normalization,
+    UnmappedPolicy, DEFAULT_KEY_DATE_FORMATS, RECORD_NUMBER_FIELD,
+};
+use crate::output::{CellTransform, NumberLocale, RecodeRule, WriteCell};
+use crate::parsing::DecimalWrapper;
+use crate::rejects::RejectWriter;
+use crate::sorting::Comparator;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The label for the values that get grouped together into a catch-all category
+pub(crate) const DEFAULT_OTHER_LABEL: &str = "Other";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(super) struct Options {
+    pub(super) rows_regex: Vec<String>,
+    pub(super) columns_regex: Vec<String>,
+    pub(super) rows_extract: Vec<String>,
+    pub(super) columns_extract: Vec<String>,
+    pub(super) rows_expr: Vec<String>,
+    pub(super) columns_expr: Vec<String>,
+    pub(super) normalize_keys: Option<String>,
+    pub(super) date_parts: Vec<String>,
+    pub(super) bins: Vec<String>,
+    pub(super) recodes: Vec<String>,
+    pub(super) unmapped: Option<UnmappedPolicy>,
+    pub(super) timeseries: Option<String>,
+    pub(super) key_date_format: Option<String>,
+    pub(super) other_values: bool,
+    pub(super) value_expr: Option<String>,
+    pub(super) null_values: Option<Vec<String>>,
+    pub(super) key_separator: Option<String>,
+    pub(super) row_labels: Vec<String>,
+    pub(super) header_prefix: String,
+    pub(super) multi_header: bool,
+    pub(super) totals: bool,
+    pub(super) subtotals: bool,
+    pub(super) drop_empty: bool,
+    pub(super) sparse: bool,
+    pub(super) comparator: Comparator,
+    pub(super) filters: Vec<String>,
+    pub(super) any_filters: Vec<String>,
+    pub(super) regex_filters: Vec<String>,
+    pub(super) numeric_filters: Vec<String>,
+    pub(super) date_column: Option<String>,
+    pub(super) since: Option<String>,
+    pub(super) until: Option<String>,
+    pub(super) other_label: Option<String>,
+    pub(super) top_rows: Option<usize>,
+    pub(super) min_count: Option<(usize, bool)>,
+    pub(super) cell_transforms: Vec<CellTransform>,
+    pub(super) recode_rules: Vec<RecodeRule>,
+    pub(super) out_locale: Option<NumberLocale>,
+    pub(super) having: Option<String>,
+    pub(super) filter_error_policy: Option<FilterErrorPolicy>,
+    pub(super) nan_policy: Option<NanPolicy>,
+    pub(super) skip_bad_records: bool,
+    pub(super) max_bad_records: Option<usize>,
+    pub(super) bad_records: Option<String>,
+    pub(super) limit: Option<usize>,
+    pub(super) group_hint: Option<usize>,
+    pub(super) max_cells: Option<usize>,
+    pub(super) max_memory: Option<usize>,
+    pub(super) sample: Option<Sample>,
+    pub(super) seed: Option<u64>,
+    pub(super) approx: bool,
+}
+
+impl Options {
+    /// Checks the options that don't depend on the input
+    pub(super) fn validate(&self) -> CsvCliResult<()> {
+        if self.key_separator.as_deref() == Some("") {
+            return Err(CsvCliError::InvalidConfiguration(
+                "The key separator cannot be an empty string".to_string(),
+            ));
+        }
+        if (self.since.is_some() || self.until.is_some()) && self.date_column.is_none() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "Keeping the records since or until a date needs a date column".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The label for the values that get grouped together into a catch-all category
+    pub(super) fn other_label(&self) -> &str {
+        self.other_label.as_deref().unwrap_or(DEFAULT_OTHER_LABEL)
+    }
+
+    /// The formats used to parse dates outside of the values column (for date parts, time
+    /// series, and the date window)
+    fn key_date_formats(&self) -> Vec<String> {
+        match &self.key_date_format {
+            Some(fmt) => vec![fmt.clone()],
+            None => DEFAULT_KEY_DATE_FORMATS
+                .iter()
+                .map(|fmt| fmt.to_string())
+                .collect(),
+        }
+    }
+
+    /// Resolves the row and column fields (named by `rows` and `columns`, plus any fields
+    /// selected by a regex, extracted from a column, or computed from an expression), along
+    /// with their transformations
+    pub(super) fn key_fields(
+        &self,
+        rows: &[String],
+        columns: &[String],
+        settings: &CsvSettings,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<(Vec<KeyField>, Vec<KeyField>)> {
+        let mut index_fields = get_selection(rows, &self.rows_regex, settings, headers)?;
+        index_fields.extend(get_extract_fields(&self.rows_extract, settings, headers)?);
+        index_fields.extend(get_expr_fields(&self.rows_expr, settings, headers)?);
+        let mut column_fields = get_selection(columns, &self.columns_regex, settings, headers)?;
+        column_fields.extend(get_extract_fields(
+            &self.columns_extract,
+            settings,
+            headers,
+        )?);
+        column_fields.extend(get_expr_fields(&self.columns_expr, settings, headers)?);
+        let mut key_fields = [&mut index_fields, &mut column_fields];
+        if let Some(spec) = &self.normalize_keys {
+            add_normalization(spec, &mut key_fields)?;
+        }
+        let formats = self.key_date_formats();
+        for spec in &self.date_parts {
+            add_date_part(spec, &formats, settings, headers, &mut key_fields)?;
+        }
+        for spec in &self.bins {
+            add_bins(spec, settings, headers, &mut key_fields)?;
+        }
+        let policy = self.unmapped.clone().unwrap_or(UnmappedPolicy::Keep);
+        for spec in &self.recodes {
+            add_recode(spec, &policy, settings, headers, &mut key_fields)?;
+        }
+        // the time series is added afterwards, so that date parts don't truncate its dates twice
+        if let Some(spec) = &self.timeseries {
+            column_fields.push(get_timeseries_field(spec, formats, settings, headers)?);
+        }
+        Ok((index_fields, column_fields))
+    }
+
+    /// The indexes of the values columns: the ones named by `values`, or every column that
+    /// isn't a row or column field
+    pub(super) fn value_columns(
+        &self,
+        values: &[String],
+        key_fields: &[&Vec<KeyField>],
+        settings: &CsvSettings,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Vec<usize>> {
+        if !self.other_values {
+            return settings
+                .get_field_indexes(&values.iter().map(String::as_str).collect(), headers);
+        }
+        let used: Vec<usize> = key_fields
+            .iter()
+            .flat_map(|fields| fields.iter())
+            .filter_map(KeyField::column_index)
+            .collect();
+        let others: Vec<usize> = (0..headers.len())
+            .filter(|idx| !used.contains(idx))
+            .collect();
+        if others.is_empty() {
+            return Err(CsvCliError::InvalidConfiguration(
+                "There aren't any columns left to use as values columns".to_string(),
+            ));
+        }
+        Ok(others)
+    }
+
+    /// Sets up everything besides the fields of an aggregator, resolving any columns the
+    /// options refer to with the headers of the input
+    pub(super) fn configure<T, I, O>(
+        &self,
+        agg: Aggregator<T, I, O>,
+        function: Agg,
+        settings: &CsvSettings,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Aggregator<T, I, O>>
+    where
+        T: Accumulate<I, O> + Sync,
+        I: std::str::FromStr + Clone + Send,
+        O: WriteCell,
+    {
+        let mut agg = agg
+            .with_row_labels(self.row_labels.clone())
+            .with_header_prefix(&self.header_prefix)
+            .with_multi_header(self.multi_header)
+            .with_totals(self.totals)
+            .with_subtotals(self.subtotals)
+            .with_drop_empty(self.drop_empty)
+            .with_sparse(self.sparse)
+            .with_comparator(self.comparator)
+            .with_skip_bad_records(self.skip_bad_records)
+            .with_recode_rules(self.recode_rules.clone());
+        if let Some(separator) = &self.key_separator {
+            agg = agg.with_key_separator(separator);
+        }
+        if let Some(values) = &self.null_values {
+            agg = agg.with_null_values(values.clone());
+        }
+        let parse_filter =
+            |filter: &str| Expr::parse(filter, |name| settings.get_field_index(name, headers));
+        if let Some(expr) = &self.value_expr {
+            agg = agg.with_value_expr(parse_filter(expr)?);
+        }
+        for filter in &self.filters {
+            agg = agg.with_filter(parse_filter(filter)?);
+        }
+        let any_filters = self
+            .any_filters
+            .iter()
+            .map(|filter| parse_filter(filter))
+            .collect::<CsvCliResult<Vec<Expr>>>()?;
+        if let Some(filter) = any_filters
+            .into_iter()
+            .reduce(|a, b| Expr::Binary(Box::new(a), BinaryOp::Or, Box::new(b)))
+        {
+            agg = agg.with_filter(filter);
+        }
+        for spec in &self.regex_filters {
+            agg = agg.with_filter(get_regex_filter(spec, settings, headers)?);
+        }
+        for spec in &self.numeric_filters {
+            agg = agg.with_filter(get_numeric_filter(spec, settings, headers)?);
+        }
+        if let Some(filter) = self.get_date_filter(settings, headers)? {
+            agg = agg.with_filter(filter);
+        }
+        if let Some(num_rows) = self.top_rows {
+            agg = agg.with_top_rows(num_rows, self.other_label());
+        }
+        if let Some((min_count, as_other)) = self.min_count {
+            let label = if as_other {
+                Some(self.other_label())
+            } else {
+                None
+            };
+            agg = agg.with_min_count(min_count, label);
+        }
+        for transform in &self.cell_transforms {
+            agg = agg.with_cell_transform(transform.clone());
+        }
+        if let Some(locale) = self.out_locale {
+            agg = agg.with_out_locale(locale);
+        }
+        if let Some(having) = &self.having {
+            agg = agg.with_having(parse_having(function, having)?);
+        }
+        if let Some(policy) = self.filter_error_policy {
+            agg = agg.with_filter_error_policy(policy);
+        }
+        if let Some(policy) = self.nan_policy {
+            agg = agg.with_nan_policy(policy);
+        }
+        if let Some(max) = self.max_bad_records {
+            agg = agg.with_max_bad_records(max);
+        }
+        if let Some(path) = &self.bad_records {
+            agg = agg.with_rejects(RejectWriter::new(Box::new(fs::File::create(path)?)));
+        }
+        if let Some(limit) = self.limit {
+            agg = agg.with_limit(limit);
+        }
+        if let Some(groups) = self.group_hint {
+            agg = agg.with_group_hint(groups);
+        }
+        if let Some(max_cells) = self.max_cells {
+            agg = agg.with_max_cells(max_cells);
+        }
+        if let Some(bytes) = self.max_memory {
+            agg = agg.with_max_memory(bytes);
+        }
+        if let Some(sample) = self.sample {
+            agg = agg.with_sample(sample);
+        }
+        if let Some(seed) = self.seed {
+            agg = agg.with_seed(seed);
+        }
+        Ok(agg)
+    }
+
+    /// Builds the filter that only keeps the records whose date column is in the window
+    /// between `since` and `until`. (A date without a time in `until` includes the whole day.)
+    fn get_date_filter(
+        &self,
+        settings: &CsvSettings,
+        headers: &Vec<&str>,
+    ) -> CsvCliResult<Option<Expr>> {
+        let column = match &self.date_column {
+            Some(column) if self.since.is_some() || self.until.is_some() => column,
+            _ => return Ok(None),
+        };
+        let formats = self.key_date_formats();
+        let column = settings.get_field_index(column, headers)?;
+        let parse_bound = |bound: &str, option: &str| {
+            parse_date(bound.trim(), &formats).ok_or_else(|| {
+                CsvCliError::InvalidConfiguration(format!(
+                    "Could not parse `{}` as a date for {}",
+                    bound, option
+                ))
+            })
+        };
+        let date = || {
+            Box::new(Expr::ParseDate(
+                Box::new(Expr::Column(column)),
+                formats.clone(),
+            ))
+        };
+        let mut conditions = Vec::new();
+        if let Some(since) = &self.since {
+            let bound = Value::Date(parse_bound(since, "--since")?);
+            conditions.push(Expr::Binary(
+                date(),
+                BinaryOp::Ge,
+                Box::new(Expr::Literal(bound)),
+            ));
+        }
+        if let Some(until) = &self.until {
+            let bound = parse_bound(until, "--until")?;
+            let condition = if until.contains(':') {
+                Expr::Binary(
+                    date(),
+                    BinaryOp::Le,
+                    Box::new(Expr::Literal(Value::Date(bound))),
+                )
+            } else {
+                let next_day = Value::Date(bound + chrono::Duration::days(1));
+                Expr::Binary(date(), BinaryOp::Lt, Box::new(Expr::Literal(next_day)))
+            };
+            conditions.push(condition);
+        }
+        Ok(conditions
+            .into_iter()
+            .reduce(|a, b| Expr::Binary(Box::new(a), BinaryOp::And, Box::new(b))))
+    }
+}
+
+/// Selects the columns named by `names`, followed by the ones whose names match one of the
+/// regular expressions in `patterns`
+fn get_selection(
+    names: &[String],
+    patterns: &[String],
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<Vec<KeyField>> {
+    let str_names = names.iter().map(String::as_str).collect();
+    let mut selection = settings.get_key_fields(&str_names, headers)?;
+    for pattern in patterns {
+        for idx in settings.get_regex_indexes(pattern, headers)? {
+            let field = KeyField::column(idx);
+            if !selection.contains(&field) {
+                selection.push(field);
+            }
+        }
+    }
+    Ok(selection)
+}
+
+/// Splits an argument like `column:spec` into the source of the field and the rest of the argument
+fn parse_field_spec<'a>(
+    spec: &'a str,
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<(KeySource, &'a str)> {
+    match spec.find(':') {
+        Some(pos) if spec[..pos].trim() == RECORD_NUMBER_FIELD => {
+            Ok((KeySource::RecordNumber, &spec[pos + 1..]))
+        }
+        Some(pos) => Ok((
+            KeySource::Column(settings.get_field_index(&spec[..pos], headers)?),
+            &spec[pos + 1..],
+        )),
+        None => Err(CsvCliError::InvalidConfiguration(format!(
+            "Expected `{}` to look like column:value",
+            spec
+        ))),
+    }
+}
+
+/// Gets the fields derived from a regex capture of a column (like `--rows-extract`)
+fn get_extract_fields(
+    specs: &[String],
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<Vec<KeyField>> {
+    let mut fields = Vec::new();
+    for spec in specs {
+        let (source, pattern) = parse_field_spec(spec, settings, headers)?;
+        let re = Regex::new(pattern).map_err(|e| {
+            CsvCliError::InvalidConfiguration(format!(
+                "Could not parse the regular expression `{}`: {}",
+                pattern, e
+            ))
+        })?;
+        fields.push(KeyField::from(source).with_transform(KeyTransform::Extract(re)));
+    }
+    Ok(fields)
+}
+
+/// Gets the fields computed from an expression (like `--rows-expr`)
+fn get_expr_fields(
+    exprs: &[String],
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<Vec<KeyField>> {
+    exprs
+        .iter()
+        .map(|text| {
+            let expr = Expr::parse(text, |name| settings.get_field_index(name, headers))?;
+            Ok(KeyField::from(KeySource::Expression(expr, text.clone())))
+        })
+        .collect()
+}
+
+/// Parses a time series (like `order_date:month`) into a column field holding the dates in a
+/// column, truncated to a period
+fn get_timeseries_field(
+    spec: &str,
+    formats: Vec<String>,
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<KeyField> {
+    let (source, part) = parse_field_spec(spec, settings, headers)?;
+    let part: DatePart = part.parse().map_err(CsvCliError::InvalidConfiguration)?;
+    if part == DatePart::Weekday {
+        return Err(CsvCliError::InvalidConfiguration(
+            "--timeseries needs a period that sorts chronologically (year, month, week, day, or hour), not weekday"
+                .to_string(),
+        ));
+    }
+    // a value that isn't a date would get sorted among the periods as a column of its own
+    let transform = KeyTransform::DatePart(part, formats, NonDatePolicy::Fail);
+    Ok(KeyField::from(source).with_transform(transform))
+}
+
+/// Parses a regex filter (like `product:^Widget`)
+fn get_regex_filter(spec: &str, settings: &CsvSettings, headers: &Vec<&str>) -> CsvCliResult<Expr> {
+    let (source, pattern) = parse_field_spec(spec, settings, headers)?;
+    let idx = match source {
+        KeySource::Column(idx) => idx,
+        _ => {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` can only filter on the columns of the file",
+                spec
+            )))
+        }
+    };
+    let re = Regex::new(pattern).map_err(|e| {
+        CsvCliError::InvalidConfiguration(format!(
+            "Could not parse the regular expression `{}`: {}",
+            pattern, e
+        ))
+    })?;
+    Ok(Expr::Matches(Box::new(Expr::Column(idx)), Pattern(re)))
+}
+
+/// Parses a numeric comparison (like `amount>=1000`)
+fn get_numeric_filter(
+    spec: &str,
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+) -> CsvCliResult<Expr> {
+    lazy_static! {
+        static ref NUMERIC_PREDICATE: Regex =
+            Regex::new(r"^\s*(.+?)\s*(>=|<=|==|!=|=|>|<)\s*(\S+)\s*$").unwrap();
+    }
+    let invalid = || {
+        CsvCliError::InvalidConfiguration(format!(
+            "Expected `{}` to look like column>=number (using one of >, >=, <, <=, =, or !=)",
+            spec
+        ))
+    };
+    let caps = NUMERIC_PREDICATE.captures(spec).ok_or_else(invalid)?;
+    let column = settings.get_field_index(&caps[1], headers)?;
+    let op = match &caps[2] {
+        ">=" => BinaryOp::Ge,
+        "<=" => BinaryOp::Le,
+        ">" => BinaryOp::Gt,
+        "<" => BinaryOp::Lt,
+        "!=" => BinaryOp::Ne,
+        _ => BinaryOp::Eq,
+    };
+    let num = caps[3].parse::<DecimalWrapper>().map_err(|_| invalid())?;
+    Ok(Expr::Binary(
+        Box::new(Expr::Column(column)),
+        op,
+        Box::new(Expr::Literal(Value::Number(num.item))),
+    ))
+}
+
+/// Adds a transformation to the row and column fields reading from `target`
+/// (or to all of them, if there isn't a target), returning an error if none of them do.
+fn transform_fields(
+    fields: &mut [&mut Vec<KeyField>],
+    target: Option<&KeySource>,
+    transform: &KeyTransform,
+    spec: &str,
+) -> CsvCliResult<()> {
+    let mut found = false;
+    for field in fields.iter_mut().flat_map(|f| f.iter_mut()) {
+        if target.map_or(true, |source| field.source() == source) {
+            *field = field.clone().with_transform(transform.clone());
+            found = true;
+        }
+    }
+    if !found {
+        return Err(CsvCliError::InvalidConfiguration(format!(
+            "`{}` doesn't refer to one of the row or column fields",
+            spec
+        )));
+    }
+    Ok(())
+}
+
+/// Applies the cleanup steps of `spec` (like `trim,lower`) to the row and column fields
+fn add_normalization(spec: &str, fields: &mut [&mut Vec<KeyField>]) -> CsvCliResult<()> {
+    let steps = spec
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<Vec<Normalization>, _>>()
+        .map_err(CsvCliError::InvalidConfiguration)?;
+    if fields.iter().all(|f| f.is_empty()) {
+        return Ok(());
+    }
+    transform_fields(fields, None, &KeyTransform::Normalize(steps), spec)
+}
+
+/// Applies a date period (like `month`, or `order_date:month` for a single field) to the row
+/// and column fields
+fn add_date_part(
+    spec: &str,
+    formats: &[String],
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+    fields: &mut [&mut Vec<KeyField>],
+) -> CsvCliResult<()> {
+    let (target, part) = if spec.contains(':') {
+        let (source, part) = parse_field_spec(spec, settings, headers)?;
+        (Some(source), part)
+    } else {
+        (None, spec)
+    };
+    let part: DatePart = part.parse().map_err(CsvCliError::InvalidConfiguration)?;
+    // without a field, the periods apply to every field, including the ones that aren't dates
+    let policy = match target {
+        Some(_) => NonDatePolicy::Fail,
+        None => NonDatePolicy::Keep,
+    };
+    let transform = KeyTransform::DatePart(part, formats.to_vec(), policy);
+    transform_fields(fields, target.as_ref(), &transform, spec)
+}
+
+/// Applies buckets (like `salary:0,50000,100000`) to the row and column fields
+fn add_bins(
+    spec: &str,
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+    fields: &mut [&mut Vec<KeyField>],
+) -> CsvCliResult<()> {
+    let (source, bins) = parse_field_spec(spec, settings, headers)?;
+    let bins: Bins = bins.parse().map_err(CsvCliError::InvalidConfiguration)?;
+    transform_fields(fields, Some(&source), &KeyTransform::Bin(bins), spec)
+}
+
+/// Reads a lookup table, mapping the values in the table's first column to the labels in its second
+fn read_lookup_table(path: &str) -> CsvCliResult<HashMap<String, String>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut lookup = HashMap::new();
+    for result in rdr.records() {
+        let record = result?;
+        match (record.get(0), record.get(1)) {
+            (Some(raw), Some(label)) => {
+                lookup.insert(raw.to_string(), label.to_string());
+            }
+            _ => {
+                return Err(CsvCliError::InvalidConfiguration(format!(
+                    "The lookup table `{}` needs at least two columns (the original values and their replacements)",
+                    path
+                )))
+            }
+        }
+    }
+    Ok(lookup)
+}
+
+/// Applies a lookup table to the row and column fields
+fn add_recode(
+    spec: &str,
+    policy: &UnmappedPolicy,
+    settings: &CsvSettings,
+    headers: &Vec<&str>,
+    fields: &mut [&mut Vec<KeyField>],
+) -> CsvCliResult<()> {
+    // a path that exists applies to every field; otherwise, it should look like column:path
+    let (target, path) = if Path::new(spec).exists() || !spec.contains(':') {
+        (None, spec)
+    } else {
+        let (source, path) = parse_field_spec(spec, settings, headers)?;
+        (Some(source), path)
+    };
+    let transform = KeyTransform::Recode(read_lookup_table(path)?, policy.clone());
+    transform_fields(fields, target.as_ref(), &transform, spec)
+}
+
+/// Parses a `having` condition, where `value` (or the name of the aggregation function)
+/// refers to the aggregate of a row and `count` refers to its number of records
+fn parse_having(function: Agg, having: &str) -> CsvCliResult<Expr> {
+    let aggfunc = function.to_string();
+    Expr::parse(having, |name| match name {
+        "value" => Ok(HAVING_VALUE),
+        name if name == aggfunc => Ok(HAVING_VALUE),
+        "count" => Ok(HAVING_COUNT),
+        _ => Err(CsvCliError::InvalidConfiguration(format!(
+            "--having conditions can only refer to `{}` (or `value`) and `count`, not `{}`",
+            aggfunc, name
+        ))),
+    })
+}
@@ -0,0 +1,269 @@
+//! `Serialize` and `Deserialize` for the description of a pivot table, with the `serde`
+//! feature, so a `PivotBuilder` can be saved (as JSON or TOML, say) and read back in.
+//!
+//! A `PivotBuilder` becomes a map whose keys are the names of its methods: `aggregate`,
+//! `rows`, `columns`, `values`, `skip_empty`, `row_order`, `column_order`, `threads`,
+//! `delimiter` (as a one-character string), `has_headers`, and `strict_headers`. The value type is split into `value_type` (`text`,
+//! `numbers`, or `dates`) and `date_format`. Functions and orders are written the way they are
+//! on the command line (like `countunique` or `by-total-desc`), with a custom order written as
+//! a list of values. Any key that's left out gets its default, like it would with `new`.
+//!
+//! The rest of the builder's methods (filters, cell transforms, and so on) don't have keys yet,
+//! so a builder that uses any of them can't be serialized, rather than losing them on the way.
+use super::{Agg, Options, PivotBuilder, ValueType};
+use crate::aggregation::OutputOrder;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, Serializer};
+use std::fmt;
+
+const FIELDS: &[&str] = &[
+    "aggregate",
+    "rows",
+    "columns",
+    "values",
+    "value_type",
+    "date_format",
+    "skip_empty",
+    "row_order",
+    "column_order",
+    "threads",
+    "delimiter",
+    "has_headers",
+    "strict_headers",
+];
+
+const VALUE_TYPES: &[&str] = &["text", "numbers", "dates"];
+
+impl Serialize for Agg {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Agg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Agg, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for OutputOrder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            OutputOrder::IndexOrder => serializer.serialize_str("input"),
+            OutputOrder::Ascending => serializer.serialize_str("asc"),
+            OutputOrder::Descending => serializer.serialize_str("desc"),
+            OutputOrder::ByTotalAscending => serializer.serialize_str("by-total-asc"),
+            OutputOrder::ByTotalDescending => serializer.serialize_str("by-total-desc"),
+            OutputOrder::Custom(order) => order.serialize(serializer),
+        }
+    }
+}
+
+struct OrderVisitor;
+
+impl<'de> Visitor<'de> for OrderVisitor {
+    type Value = OutputOrder;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an order (`input`, `asc`, `desc`, `by-total-asc`, or `by-total-desc`) or a list of values")
+    }
+
+    fn visit_str<E: de::Error>(self, order: &str) -> Result<OutputOrder, E> {
+        match order {
+            "input" => Ok(OutputOrder::IndexOrder),
+            "asc" => Ok(OutputOrder::Ascending),
+            "desc" => Ok(OutputOrder::Descending),
+            "by-total-asc" => Ok(OutputOrder::ByTotalAscending),
+            "by-total-desc" => Ok(OutputOrder::ByTotalDescending),
+            _ => Err(E::invalid_value(Unexpected::Str(order), &self)),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OutputOrder, A::Error> {
+        let mut order = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            order.push(value);
+        }
+        Ok(OutputOrder::Custom(order))
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputOrder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<OutputOrder, D::Error> {
+        deserializer.deserialize_any(OrderVisitor)
+    }
+}
+
+impl Serialize for PivotBuilder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.options != Options::default() {
+            return Err(ser::Error::custom(format!(
+                "only the {} of a pivot table can be saved",
+                FIELDS.join(", ")
+            )));
+        }
+        // a byte past ASCII would turn into a character that takes up more than one byte
+        if !self.delimiter.is_ascii() {
+            return Err(ser::Error::custom(
+                "only a pivot table with an ASCII delimiter can be saved",
+            ));
+        }
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(function) = &self.function {
+            map.serialize_entry("aggregate", function)?;
+        }
+        map.serialize_entry("rows", &self.rows)?;
+        map.serialize_entry("columns", &self.columns)?;
+        map.serialize_entry("values", &self.values)?;
+        match &self.value_type {
+            Some(ValueType::Text) => map.serialize_entry("value_type", "text")?,
+            Some(ValueType::Numbers) => map.serialize_entry("value_type", "numbers")?,
+            Some(ValueType::Dates(format)) => {
+                map.serialize_entry("value_type", "dates")?;
+                map.serialize_entry("date_format", format)?;
+            }
+            None => {}
+        }
+        map.serialize_entry("skip_empty", &self.skip_empty)?;
+        map.serialize_entry("row_order", &self.row_order)?;
+        map.serialize_entry("column_order", &self.column_order)?;
+        map.serialize_entry("threads", &self.threads)?;
+        map.serialize_entry("delimiter", &char::from(self.delimiter).to_string())?;
+        map.serialize_entry("has_headers", &self.has_headers)?;
+        map.serialize_entry("strict_headers", &self.strict_headers)?;
+        map.end()
+    }
+}
+
+struct BuilderVisitor;
+
+impl<'de> Visitor<'de> for BuilderVisitor {
+    type Value = PivotBuilder;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the description of a pivot table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PivotBuilder, A::Error> {
+        let mut builder = PivotBuilder::new();
+        let mut value_type: Option<String> = None;
+        let mut date_format: Option<String> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "aggregate" => builder.function = Some(map.next_value()?),
+                "rows" => builder.rows = map.next_value()?,
+                "columns" => builder.columns = map.next_value()?,
+                "values" => builder.values = map.next_value()?,
+                "value_type" => value_type = Some(map.next_value()?),
+                "date_format" => date_format = Some(map.next_value()?),
+                "skip_empty" => builder.skip_empty = map.next_value()?,
+                "row_order" => builder.row_order = map.next_value()?,
+                "column_order" => builder.column_order = map.next_value()?,
+                "threads" => builder = builder.threads(map.next_value()?),
+                "delimiter" => {
+                    let delimiter: String = map.next_value()?;
+                    builder.delimiter = match delimiter.as_bytes() {
+                        [byte] => *byte,
+                        _ => {
+                            return Err(de::Error::invalid_value(
+                                Unexpected::Str(&delimiter),
+                                &"a single ASCII character",
+                            ))
+                        }
+                    };
+                }
+                "has_headers" => builder.has_headers = map.next_value()?,
+                "strict_headers" => builder.strict_headers = map.next_value()?,
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+            }
+        }
+        builder.value_type = match (value_type.as_deref(), date_format) {
+            (None, None) => None,
+            (Some("text"), None) => Some(ValueType::Text),
+            (Some("numbers"), None) => Some(ValueType::Numbers),
+            (Some("dates"), Some(format)) | (None, Some(format)) => Some(ValueType::Dates(format)),
+            (Some("dates"), None) => return Err(de::Error::missing_field("date_format")),
+            (Some(value_type @ ("text" | "numbers")), Some(_)) => {
+                return Err(de::Error::custom(format!(
+                    "a `date_format` can't go with the value type `{}`",
+                    value_type
+                )))
+            }
+            (Some(value_type), _) => {
+                return Err(de::Error::unknown_variant(value_type, VALUE_TYPES))
+            }
+        };
+        Ok(builder)
+    }
+}
+
+impl<'de> Deserialize<'de> for PivotBuilder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PivotBuilder, D::Error> {
+        deserializer.deserialize_map(BuilderVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let builder = PivotBuilder::new()
+            .rows("region")
+            .columns("date")
+            .values("amount")
+            .aggregate(Agg::Range)
+            .value_type(ValueType::Dates("%Y-%m-%d".to_string()))
+            .row_order(OutputOrder::Custom(vec!["west".to_string()]))
+            .column_order(OutputOrder::ByTotalDescending)
+            .delimiter(b'\t')
+            .has_headers(false)
+            .strict_headers(true);
+        let json = serde_json::to_string(&builder).unwrap();
+        assert_eq!(
+            serde_json::from_str::<PivotBuilder>(&json).unwrap(),
+            builder
+        );
+        assert_eq!(
+            serde_json::from_str::<PivotBuilder>("{}").unwrap(),
+            PivotBuilder::new()
+        );
+        assert_eq!(
+            serde_json::from_str::<PivotBuilder>(r#"{"aggregate": "sum", "row_order": "desc"}"#)
+                .unwrap(),
+            PivotBuilder::new()
+                .aggregate(Agg::Sum)
+                .row_order(OutputOrder::Descending)
+        );
+    }
+
+    #[test]
+    fn test_unsaved_options() {
+        let builder = PivotBuilder::new().aggregate(Agg::Count).totals(true);
+        assert!(serde_json::to_string(&builder).is_err());
+        let builder = PivotBuilder::new().aggregate(Agg::Count).delimiter(0xa7);
+        assert!(serde_json::to_string(&builder).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for json in &[
+            r#"{"aggregate": "average"}"#,
+            r#"{"rows": "region"}"#,
+            r#"{"delimiter": ";;"}"#,
+            r#"{"row_order": "sideways"}"#,
+            r#"{"value_type": "dates"}"#,
+            r#"{"value_type": "numbers", "date_format": "%Y"}"#,
+            r#"{"value_type": "bytes"}"#,
+            r#"{"color": "blue"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<PivotBuilder>(json).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! The module for drawing a progress bar while a large file gets aggregated.
+//!
+//! The progress bar is drawn on standard error (so it never ends up in the pivot table) and
+//! shows how many bytes of the file have been read, how many records are being read each
+//! second, and roughly how long it will take to read the rest of the file. It gets redrawn
+//! at most a few times a second, and it's erased once the whole file has been read. If the file
+//! has an index (so the number of records is known ahead of time), it counts records instead.
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// The number of characters in the bar itself
+const BAR_WIDTH: usize = 30;
+/// How long to wait between redrawing the progress bar
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A progress bar for reading a file of a known size
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressBar {
+    /// The size of the file, in bytes
+    total_bytes: u64,
+    /// The number of records in the file, if it's known
+    total_records: Option<usize>,
+    start: Instant,
+    last_drawn: Option<Instant>,
+    /// The length of the last line that was drawn, so it can be erased
+    last_len: usize,
+}
+
+impl ProgressBar {
+    /// Starts a progress bar for a file with `total_bytes` bytes
+    pub fn new(total_bytes: u64) -> ProgressBar {
+        ProgressBar {
+            total_bytes,
+            total_records: None,
+            start: Instant::now(),
+            last_drawn: None,
+            last_len: 0,
+        }
+    }
+
+    /// Measures the progress by the number of records that have been read, out of
+    /// `total_records`, rather than the number of bytes
+    pub fn set_total_records(&mut self, total_records: usize) {
+        self.total_records = Some(total_records);
+    }
+
+    /// Redraws the progress bar after `bytes` bytes and `records` records have been read,
+    /// unless it was redrawn very recently
+    pub fn update(&mut self, bytes: u64, records: usize) {
+        let now = Instant::now();
+        if self
+            .last_drawn
+            .is_some_and(|drawn| now.duration_since(drawn) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_drawn = Some(now);
+        let line = self.render(bytes, records, now.duration_since(self.start));
+        // pads the line with spaces, in case the last line was longer
+        let padding = " ".repeat(self.last_len.saturating_sub(line.len()));
+        self.last_len = line.len();
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r{}{}", line, padding);
+        let _ = stderr.flush();
+    }
+
+    /// Erases the progress bar
+    pub fn finish(&mut self) {
+        if self.last_drawn.is_some() {
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r{}\r", " ".repeat(self.last_len));
+            let _ = stderr.flush();
+        }
+    }
+
+    /// The text of the progress bar, after `bytes` bytes and `records` records
+    /// have been read in `elapsed` time
+    fn render(&self, bytes: u64, records: usize, elapsed: Duration) -> String {
+        let (done, total) = match self.total_records {
+            Some(total_records) => (records as u64, total_records as u64),
+            None => (bytes, self.total_bytes),
+        };
+        let fraction = if total == 0 {
+            1.0
+        } else {
+            (done as f64 / total as f64).min(1.0)
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let bar = match filled {
+            0 => " ".repeat(BAR_WIDTH),
+            _ if filled >= BAR_WIDTH => "=".repeat(BAR_WIDTH),
+            _ => format!(
+                "{}>{}",
+                "=".repeat(filled - 1),
+                " ".repeat(BAR_WIDTH - filled)
+            ),
+        };
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0.0 {
+            records as f64 / seconds
+        } else {
+            0.0
+        };
+        let eta = if done > 0 && fraction < 1.0 {
+            let remaining = seconds * (1.0 - fraction) / fraction;
+            format_seconds(remaining as u64)
+        } else {
+            "--".to_string()
+        };
+        let amount = match self.total_records {
+            Some(total_records) => format!("{}/{} records", records, total_records),
+            None => format!("{}/{}", format_bytes(bytes), format_bytes(self.total_bytes)),
+        };
+        format!(
+            "[{}] {:>3}% {} {:.0} records/s ETA {}",
+            bar,
+            (fraction * 100.0) as u32,
+            amount,
+            rate,
+            eta
+        )
+    }
+}
+
+/// Formats a number of bytes in the largest unit that keeps it above 1
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Formats a number of seconds like `1h 02m 03s`, leaving out the hours and minutes if they're 0
+fn format_seconds(seconds: u64) -> String {
+    match (seconds / 3600, (seconds % 3600) / 60, seconds % 60) {
+        (0, 0, secs) => format!("{}s", secs),
+        (0, mins, secs) => format!("{}m {:02}s", mins, secs),
+        (hours, mins, secs) => format!("{}h {:02}m {:02}s", hours, mins, secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn test_format_seconds() {
+        assert_eq!(format_seconds(42), "42s");
+        assert_eq!(format_seconds(125), "2m 05s");
+        assert_eq!(format_seconds(3723), "1h 02m 03s");
+    }
+
+    #[test]
+    fn test_render() {
+        let bar = ProgressBar::new(2048);
+        assert_eq!(
+            bar.render(1024, 500, Duration::from_secs(10)),
+            format!(
+                "[{}>{}]  50% 1.0 KB/2.0 KB 50 records/s ETA 10s",
+                "=".repeat(14),
+                " ".repeat(15)
+            )
+        );
+        assert_eq!(
+            bar.render(0, 0, Duration::from_secs(0)),
+            format!("[{}]   0% 0 B/2.0 KB 0 records/s ETA --", " ".repeat(30))
+        );
+        assert!(bar
+            .render(2048, 1000, Duration::from_secs(1))
+            .starts_with(&format!("[{}] 100%", "=".repeat(30))));
+        let mut by_records = ProgressBar::new(2048);
+        by_records.set_total_records(2000);
+        assert_eq!(
+            by_records.render(0, 500, Duration::from_secs(10)),
+            format!(
+                "[{}>{}]  25% 500/2000 records 50 records/s ETA 30s",
+                "=".repeat(6),
+                " ".repeat(23)
+            )
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! The module for reading individual fields out of a record.
+//!
+//! The aggregator reads each record as raw bytes (a `csv::ByteRecord`) rather than as text,
+//! and only checks that a field is valid UTF-8 once it actually gets used (as part of a key, as
+//! a value, or in an expression). In a wide file where a pivot table only uses a few of the
+//! columns, that skips validating (and allocating) every other field of every record, and it means
+//! invalid UTF-8 in a column that isn't used doesn't stop the file from getting aggregated.
+//! Summary records (for `--having`) are still `csv::StringRecord`s, so both kinds of records
+//! implement `Record`.
+
+/// A record whose fields can be read as text
+pub trait Record {
+    /// The field at position `idx`, or an error message if the record doesn't have that many
+    /// fields or the field isn't valid UTF-8
+    fn field(&self, idx: usize) -> Result<&str, String>;
+}
+
+impl Record for csv::StringRecord {
+    fn field(&self, idx: usize) -> Result<&str, String> {
+        self.get(idx)
+            .ok_or_else(|| format!("The record doesn't have a column {}", idx))
+    }
+}
+
+impl Record for csv::ByteRecord {
+    fn field(&self, idx: usize) -> Result<&str, String> {
+        let bytes = self
+            .get(idx)
+            .ok_or_else(|| format!("The record doesn't have a column {}", idx))?;
+        std::str::from_utf8(bytes).map_err(|err| {
+            format!(
+                "Column {} isn't valid UTF-8 (near byte {} of the field)",
+                idx,
+                err.valid_up_to()
+            )
+        })
+    }
+}
+
+/// The fields of a record joined by commas (replacing any invalid UTF-8), for error messages
+pub fn record_text(record: &csv::ByteRecord) -> String {
+    record
+        .iter()
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let record = csv::ByteRecord::from(vec![&b"CA"[..], &b"caf\xe9"[..]]);
+        assert_eq!(record.field(0), Ok("CA"));
+        assert_eq!(
+            record.field(1),
+            Err("Column 1 isn't valid UTF-8 (near byte 3 of the field)".to_string())
+        );
+        assert!(record.field(2).is_err());
+        assert_eq!(record_text(&record), "CA,caf\u{fffd}");
+        let text = csv::StringRecord::from(vec!["CA"]);
+        assert_eq!(text.field(0), Ok("CA"));
+        assert!(text.field(1).is_err());
+    }
+}
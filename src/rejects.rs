@@ -0,0 +1,110 @@
+//! The module for writing the records that get skipped with `--skip-bad-records` to a file of
+//! their own (with `--bad-records`), so they can be handed to whoever needs to fix them.
+//!
+//! Each record gets written with the same fields it was read with, followed by the reason it
+//! got skipped (which names the line it came from). The records get written as soon as they
+//! get skipped, so the file holds every bad record that was found even if the aggregation
+//! stops with an error. With several threads, that also means the records can come out of
+//! order.
+
+use crate::errors::CsvCliResult;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// The name of the column holding the reason each record got skipped
+pub const REASON_COLUMN: &str = "reason";
+
+/// A CSV file for the records that couldn't be parsed. Its clones all write to the same file,
+/// so each of an aggregator's threads can hold one.
+#[derive(Clone)]
+pub struct RejectWriter {
+    file: Arc<Mutex<RejectFile>>,
+}
+
+struct RejectFile {
+    wtr: csv::Writer<Box<dyn io::Write + Send>>,
+    wrote_headers: bool,
+}
+
+impl RejectWriter {
+    pub fn new(wtr: Box<dyn io::Write + Send>) -> Self {
+        // the records that have the wrong number of fields get written as they are
+        let wtr = csv::WriterBuilder::new().flexible(true).from_writer(wtr);
+        RejectWriter {
+            file: Arc::new(Mutex::new(RejectFile {
+                wtr,
+                wrote_headers: false,
+            })),
+        }
+    }
+
+    /// Writes the header row of the input, followed by the reason column. Only the first
+    /// header row gets written (so reading the input twice, or reading several files with the
+    /// same header row, doesn't write it again).
+    pub fn write_headers(&self, headers: &csv::ByteRecord) -> CsvCliResult<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.wrote_headers {
+            return Ok(());
+        }
+        file.wrote_headers = true;
+        file.write(headers, REASON_COLUMN)
+    }
+
+    /// Writes a record, followed by the reason it got skipped
+    pub fn write_record(&self, record: &csv::ByteRecord, reason: &str) -> CsvCliResult<()> {
+        let mut file = self.file.lock().unwrap();
+        // a header row can't come after the records
+        file.wrote_headers = true;
+        file.write(record, reason)
+    }
+}
+
+impl RejectFile {
+    fn write(&mut self, record: &csv::ByteRecord, last: &str) -> CsvCliResult<()> {
+        self.wtr
+            .write_record(record.iter().chain(std::iter::once(last.as_bytes())))?;
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RejectWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RejectWriter")
+    }
+}
+
+impl PartialEq for RejectWriter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reject_writer() {
+        let path = std::env::temp_dir().join("clipivot_reject_writer.csv");
+        let rejects = RejectWriter::new(Box::new(fs::File::create(&path).unwrap()));
+        let headers = csv::ByteRecord::from(vec!["region", "amount"]);
+        rejects.write_headers(&headers).unwrap();
+        // a clone writes to the same file
+        rejects
+            .clone()
+            .write_record(&csv::ByteRecord::from(vec!["East", "NA"]), "not a number")
+            .unwrap();
+        rejects.write_headers(&headers).unwrap();
+        rejects
+            .write_record(&csv::ByteRecord::from(vec!["West"]), "too few fields")
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "region,amount,reason\nEast,NA,not a number\nWest,too few fields\n"
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,288 @@
+//! The module for comparing the keys of rows and columns when they get sorted.
+//!
+//! Keys are compared field by field, using a `Comparator` to compare the values of each field.
+//! By default, values are compared alphabetically (so `10` comes before `9`), but they can
+//! also be compared as numbers or "naturally," comparing the runs of digits inside of them
+//! as numbers (so `item 9` comes before `item 10`), or chronologically, parsing them as dates
+//! or periods (so `Feb 2020` comes before `Jan 2021`).
+//!
+//! Tables with a lot of rows can sort their keys on several threads with `par_sort_by`, which
+//! sorts a run of the keys on each thread and then merges the runs.
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::keys::parse_period;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// How to compare the values of a row or column field
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Comparator {
+    /// Compares values as text, character by character
+    #[default]
+    Alphabetical,
+    /// Compares values as numbers, with any values that aren't numbers sorted
+    /// (alphabetically) after the numbers
+    Numeric,
+    /// Compares the runs of digits in values as numbers and everything else as text
+    Natural,
+    /// Compares values as dates or periods (like `2019-03`, `Mar 2019`, or `2019-W09`), with
+    /// any values that aren't dates sorted (alphabetically) after the dates
+    Chronological,
+}
+
+impl FromStr for Comparator {
+    type Err = CsvCliError;
+
+    fn from_str(s: &str) -> CsvCliResult<Comparator> {
+        match s {
+            "alphabetical" => Ok(Comparator::Alphabetical),
+            "numeric" => Ok(Comparator::Numeric),
+            "natural" => Ok(Comparator::Natural),
+            "chronological" => Ok(Comparator::Chronological),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` needs to be one of alphabetical, numeric, natural, or chronological",
+                s
+            ))),
+        }
+    }
+}
+
+impl Comparator {
+    /// Compares two values of a field
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Comparator::Alphabetical => a.cmp(b),
+            Comparator::Numeric => compare_numbers(a, b),
+            Comparator::Natural => compare_naturally(a, b),
+            Comparator::Chronological => compare_dates(a, b),
+        }
+    }
+
+    /// Compares two keys, one field at a time
+    pub fn compare_keys(self, a: &[String], b: &[String]) -> Ordering {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| self.compare(a, b))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    }
+}
+
+/// The fewest items that get sorted on several threads, since smaller sorts are quicker
+/// than starting the threads
+pub const PARALLEL_SORT_MIN: usize = 50_000;
+
+/// Sorts `items` on up to `threads` threads. Like `sort_by`, the sort is stable.
+pub fn par_sort_by<T, F>(items: &mut Vec<T>, threads: usize, compare: F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    if threads <= 1 || items.len() < PARALLEL_SORT_MIN {
+        items.sort_by(compare);
+        return;
+    }
+    let run_size = items.len().div_ceil(threads);
+    // splitting the runs off of the end means each item only gets moved once
+    let mut runs = Vec::with_capacity(threads);
+    while items.len() > run_size {
+        let start = (items.len() - 1) / run_size * run_size;
+        runs.push(items.split_off(start));
+    }
+    runs.push(std::mem::take(items));
+    runs.reverse();
+    let compare = &compare;
+    std::thread::scope(|scope| {
+        for run in runs.iter_mut() {
+            scope.spawn(move || run.sort_by(compare));
+        }
+    });
+    let mut runs: Vec<_> = runs.into_iter().map(Vec::into_iter).collect();
+    loop {
+        // on ties, the item from the earliest run comes first, which keeps the sort stable
+        let mut next: Option<usize> = None;
+        for idx in 0..runs.len() {
+            let item = match runs[idx].as_slice().first() {
+                Some(item) => item,
+                None => continue,
+            };
+            let is_smaller = match next {
+                Some(best) => compare(item, &runs[best].as_slice()[0]) == Ordering::Less,
+                None => true,
+            };
+            if is_smaller {
+                next = Some(idx);
+            }
+        }
+        match next {
+            Some(idx) => items.push(runs[idx].next().unwrap()),
+            None => return,
+        }
+    }
+}
+
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    match (Decimal::from_str(a.trim()), Decimal::from_str(b.trim())) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+fn compare_dates(a: &str, b: &str) -> Ordering {
+    match (parse_period(a), parse_period(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+fn compare_naturally(a: &str, b: &str) -> Ordering {
+    let (a_chunks, b_chunks) = (chunks(a), chunks(b));
+    for (a_chunk, b_chunk) in a_chunks.iter().zip(&b_chunks) {
+        let is_digits = |chunk: &str| chunk.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = if is_digits(a_chunk) && is_digits(b_chunk) {
+            // comparing the lengths first compares the digits as numbers, without overflowing
+            let (a_num, b_num) = (
+                a_chunk.trim_start_matches('0'),
+                b_chunk.trim_start_matches('0'),
+            );
+            a_num
+                .len()
+                .cmp(&b_num.len())
+                .then_with(|| a_num.cmp(b_num))
+                .then_with(|| a_chunk.len().cmp(&b_chunk.len()))
+        } else {
+            a_chunk.cmp(b_chunk)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a_chunks.len().cmp(&b_chunks.len())
+}
+
+/// Splits text into runs of digits and runs of everything else
+fn chunks(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let is_digit = c.is_ascii_digit();
+        if let Some(&(next_idx, next)) = chars.peek() {
+            if next.is_ascii_digit() != is_digit {
+                chunks.push(&text[start..next_idx]);
+                start = next_idx;
+            }
+        }
+    }
+    if start < text.len() {
+        chunks.push(&text[start..]);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(values: &[&str], comparator: Comparator) -> Vec<String> {
+        let mut values: Vec<String> = values.iter().map(|s| s.to_string()).collect();
+        values.sort_by(|a, b| comparator.compare(a, b));
+        values
+    }
+
+    #[test]
+    fn test_par_sort_by() {
+        let mut items: Vec<(u32, usize)> = (0..PARALLEL_SORT_MIN * 2 + 7)
+            .map(|idx| ((idx as u32).wrapping_mul(2_654_435_761) % 1000, idx))
+            .collect();
+        let mut expected = items.clone();
+        expected.sort_by_key(|item| item.0);
+        for threads in 1..=4 {
+            let mut sorted = items.clone();
+            par_sort_by(&mut sorted, threads, |a, b| a.0.cmp(&b.0));
+            // items with the same first field stay in the order they were in
+            assert_eq!(sorted, expected);
+        }
+        items.truncate(10);
+        par_sort_by(&mut items, 4, |a, b| b.0.cmp(&a.0));
+        assert!(items.windows(2).all(|pair| pair[0].0 >= pair[1].0));
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks("item10b2"), vec!["item", "10", "b", "2"]);
+        assert_eq!(chunks("42"), vec!["42"]);
+        assert!(chunks("").is_empty());
+    }
+
+    #[test]
+    fn test_comparators() {
+        let values = ["item 10", "item 9", "2", "10", "item 009", "-1.5"];
+        assert_eq!(
+            sorted(&values, Comparator::Alphabetical),
+            vec!["-1.5", "10", "2", "item 009", "item 10", "item 9"]
+        );
+        assert_eq!(
+            sorted(&values, Comparator::Numeric),
+            vec!["-1.5", "2", "10", "item 009", "item 10", "item 9"]
+        );
+        assert_eq!(
+            sorted(&values, Comparator::Natural),
+            vec!["-1.5", "2", "10", "item 9", "item 009", "item 10"]
+        );
+    }
+
+    #[test]
+    fn test_chronological() {
+        let values = [
+            "Feb 2020",
+            "2019-11",
+            "not a date",
+            "January 2020",
+            "2019",
+            "2020-01-15",
+        ];
+        assert_eq!(
+            sorted(&values, Comparator::Chronological),
+            vec![
+                "2019",
+                "2019-11",
+                "January 2020",
+                "2020-01-15",
+                "Feb 2020",
+                "not a date"
+            ]
+        );
+        assert_eq!(
+            sorted(
+                &["2020-W02", "2019-W52", "2020-W01"],
+                Comparator::Chronological
+            ),
+            vec!["2019-W52", "2020-W01", "2020-W02"]
+        );
+        assert_eq!(
+            sorted(
+                &["Wednesday", "Sunday", "Monday"],
+                Comparator::Chronological
+            ),
+            vec!["Monday", "Wednesday", "Sunday"]
+        );
+    }
+
+    #[test]
+    fn test_compare_keys() {
+        let key = |vals: &[&str]| vals.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            Comparator::Numeric.compare_keys(&key(&["a", "10"]), &key(&["a", "9"])),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Comparator::Alphabetical.compare_keys(&key(&["a", "10"]), &key(&["a", "9"])),
+            Ordering::Less
+        );
+    }
+}
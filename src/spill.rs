@@ -0,0 +1,428 @@
+//! The module for spilling the cells of an enormous pivot table to disk (with `--max-cells`).
+//!
+//! Normally, every cell of a pivot table stays in memory until the whole file has been read.
+//! That stops working once a table has hundreds of millions of (row, column) combinations.
+//! With a limit on the number of cells, the aggregator writes its cells to a temporary file
+//! (a *run*) whenever it reaches the limit, sorted by their rows, and starts over with an empty
+//! table. Once the file has been read, the runs get merged back together one row at a time
+//! (combining the parts of a cell that ended up in different runs), so the table can be
+//! written without ever holding more than one row of it in memory.
+//!
+//! To make that work, the state of each accumulator needs to be written to a run and read back
+//! out of it, which is what the `Spill` trait does. Runs are CSV files holding the row key,
+//! the column key, and the position of the values column of each cell, followed by its state.
+use crate::errors::{CsvCliError, CsvCliResult};
+use crate::parsing::DecimalWrapper;
+use crate::sorting::Comparator;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+/// The number of runs that have been created, so each of them gets its own file
+static NUM_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// A value (or the state of an accumulator) that can be written to a run and read back out
+pub trait Spill: Sized {
+    /// Adds the fields describing the value to the end of `fields`
+    fn save(&self, fields: &mut Vec<String>);
+    /// Reads a value written by `save`, taking the fields it needs from the front of `fields`
+    /// (or returns `None` if they don't describe a value)
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self>;
+}
+
+impl Spill for usize {
+    fn save(&self, fields: &mut Vec<String>) {
+        fields.push(self.to_string());
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        fields.next()?.parse().ok()
+    }
+}
+
+impl Spill for f64 {
+    fn save(&self, fields: &mut Vec<String>) {
+        // the shortest representation that parses back to the same number
+        fields.push(self.to_string());
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        fields.next()?.parse().ok()
+    }
+}
+
+impl Spill for String {
+    fn save(&self, fields: &mut Vec<String>) {
+        fields.push(self.clone());
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        fields.next().map(String::from)
+    }
+}
+
+impl Spill for DecimalWrapper {
+    fn save(&self, fields: &mut Vec<String>) {
+        // decimals keep their scale when they're written out, so this is exact
+        fields.push(self.item.to_string());
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        fields.next()?.parse().ok()
+    }
+}
+
+impl<I: Spill> Spill for Vec<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.len().save(fields);
+        for item in self {
+            item.save(fields);
+        }
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        let len = usize::load(fields)?;
+        (0..len).map(|_| I::load(fields)).collect()
+    }
+}
+
+impl<I: Spill + Eq + Hash> Spill for HashSet<I> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.len().save(fields);
+        for item in self {
+            item.save(fields);
+        }
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        let len = usize::load(fields)?;
+        (0..len).map(|_| I::load(fields)).collect()
+    }
+}
+
+impl<K: Spill + Eq + Hash, V: Spill> Spill for HashMap<K, V> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.len().save(fields);
+        for (key, value) in self {
+            key.save(fields);
+            value.save(fields);
+        }
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        let len = usize::load(fields)?;
+        (0..len)
+            .map(|_| Some((K::load(fields)?, V::load(fields)?)))
+            .collect()
+    }
+}
+
+impl<K: Spill + Ord, V: Spill> Spill for BTreeMap<K, V> {
+    fn save(&self, fields: &mut Vec<String>) {
+        self.len().save(fields);
+        for (key, value) in self {
+            key.save(fields);
+            value.save(fields);
+        }
+    }
+
+    fn load<'a, F: Iterator<Item = &'a str>>(fields: &mut F) -> Option<Self> {
+        let len = usize::load(fields)?;
+        (0..len)
+            .map(|_| Some((K::load(fields)?, V::load(fields)?)))
+            .collect()
+    }
+}
+
+/// The order the cells of a run are sorted in: by their rows (in the order they get written),
+/// then by their columns, then by their values columns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellOrder {
+    pub comparator: Comparator,
+    /// Whether the rows get written in descending order
+    pub descending: bool,
+}
+
+impl CellOrder {
+    /// Compares two cells, given their row keys, column keys, and values columns
+    pub fn compare(
+        self,
+        a: (&[String], &[String], usize),
+        b: (&[String], &[String], usize),
+    ) -> Ordering {
+        // breaking ties between keys the comparator sees as equal (like `1` and `1.0`)
+        // keeps different rows from getting mixed together
+        let rows = self
+            .comparator
+            .compare_keys(a.0, b.0)
+            .then_with(|| a.0.cmp(b.0));
+        let rows = if self.descending {
+            rows.reverse()
+        } else {
+            rows
+        };
+        rows.then_with(|| a.1.cmp(b.1)).then_with(|| a.2.cmp(&b.2))
+    }
+}
+
+/// A cell read back out of a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpilledCell<T> {
+    pub row: Vec<String>,
+    pub column: Vec<String>,
+    pub value_idx: usize,
+    pub state: T,
+}
+
+/// A temporary file holding the cells of a pivot table, which gets deleted once it's dropped
+#[derive(Debug, PartialEq)]
+pub struct SpillRun {
+    path: PathBuf,
+}
+
+impl SpillRun {
+    /// Writes a run holding `cells`, which need to be sorted in the order the runs get merged in
+    pub fn write<'a, T, C>(cells: C) -> CsvCliResult<SpillRun>
+    where
+        T: Spill + 'a,
+        C: IntoIterator<Item = (&'a [String], &'a [String], usize, &'a T)>,
+    {
+        let path = std::env::temp_dir().join(format!(
+            "clipivot-{}-{}.csv",
+            std::process::id(),
+            NUM_RUNS.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        let run = SpillRun { path };
+        let mut wtr = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(BufWriter::new(File::create(&run.path)?));
+        let mut fields = Vec::new();
+        for (row, column, value_idx, state) in cells {
+            fields.clear();
+            fields.extend(row.iter().cloned());
+            fields.extend(column.iter().cloned());
+            value_idx.save(&mut fields);
+            state.save(&mut fields);
+            wtr.write_record(&fields)?;
+        }
+        wtr.flush()?;
+        Ok(run)
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads the cells of a run, one at a time
+struct RunReader {
+    rdr: csv::Reader<File>,
+    record: csv::StringRecord,
+    /// The number of fields in the row keys and the column keys
+    widths: (usize, usize),
+}
+
+impl RunReader {
+    fn next_cell<T: Spill>(&mut self) -> CsvCliResult<Option<SpilledCell<T>>> {
+        if !self.rdr.read_record(&mut self.record)? {
+            return Ok(None);
+        }
+        let (row_width, column_width) = self.widths;
+        let mut fields = self.record.iter();
+        let row = fields.by_ref().take(row_width).map(String::from).collect();
+        let column = fields
+            .by_ref()
+            .take(column_width)
+            .map(String::from)
+            .collect();
+        let cell = usize::load(&mut fields).and_then(|value_idx| {
+            T::load(&mut fields).map(|state| SpilledCell {
+                row,
+                column,
+                value_idx,
+                state,
+            })
+        });
+        match cell {
+            Some(cell) => Ok(Some(cell)),
+            None => Err(CsvCliError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "A temporary file of spilled cells was corrupted",
+            ))),
+        }
+    }
+}
+
+/// The next cell of one of the runs, waiting to get merged
+struct Pending<T> {
+    cell: SpilledCell<T>,
+    run: usize,
+    order: CellOrder,
+}
+
+impl<T> Pending<T> {
+    fn key(&self) -> (&[String], &[String], usize) {
+        (&self.cell.row, &self.cell.column, self.cell.value_idx)
+    }
+}
+
+impl<T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Pending<T> {}
+
+impl<T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Pending<T> {
+    // reversed, so the heap gives back the first cell rather than the last one
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order
+            .compare(other.key(), self.key())
+            .then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+/// Merges the cells of several runs (each sorted in `order`), passing each cell to `emit`
+/// in `order`. The parts of a cell that ended up in different runs get combined with `combine`
+/// first, so every cell is passed to `emit` once.
+pub fn merge_runs<T, M, E>(
+    runs: &[SpillRun],
+    widths: (usize, usize),
+    order: CellOrder,
+    mut combine: M,
+    mut emit: E,
+) -> CsvCliResult<()>
+where
+    T: Spill,
+    M: FnMut(&mut T, T),
+    E: FnMut(SpilledCell<T>) -> CsvCliResult<()>,
+{
+    let mut readers = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (run, spilled) in runs.iter().enumerate() {
+        let mut reader = RunReader {
+            rdr: csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_path(&spilled.path)?,
+            record: csv::StringRecord::new(),
+            widths,
+        };
+        if let Some(cell) = reader.next_cell()? {
+            heap.push(Pending { cell, run, order });
+        }
+        readers.push(reader);
+    }
+    let mut current: Option<SpilledCell<T>> = None;
+    while let Some(Pending { cell, run, .. }) = heap.pop() {
+        if let Some(next) = readers[run].next_cell()? {
+            heap.push(Pending {
+                cell: next,
+                run,
+                order,
+            });
+        }
+        current = match current {
+            Some(mut merged)
+                if (&merged.row, &merged.column, merged.value_idx)
+                    == (&cell.row, &cell.column, cell.value_idx) =>
+            {
+                combine(&mut merged.state, cell.state);
+                Some(merged)
+            }
+            Some(done) => {
+                emit(done)?;
+                Some(cell)
+            }
+            None => Some(cell),
+        };
+    }
+    if let Some(done) = current {
+        emit(done)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Spill>(value: &T) -> Option<T> {
+        let mut fields = Vec::new();
+        value.save(&mut fields);
+        let mut iter = fields.iter().map(String::as_str);
+        let loaded = T::load(&mut iter);
+        assert!(iter.next().is_none());
+        loaded
+    }
+
+    #[test]
+    fn test_round_trips() {
+        assert_eq!(round_trip(&0.1f64), Some(0.1));
+        assert_eq!(
+            round_trip(&"a,\"b\"".to_string()),
+            Some("a,\"b\"".to_string())
+        );
+        let decimal: DecimalWrapper = "1.500".parse().unwrap();
+        assert_eq!(round_trip(&decimal).unwrap().item.to_string(), "1.500");
+        let set: HashSet<String> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(round_trip(&set), Some(set));
+        let mut map = BTreeMap::new();
+        map.insert(decimal, 3usize);
+        assert_eq!(round_trip(&map), Some(map));
+        assert_eq!(usize::load(&mut vec!["x"].into_iter()), None);
+    }
+
+    #[test]
+    fn test_merge_runs() {
+        let key = |s: &str| vec![s.to_string()];
+        let order = CellOrder {
+            comparator: Comparator::default(),
+            descending: false,
+        };
+        let (a, b, c, x) = (key("a"), key("b"), key("c"), key("x"));
+        let first = SpillRun::write(vec![
+            (&a[..], &x[..], 0, &1usize),
+            (&c[..], &x[..], 0, &2usize),
+        ])
+        .unwrap();
+        let second = SpillRun::write(vec![
+            (&a[..], &x[..], 0, &10usize),
+            (&b[..], &x[..], 0, &20usize),
+        ])
+        .unwrap();
+        let mut cells = Vec::new();
+        merge_runs(
+            &[first, second],
+            (1, 1),
+            order,
+            |total: &mut usize, part| *total += part,
+            |cell| {
+                cells.push((cell.row[0].clone(), cell.state));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            cells,
+            vec![
+                ("a".to_string(), 11),
+                ("b".to_string(), 20),
+                ("c".to_string(), 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! The module for writing a pivot table as an SQL query (for `--to-sql`), instead of computing it.
+//!
+//! The query groups the records by the row fields and turns every column of the pivot table into
+//! a conditional aggregation: `SUM("amount") FILTER (WHERE "year" = '2019')` in PostgreSQL, or
+//! `SUM(CASE WHEN "year" = '2019' THEN "amount" END)` in SQLite and MySQL, which don't support
+//! `FILTER`. Since SQL can't create columns from the values in a table, the columns of the pivot
+//! table get read from the input first, so the query only works on tables with the same values.
+use crate::aggregation::{join_key, OutputOrder, FIELD_SEPARATOR};
+use crate::errors::{CsvCliError, CsvCliResult};
+use std::str::FromStr;
+
+/// The dialect of SQL a query gets written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Postgres,
+    Sqlite,
+    Mysql,
+}
+
+impl FromStr for Dialect {
+    type Err = CsvCliError;
+
+    fn from_str(s: &str) -> CsvCliResult<Dialect> {
+        match s {
+            "postgres" => Ok(Dialect::Postgres),
+            "sqlite" => Ok(Dialect::Sqlite),
+            "mysql" => Ok(Dialect::Mysql),
+            _ => Err(CsvCliError::InvalidConfiguration(format!(
+                "`{}` isn't one of the SQL dialects (postgres, sqlite, or mysql)",
+                s
+            ))),
+        }
+    }
+}
+
+impl Dialect {
+    fn name(self) -> &'static str {
+        match self {
+            Dialect::Postgres => "PostgreSQL",
+            Dialect::Sqlite => "SQLite",
+            Dialect::Mysql => "MySQL",
+        }
+    }
+
+    /// Quotes the name of a table or column
+    fn ident(self, name: &str) -> String {
+        match self {
+            Dialect::Mysql => format!("`{}`", name.replace('`', "``")),
+            _ => format!("\"{}\"", name.replace('"', "\"\"")),
+        }
+    }
+
+    /// Quotes a string literal
+    fn literal(self, text: &str) -> String {
+        let text = text.replace('\'', "''");
+        match self {
+            // MySQL treats backslashes in strings as escapes by default
+            Dialect::Mysql => format!("'{}'", text.replace('\\', "\\\\")),
+            _ => format!("'{}'", text),
+        }
+    }
+}
+
+/// The shape of a pivot table, once its columns have been read from the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crosstab {
+    /// The names of the row fields
+    pub rows: Vec<String>,
+    /// The names of the column fields
+    pub columns: Vec<String>,
+    /// The key of each column of the pivot table, in order
+    pub column_keys: Vec<Vec<String>>,
+    /// The names of the values columns
+    pub values: Vec<String>,
+    /// Whether empty values get skipped (with `-e`), which only changes how `count` is written
+    pub skip_empty: bool,
+    /// The order of the rows
+    pub row_order: OutputOrder,
+}
+
+impl Crosstab {
+    /// Writes the query that computes the pivot table with the function `function` from the
+    /// table `table`
+    pub fn to_sql(&self, function: &str, table: &str, dialect: Dialect) -> CsvCliResult<String> {
+        let mut select: Vec<String> = self.rows.iter().map(|row| dialect.ident(row)).collect();
+        let num_values = self.values.len().max(1);
+        for key in &self.column_keys {
+            let label = join_key(key, FIELD_SEPARATOR);
+            let condition = self.condition(key, dialect);
+            for value_idx in 0..num_values {
+                let value = self.values.get(value_idx).map(|value| dialect.ident(value));
+                let expr = aggregate(
+                    function,
+                    value.as_deref(),
+                    condition.as_deref(),
+                    self.skip_empty,
+                    dialect,
+                )?;
+                let alias = match self.values.get(value_idx) {
+                    Some(name) if num_values > 1 && self.columns.is_empty() => name.clone(),
+                    Some(name) if num_values > 1 => format!("{}_{}", label, name),
+                    _ => label.clone(),
+                };
+                select.push(format!("{} AS {}", expr, dialect.ident(&alias)));
+            }
+        }
+        let mut query = format!(
+            "SELECT\n    {}\nFROM {}",
+            select.join(",\n    "),
+            dialect.ident(table)
+        );
+        if !self.rows.is_empty() {
+            let rows: Vec<String> = self.rows.iter().map(|row| dialect.ident(row)).collect();
+            query.push_str(&format!("\nGROUP BY {}", rows.join(", ")));
+            if let Some(order) = self.order_by(function, &rows, dialect)? {
+                query.push_str(&format!("\nORDER BY {}", order));
+            }
+        }
+        query.push_str(";\n");
+        Ok(query)
+    }
+
+    /// The condition a record needs to meet to be in the column with the key `key`, or `None`
+    /// if there aren't any column fields (and every record is in the one column)
+    fn condition(&self, key: &[String], dialect: Dialect) -> Option<String> {
+        if self.columns.is_empty() {
+            return None;
+        }
+        let conditions: Vec<String> = self
+            .columns
+            .iter()
+            .zip(key)
+            .map(|(column, value)| {
+                format!("{} = {}", dialect.ident(column), dialect.literal(value))
+            })
+            .collect();
+        Some(conditions.join(" AND "))
+    }
+
+    fn order_by(
+        &self,
+        function: &str,
+        rows: &[String],
+        dialect: Dialect,
+    ) -> CsvCliResult<Option<String>> {
+        let by_total = |direction: &str| -> CsvCliResult<Option<String>> {
+            let value = self.values.first().map(|value| dialect.ident(value));
+            let total = aggregate(function, value.as_deref(), None, self.skip_empty, dialect)?;
+            Ok(Some(format!("{} {}", total, direction)))
+        };
+        let sorted = |direction: &str| {
+            let rows: Vec<String> = rows
+                .iter()
+                .map(|row| format!("{} {}", row, direction))
+                .collect();
+            Ok(Some(rows.join(", ")))
+        };
+        match &self.row_order {
+            OutputOrder::IndexOrder => Ok(None),
+            OutputOrder::Ascending => sorted("ASC"),
+            OutputOrder::Descending => sorted("DESC"),
+            OutputOrder::ByTotalAscending => by_total("ASC"),
+            OutputOrder::ByTotalDescending => by_total("DESC"),
+            OutputOrder::Custom(_) => Err(CsvCliError::InvalidConfiguration(
+                "A custom order of the rows (like --row-order) can't be written as SQL".to_string(),
+            )),
+        }
+    }
+}
+
+/// The aggregation of `value` (a quoted column name, or `None` for `count` without a values
+/// column) over the records that meet `condition`
+fn aggregate(
+    function: &str,
+    value: Option<&str>,
+    condition: Option<&str>,
+    skip_empty: bool,
+    dialect: Dialect,
+) -> CsvCliResult<String> {
+    let unsupported = || {
+        Err(CsvCliError::InvalidConfiguration(format!(
+            "{} doesn't have an aggregate function for {}",
+            dialect.name(),
+            function
+        )))
+    };
+    let value = match (function, value) {
+        // every row counts, unless empty values get skipped (which SQL does with COUNT(column))
+        ("count", Some(value)) if skip_empty => value,
+        ("count", _) => "*",
+        (_, Some(value)) => value,
+        (_, None) => {
+            return Err(CsvCliError::InvalidConfiguration(format!(
+                "{} needs a values column",
+                function
+            )))
+        }
+    };
+    // PostgreSQL filters the records of any aggregate with FILTER, but the other dialects need
+    // the values outside of the column to be NULL
+    let (arg, filter) = match (dialect, condition) {
+        (_, None) => (value.to_string(), String::new()),
+        (Dialect::Postgres, Some(condition)) => {
+            (value.to_string(), format!(" FILTER (WHERE {})", condition))
+        }
+        (_, Some(condition)) => {
+            let then = if value == "*" { "1" } else { value };
+            (
+                format!("CASE WHEN {} THEN {} END", condition, then),
+                String::new(),
+            )
+        }
+    };
+    let call = |name: &str| format!("{}({}){}", name, arg, filter);
+    let expr = match (function, dialect) {
+        ("count", _) => call("COUNT"),
+        ("countunique", _) => format!("COUNT(DISTINCT {}){}", arg, filter),
+        ("max", _) => call("MAX"),
+        ("mean", _) => call("AVG"),
+        ("min", _) => call("MIN"),
+        ("sum", _) => call("SUM"),
+        ("range", _) => format!("{} - {}", call("MAX"), call("MIN")),
+        ("minmax", Dialect::Mysql) => format!("CONCAT({}, ' - ', {})", call("MIN"), call("MAX")),
+        ("minmax", _) => format!("{} || ' - ' || {}", call("MIN"), call("MAX")),
+        ("stddev", Dialect::Postgres) | ("stddev", Dialect::Mysql) => call("STDDEV_SAMP"),
+        ("median", Dialect::Postgres) => format!(
+            "PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY {}){}",
+            arg, filter
+        ),
+        ("mode", Dialect::Postgres) => format!("MODE() WITHIN GROUP (ORDER BY {}){}", arg, filter),
+        _ => return unsupported(),
+    };
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crosstab() -> Crosstab {
+        Crosstab {
+            rows: vec!["region".to_string()],
+            columns: vec!["year".to_string()],
+            column_keys: vec![vec!["2019".to_string()], vec!["it's".to_string()]],
+            values: vec!["amount".to_string()],
+            skip_empty: false,
+            row_order: OutputOrder::IndexOrder,
+        }
+    }
+
+    #[test]
+    fn test_to_sql() {
+        assert_eq!(
+            crosstab()
+                .to_sql("sum", "sales", Dialect::Postgres)
+                .unwrap(),
+            "SELECT\n    \"region\",\n    \
+             SUM(\"amount\") FILTER (WHERE \"year\" = '2019') AS \"2019\",\n    \
+             SUM(\"amount\") FILTER (WHERE \"year\" = 'it''s') AS \"it's\"\n\
+             FROM \"sales\"\nGROUP BY \"region\";\n"
+        );
+        let mut sorted = crosstab();
+        sorted.row_order = OutputOrder::ByTotalDescending;
+        sorted.column_keys.truncate(1);
+        assert_eq!(
+            sorted.to_sql("count", "sales", Dialect::Sqlite).unwrap(),
+            "SELECT\n    \"region\",\n    \
+             COUNT(CASE WHEN \"year\" = '2019' THEN 1 END) AS \"2019\"\n\
+             FROM \"sales\"\nGROUP BY \"region\"\nORDER BY COUNT(*) DESC;\n"
+        );
+        sorted.skip_empty = true;
+        sorted.row_order = OutputOrder::Ascending;
+        assert_eq!(
+            sorted.to_sql("count", "sales", Dialect::Mysql).unwrap(),
+            "SELECT\n    `region`,\n    \
+             COUNT(CASE WHEN `year` = '2019' THEN `amount` END) AS `2019`\n\
+             FROM `sales`\nGROUP BY `region`\nORDER BY `region` ASC;\n"
+        );
+    }
+
+    #[test]
+    fn test_without_fields() {
+        let total = Crosstab {
+            rows: Vec::new(),
+            columns: Vec::new(),
+            column_keys: vec![vec!["total".to_string()]],
+            values: vec!["amount".to_string(), "price".to_string()],
+            skip_empty: false,
+            row_order: OutputOrder::Ascending,
+        };
+        assert_eq!(
+            total.to_sql("minmax", "t", Dialect::Mysql).unwrap(),
+            "SELECT\n    \
+             CONCAT(MIN(`amount`), ' - ', MAX(`amount`)) AS `amount`,\n    \
+             CONCAT(MIN(`price`), ' - ', MAX(`price`)) AS `price`\nFROM `t`;\n"
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let expr =
+            |function, dialect| aggregate(function, Some("\"x\""), Some("c"), false, dialect);
+        assert_eq!(
+            expr("median", Dialect::Postgres).unwrap(),
+            "PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY \"x\") FILTER (WHERE c)"
+        );
+        assert_eq!(
+            expr("range", Dialect::Sqlite).unwrap(),
+            "MAX(CASE WHEN c THEN \"x\" END) - MIN(CASE WHEN c THEN \"x\" END)"
+        );
+        assert_eq!(
+            expr("countunique", Dialect::Postgres).unwrap(),
+            "COUNT(DISTINCT \"x\") FILTER (WHERE c)"
+        );
+        assert!(expr("median", Dialect::Sqlite).is_err());
+        assert!(expr("stddev", Dialect::Sqlite).is_err());
+        assert!(expr("mode", Dialect::Mysql).is_err());
+        assert!(aggregate("sum", None, None, false, Dialect::Postgres).is_err());
+        assert_eq!(Dialect::Mysql.literal("a\\'b"), "'a\\\\''b'");
+        assert_eq!(Dialect::Postgres.ident("a\"b"), "\"a\"\"b\"");
+        assert!("oracle".parse::<Dialect>().is_err());
+    }
+
+    #[test]
+    fn test_custom_row_order() {
+        let mut custom = crosstab();
+        custom.row_order = OutputOrder::Custom(vec!["west".to_string()]);
+        assert!(custom.to_sql("sum", "sales", Dialect::Postgres).is_err());
+    }
+}
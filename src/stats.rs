@@ -0,0 +1,205 @@
+//! The module for the association statistics `--chi2` writes about a table of counts.
+//!
+//! A crosstab can make it look like two fields are related (say, that one department lays off
+//! more of its employees than the others) when the difference could easily be chance. Pearson's
+//! chi-square test compares each cell of the table against the count it would have if the row
+//! and column fields had nothing to do with each other, and the p-value is the probability of a
+//! difference at least that large arising by chance. Cramér's V scales the statistic to between
+//! 0 (no association) and 1 (the row determines the column), so tables of different sizes can be
+//! compared.
+//!
+//! The test assumes each cell is expected to hold at least 5 records, so the summary also
+//! counts the cells that fall short of that, since the p-value isn't reliable when many do.
+use std::fmt;
+
+/// The count below which the expected count of a cell is too small for the test to be reliable
+pub const MIN_EXPECTED_COUNT: f64 = 5.;
+
+/// The results of a chi-square test of independence between the rows and columns of a table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChiSquare {
+    /// Pearson's chi-square statistic
+    pub statistic: f64,
+    /// The degrees of freedom of the test, (rows - 1) * (columns - 1)
+    pub degrees_of_freedom: usize,
+    /// The probability of a statistic at least this large if the rows and columns were independent
+    pub p_value: f64,
+    /// Cramér's V, between 0 and 1
+    pub cramers_v: f64,
+    /// The number of cells whose expected count is below `MIN_EXPECTED_COUNT`
+    pub small_cells: usize,
+    /// The number of cells in the table
+    pub cells: usize,
+}
+
+impl ChiSquare {
+    /// Runs the test on a table of counts, with a row of counts for each row of the table.
+    /// Rows and columns that don't have any records get left out, and there's no test (so this
+    /// returns `None`) unless at least two rows and two columns are left.
+    pub fn from_counts(counts: &[Vec<f64>]) -> Option<ChiSquare> {
+        let num_columns = counts.iter().map(Vec::len).max().unwrap_or(0);
+        let count = |row: &Vec<f64>, col: usize| row.get(col).copied().unwrap_or(0.);
+        let row_totals: Vec<f64> = counts.iter().map(|row| row.iter().sum()).collect();
+        let col_totals: Vec<f64> = (0..num_columns)
+            .map(|col| counts.iter().map(|row| count(row, col)).sum())
+            .collect();
+        let total: f64 = row_totals.iter().sum();
+        let rows: Vec<usize> = (0..counts.len()).filter(|&i| row_totals[i] > 0.).collect();
+        let columns: Vec<usize> = (0..num_columns).filter(|&i| col_totals[i] > 0.).collect();
+        if rows.len() < 2 || columns.len() < 2 {
+            return None;
+        }
+        let mut statistic = 0.;
+        let mut small_cells = 0;
+        for &row in &rows {
+            for &col in &columns {
+                let expected = row_totals[row] * col_totals[col] / total;
+                if expected < MIN_EXPECTED_COUNT {
+                    small_cells += 1;
+                }
+                statistic += (count(&counts[row], col) - expected).powi(2) / expected;
+            }
+        }
+        let degrees_of_freedom = (rows.len() - 1) * (columns.len() - 1);
+        let smaller_side = rows.len().min(columns.len()) - 1;
+        Some(ChiSquare {
+            statistic,
+            degrees_of_freedom,
+            p_value: upper_gamma(degrees_of_freedom as f64 / 2., statistic / 2.),
+            cramers_v: (statistic / (total * smaller_side as f64)).sqrt(),
+            small_cells,
+            cells: rows.len() * columns.len(),
+        })
+    }
+}
+
+impl fmt::Display for ChiSquare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p_value = if self.p_value < 0.0001 {
+            "< 0.0001".to_string()
+        } else {
+            format!("{:.4}", self.p_value)
+        };
+        write!(
+            f,
+            "Chi-square: {:.4} with {} degrees of freedom (p-value: {}), Cramér's V: {:.4}",
+            self.statistic, self.degrees_of_freedom, p_value, self.cramers_v
+        )
+    }
+}
+
+/// The regularized upper incomplete gamma function Q(a, x), which gives the p-value of a
+/// chi-square statistic `2x` with `2a` degrees of freedom. This uses the series for P(a, x)
+/// when `x` is small and a continued fraction for Q(a, x) otherwise, following Numerical Recipes.
+fn upper_gamma(a: f64, x: f64) -> f64 {
+    const EPSILON: f64 = 1e-15;
+    const MAX_ITERATIONS: usize = 1000;
+    if x <= 0. {
+        return 1.;
+    }
+    let log_prefix = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1. {
+        let mut term = 1. / a;
+        let mut sum = term;
+        for n in 1..MAX_ITERATIONS {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        return (1. - sum * log_prefix.exp()).max(0.);
+    }
+    // the modified Lentz method for the continued fraction
+    let tiny = f64::MIN_POSITIVE / EPSILON;
+    let mut b = x + 1. - a;
+    let mut c = 1. / tiny;
+    let mut d = 1. / b;
+    let mut h = d;
+    for i in 1..MAX_ITERATIONS {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.;
+        d = an * d + b;
+        if d.abs() < tiny {
+            d = tiny;
+        }
+        c = b + an / c;
+        if c.abs() < tiny {
+            c = tiny;
+        }
+        d = 1. / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.).abs() < EPSILON {
+            break;
+        }
+    }
+    (log_prefix.exp() * h).min(1.)
+}
+
+/// The natural logarithm of the gamma function, using the Lanczos approximation
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.001_208_650_973_866_179,
+        -0.000_005_395_239_384_953,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let series = COEFFICIENTS
+        .iter()
+        .enumerate()
+        .fold(1.000_000_000_190_015, |sum, (i, coef)| {
+            sum + coef / (x + 1. + i as f64)
+        });
+    -tmp + (2.506_628_274_631_000_5 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} isn't close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_upper_gamma() {
+        // the critical values of the chi-square distribution at the 5% level
+        assert_close(upper_gamma(0.5, 3.841_459 / 2.), 0.05);
+        assert_close(upper_gamma(1., 5.991_465 / 2.), 0.05);
+        assert_close(upper_gamma(5., 18.307_04 / 2.), 0.05);
+        // with 2 degrees of freedom, the p-value is exp(-x / 2)
+        assert_close(upper_gamma(1., 0.3), (-0.3f64).exp());
+        assert_close(upper_gamma(3., 0.), 1.);
+        assert_close(ln_gamma(5.), 24f64.ln());
+    }
+
+    #[test]
+    fn test_chi_square() {
+        let counts = vec![vec![20., 30.], vec![30., 20.]];
+        let chi2 = ChiSquare::from_counts(&counts).unwrap();
+        assert_close(chi2.statistic, 4.);
+        assert_eq!(chi2.degrees_of_freedom, 1);
+        assert_close(chi2.p_value, 0.045_500_263_9);
+        assert_close(chi2.cramers_v, 0.2);
+        assert_eq!((chi2.small_cells, chi2.cells), (0, 4));
+        assert_eq!(
+            chi2.to_string(),
+            "Chi-square: 4.0000 with 1 degrees of freedom (p-value: 0.0455), Cramér's V: 0.2000"
+        );
+        // the empty column gets left out, and the missing cell counts as 0
+        let chi2 = ChiSquare::from_counts(&[vec![1., 0., 2.], vec![3.]]).unwrap();
+        assert_eq!((chi2.degrees_of_freedom, chi2.small_cells), (1, 4));
+        assert_eq!(ChiSquare::from_counts(&[vec![1., 2.]]), None);
+        assert_eq!(ChiSquare::from_counts(&[vec![1.], vec![2.]]), None);
+    }
+}
@@ -198,3 +198,2658 @@ fn test_custom_delim() {
         .assert()
         .success();
 }
+
+#[test]
+fn test_regex_selection() {
+    let args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-v",
+        "id",
+        "--cols-regex",
+        "^was_",
+    ];
+    assert_eq!(
+        setup_sorting_columns(&args),
+        vec!["false".to_string(), "true".to_string()]
+    );
+    let no_match = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-v",
+        "id",
+        "--rows-regex",
+        "^nothing",
+    ];
+    assert!(!setup_cmd(&no_match).status.success());
+}
+
+#[test]
+fn test_multiple_values_columns() {
+    let args = vec![
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "salary,id",
+    ];
+    assert_eq!(
+        setup_sorting_columns(&args),
+        vec![
+            "false_salary".to_string(),
+            "false_id".to_string(),
+            "true_salary".to_string(),
+            "true_id".to_string(),
+        ]
+    );
+    assert_eq!(
+        setup_results(&args)[0],
+        vec![
+            "sales".to_string(),
+            "85000".to_string(),
+            "5".to_string(),
+            "90000".to_string(),
+            "5".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_composite_row_keys() {
+    let args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department,was_fired",
+        "-v",
+        "id",
+        "--asc-rows",
+    ];
+    let expected = vec![
+        vec!["engineering", "false", "1"],
+        vec!["engineering", "true", "1"],
+        vec!["sales", "false", "1"],
+        vec!["sales", "true", "2"],
+    ];
+    assert_eq!(setup_results(&args), expected);
+}
+
+#[test]
+fn test_key_separator() {
+    let args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-c",
+        "department,was_fired",
+        "-v",
+        "id",
+        "--key-sep",
+        " | ",
+    ];
+    assert_eq!(
+        setup_sorting_columns(&args)[0],
+        "engineering | false".to_string()
+    );
+}
+
+#[test]
+fn test_output_labels() {
+    let args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "id",
+        "--row-label",
+        "dept",
+        "--header-prefix",
+        "fired_",
+    ];
+    let output = setup_cmd(&args).stdout;
+    let header = str::from_utf8(&output).unwrap().lines().next().unwrap();
+    assert_eq!(header, "dept,fired_false,fired_true");
+}
+
+#[test]
+fn test_group_by_without_columns() {
+    let args = vec![
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+    ];
+    let results = setup_results(&args);
+    assert_eq!(
+        results,
+        vec![vec!["sales", "175000"], vec!["engineering", "250000"]]
+    );
+    assert_eq!(setup_sorting_columns(&args), vec!["total".to_string()]);
+}
+
+#[test]
+fn test_count_without_values_column() {
+    let args = vec!["count", "test_csvs/layoffs.csv", "-r", "was_fired"];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["true", "3"], vec!["false", "2"]]
+    );
+    let sum_args = vec!["sum", "test_csvs/layoffs.csv", "-r", "was_fired"];
+    assert!(!setup_cmd(&sum_args).status.success());
+}
+
+#[test]
+fn test_all_other_values() {
+    let args = vec![
+        "max",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "all-other",
+    ];
+    assert_eq!(
+        setup_sorting_columns(&args),
+        vec!["false_id", "false_salary", "true_id", "true_salary"]
+    );
+}
+
+#[test]
+fn test_index_column_named_after_field() {
+    let args = vec!["count", "test_csvs/layoffs.csv", "-r", "was_fired"];
+    let output = setup_cmd(&args).stdout;
+    let header = str::from_utf8(&output).unwrap().lines().next().unwrap();
+    assert_eq!(header, "was_fired,total");
+}
+
+#[test]
+fn test_multi_header() {
+    let args = vec![
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "id,salary",
+        "--multi-header",
+    ];
+    let output = setup_cmd(&args).stdout;
+    let lines: Vec<&str> = str::from_utf8(&output).unwrap().lines().take(2).collect();
+    assert_eq!(
+        lines,
+        vec![",false,false,true,true", "department,id,salary,id,salary"]
+    );
+}
+
+#[test]
+fn test_record_number_field() {
+    let args = vec!["count", "test_csvs/layoffs.csv", "-r", "@index,department"];
+    let output = setup_cmd(&args).stdout;
+    let lines: Vec<&str> = str::from_utf8(&output).unwrap().lines().take(3).collect();
+    assert_eq!(
+        lines,
+        vec!["@index,department,total", "0,sales,1", "1,engineering,1"]
+    );
+}
+
+#[test]
+fn test_regex_extract_keys() {
+    let args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "--rows-extract",
+        "department:^(.)",
+        "-c",
+        "was_fired",
+    ];
+    let output = setup_cmd(&args).stdout;
+    let lines: Vec<&str> = str::from_utf8(&output).unwrap().lines().collect();
+    assert_eq!(lines, vec!["department,false,true", "s,1,2", "e,1,1"]);
+    let bad_regex = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "--rows-extract",
+        "department:(",
+    ];
+    assert!(!setup_cmd(&bad_regex).status.success());
+}
+
+#[test]
+fn test_rows_expr() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "--rows-expr",
+        "region ~ '-' ~ id",
+        "--cols-expr",
+        "age > 30",
+        "-v",
+        "amount",
+    ];
+    let results = setup_results(&args);
+    assert_eq!(results[0], vec!["east-1", "10", ""]);
+    assert_eq!(results[1], vec!["west-2", "", "20"]);
+    assert_eq!(results.len(), 6);
+    let output = setup_cmd(&args);
+    let stdout = str::from_utf8(&output.stdout).unwrap();
+    assert!(stdout.starts_with("region ~ '-' ~ id,false,true"));
+}
+
+#[test]
+fn test_date_part_grouping() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "created",
+        "-v",
+        "amount",
+        "--by",
+        "month",
+    ];
+    let output = setup_cmd(&args).stdout;
+    let lines: Vec<&str> = str::from_utf8(&output).unwrap().lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "created,total",
+            "2019-01,30",
+            "2019-02,20",
+            "2020-01,30",
+            "2020-03,25"
+        ]
+    );
+    let not_a_field = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "created",
+        "-v",
+        "amount",
+        "--by",
+        "region:year",
+    ];
+    assert!(!setup_cmd(&not_a_field).status.success());
+}
+
+#[test]
+fn test_binning() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "age",
+        "-v",
+        "amount",
+        "--bin",
+        "age:0,18,35,50",
+        "-A",
+    ];
+    let results = setup_results(&args);
+    assert_eq!(
+        results,
+        vec![
+            vec![">=50", "40"],
+            vec!["[0,18)", "5"],
+            vec!["[18,35)", "10"],
+            vec!["[35,50)", "50"]
+        ]
+    );
+    let by_chunk = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "@index",
+        "--bin",
+        "@index:width=4",
+    ];
+    assert_eq!(
+        setup_results(&by_chunk),
+        vec![vec!["[0,4)", "4"], vec!["[4,8)", "2"]]
+    );
+}
+
+#[test]
+fn test_recode_lookup_table() {
+    let recode = |policy: &str| {
+        let args = vec![
+            "sum",
+            "test_csvs/orders.csv",
+            "-r",
+            "region",
+            "-v",
+            "amount",
+            "--recode",
+            "region:test_csvs/region_map.csv",
+            "--unmapped",
+            policy,
+        ];
+        setup_results(&args)
+    };
+    assert_eq!(
+        recode("keep"),
+        vec![
+            vec!["Atlantic", "15"],
+            vec!["Pacific", "50"],
+            vec!["East", "15"],
+            vec!["north", "25"]
+        ]
+    );
+    assert_eq!(
+        recode("drop"),
+        vec![vec!["Atlantic", "15"], vec!["Pacific", "50"]]
+    );
+    assert_eq!(
+        recode("other"),
+        vec![
+            vec!["Atlantic", "15"],
+            vec!["Pacific", "50"],
+            vec!["Other", "40"]
+        ]
+    );
+}
+
+#[test]
+fn test_top_rows_with_other() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--top-rows",
+        "2",
+        "--other-label",
+        "Everything else",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["west", "50"],
+            vec!["north", "25"],
+            vec!["Everything else", "30"]
+        ]
+    );
+}
+
+#[test]
+fn test_min_count() {
+    let args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--min-count",
+        "2",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "2"], vec!["west", "2"]]
+    );
+    let bucketed = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--min-count",
+        "2",
+        "--small-groups",
+        "other",
+    ];
+    assert_eq!(
+        setup_results(&bucketed),
+        vec![vec!["east", "2"], vec!["west", "2"], vec!["Other", "2"]]
+    );
+}
+
+#[test]
+fn test_normalize_keys() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--normalize-keys",
+        "trim,lower,collapse-spaces",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "30"], vec!["west", "50"], vec!["north", "25"]]
+    );
+    let bad_step = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--normalize-keys",
+        "titlecase",
+    ];
+    assert!(!setup_cmd(&bad_step).status.success());
+}
+
+#[test]
+fn test_filter_expression() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter",
+        "region == \"east\" || amount >= 25",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "15"], vec!["west", "30"], vec!["north", "25"]]
+    );
+    let unparseable = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter",
+        "amount >",
+    ];
+    let output = setup_cmd(&unparseable);
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("unexpected end of expression"));
+}
+
+#[test]
+fn test_multiple_filters() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter",
+        "amount > 5",
+        "--filter",
+        "age < 60",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "10"], vec!["west", "50"], vec!["north", "25"]]
+    );
+    let any_args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter",
+        "amount > 5",
+        "--filter-any",
+        "region == \"east\"",
+        "--filter-any",
+        "region == \"west\"",
+    ];
+    assert_eq!(
+        setup_results(&any_args),
+        vec![vec!["east", "10"], vec!["west", "50"]]
+    );
+}
+
+#[test]
+fn test_sampling() {
+    let args = vec!["count", "test_csvs/orders.csv", "--sample-n", "100"];
+    let output = setup_cmd(&args);
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("Sampled 6 of 6 records"));
+    assert_eq!(setup_results(&args), vec![vec!["total", "6"]]);
+    let args = vec!["count", "test_csvs/orders.csv", "--sample-n", "2"];
+    assert_eq!(setup_results(&args), vec![vec!["total", "2"]]);
+    let args = vec!["count", "test_csvs/orders.csv", "--sample", "1"];
+    assert_eq!(setup_results(&args), vec![vec!["total", "6"]]);
+    let args = vec!["count", "test_csvs/orders.csv", "--sample", "1.5"];
+    assert!(!setup_cmd(&args).status.success());
+
+    // the same seed draws the same sample on every run
+    for sample in &["--sample", "--sample-n"] {
+        let size = if *sample == "--sample" { "0.1" } else { "200" };
+        let mut args = vec![
+            "count",
+            "test_csvs/golden_sales.csv",
+            "-r",
+            "product",
+            sample,
+            size,
+            "--seed",
+            "2020",
+        ];
+        let seeded = setup_cmd(&args);
+        assert!(seeded.status.success());
+        assert_eq!(setup_cmd(&args).stdout, seeded.stdout);
+        args[7] = "2021";
+        assert_ne!(setup_cmd(&args).stdout, seeded.stdout);
+    }
+    let args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "--sample",
+        "0.5",
+        "--seed",
+        "-1",
+    ];
+    assert_eq!(setup_cmd(&args).status.code(), Some(2));
+}
+
+#[test]
+fn test_limit() {
+    let args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--limit",
+        "3",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "2"], vec!["west", "1"]]
+    );
+    let args = vec!["count", "test_csvs/orders.csv", "--limit", "0"];
+    assert!(!setup_cmd(&args).status.success());
+}
+
+#[test]
+fn test_filter_error_policy() {
+    // the filter can't be evaluated on the first two records, since `region` isn't a number
+    let mut args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter",
+        "$0 > 2 || region > 1",
+    ];
+    assert!(!setup_cmd(&args).status.success());
+    args.extend(&["--on-filter-error", "skip"]);
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "5"],
+            vec!["East", "15"],
+            vec!["west", "30"],
+            vec!["north", "25"]
+        ]
+    );
+    args.pop();
+    args.push("keep");
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "15"],
+            vec!["west", "50"],
+            vec!["East", "15"],
+            vec!["north", "25"]
+        ]
+    );
+}
+
+#[test]
+fn test_value_expr() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--value-expr",
+        "amount * age / 10",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "31.5"],
+            vec!["west", "187"],
+            vec!["East", "97.5"],
+            vec!["north", "130"]
+        ]
+    );
+    let bad_args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "--value-expr",
+        "amount * region",
+    ];
+    let output = setup_cmd(&bad_args);
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("Could not compute the value"));
+}
+
+#[test]
+fn test_having() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--having",
+        "sum > 20",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["west", "50"], vec!["north", "25"]]
+    );
+    let args = vec![
+        "mean",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--having",
+        "count >= 2 && value < 20",
+    ];
+    assert_eq!(setup_results(&args), vec![vec!["east", "7.5"]]);
+    let args = vec![
+        "mean",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--having",
+        "amount < 20",
+    ];
+    assert!(!setup_cmd(&args).status.success());
+}
+
+#[test]
+fn test_percent() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--percent",
+        "total",
+        "--percent-decimals",
+        "2",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "14.29"],
+            vec!["west", "47.62"],
+            vec!["East", "14.29"],
+            vec!["north", "23.81"]
+        ]
+    );
+    let args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--cols-expr",
+        "age > 30",
+        "--percent",
+        "column",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "100.0", ""],
+            vec!["west", "", "50.0"],
+            vec!["East", "", "25.0"],
+            vec!["north", "", "25.0"]
+        ]
+    );
+}
+
+#[test]
+fn test_accumulate() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--accumulate",
+        "columns",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "15", "15"],
+            vec!["west", "20", "50"],
+            vec!["East", "15", "15"],
+            vec!["north", "", "25"]
+        ]
+    );
+}
+
+#[test]
+fn test_diff() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--diff",
+        "columns",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "", ""],
+            vec!["west", "", "10"],
+            vec!["East", "", ""],
+            vec!["north", "", ""]
+        ]
+    );
+    args.push("--pct-change");
+    assert_eq!(setup_results(&args)[1], vec!["west", "", "50.0"]);
+}
+
+#[test]
+fn test_rank() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--rank",
+        "columns",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "3"],
+            vec!["west", "1"],
+            vec!["East", "3"],
+            vec!["north", "2"]
+        ]
+    );
+    args.extend(&["--rank-ties", "average"]);
+    assert_eq!(setup_results(&args)[0], vec!["east", "3.5"]);
+}
+
+#[test]
+fn test_zscore() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--zscore",
+        "columns",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "-0.68"],
+            vec!["west", "1.44"],
+            vec!["East", "-0.68"],
+            vec!["north", "-0.08"]
+        ]
+    );
+}
+
+#[test]
+fn test_baseline() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--baseline",
+        "2019",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "1.00", ""],
+            vec!["west", "1.00", "1.50"],
+            vec!["East", "1.00", ""],
+            vec!["north", "", ""]
+        ]
+    );
+    args.extend(&["--baseline-scale", "100"]);
+    assert_eq!(setup_results(&args)[1], vec!["west", "100.00", "150.00"]);
+    args[11] = "2018";
+    let output = setup_cmd(&args);
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("Could not find the baseline column `2018`"));
+}
+
+#[test]
+fn test_output_recode() {
+    let mut args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--totals",
+        "--output-recode",
+        ">5=many,1=-",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "2"],
+            vec!["west", "2"],
+            vec!["East", "-"],
+            vec!["north", "-"],
+            vec!["Total", "many"]
+        ]
+    );
+    args.extend(&["--suppress-below", "3"]);
+    assert_eq!(setup_results(&args)[0], vec!["east", "*"]);
+}
+
+#[test]
+fn test_chi2() {
+    let mut args = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "was_fired",
+        "-c",
+        "department",
+        "--chi2",
+    ];
+    let output = setup_cmd(&args);
+    assert!(output.status.success());
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains(
+        "Chi-square: 0.1389 with 1 degrees of freedom (p-value: 0.7094), Cramér's V: 0.1667"
+    ));
+    assert!(stderr.contains("4 of the 4 cells are expected to have fewer than 5 records"));
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["true", "1", "2"], vec!["false", "1", "1"]]
+    );
+    args[0] = "sum";
+    assert_eq!(setup_cmd(&args).status.code(), Some(2));
+    // without column fields, there's nothing to test against
+    let no_columns = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "was_fired",
+        "--chi2",
+    ];
+    assert_eq!(setup_cmd(&no_columns).status.code(), Some(2));
+}
+
+#[test]
+fn test_out_locale() {
+    let mut args = vec![
+        "mean",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--out-locale",
+        "de-DE",
+    ];
+    assert_eq!(
+        str::from_utf8(&setup_cmd(&args).stdout).unwrap(),
+        "region;total\neast;7,5\nwest;25\nEast;15\nnorth;25\n"
+    );
+    // the row keys don't get grouped like numbers
+    args[3] = "age";
+    args[0] = "sum";
+    let output = setup_cmd(&args);
+    assert!(str::from_utf8(&output.stdout)
+        .unwrap()
+        .starts_with("age;total\n23;10\n"));
+    args[7] = "en-US";
+    args.extend(&["--totals", "-c", "region"]);
+    let results = setup_results(&args);
+    assert_eq!(results.last().unwrap()[0], "Total");
+    args[7] = "klingon";
+    assert_eq!(setup_cmd(&args).status.code(), Some(2));
+}
+
+#[test]
+fn test_drop_empty() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--diff",
+        "columns",
+        "--drop-empty",
+    ];
+    let output = setup_cmd(&args);
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "region,2020\nwest,10\n"
+    );
+}
+
+#[test]
+fn test_sparse() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--sparse",
+    ];
+    let output = setup_cmd(&args);
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "region,created,value\neast,2019,15\nwest,2019,20\nwest,2020,30\nEast,2019,15\nnorth,2020,25\n"
+    );
+}
+
+#[test]
+fn test_timeseries() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--timeseries",
+        "created:month",
+        "-v",
+        "amount",
+    ];
+    let output = setup_cmd(&args);
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "region,2019-01,2019-02,2020-01,2020-03\neast,10,5,,\nwest,20,,30,\nEast,,15,,\nnorth,,,,25\n"
+    );
+    let weekday = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "--timeseries",
+        "created:weekday",
+        "-v",
+        "amount",
+    ];
+    assert!(!setup_cmd(&weekday).status.success());
+}
+
+#[test]
+fn test_dates_that_dont_parse() {
+    let path = std::env::temp_dir().join("clipivot_test_bad_date.csv");
+    std::fs::write(
+        &path,
+        "region,created,amount\neast,2020-01-04,1\nwest,13/45/2020,2\neast,2020-02-01,3\n",
+    )
+    .unwrap();
+    let filename = path.to_str().unwrap();
+    for (flag, spec) in &[("--timeseries", "created:month"), ("--by", "created:month")] {
+        let mut args = vec!["sum", filename, "-v", "amount", flag, spec];
+        if *flag == "--by" {
+            args.extend(&["-r", "created"]);
+        }
+        let output = setup_cmd(&args);
+        assert_eq!(output.status.code(), Some(3), "{}", flag);
+        let stderr = str::from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains("column `created`"), "{}", stderr);
+        assert!(stderr.contains("`13/45/2020` isn't a date in any of the formats"));
+    }
+    // without a field, `--by` leaves the values that aren't dates alone
+    let output = setup_cmd(&["count", filename, "-r", "region", "--by", "month"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_sort_type() {
+    let mut args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "amount",
+        "--sort-rows",
+        "desc",
+    ];
+    let rows = |args: &[&str]| -> Vec<String> {
+        setup_results(args)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect()
+    };
+    assert_eq!(rows(&args), vec!["5", "30", "25", "20", "15", "10"]);
+    args.extend(&["--sort-type", "numeric"]);
+    assert_eq!(rows(&args), vec!["30", "25", "20", "15", "10", "5"]);
+}
+
+#[test]
+fn test_sort_by_totals() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--sort-rows",
+        "by-total-desc",
+        "--sort-cols",
+        "by-total-desc",
+    ];
+    let output = setup_cmd(&args);
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "region,2020,2019\nwest,30,20\nnorth,25,\neast,,15\nEast,,15\n"
+    );
+}
+
+#[test]
+fn test_chronological_sort() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/monthly.csv",
+        "-c",
+        "month",
+        "-v",
+        "amount",
+    ];
+    let header = |args: &[&str]| -> String {
+        let output = setup_cmd(args).stdout;
+        str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(header(&args), ",Dec-2019,Feb-2019,Jan-2020,Mar-2020");
+    args.extend(&["--sort-type", "chronological"]);
+    assert_eq!(header(&args), ",Feb-2019,Dec-2019,Jan-2020,Mar-2020");
+}
+
+#[test]
+fn test_order() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/monthly.csv",
+        "-r",
+        "month",
+        "-c",
+        "month",
+        "-v",
+        "amount",
+        "--order",
+        "input",
+    ];
+    let output = setup_cmd(&args);
+    assert_eq!(
+        str::from_utf8(&output.stdout)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap(),
+        "month,Jan-2020,Feb-2019,Mar-2020,Dec-2019"
+    );
+    args[9] = "sorted";
+    let rows: Vec<String> = setup_results(&args)
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    assert_eq!(rows, vec!["Dec-2019", "Feb-2019", "Jan-2020", "Mar-2020"]);
+}
+
+#[test]
+fn test_custom_order() {
+    let mut args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--row-order",
+        "north,west,South",
+    ];
+    let rows = |args: &[&str]| -> Vec<String> {
+        setup_results(args)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect()
+    };
+    assert_eq!(rows(&args), vec!["north", "west", "east", "East"]);
+    args[6] = "--row-order-file";
+    args[7] = "test_csvs/region_order.txt";
+    assert_eq!(rows(&args), vec!["west", "north", "east", "East"]);
+}
+
+#[test]
+fn test_completions() {
+    for shell in &["bash", "zsh", "fish", "powershell"] {
+        let output = setup_cmd(&["completions", shell]);
+        assert!(output.status.success());
+        let script = str::from_utf8(&output.stdout).unwrap();
+        assert!(script.contains("countunique"));
+        assert!(script.contains("rows"));
+    }
+    assert!(!setup_cmd(&["completions", "tcsh"]).status.success());
+}
+
+#[test]
+fn test_config_file() {
+    let mut args = vec![
+        "count",
+        "test_csvs/tab_tsv.tsv",
+        "-r",
+        "0",
+        "--config",
+        "test_csvs/tab_config.toml",
+    ];
+    assert_eq!(setup_results(&args)[0], vec!["aaa", "1"]);
+    // options on the command line override the config file
+    args.extend(&["--delim", ";"]);
+    assert_eq!(setup_results(&args)[0], vec!["aaa\tbbb\tccc", "1"]);
+    // the config file gets read from the default location
+    let config_dir = std::env::temp_dir().join("clipivot_test_config");
+    std::fs::create_dir_all(config_dir.join("clipivot")).unwrap();
+    std::fs::copy(
+        "test_csvs/tab_config.toml",
+        config_dir.join("clipivot").join("config.toml"),
+    )
+    .unwrap();
+    let stdin_contents = "foo, bar	bar	baz
+    aaa	bbb	ccc
+    1	2	3";
+    let _cmd = Command::new(program_path!())
+        .args(vec!["count", "-v", "0"])
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .write_stdin(stdin_contents)
+        .assert()
+        .success();
+    let _cmd_without_config = Command::new(program_path!())
+        .args(vec!["count", "-v", "0", "--no-config"])
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .write_stdin(stdin_contents)
+        .assert()
+        // should fail because of the comma on the header
+        .failure();
+    let bad_config = config_dir.join("bad_config.toml");
+    std::fs::write(&bad_config, "delimiter = \";\"\n").unwrap();
+    let _cmd_with_bad_config = Command::new(program_path!())
+        .args(vec!["count", "test_csvs/tab_tsv.tsv", "--config"])
+        .arg(&bad_config)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_presets() {
+    let config = std::env::temp_dir().join("clipivot_test_presets.toml");
+    let _ = std::fs::remove_file(&config);
+    let config_arg = config.to_str().unwrap();
+    let saved = setup_cmd(&[
+        "save-preset",
+        "layoffs",
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "0",
+        "--config",
+        config_arg,
+    ]);
+    assert!(saved.status.success());
+    let expected = setup_results(&["count", "test_csvs/layoffs.csv", "-r", "0"]);
+    assert_eq!(
+        setup_results(&["run", "layoffs", "--config", config_arg]),
+        expected
+    );
+    // extra arguments get added to the preset
+    let desc = setup_results(&["run", "layoffs", "--config", config_arg, "--desc-rows"]);
+    assert_eq!(
+        desc,
+        setup_results(&["count", "test_csvs/layoffs.csv", "-r", "0", "--desc-rows"])
+    );
+    // presets can't be saved twice, and have to be valid
+    let args = ["save-preset", "layoffs", "count", "--config", config_arg];
+    assert!(!setup_cmd(&args).status.success());
+    let args = ["save-preset", "other", "bogus", "--config", config_arg];
+    assert!(!setup_cmd(&args).status.success());
+    let output = setup_cmd(&["run", "missing", "--config", config_arg]);
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("There is no preset named `missing`"));
+}
+
+#[test]
+fn test_null_values() {
+    let base_query = vec!["count", "test_csvs/empty_count.csv", "-v", "2"];
+    assert_eq!(setup_results(&base_query)[0], vec!["total", "2"]);
+    let mut skip_query = base_query.clone();
+    skip_query.push("-e");
+    assert_eq!(setup_results(&skip_query)[0], vec!["total", "1"]);
+    // custom null values replace the default ones
+    let mut custom_query = base_query.clone();
+    custom_query.extend(&["--null-values", "3,NA"]);
+    assert_eq!(setup_results(&custom_query)[0], vec!["total", "1"]);
+}
+
+#[test]
+fn test_env_vars() {
+    let stdin_contents = "a;b
+    1;2";
+    let _cmd = Command::new(program_path!())
+        .args(vec!["count", "-v", "b", "--no-config"])
+        .env("CSVPIVOT_DELIMITER", ";")
+        .write_stdin(stdin_contents)
+        .assert()
+        .success();
+    // options on the command line override environment variables
+    let _cmd_with_delim = Command::new(program_path!())
+        .args(vec!["count", "-v", "b", "--no-config", "-d", ","])
+        .env("CSVPIVOT_DELIMITER", ";")
+        .write_stdin(stdin_contents)
+        .assert()
+        .failure();
+    let output = Command::new(program_path!())
+        .args(vec!["count", "test_csvs/empty_count.csv", "-v", "2"])
+        .env("CSVPIVOT_NULL_VALUES", "3")
+        .output()
+        .unwrap();
+    assert_eq!(str::from_utf8(&output.stdout).unwrap(), ",total\ntotal,1\n");
+    let _cmd_with_bad_flag = Command::new(program_path!())
+        .args(vec!["count", "test_csvs/empty_count.csv"])
+        .env("CSVPIVOT_TAB", "maybe")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_interactive() {
+    let output = Command::new(program_path!())
+        .args(vec![
+            "interactive",
+            "test_csvs/sample_median.csv",
+            "--no-config",
+        ])
+        .write_stdin("\nis_true\n\n\ny\n")
+        .output()
+        .unwrap();
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "clipivot count test_csvs/sample_median.csv -r is_true\n"
+    );
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("  2: is_true"));
+    assert!(stderr.contains("true,"));
+    let _cmd_without_input = Command::new(program_path!())
+        .args(vec!["interactive", "test_csvs/sample_median.csv"])
+        .write_stdin("count\n")
+        .assert()
+        .failure();
+    assert!(!setup_cmd(&["interactive", "test_csvs/missing.csv"])
+        .status
+        .success());
+}
+
+#[test]
+fn test_dry_run() {
+    let output = setup_cmd(&[
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "was_fired",
+        "--dry-run",
+    ]);
+    assert!(output.status.success());
+    let plan = str::from_utf8(&output.stdout).unwrap();
+    assert!(plan.contains("Delimiter: `,` (the default)"));
+    assert!(plan.contains("Rows: department (column 3)"));
+    assert!(plan.contains("Values: was_fired (column 1)"));
+    assert!(plan.contains("sales,true,could not parse `true`: Failed to parse as numeric"));
+    // the first five records and a header
+    assert_eq!(
+        plan.lines()
+            .skip_while(|l| !l.starts_with("The first"))
+            .count(),
+        7
+    );
+    let tsv_plan = setup_cmd(&["count", "test_csvs/tab_tsv.tsv", "--dry-run"]);
+    assert!(str::from_utf8(&tsv_plan.stdout)
+        .unwrap()
+        .contains("Delimiter: tab (detected from the file extension)"));
+}
+
+#[test]
+fn test_verbose() {
+    let query = vec![
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+    ];
+    let quiet = setup_cmd(&query);
+    assert!(quiet.stderr.is_empty());
+    let mut verbose_query = query.clone();
+    verbose_query.extend(&["--filter", "salary > 30000", "--verbose"]);
+    let verbose = setup_cmd(&verbose_query);
+    let log = str::from_utf8(&verbose.stderr).unwrap();
+    assert!(log.contains("[info] Read 5 records"));
+    assert!(log.contains("skipping 1 that didn't meet the filters"));
+    assert!(!log.contains("[debug]"));
+    // the log doesn't change the output
+    assert!(str::from_utf8(&verbose.stdout)
+        .unwrap()
+        .starts_with("department,total\n"));
+    verbose_query.push("--verbose");
+    let debug = setup_cmd(&verbose_query);
+    assert!(str::from_utf8(&debug.stderr)
+        .unwrap()
+        .contains("[debug] Rows: department (column 3)"));
+}
+
+#[test]
+fn test_progress_bar() {
+    let path = std::env::temp_dir().join("clipivot_test_progress.csv");
+    let mut contents = String::from("group,value\n");
+    for i in 0..5000 {
+        contents.push_str(&format!("{},{}\n", i % 3, i));
+    }
+    std::fs::write(&path, contents).unwrap();
+    let filename = path.to_str().unwrap();
+    let query = vec!["sum", filename, "-r", "group", "-v", "value"];
+    let mut always = query.clone();
+    always.extend(&["--progress", "always"]);
+    let output = setup_cmd(&always);
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("records/s"));
+    // the progress bar doesn't change the output
+    assert_eq!(output.stdout, setup_cmd(&query).stdout);
+    let mut never = query.clone();
+    never.extend(&["--progress", "never"]);
+    assert!(setup_cmd(&never).stderr.is_empty());
+}
+
+#[test]
+fn test_index_file() {
+    let path = std::env::temp_dir().join("clipivot_test_indexed.csv");
+    let mut contents = String::from("group,value\n");
+    let mut index = 0u64.to_be_bytes().to_vec();
+    for i in 0..3000 {
+        index.extend(&(contents.len() as u64).to_be_bytes());
+        contents.push_str(&format!("g{},{}\n", i % 5, i));
+    }
+    index.extend(&3001u64.to_be_bytes());
+    std::fs::write(&path, &contents).unwrap();
+    let filename = path.to_str().unwrap();
+    let query = vec!["sum", filename, "-r", "group", "-v", "value"];
+    let unindexed = setup_cmd(&query);
+    let index_path = format!("{}.idx", filename);
+    std::fs::write(&index_path, &index).unwrap();
+    let mut threaded = query.clone();
+    threaded.extend(&["--threads", "3"]);
+    let indexed = setup_cmd(&threaded);
+    assert!(indexed.stderr.is_empty());
+    assert_eq!(indexed.stdout, unindexed.stdout);
+    let mut progress = query.clone();
+    progress.extend(&["--progress", "always"]);
+    assert!(str::from_utf8(&setup_cmd(&progress).stderr)
+        .unwrap()
+        .contains("/3000 records"));
+    // an index that doesn't match the file gets ignored
+    std::fs::write(&index_path, &index[8..]).unwrap();
+    let mismatched = setup_cmd(&threaded);
+    assert!(str::from_utf8(&mismatched.stderr)
+        .unwrap()
+        .contains("Ignoring the index"));
+    assert_eq!(mismatched.stdout, unindexed.stdout);
+    std::fs::remove_file(&index_path).unwrap();
+}
+
+#[test]
+fn test_two_pass() {
+    let query = vec![
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "salary",
+        "-e",
+    ];
+    let mut two_pass = query.clone();
+    two_pass.push("--two-pass");
+    let output = setup_cmd(&two_pass);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, setup_cmd(&query).stdout);
+    // standard input can only be read once
+    Command::new(program_path!())
+        .args(["sum", "-r", "department", "-v", "salary", "--two-pass"])
+        .write_stdin("department,salary\nsales,1\n")
+        .assert()
+        .failure()
+        .code(2);
+}
+
+#[test]
+fn test_quiet() {
+    let query = vec!["count", "test_csvs/orders.csv", "--sample-n", "100"];
+    assert!(!setup_cmd(&query).stderr.is_empty());
+    let mut quiet_query = query.clone();
+    quiet_query.push("-q");
+    let quiet = setup_cmd(&quiet_query);
+    assert!(quiet.stderr.is_empty());
+    assert_eq!(quiet.stdout, setup_cmd(&query).stdout);
+    // errors still get written
+    let error = setup_cmd(&["count", "test_csvs/orders.csv", "-r", "nope", "-q"]);
+    assert!(!error.status.success());
+    assert!(!error.stderr.is_empty());
+    let _cmd_with_env_var = Command::new(program_path!())
+        .args(&query)
+        .env("CSVPIVOT_QUIET", "true")
+        .assert()
+        .stderr("");
+    quiet_query.push("--verbose");
+    assert!(!setup_cmd(&quiet_query).status.success());
+}
+
+#[test]
+fn test_subcommands() {
+    let legacy = setup_cmd(&["count", "test_csvs/layoffs.csv", "-r", "1", "-c", "3"]);
+    let pivot = setup_cmd(&[
+        "pivot",
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "1",
+        "-c",
+        "3",
+    ]);
+    assert!(pivot.status.success());
+    assert_eq!(pivot.stdout, legacy.stdout);
+    let agg = setup_cmd(&["agg", "count", "test_csvs/layoffs.csv", "-r", "1"]);
+    assert_eq!(
+        String::from_utf8(agg.stdout).unwrap(),
+        "was_fired,value\ntrue,3\nfalse,2\n"
+    );
+    let _melt = Command::new(program_path!())
+        .args(vec!["melt", "--id", "region", "--var-name", "month"])
+        .write_stdin("region,jan,feb\nEast,10,20\nWest,,5\n")
+        .assert()
+        .success()
+        .stdout("region,month,value\nEast,jan,10\nEast,feb,20\nWest,jan,\nWest,feb,5\n");
+    let describe = setup_cmd(&["describe", "test_csvs/layoffs.csv"]);
+    let describe = String::from_utf8(describe.stdout).unwrap();
+    assert!(describe.starts_with("field,type,count,empty,unique,min,max,mean\n"));
+    assert!(describe.contains("salary,number,5,0,5,25000,175000,85000\n"));
+    assert!(
+        !setup_cmd(&["melt", "test_csvs/layoffs.csv", "--id", "nope"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn test_melt_round_trip() {
+    let pivot = ["sum", "-r", "region", "-c", "age", "-v", "amount"];
+    let wide = setup_cmd(&[&pivot[..], &["test_csvs/orders.csv"]].concat()).stdout;
+    let long = Command::new(program_path!())
+        .args([
+            "melt",
+            "--id",
+            "region",
+            "--var-name",
+            "age",
+            "--value-name",
+            "amount",
+            "-e",
+        ])
+        .write_stdin(wide.clone())
+        .output()
+        .unwrap()
+        .stdout;
+    // only the cells with records get melted
+    assert_eq!(str::from_utf8(&long).unwrap().lines().count(), 7);
+    let round_trip = Command::new(program_path!())
+        .args(pivot)
+        .write_stdin(long)
+        .output()
+        .unwrap();
+    assert_eq!(round_trip.stdout, wide);
+}
+
+#[test]
+fn test_aggfunc_suggestions() {
+    let _typo = Command::new(program_path!())
+        .args(vec!["mediam", "test_csvs/layoffs.csv", "-v", "salary"])
+        .assert()
+        .failure()
+        .stderr("error: unknown aggregation `mediam`; did you mean `median`?\n");
+    let case = setup_cmd(&["SUM", "test_csvs/layoffs.csv"]);
+    assert!(!case.status.success());
+    assert!(str::from_utf8(&case.stderr)
+        .unwrap()
+        .contains("did you mean `sum`?"));
+    let unknown = setup_cmd(&["average", "test_csvs/layoffs.csv"]);
+    assert!(str::from_utf8(&unknown.stderr).unwrap().starts_with(
+        "error: unknown aggregation `average`; it needs to be one of count, countunique"
+    ));
+}
+
+#[test]
+fn test_list_aggregations() {
+    let list = setup_cmd(&["--list-aggregations"]);
+    assert!(list.status.success());
+    let list = String::from_utf8(list.stdout).unwrap();
+    assert!(list.starts_with("count\n    Counts the number of matching records.\n"));
+    let names: Vec<&str> = list.lines().filter(|line| !line.starts_with(' ')).collect();
+    assert_eq!(names.len(), 11);
+    // every function in the list can actually be run
+    for name in names {
+        let output = setup_cmd(&[name, "test_csvs/layoffs.csv", "-v", "salary", "-N"]);
+        assert!(output.status.success(), "{} failed", name);
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    let output = setup_cmd(&["--capabilities", "json"]);
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert!(json.starts_with("{\n  \"name\": \"clipivot\",\n  \"version\": \""));
+    assert!(json.contains(
+        r#"{"name": "median", "description": "Returns the median value.", "values": "Numbers.""#
+    ));
+    assert!(json.contains(r#""subcommands": ["pivot", "agg", "melt", "describe""#));
+    assert!(json.contains(r#"{"name": "progress", "positional": false, "long": "--progress", "short": null, "takes_value": true, "possible_values": ["auto", "always", "never"]"#));
+    assert!(json.contains(r#"{"name": "totals", "positional": false, "long": "--totals", "short": null, "takes_value": false"#));
+    assert!(!setup_cmd(&["--capabilities", "xml"]).status.success());
+}
+
+#[test]
+fn test_exit_codes() {
+    let code = |args: &[&str]| setup_cmd(args).status.code();
+    assert_eq!(code(&["count", "test_csvs/layoffs.csv"]), Some(0));
+    assert_eq!(
+        code(&["count", "test_csvs/layoffs.csv", "-r", "nope"]),
+        Some(2)
+    );
+    assert_eq!(code(&["count", "test_csvs/layoffs.csv", "--nope"]), Some(2));
+    assert_eq!(code(&["mediam", "test_csvs/layoffs.csv"]), Some(2));
+    assert_eq!(
+        code(&["sum", "test_csvs/layoffs.csv", "-v", "department"]),
+        Some(3)
+    );
+    assert_eq!(code(&["count", "test_csvs/nope.csv"]), Some(4));
+    assert_eq!(code(&["describe", "test_csvs/nope.csv"]), Some(4));
+    let _csv_error = Command::new(program_path!())
+        .args(vec!["count"])
+        .write_stdin("a,b\n1,2\n3\n")
+        .assert()
+        .code(5);
+}
+
+#[test]
+fn test_configuration_warnings() {
+    let output = setup_cmd(&[
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "3",
+        "-v",
+        "department",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Warning: `department` is both a values column and a row field, so every value in a cell is the same\n"
+    );
+    let output = setup_cmd(&[
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "3",
+        "-v",
+        "2",
+        "--percent",
+        "column",
+    ]);
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .starts_with("Warning: --percent adds up the cells of the table"));
+    // the warnings don't change the table, and --quiet hides them
+    let quiet = setup_cmd(&[
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "3",
+        "-v",
+        "2",
+        "--percent",
+        "column",
+        "-q",
+    ]);
+    assert!(quiet.stderr.is_empty());
+    assert_eq!(quiet.stdout, output.stdout);
+    assert!(setup_cmd(&[
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "3",
+        "-v",
+        "2",
+        "--percent",
+        "column"
+    ])
+    .stderr
+    .is_empty());
+}
+
+#[test]
+fn test_stats() {
+    let query = vec![
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "3",
+        "-c",
+        "1",
+        "--filter",
+        "salary > 30000",
+    ];
+    let mut stats_query = query.clone();
+    stats_query.push("--stats");
+    let output = setup_cmd(&stats_query);
+    assert_eq!(output.stdout, setup_cmd(&query).stdout);
+    let stats = String::from_utf8(output.stderr).unwrap();
+    assert!(stats.starts_with(
+        "Records read: 5\nRecords skipped: 1\nRow keys: 2\nColumn keys: 2\nPopulated cells: 4\nWall time: "
+    ));
+    assert!(stats.contains("\nPeak memory: "));
+}
+
+#[test]
+fn test_man_page() {
+    let output = setup_cmd(&["man"]);
+    assert!(output.status.success());
+    let page = String::from_utf8(output.stdout).unwrap();
+    assert!(page.starts_with(".TH CLIPIVOT 1 "));
+    assert!(page.contains(
+        ".SH NAME\nclipivot \\- A tool for creating pivot tables from the command line.\n"
+    ));
+    assert!(page.contains(".TP\n\\fB\\-r\\fR, \\fB\\-\\-rows\\fR \\fIrows\\fR\n"));
+    assert!(page.contains(".TP\n\\fB\\-\\-totals\\fR\nAdds a Total column"));
+    assert!(page.contains("Possible values: auto, always, never\n"));
+    assert!(page.contains(".SH SUBCOMMANDS\n.TP\n\\fBpivot <aggfunc> ...\\fR\n"));
+    assert!(page.contains(".SH EXIT STATUS\n"));
+    // none of the lines start with a (made-up) request
+    assert!(page
+        .lines()
+        .filter(|line| line.starts_with('.'))
+        .all(|line| [".TH", ".SH", ".TP", ".br"].contains(&&line[..3])));
+}
+
+#[test]
+fn test_argument_order() {
+    let expected = setup_cmd(&[
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department,was_fired",
+        "-c",
+        "id",
+    ])
+    .stdout;
+    let orders: Vec<Vec<&str>> = vec![
+        vec![
+            "count",
+            "-r",
+            "department",
+            "-r",
+            "was_fired",
+            "-c",
+            "id",
+            "test_csvs/layoffs.csv",
+        ],
+        vec![
+            "count",
+            "-r",
+            "department,was_fired",
+            "test_csvs/layoffs.csv",
+            "-c",
+            "id",
+        ],
+        vec![
+            "-c",
+            "id",
+            "count",
+            "--rows",
+            "department",
+            "--rows=was_fired",
+            "test_csvs/layoffs.csv",
+        ],
+        vec![
+            "count",
+            "test_csvs/layoffs.csv",
+            "-c",
+            "id",
+            "-r",
+            "department",
+            "-r",
+            "was_fired",
+        ],
+    ];
+    for args in orders {
+        let output = setup_cmd(&args);
+        assert!(output.status.success(), "{:?} failed", args);
+        assert_eq!(output.stdout, expected, "{:?}", args);
+    }
+    // values with commas (like the edges of bins) aren't split up
+    let bins = setup_cmd(&[
+        "count",
+        "-r",
+        "salary",
+        "--bin",
+        "salary:0,50000,100000",
+        "test_csvs/layoffs.csv",
+    ]);
+    assert!(bins.status.success());
+}
+
+#[test]
+fn test_missing_arguments_without_terminal() {
+    // outside of a terminal, missing arguments are errors rather than prompts
+    let missing_values = setup_cmd(&["sum", "test_csvs/layoffs.csv", "-r", "3"]);
+    assert_eq!(missing_values.status.code(), Some(2));
+    assert!(str::from_utf8(&missing_values.stderr)
+        .unwrap()
+        .contains("You need to select a values column"));
+    let missing_aggfunc = setup_cmd(&["test_csvs/layoffs.csv", "-r", "3"]);
+    assert_eq!(missing_aggfunc.status.code(), Some(2));
+    assert!(str::from_utf8(&missing_aggfunc.stderr)
+        .unwrap()
+        .starts_with("error: unknown aggregation `test_csvs/layoffs.csv`"));
+}
+
+#[test]
+fn test_approx() {
+    // on small files, --approx gives the same results as the exact functions
+    for (aggfunc, file, column) in &[
+        ("median", "test_csvs/sample_median.csv", "b"),
+        ("mode", "test_csvs/sample_mode.csv", "a"),
+    ] {
+        let exact = setup_cmd(&[aggfunc, file, "-v", column]);
+        let approx = setup_cmd(&[aggfunc, file, "-v", column, "--approx"]);
+        assert!(approx.status.success());
+        assert_eq!(approx.stdout, exact.stdout);
+    }
+    let sum = setup_cmd(&["sum", "test_csvs/sample_median.csv", "-v", "b", "--approx"]);
+    assert!(str::from_utf8(&sum.stderr)
+        .unwrap()
+        .contains("--approx only changes median and mode"));
+}
+
+#[test]
+fn test_threads() {
+    let args = [
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+    ];
+    let single = setup_cmd(&args);
+    for threads in &["2", "0"] {
+        let mut threaded_args = args.to_vec();
+        threaded_args.extend(&["--threads", threads]);
+        let threaded = setup_cmd(&threaded_args);
+        assert!(threaded.status.success());
+        assert_eq!(threaded.stdout, single.stdout);
+    }
+    let invalid = setup_cmd(&["count", "test_csvs/layoffs.csv", "--threads", "many"]);
+    assert_eq!(invalid.status.code(), Some(2));
+    // small files get read on a single thread unless --threads says otherwise
+    let threads_used = |extra: &[&str]| {
+        let mut verbose_args = args.to_vec();
+        verbose_args.push("--verbose");
+        verbose_args.extend(extra);
+        let output = setup_cmd(&verbose_args);
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("[info] Threads: ").map(str::to_string))
+            .unwrap()
+    };
+    assert_eq!(threads_used(&[]), "1");
+    assert_eq!(threads_used(&["--threads", "3"]), "3");
+}
+
+#[test]
+fn test_mode_ties_across_threads() {
+    let path = std::env::temp_dir().join("clipivot_test_mode_ties.csv");
+    let mut contents = String::from("group,value\n");
+    // every value appears as often as the others, and "d" gets there first
+    for i in 0..8000 {
+        contents.push_str(&format!(
+            "{},{}\n",
+            i % 4,
+            ["d", "c", "b", "a"][(i / 4) % 4]
+        ));
+    }
+    std::fs::write(&path, contents).unwrap();
+    let filename = path.to_str().unwrap();
+    let query = vec!["mode", filename, "-r", "group", "-v", "value"];
+    let mut single = query.clone();
+    single.extend(&["--threads", "1"]);
+    let single = setup_cmd(&single);
+    assert!(str::from_utf8(&single.stdout).unwrap().contains("0,d\n"));
+    for extra in &[
+        &["--threads", "4"][..],
+        &["--threads", "3"],
+        &["--threads", "1", "--max-cells", "2"],
+        &["--threads", "2", "--max-cells", "2"],
+    ] {
+        let mut split = query.clone();
+        split.extend(*extra);
+        assert_eq!(setup_cmd(&split).stdout, single.stdout, "{:?}", extra);
+    }
+}
+
+#[test]
+fn test_hint_groups() {
+    let args = [
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+    ];
+    let plain = setup_cmd(&args);
+    for extra in &[
+        &["--hint-groups", "1000"][..],
+        &["--hint-groups", "10", "--threads", "2"],
+    ] {
+        let mut hinted_args = args.to_vec();
+        hinted_args.extend(*extra);
+        let hinted = setup_cmd(&hinted_args);
+        assert!(hinted.status.success());
+        assert_eq!(hinted.stdout, plain.stdout);
+    }
+    let invalid = setup_cmd(&["count", "test_csvs/layoffs.csv", "--hint-groups", "-1"]);
+    assert_eq!(invalid.status.code(), Some(2));
+}
+
+#[test]
+fn test_max_cells() {
+    let args = [
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+        "--asc-rows",
+    ];
+    let in_memory = setup_cmd(&args);
+    let mut spilled_args = args.to_vec();
+    spilled_args.extend(&["--max-cells", "2"]);
+    let spilled = setup_cmd(&spilled_args);
+    assert!(spilled.status.success());
+    assert_eq!(spilled.stdout, in_memory.stdout);
+    spilled_args.push("--totals");
+    assert_eq!(setup_cmd(&spilled_args).status.code(), Some(2));
+}
+
+#[test]
+fn test_max_memory() {
+    let args = [
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+        "--asc-rows",
+    ];
+    let in_memory = setup_cmd(&args);
+    let mut limited_args = args.to_vec();
+    limited_args.extend(&["--max-memory", "100"]);
+    let limited = setup_cmd(&limited_args);
+    assert!(limited.status.success());
+    assert_eq!(limited.stdout, in_memory.stdout);
+    limited_args.push("--totals");
+    let too_large = setup_cmd(&limited_args);
+    assert_eq!(too_large.status.code(), Some(2));
+    assert!(str::from_utf8(&too_large.stderr)
+        .unwrap()
+        .contains("memory budget of 100 B"));
+    let mut invalid_args = args.to_vec();
+    invalid_args.extend(&["--max-memory", "lots"]);
+    assert_eq!(setup_cmd(&invalid_args).status.code(), Some(2));
+}
+
+#[test]
+fn test_multiple_files() {
+    let output = setup_cmd(&[
+        "sum",
+        "test_csvs/layoffs.csv",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "salary",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "department,false,true\nsales,170000,180000\nengineering,350000,150000\n"
+    );
+    let mismatched = setup_cmd(&[
+        "count",
+        "test_csvs/layoffs.csv",
+        "test_csvs/orders.csv",
+        "-r",
+        "0",
+    ]);
+    assert_eq!(mismatched.status.code(), Some(2));
+    assert!(str::from_utf8(&mismatched.stderr)
+        .unwrap()
+        .contains("Failed to aggregate test_csvs/orders.csv"));
+
+    // sums that only overflow once the files get merged don't blame either file
+    let path = std::env::temp_dir().join("clipivot_test_half_max.csv");
+    std::fs::write(&path, "group,value\nx,39614081257132168796771975168\n").unwrap();
+    let path = path.to_str().unwrap();
+    let overflow = setup_cmd(&["sum", path, path, "-r", "group", "-v", "value"]);
+    assert_eq!(overflow.status.code(), Some(3));
+    let stderr = str::from_utf8(&overflow.stderr).unwrap();
+    assert!(!stderr.contains("Failed to aggregate"), "{}", stderr);
+    assert!(!stderr.contains("with index"), "{}", stderr);
+    assert!(
+        stderr.contains("Could not combine the cell in row `x`"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_mode_multiple_files() {
+    let dir = std::env::temp_dir();
+    // every value in each file is tied with another one somewhere
+    let parts = [
+        "group,value\nx,2\ny,b\ny,a\n",
+        "group,value\nx,1\ny,a\ny,b\n",
+        "group,value\nx,1\ny,c\ny,c\n",
+    ];
+    let write = |name: String, contents: String| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let paths: Vec<String> = (0..parts.len())
+        .map(|i| {
+            write(
+                format!("clipivot_test_mode_part{}.csv", i),
+                parts[i].to_string(),
+            )
+        })
+        .collect();
+    let query = ["mode", "-r", "group", "-v", "value"];
+    let mode = |files: &[String], extra: &[&str]| {
+        let mut args = query.to_vec();
+        args.extend(files.iter().map(String::as_str));
+        args.extend(extra);
+        setup_cmd(&args).stdout
+    };
+    // the files get aggregated as if they were one file, however many threads read them
+    for count in 2..=parts.len() {
+        let rows: Vec<&str> = parts[..count]
+            .iter()
+            .flat_map(|part| part.lines().skip(1))
+            .collect();
+        let concatenated = write(
+            format!("clipivot_test_mode_parts{}.csv", count),
+            format!("group,value\n{}\n", rows.join("\n")),
+        );
+        let whole = mode(&[concatenated], &[]);
+        for threads in &["1", "2", "3"] {
+            assert_eq!(mode(&paths[..count], &["--threads", threads]), whole);
+        }
+    }
+    assert_eq!(
+        String::from_utf8(mode(&paths, &[])).unwrap(),
+        "group,total\nx,1\ny,a\n"
+    );
+}
+
+#[test]
+fn test_state() {
+    let state = std::env::temp_dir().join("clipivot_test_state.csv");
+    let _ = std::fs::remove_file(&state);
+    let state_arg = state.to_str().unwrap();
+    let args = [
+        "median",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "salary",
+        "--state",
+        state_arg,
+    ];
+    let first = setup_cmd(&args);
+    assert!(first.status.success());
+    assert_eq!(first.stdout, setup_cmd(&args[..8]).stdout);
+    // the second run merges the file into the state the first run saved
+    let second = setup_cmd(&args);
+    assert!(second.status.success());
+    let both_files = setup_cmd(&[
+        "median",
+        "test_csvs/layoffs.csv",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-c",
+        "was_fired",
+        "-v",
+        "salary",
+    ]);
+    assert_eq!(second.stdout, both_files.stdout);
+    let other_table = setup_cmd(&[
+        "mean",
+        "test_csvs/layoffs.csv",
+        "-v",
+        "salary",
+        "--state",
+        state_arg,
+    ]);
+    assert_eq!(other_table.status.code(), Some(2));
+    assert!(str::from_utf8(&other_table.stderr)
+        .unwrap()
+        .contains("different pivot table"));
+    let _ = std::fs::remove_file(&state);
+}
+
+#[test]
+fn test_checkpoint() {
+    let checkpoint = std::env::temp_dir().join("clipivot_test_checkpoint.csv");
+    let checkpoint_arg = checkpoint.to_str().unwrap();
+    let args = [
+        "sum",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+        "-v",
+        "salary",
+        "--checkpoint",
+        checkpoint_arg,
+        "--resume",
+    ];
+    // without a checkpoint to resume from, the file gets read from the start
+    let output = setup_cmd(&args);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, setup_cmd(&args[..6]).stdout);
+    assert!(!checkpoint.exists());
+    let mut threaded = args.to_vec();
+    threaded.extend(&["--threads", "2"]);
+    assert_eq!(setup_cmd(&threaded).status.code(), Some(2));
+    let mut sampled = args.to_vec();
+    sampled.extend(&["--sample", "0.5"]);
+    let sampled = setup_cmd(&sampled);
+    assert_eq!(sampled.status.code(), Some(2));
+    assert!(str::from_utf8(&sampled.stderr)
+        .unwrap()
+        .contains("--checkpoint can't be combined with sampling"));
+}
+
+#[test]
+fn test_bench() {
+    let output = setup_cmd(&[
+        "bench",
+        "sum",
+        "--synthetic",
+        "500",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--runs",
+        "2",
+    ]);
+    assert!(output.status.success());
+    // the table doesn't get written, only the timings
+    assert!(output.stdout.is_empty());
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.starts_with("Run 1: configured in "));
+    assert!(stderr.contains("aggregated 500 records in"));
+    assert!(stderr.contains("\nRun 2: "));
+    assert!(stderr.contains("Fastest of 2 runs: "));
+    let file = setup_cmd(&[
+        "bench",
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+    ]);
+    assert!(str::from_utf8(&file.stderr)
+        .unwrap()
+        .contains("Fastest of 3 runs"));
+    let outside_bench = setup_cmd(&["count", "test_csvs/layoffs.csv", "--runs", "2"]);
+    assert!(outside_bench.status.success());
+    assert!(str::from_utf8(&outside_bench.stderr)
+        .unwrap()
+        .contains("--runs only applies to `clipivot bench`"));
+}
+
+#[test]
+fn test_invalid_utf8_outside_selected_columns() {
+    let stdin_contents: &[u8] = b"state,notes,amount\nCA,caf\xe9,1\nCA,ok,2\n";
+    let output = Command::new(program_path!())
+        .args(vec!["sum", "-v", "amount", "-r", "state"])
+        .write_stdin(stdin_contents)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "state,total\nCA,3\n"
+    );
+    let output = Command::new(program_path!())
+        .args(vec!["count", "-v", "amount", "-r", "notes"])
+        .write_stdin(stdin_contents)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("Column 1 isn't valid UTF-8"));
+}
+
+#[test]
+fn test_totals() {
+    let args = vec![
+        "mean",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--by",
+        "year",
+        "-c",
+        "created",
+        "-v",
+        "amount",
+        "--totals",
+    ];
+    let output = setup_cmd(&args);
+    assert!(str::from_utf8(&output.stdout)
+        .unwrap()
+        .starts_with("region,2019,2020,Total\n"));
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "7.5", "", "7.5"],
+            vec!["west", "20", "30", "25"],
+            vec!["East", "15", "", "15"],
+            vec!["north", "", "25", "25"],
+            vec!["Total", "12.5", "27.5", "17.5"]
+        ]
+    );
+}
+
+#[test]
+fn test_subtotals() {
+    let args = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region,age",
+        "--bin",
+        "age:0,40,100",
+        "--subtotals",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![
+            vec!["east", "Total", "2"],
+            vec!["east", "[0,40)", "2"],
+            vec!["west", "Total", "2"],
+            vec!["west", "[40,100)", "1"],
+            vec!["west", "[0,40)", "1"],
+            vec!["East", "Total", "1"],
+            vec!["East", "[40,100)", "1"],
+            vec!["north", "Total", "1"],
+            vec!["north", "[40,100)", "1"]
+        ]
+    );
+}
+
+#[test]
+fn test_filter_regex() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--filter-regex",
+        "region:^[ew]",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "15"], vec!["west", "50"]]
+    );
+}
+
+#[test]
+fn test_date_range_filter() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--since",
+        "2019-01-10",
+        "--until",
+        "2019-02-14",
+        "--date-col",
+        "created",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["west", "20"], vec!["east", "5"], vec!["East", "15"]]
+    );
+    let missing_col = vec!["count", "test_csvs/orders.csv", "--since", "2019-01-10"];
+    assert!(!setup_cmd(&missing_col).status.success());
+}
+
+#[test]
+fn test_numeric_where() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--where",
+        "age>=35",
+        "--where",
+        "amount < 30",
+    ];
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["west", "20"], vec!["East", "15"], vec!["north", "25"]]
+    );
+    let not_numeric = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--where",
+        "region>3",
+    ];
+    assert!(!setup_cmd(&not_numeric).status.success());
+}
+
+#[test]
+fn test_to_sql() {
+    let args = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--to-sql",
+        "sqlite",
+    ];
+    let output = setup_cmd(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "SELECT\n    \"region\",\n    SUM(\"amount\") AS \"total\"\nFROM \"orders\"\nGROUP BY \"region\";\n"
+    );
+    let renamed = vec![
+        "count",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "--to-sql",
+        "mysql",
+        "--sql-table",
+        "sales",
+    ];
+    let output = setup_cmd(&renamed);
+    assert!(str::from_utf8(&output.stdout)
+        .unwrap()
+        .contains("FROM `sales`"));
+    let filtered = vec![
+        "sum",
+        "test_csvs/orders.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--where",
+        "age>=35",
+        "--to-sql",
+        "postgres",
+    ];
+    assert!(!setup_cmd(&filtered).status.success());
+}
+
+#[test]
+fn test_sum_overflow() {
+    for aggfunc in &["sum", "mean"] {
+        let args = vec![
+            aggfunc,
+            "test_csvs/overflow.csv",
+            "-r",
+            "region",
+            "-v",
+            "amount",
+        ];
+        let output = setup_cmd(&args);
+        assert_eq!(output.status.code(), Some(3));
+        let stderr = str::from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains("Could not parse record `1` with index 1"));
+        assert!(stderr.contains("overflowed"));
+    }
+}
+
+#[test]
+fn test_range_and_median_overflow() {
+    let path = std::env::temp_dir().join("clipivot_test_range_overflow.csv");
+    let max = "79228162514264337593543950335";
+    let contents = format!("amount\n-{0}\n{0}\n79228162514264337593543950334\n", max);
+    std::fs::write(&path, contents).unwrap();
+    let filename = path.to_str().unwrap();
+    let output = setup_cmd(&["range", filename, "-v", "amount"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("Could not parse record `79228162514264337593543950335` with index 1"));
+    assert!(stderr.contains("The range overflowed"));
+    // the median of the two largest values doesn't overflow either
+    std::fs::write(
+        &path,
+        format!("amount\n{}\n79228162514264337593543950334\n", max),
+    )
+    .unwrap();
+    let output = setup_cmd(&["median", filename, "-v", "amount"]);
+    assert!(output.status.success());
+    assert!(str::from_utf8(&output.stdout)
+        .unwrap()
+        .contains("total,7922816251426433759354395033"));
+}
+
+#[test]
+fn test_totals_overflow() {
+    let path = std::env::temp_dir().join("clipivot_test_totals_overflow.csv");
+    let max = "79228162514264337593543950335";
+    let contents = format!("region,office,amount\neast,a,{}\neast,b,1\nwest,a,5\n", max);
+    std::fs::write(&path, contents).unwrap();
+    let filename = path.to_str().unwrap();
+    let args = ["sum", filename, "-r", "region,office", "-v", "amount"];
+    assert!(setup_cmd(&args).status.success());
+    // every cell fits, but their totals don't
+    for extra in &["--totals", "--subtotals"] {
+        let mut totaled = args.to_vec();
+        totaled.push(extra);
+        let output = setup_cmd(&totaled);
+        assert_eq!(output.status.code(), Some(3), "{}", extra);
+        let stderr = str::from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains("The sum overflowed"));
+        assert!(stderr.contains("when adding up the cells of a total"));
+    }
+}
+
+#[test]
+fn test_json_errors() {
+    let output = setup_cmd(&[
+        "sum",
+        "test_csvs/overflow.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--errors",
+        "json",
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 1);
+    assert!(
+        stderr.starts_with("{\"class\": \"parsing\", \"message\": \"Could not parse record `1`")
+    );
+    assert!(stderr.contains("\"exit_code\": 3, \"record\": 1, \"value\": \"1\""));
+    assert!(stderr.contains("\"column\": \"amount\", \"column_index\": 1, \"line\": "));
+
+    let output = setup_cmd(&["sum", "--errors=json", "--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 1);
+    assert!(stderr.starts_with("{\"class\": \"configuration\", \"message\": "));
+    assert!(stderr.contains("'--bogus'"));
+    assert!(stderr.contains("\"column\": null"));
+
+    let output = setup_cmd(&["count", "test_csvs/missing.csv", "--errors", "json"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = str::from_utf8(&output.stderr).unwrap();
+    assert!(stderr.starts_with("{\"class\": \"io\""));
+}
+
+#[test]
+fn test_skip_bad_records() {
+    let args = vec![
+        "sum",
+        "test_csvs/bad_records.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--skip-bad-records",
+    ];
+    let output = setup_cmd(&args);
+    assert!(output.status.success());
+    assert!(str::from_utf8(&output.stderr).unwrap().contains(
+        "Skipped 6 records that couldn't be parsed (the first 5 on lines 3, 4, 6, 8, 9)"
+    ));
+    assert_eq!(
+        setup_results(&args),
+        vec![vec!["east", "11"], vec!["west", "5"]]
+    );
+    assert_eq!(setup_cmd(&args[..6]).status.code(), Some(3));
+
+    let mut max_errors = args.clone();
+    max_errors.extend(&["--max-errors", "6"]);
+    assert!(setup_cmd(&max_errors).status.success());
+    max_errors[8] = "5";
+    let output = setup_cmd(&max_errors);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(str::from_utf8(&output.stderr)
+        .unwrap()
+        .contains("More than 5 records couldn't be parsed (see --max-errors)"));
+    max_errors[8] = "1";
+    assert!(str::from_utf8(&setup_cmd(&max_errors).stderr)
+        .unwrap()
+        .contains("More than 1 record couldn't be parsed (see --max-errors)"));
+}
+
+#[test]
+fn test_bad_records_file() {
+    let rejects = std::env::temp_dir().join("clipivot_test_rejects.csv");
+    let output = setup_cmd(&[
+        "sum",
+        "test_csvs/bad_records.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--skip-bad-records",
+        "--bad-records",
+        rejects.to_str().unwrap(),
+        "--threads",
+        "1",
+    ]);
+    assert!(output.status.success());
+    let written = std::fs::read_to_string(&rejects).unwrap();
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(lines[0], "region,amount,reason");
+    assert!(lines[1].starts_with("east,ten,\"Could not parse record `ten` with index 1"));
+    assert!(lines[2].starts_with("west,5,5,\"CSV error: record 3 (line: 4"));
+    assert!(lines[3].starts_with("east,\"CSV error: record 5 (line: 6"));
+    let _ = std::fs::remove_file(&rejects);
+}
+
+#[test]
+fn test_validate() {
+    let output = setup_cmd(&[
+        "validate",
+        "sum",
+        "test_csvs/bad_records.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    let report = str::from_utf8(&output.stdout).unwrap();
+    assert!(report.starts_with("Read 9 records\n6 records can't be aggregated:\n  line 3: "));
+    // every bad record gets listed, rather than the first few
+    assert!(report.contains("  line 10: Could not parse record `z`"));
+    assert!(report.ends_with("Column types:\n  region: text\n  amount: text\n"));
+
+    let output = setup_cmd(&[
+        "validate",
+        "count",
+        "test_csvs/layoffs.csv",
+        "-r",
+        "department",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "Read 5 records\nEvery record can be aggregated\nColumn types:\n  \
+         id: number\n  was_fired: text\n  salary: number\n  department: text\n"
+    );
+}
+
+#[test]
+fn test_nan_policy() {
+    let args = |policy: &'static str| {
+        vec![
+            "max",
+            "test_csvs/nan_values.csv",
+            "-r",
+            "region",
+            "-v",
+            "amount",
+            "-N",
+            "--nan",
+            policy,
+        ]
+    };
+    assert_eq!(setup_cmd(&args("error")).status.code(), Some(3));
+    let skipped = setup_cmd(&args("skip"));
+    assert!(str::from_utf8(&skipped.stderr)
+        .unwrap()
+        .contains("Skipped 2 NaN or infinite values"));
+    assert_eq!(
+        setup_results(&args("skip")),
+        vec![vec!["east", "10"], vec!["west", "5"]]
+    );
+    assert_eq!(
+        setup_results(&args("propagate")),
+        vec![vec!["east", "NaN"], vec!["west", "5"]]
+    );
+    let sum = vec![
+        "sum",
+        "test_csvs/nan_values.csv",
+        "-r",
+        "region",
+        "-v",
+        "amount",
+        "--nan",
+        "propagate",
+    ];
+    assert_eq!(setup_cmd(&sum).status.code(), Some(3));
+}
@@ -0,0 +1,89 @@
+//! This module checks that `clipivot` writes byte-for-byte the same output every time it gets
+//! the same input and arguments, by comparing its output against files in `tests/golden`.
+//!
+//! Each case gets run several times, with one thread and with several (reading both a file
+//! and standard input), since the threads are the part of `clipivot` most likely to make the
+//! output depend on something other than the input. `test_csvs/golden_sales.csv` has a few
+//! thousand records, so that the records get split between the threads.
+//!
+//! If a change to `clipivot` is meant to change its output, you can rewrite the golden files
+//! by running the tests with `CLIPIVOT_UPDATE_GOLDEN=1` set (and then checking the diff).
+
+use assert_cmd::Command;
+use std::fs;
+use std::path::Path;
+
+#[macro_use]
+mod common;
+
+const INPUT: &str = "test_csvs/golden_sales.csv";
+
+/// The name of each golden file, along with the arguments that produce it (besides the input)
+const CASES: &[(&str, &[&str])] = &[
+    ("count", &["count", "-r", "region", "-c", "product"]),
+    (
+        "sum",
+        &["sum", "-r", "region", "-c", "quarter", "-v", "units"],
+    ),
+    ("mean", &["mean", "-r", "product", "-v", "price"]),
+    (
+        "stddev",
+        &["stddev", "-r", "region", "-c", "product", "-v", "price"],
+    ),
+    ("median", &["median", "-r", "region,quarter", "-v", "price"]),
+    (
+        "mode",
+        &["mode", "-r", "region", "-c", "quarter", "-v", "product"],
+    ),
+    ("minmax", &["minmax", "-r", "product", "-v", "price", "-N"]),
+    (
+        "totals",
+        &[
+            "sum", "-r", "product", "-c", "region", "-v", "price", "--totals",
+        ],
+    ),
+];
+
+fn run(args: &[&str], threads: &str, stdin: bool) -> Vec<u8> {
+    let mut cmd = Command::new(program_path!());
+    cmd.args(args).args(["--threads", threads]);
+    if stdin {
+        cmd.write_stdin(fs::read(INPUT).unwrap());
+    } else {
+        cmd.arg(INPUT);
+    }
+    let output = cmd.output().expect("Processed failed to execute");
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+#[test]
+fn test_golden_files() {
+    for (name, args) in CASES {
+        let path = Path::new("tests/golden").join(format!("{}.csv", name));
+        let expected = run(args, "1", false);
+        if std::env::var_os("CLIPIVOT_UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &expected).unwrap();
+        }
+        let golden = fs::read(&path).unwrap();
+        assert!(expected == golden, "{} doesn't match {:?}", name, path);
+        for &threads in &["1", "3", "4"] {
+            for &stdin in &[false, true] {
+                for _ in 0..2 {
+                    assert!(
+                        run(args, threads, stdin) == golden,
+                        "{} changed with {} threads (reading from standard input: {})",
+                        name,
+                        threads,
+                        stdin
+                    );
+                }
+            }
+        }
+    }
+}
@@ -35,7 +35,7 @@ fn get_actual_result(filename: &str, aggfunc: &str) -> f64 {
     // the match formatting is required to get these tests to work in Travis CI
     let program_name = program_path!();
     let output = Command::new(program_name)
-        .args(&[aggfunc, filename, "-v", "0"])
+        .args([aggfunc, filename, "-v", "0"])
         .output()
         .expect("Process failed to execute")
         .stdout;
@@ -154,3 +154,10 @@ fn test_lew_mean() {
     let result = get_actual_result("test_csvs/Lew.csv", "mean");
     assert_abs_diff_eq!(result, -177.435000000000, epsilon = mean_epsilon());
 }
+
+#[test]
+fn test_huge_values_std() {
+    // the squares of these values overflow, which shouldn't look like a standard deviation of 0
+    let result = get_actual_result("test_csvs/HugeValues.csv", "stddev");
+    assert!(result.is_infinite(), "{}", result);
+}